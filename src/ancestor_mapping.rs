@@ -1,5 +1,8 @@
 use lazy_static::lazy_static;
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{Hash, Hasher},
+};
 use thiserror::Error;
 
 lazy_static! {
@@ -33,6 +36,10 @@ pub struct AncestorMapping {
 pub enum RootMappingError {
     #[error("Mapping for ancestor '{0}' already exists and cannot be overwritten")]
     DuplicateAncestor(String),
+    #[error("The ancestor environments {0:?} are not mapped to any environment")]
+    UnmappedAncestors(Vec<String>),
+    #[error("The ancestor environment '{0}' is mapped to several environments {1:?}, it can only be mapped to one")]
+    AmbiguousAncestor(String, Vec<String>),
 }
 
 impl AncestorMapping {
@@ -44,9 +51,35 @@ impl AncestorMapping {
         }
     }
 
+    /// Creates a mapping from the ancestor environments of each space environment.
+    ///
+    /// An ancestor environment listed for several space environments is an error naming all of them,
+    /// the first one in sorted order is reported if there are several.
     pub fn from_space_to_ancestors(
         space_to_ancestors: HashMap<String, HashSet<String>>,
     ) -> Result<Self, RootMappingError> {
+        let mut spaces_by_ancestor: HashMap<&String, Vec<String>> = HashMap::new();
+        for (space, ancestors) in &space_to_ancestors {
+            for ancestor in ancestors {
+                spaces_by_ancestor
+                    .entry(ancestor)
+                    .or_default()
+                    .push(space.clone());
+            }
+        }
+        let mut ambiguous: Vec<_> = spaces_by_ancestor
+            .into_iter()
+            .filter(|(_, spaces)| spaces.len() > 1)
+            .collect();
+        ambiguous.sort();
+        if let Some((ancestor, mut spaces)) = ambiguous.into_iter().next() {
+            spaces.sort();
+            return Err(RootMappingError::AmbiguousAncestor(
+                ancestor.clone(),
+                spaces,
+            ));
+        }
+
         let mut mapping = AncestorMapping::new();
         for (space, ancestors) in space_to_ancestors {
             for ancestor in ancestors {
@@ -74,7 +107,10 @@ impl AncestorMapping {
     /// # Example
     ///
     /// ```rust
+    /// # use weaveconfig::AncestorMapping;
+    /// # let mut root_mapping = AncestorMapping::new();
     /// root_mapping.add_mapping("prod1".to_string(), "prod".to_string()).unwrap();
+    /// assert!(root_mapping.add_mapping("prod1".to_string(), "dev".to_string()).is_err());
     /// ```
     pub fn add_mapping(&mut self, ancestor: String, space: String) -> Result<(), RootMappingError> {
         if self.ancestor_to_space.contains_key(&ancestor) {
@@ -88,7 +124,7 @@ impl AncestorMapping {
         // Add the ancestor to the space_to_ancestor map.
         self.space_to_ancestor
             .entry(space)
-            .or_insert_with(HashSet::new)
+            .or_default()
             .insert(ancestor);
 
         Ok(())
@@ -112,7 +148,11 @@ impl AncestorMapping {
     /// # Example
     ///
     /// ```rust
+    /// # use weaveconfig::AncestorMapping;
+    /// # let mut root_mapping = AncestorMapping::new();
+    /// # root_mapping.add_mapping("prod1".to_string(), "prod".to_string()).unwrap();
     /// let previous = root_mapping.replace_mapping("prod1".to_string(), "staging".to_string());
+    /// assert_eq!(previous, Some("prod".to_string()));
     /// ```
     pub fn replace_mapping(&mut self, ancestor: String, new_space: String) -> Option<String> {
         // Remove the existing mapping if it exists.
@@ -133,7 +173,7 @@ impl AncestorMapping {
             // Add the ancestor to the new space's set.
             self.space_to_ancestor
                 .entry(new_space)
-                .or_insert_with(HashSet::new)
+                .or_default()
                 .insert(ancestor.clone());
 
             // Return the previous space.
@@ -157,7 +197,11 @@ impl AncestorMapping {
     /// # Example
     ///
     /// ```rust
-    /// let removed = root_mapping.remove_mapping_by_ancestor("prod1".to_string());
+    /// # use weaveconfig::AncestorMapping;
+    /// # let mut root_mapping = AncestorMapping::new();
+    /// # root_mapping.add_mapping("prod1".to_string(), "prod".to_string()).unwrap();
+    /// let removed = root_mapping.remove_mapping_by_ancestor(&"prod1".to_string());
+    /// assert!(removed);
     /// ```
     pub fn remove_mapping_by_ancestor(&mut self, ancestor: &String) -> bool {
         if let Some(space) = self.ancestor_to_space.remove(ancestor) {
@@ -186,7 +230,11 @@ impl AncestorMapping {
     /// # Example
     ///
     /// ```rust
-    /// let removed = root_mapping.remove_mapping_by_space("prod".to_string());
+    /// # use weaveconfig::AncestorMapping;
+    /// # let mut root_mapping = AncestorMapping::new();
+    /// # root_mapping.add_mapping("prod1".to_string(), "prod".to_string()).unwrap();
+    /// let removed = root_mapping.remove_mapping_by_space(&"prod".to_string());
+    /// assert!(removed);
     /// ```
     pub fn remove_mapping_by_space(&mut self, space: &String) -> bool {
         if let Some(ancestors) = self.space_to_ancestor.remove(space) {
@@ -212,6 +260,9 @@ impl AncestorMapping {
     /// # Example
     ///
     /// ```rust
+    /// # use weaveconfig::AncestorMapping;
+    /// # let mut root_mapping = AncestorMapping::new();
+    /// # root_mapping.add_mapping("prod1".to_string(), "prod".to_string()).unwrap();
     /// if let Some(space) = root_mapping.get_space(&"prod1".to_string()) {
     ///     println!("prod1 maps to {}", space);
     /// }
@@ -228,15 +279,16 @@ impl AncestorMapping {
     ///
     /// # Returns
     ///
-    /// `Some(&HashSet<String>)` if the space exists, `None` otherwise.
+    /// The ancestor environments, empty if the space does not exist.
     ///
     /// # Example
     ///
     /// ```rust
-    /// if let Some(ancestors) = root_mapping.get_ancestors(&"prod".to_string()) {
-    ///     for ancestor in ancestors {
-    ///         println!("prod is mapped by {}", ancestor);
-    ///     }
+    /// # use weaveconfig::AncestorMapping;
+    /// # let mut root_mapping = AncestorMapping::new();
+    /// # root_mapping.add_mapping("prod1".to_string(), "prod".to_string()).unwrap();
+    /// for ancestor in root_mapping.get_ancestors(&"prod".to_string()) {
+    ///     println!("prod is mapped by {}", ancestor);
     /// }
     /// ```
     pub fn get_ancestors(&self, space: &String) -> &HashSet<String> {
@@ -254,6 +306,9 @@ impl AncestorMapping {
     /// # Example
     ///
     /// ```rust
+    /// # use weaveconfig::AncestorMapping;
+    /// # let mut root_mapping = AncestorMapping::new();
+    /// # root_mapping.add_mapping("prod1".to_string(), "prod".to_string()).unwrap();
     /// for (ancestor, space) in root_mapping.list_ancestor_to_space() {
     ///     println!("{} -> {}", ancestor, space);
     /// }
@@ -271,6 +326,9 @@ impl AncestorMapping {
     /// # Example
     ///
     /// ```rust
+    /// # use weaveconfig::AncestorMapping;
+    /// # let mut root_mapping = AncestorMapping::new();
+    /// # root_mapping.add_mapping("prod1".to_string(), "prod".to_string()).unwrap();
     /// for (space, ancestors) in root_mapping.list_space_to_ancestor() {
     ///     println!("{} -> {:?}", space, ancestors);
     /// }
@@ -292,6 +350,9 @@ impl AncestorMapping {
     /// # Example
     ///
     /// ```rust
+    /// # use weaveconfig::AncestorMapping;
+    /// # let mut root_mapping = AncestorMapping::new();
+    /// # root_mapping.add_mapping("prod1".to_string(), "prod".to_string()).unwrap();
     /// if root_mapping.contains_ancestor(&"prod1".to_string()) {
     ///     println!("prod1 exists in the mapping.");
     /// }
@@ -313,6 +374,9 @@ impl AncestorMapping {
     /// # Example
     ///
     /// ```rust
+    /// # use weaveconfig::AncestorMapping;
+    /// # let mut root_mapping = AncestorMapping::new();
+    /// # root_mapping.add_mapping("prod1".to_string(), "prod".to_string()).unwrap();
     /// if root_mapping.contains_space(&"prod".to_string()) {
     ///     println!("prod space exists in the mapping.");
     /// }
@@ -321,12 +385,74 @@ impl AncestorMapping {
         self.space_to_ancestor.contains_key(space)
     }
 
+    /// Returns a canonical form of the mapping, all ancestor to space mappings sorted by ancestor.
+    ///
+    /// The space to ancestor mappings are derived from these, so two mappings are equal
+    /// exactly when their canonical forms are equal. This is also what the mapping is hashed by.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use weaveconfig::AncestorMapping;
+    /// # let mut root_mapping = AncestorMapping::new();
+    /// # root_mapping.add_mapping("prod1".to_string(), "prod".to_string()).unwrap();
+    /// for (ancestor, space) in root_mapping.canonical() {
+    ///     println!("{} -> {}", ancestor, space);
+    /// }
+    /// ```
+    pub fn canonical(&self) -> Vec<(&String, &String)> {
+        let mut pairs: Vec<(&String, &String)> = self.ancestor_to_space.iter().collect();
+        pairs.sort();
+        pairs
+    }
+
+    /// Checks that every ancestor environment is mapped to a space environment.
+    /// Unmapped ancestor environments are dropped when inheriting, so selecting them selects nothing.
+    ///
+    /// # Arguments
+    ///
+    /// * `ancestors` - The environments of the ancestor.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` if every ancestor environment is mapped.
+    /// * `Err(RootMappingError::UnmappedAncestors)` with the unmapped ones, in the given order.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use weaveconfig::AncestorMapping;
+    /// # let mut root_mapping = AncestorMapping::new();
+    /// # root_mapping.add_mapping("prod1".to_string(), "prod".to_string()).unwrap();
+    /// let parent_environments = vec!["prod1".to_string(), "prod2".to_string()];
+    /// assert!(root_mapping.validate(&parent_environments).is_err());
+    /// ```
+    pub fn validate<'a>(
+        &self,
+        ancestors: impl IntoIterator<Item = &'a String>,
+    ) -> Result<(), RootMappingError> {
+        let unmapped: Vec<String> = ancestors
+            .into_iter()
+            .filter(|ancestor| !self.contains_ancestor(ancestor))
+            .cloned()
+            .collect();
+        if unmapped.is_empty() {
+            Ok(())
+        } else {
+            Err(RootMappingError::UnmappedAncestors(unmapped))
+        }
+    }
+
     /// Clears all mappings.
     ///
     /// # Example
     ///
     /// ```rust
+    /// # use weaveconfig::AncestorMapping;
+    /// # let mut root_mapping = AncestorMapping::new();
+    /// # root_mapping.add_mapping("prod1".to_string(), "prod".to_string()).unwrap();
     /// root_mapping.clear();
+    /// assert!(!root_mapping.contains_space(&"prod".to_string()));
     /// ```
     pub fn clear(&mut self) {
         self.space_to_ancestor.clear();
@@ -334,9 +460,16 @@ impl AncestorMapping {
     }
 }
 
+impl Hash for AncestorMapping {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.canonical().hash(state);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::hash_map::DefaultHasher;
 
     #[test]
     fn test_add_mapping_success() {
@@ -525,4 +658,68 @@ mod tests {
         assert!(mapping.contains_space(&"prod".to_string()));
         assert!(!mapping.contains_space(&"test".to_string()));
     }
+
+    #[test]
+    fn test_canonical_equality_and_hash() {
+        let mut first = AncestorMapping::new();
+        first
+            .add_mapping("prod1".to_string(), "prod".to_string())
+            .unwrap();
+        first
+            .add_mapping("dev".to_string(), "dev".to_string())
+            .unwrap();
+        first
+            .add_mapping("prod2".to_string(), "prod".to_string())
+            .unwrap();
+
+        let mut second = AncestorMapping::new();
+        second
+            .add_mapping("prod2".to_string(), "prod".to_string())
+            .unwrap();
+        second
+            .add_mapping("prod1".to_string(), "prod".to_string())
+            .unwrap();
+        second
+            .add_mapping("dev".to_string(), "dev".to_string())
+            .unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(
+            first.canonical(),
+            vec![
+                (&"dev".to_string(), &"dev".to_string()),
+                (&"prod1".to_string(), &"prod".to_string()),
+                (&"prod2".to_string(), &"prod".to_string()),
+            ]
+        );
+
+        let hash = |mapping: &AncestorMapping| {
+            let mut hasher = DefaultHasher::new();
+            mapping.hash(&mut hasher);
+            hasher.finish()
+        };
+        assert_eq!(hash(&first), hash(&second));
+
+        second.replace_mapping("prod2".to_string(), "staging".to_string());
+        assert_ne!(first, second);
+        assert_ne!(hash(&first), hash(&second));
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut mapping = AncestorMapping::new();
+        mapping
+            .add_mapping("prod1".to_string(), "prod".to_string())
+            .unwrap();
+        mapping
+            .add_mapping("dev".to_string(), "dev".to_string())
+            .unwrap();
+
+        let ancestors = ["dev", "staging", "prod1", "prod2"].map(String::from);
+        assert!(matches!(
+            mapping.validate(&ancestors),
+            Err(RootMappingError::UnmappedAncestors(unmapped)) if unmapped == vec!["staging", "prod2"]
+        ));
+        assert!(mapping.validate(&ancestors[..1]).is_ok());
+    }
 }
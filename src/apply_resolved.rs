@@ -1,5 +1,5 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap},
     path::{Path, PathBuf},
 };
 
@@ -8,75 +8,299 @@ use futures::{stream::FuturesUnordered, StreamExt};
 use serde_json::{Map, Value};
 
 use crate::{
+    cancellation::CancellationToken,
+    file_system::FileSystem,
     get_environment_value::get_environment_value,
-    map_path::map_path,
-    merging::merge_values_consume,
-    resolve_spaces::ResolvedSpace,
+    map_path::map_canonical_path,
+    resolve_spaces::{CopyOutput, ResolvedSpace},
+    schemas::{apply_final_newline, ConfigFormat},
     space_graph::{CopyTree, ToCopy},
-    template_file::template_file,
+    template_file::{collect_references, condition_reference, evaluate_condition, template_file},
     ts_binding::generate_binding::generate_binding,
     write_json_file::write_json_file,
 };
 
-async fn gen_folder(real_path: &PathBuf) -> Result<PathBuf, anyhow::Error> {
+async fn gen_folder(fs: &dyn FileSystem, real_path: &Path) -> Result<PathBuf, anyhow::Error> {
     let gen_folder = real_path.join("gen");
-    if !gen_folder.exists() {
-        tokio::fs::create_dir_all(&gen_folder).await?;
-    }
+    create_dir(fs, &gen_folder).await?;
     Ok(gen_folder)
 }
 
+/// Creates a directory and its parents if they are missing.
+/// Spaces are applied concurrently and can share parent directories, so a directory created by
+/// another space in the meantime is not an error.
+async fn create_dir(fs: &dyn FileSystem, path: &Path) -> Result<(), anyhow::Error> {
+    match fs.create_dir(path).await {
+        Err(error)
+            if error.kind() == std::io::ErrorKind::AlreadyExists
+                && fs
+                    .metadata(path)
+                    .await
+                    .is_ok_and(|metadata| metadata.is_dir) =>
+        {
+            Ok(())
+        }
+        result => result.with_context(|| format!("Failed to create directory: {:?}", path)),
+    }
+}
+
+/// Maps a directory in the weaveconfig directory to the directory its output is written to,
+/// like [`crate::map_path::map_path`] but canonicalizing within `fs`.
+async fn output_path(
+    fs: &dyn FileSystem,
+    weave_config_root: &Path,
+    path: &Path,
+    out_dir: Option<&Path>,
+) -> Result<PathBuf, anyhow::Error> {
+    let canonical_root = fs.canonicalize(weave_config_root).await.with_context(|| {
+        format!(
+            "Failed to canonicalize root path: {}",
+            weave_config_root.display()
+        )
+    })?;
+    let canonical_path = fs
+        .canonicalize(path)
+        .await
+        .with_context(|| format!("Failed to canonicalize path: {}", path.display()))?;
+    map_canonical_path(&canonical_root, &canonical_path, out_dir)
+}
+
+/// Statistics about a generation, accumulated while applying the resolved spaces.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ApplyStats {
+    /// The number of spaces that were applied.
+    pub spaces: usize,
+    /// The number of environments across all applied spaces.
+    pub environments: usize,
+    /// The number of files copied from the weaveconfig directory, counting every environment.
+    pub files_copied: usize,
+    /// The number of files symlinked from the weaveconfig directory instead of copied, see `_weavelink`.
+    pub files_linked: usize,
+    /// The number of times a copied file was templated. A `_forenv` file that renders identically
+    /// for several environments is templated once for them, see [`minimal_environments`].
+    pub files_rendered: usize,
+    /// The number of files written, both generated and copied.
+    pub files_written: usize,
+    /// The number of bytes written across all files.
+    pub bytes_written: usize,
+    /// The typescript bindings written unformatted, as formatting them took too long.
+    pub unformatted_bindings: Vec<PathBuf>,
+}
+
+impl ApplyStats {
+    pub(crate) fn record_write(&mut self, bytes: usize) {
+        self.files_written += 1;
+        self.bytes_written += bytes;
+    }
+
+    pub(crate) fn add(&mut self, other: ApplyStats) {
+        self.spaces += other.spaces;
+        self.environments += other.environments;
+        self.files_copied += other.files_copied;
+        self.files_linked += other.files_linked;
+        self.files_rendered += other.files_rendered;
+        self.files_written += other.files_written;
+        self.bytes_written += other.bytes_written;
+        self.unformatted_bindings.extend(other.unformatted_bindings);
+    }
+}
+
+/// Applies all resolved spaces. With `out_dir`, the outputs are written below it instead of
+/// next to the weaveconfig directory, creating the directories the spaces map to.
 pub async fn apply_resolved(
-    spaces: HashMap<String, ResolvedSpace>,
+    spaces: &HashMap<String, ResolvedSpace>,
     weave_config_root: &Path,
-) -> Result<(), anyhow::Error> {
+    out_dir: Option<&Path>,
+    all_config: bool,
+    cancel: &CancellationToken,
+    fs: &dyn FileSystem,
+) -> Result<ApplyStats, anyhow::Error> {
+    let mut stats = ApplyStats::default();
+    if all_config {
+        cancel.check()?;
+        stats.record_write(write_all_config(spaces, weave_config_root, out_dir, fs).await?);
+    }
     let mut futures = FuturesUnordered::new();
-    for (_, space) in spaces {
-        let real_path = map_path(weave_config_root, &space.path)?;
-        futures.push(apply_space(space, real_path));
+    for space in spaces.values() {
+        let real_path = output_path(fs, weave_config_root, &space.path, out_dir).await?;
+        if out_dir.is_some() {
+            create_dir(fs, &real_path).await?;
+        }
+        futures.push(apply_space(space, real_path, cancel, fs));
     }
     while let Some(result) = futures.next().await {
-        result?;
+        stats.add(result?);
     }
-    Ok(())
+    Ok(stats)
+}
+
+/// The name of the file aggregating the variables of all spaces, see [`write_all_config`].
+pub const ALL_CONFIG_FILE_NAME: &str = "all-config.json";
+
+/// Writes the `all-config.json` mapping each space name to its resolved variables, sorted by name,
+/// into the directory the weaveconfig root maps to. Returns the number of bytes written.
+pub async fn write_all_config(
+    spaces: &HashMap<String, ResolvedSpace>,
+    weave_config_root: &Path,
+    out_dir: Option<&Path>,
+    fs: &dyn FileSystem,
+) -> Result<usize, anyhow::Error> {
+    let all_config: BTreeMap<&str, Value> = spaces
+        .values()
+        .map(|space| {
+            let variables = space.variables.clone().unwrap_or_default();
+            (space.name.as_str(), Value::Object(variables))
+        })
+        .collect();
+    let content = serde_json::to_string_pretty(&all_config)?;
+    let path = output_path(fs, weave_config_root, weave_config_root, out_dir)
+        .await?
+        .join(ALL_CONFIG_FILE_NAME);
+    fs.write(&path, content.as_bytes())
+        .await
+        .with_context(|| format!("Failed to write {:?}", path))?;
+    Ok(content.len())
 }
 
-async fn apply_space(space: ResolvedSpace, real_path: PathBuf) -> Result<(), anyhow::Error> {
-    if !real_path.exists() {
+/// Applies a single resolved space, writing its generated files and copying its files
+/// to the directory the space maps to.
+/// This allows callers that only resolved or changed one space to write just that space.
+pub async fn apply_space_public(
+    space: &ResolvedSpace,
+    weave_config_root: &Path,
+    fs: &dyn FileSystem,
+) -> Result<ApplyStats, anyhow::Error> {
+    let real_path = output_path(fs, weave_config_root, &space.path, None).await?;
+    apply_space(space, real_path, &CancellationToken::new(), fs).await
+}
+
+async fn apply_space(
+    space: &ResolvedSpace,
+    real_path: PathBuf,
+    cancel: &CancellationToken,
+    fs: &dyn FileSystem,
+) -> Result<ApplyStats, anyhow::Error> {
+    cancel.check()?;
+    if fs.metadata(&real_path).await.is_err() {
         return Err(anyhow::anyhow!(
             "Could not output to path, does not exist: {}",
             real_path.display()
         ));
     }
+    let mut stats = ApplyStats {
+        spaces: 1,
+        environments: space.environments.len(),
+        ..Default::default()
+    };
     if space.generate.generate && space.variables.is_some() {
-        let gen_folder = gen_folder(&real_path).await?;
-        write_gitignore(&gen_folder).await?;
-        write_json_file(&space, &gen_folder).await?;
+        if space.generate.typescript
+            && !space.generate.declaration_only
+            && space.generate.config_format != ConfigFormat::Json
+        {
+            return Err(anyhow::anyhow!(
+                "The space {:?} generates typescript bindings, which read config.json, but its config format is {:?}. Disable typescript or use the json config format.",
+                space.name,
+                space.generate.config_format
+            ));
+        }
+        let gen_folder = gen_folder(fs, &real_path).await?;
+        if let Some(bytes) = write_gitignore(fs, space, &gen_folder).await? {
+            stats.record_write(bytes);
+        }
+        stats.record_write(write_json_file(fs, space, &gen_folder).await?);
         if space.generate.typescript {
-            generate_binding(&space, &gen_folder).await?;
+            let (bytes, unformatted) = generate_binding(fs, space, &gen_folder).await?;
+            stats.record_write(bytes);
+            if unformatted {
+                stats
+                    .unformatted_bindings
+                    .push(gen_folder.join(binding_file_name(space)));
+            }
         }
     }
-    write_to_copy(&space, &real_path).await?;
-    Ok(())
+    write_to_copy(space, &real_path, &mut stats, cancel, fs).await?;
+    Ok(stats)
+}
+
+/// The names of the files generated into the gen folder of a space.
+fn generated_files(space: &ResolvedSpace) -> Vec<&'static str> {
+    let mut files = vec![space.generate.config_format.file_name()];
+    if space.generate.typescript {
+        files.push(binding_file_name(space));
+    }
+    files
+}
+
+/// The name of the typescript binding of a space.
+pub(crate) fn binding_file_name(space: &ResolvedSpace) -> &'static str {
+    if space.generate.declaration_only {
+        "binding.d.ts"
+    } else {
+        "binding.ts"
+    }
 }
 
-async fn write_gitignore(gen_folder: &PathBuf) -> Result<(), anyhow::Error> {
+/// Writes the .gitignore of the gen folder, listing the files generated for the space.
+/// An existing .gitignore keeps its lines, including ones added by hand, the generated files it
+/// does not list yet are appended. It is only written if something is missing, so it is not
+/// touched on every run. Returns the number of bytes written, if it was written.
+async fn write_gitignore(
+    fs: &dyn FileSystem,
+    space: &ResolvedSpace,
+    gen_folder: &Path,
+) -> Result<Option<usize>, anyhow::Error> {
     let gitignore_path = gen_folder.join(".gitignore");
-    if !gitignore_path.exists() {
-        tokio::fs::write(gitignore_path, "config.json\nbinding.ts\n").await?;
+    let mut content = fs.read_to_string(&gitignore_path).await.unwrap_or_default();
+    let missing: Vec<&str> = generated_files(space)
+        .into_iter()
+        .filter(|file| !content.lines().any(|line| line.trim() == *file))
+        .collect();
+    if missing.is_empty() {
+        return Ok(None);
     }
-    Ok(())
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
+    }
+    for file in missing {
+        content.push_str(file);
+        content.push('\n');
+    }
+    fs.write(&gitignore_path, content.as_bytes()).await?;
+    Ok(Some(content.len()))
+}
+
+/// The state shared while copying the files of a space.
+struct CopyContext<'a> {
+    space: &'a ResolvedSpace,
+    /// The directory the space maps to, copied files are placed relative to it.
+    output_root: &'a Path,
+    stats: &'a mut ApplyStats,
+    cancel: &'a CancellationToken,
+    fs: &'a dyn FileSystem,
 }
 
 // Function to write files and directories to be copied
-async fn write_to_copy(space: &ResolvedSpace, real_path: &Path) -> Result<(), anyhow::Error> {
+async fn write_to_copy(
+    space: &ResolvedSpace,
+    real_path: &Path,
+    stats: &mut ApplyStats,
+    cancel: &CancellationToken,
+    fs: &dyn FileSystem,
+) -> Result<(), anyhow::Error> {
+    let mut ctx = CopyContext {
+        space,
+        output_root: real_path,
+        stats,
+        cancel,
+        fs,
+    };
     // Copy the tree structure with files and directories
     copy_tree(
         &space.files_to_copy,
         real_path,
         None,
         &space.variables,
-        &space.environments,
+        &mut ctx,
     )
     .await
     .with_context(|| format!("Failed to copy tree structure for: {}", real_path.display()))?;
@@ -84,64 +308,84 @@ async fn write_to_copy(space: &ResolvedSpace, real_path: &Path) -> Result<(), an
     Ok(())
 }
 
-// Recursive function to copy a tree of files and directories
+/// The variant a `_forenv` file or directory is copied as.
+#[derive(Debug, Clone, Copy)]
+enum ForEnv<'a> {
+    /// A copy for a single environment, using the variables of that environment.
+    Env(&'a str),
+    /// The additional default copy, using only the top-level variables.
+    Default(&'a str),
+}
+
+impl<'a> ForEnv<'a> {
+    /// The name `_forenv` is replaced with.
+    fn name(&self) -> &'a str {
+        match self {
+            ForEnv::Env(name) | ForEnv::Default(name) => name,
+        }
+    }
+
+    fn env(&self) -> Option<&'a str> {
+        match self {
+            ForEnv::Env(env) => Some(env),
+            ForEnv::Default(_) => None,
+        }
+    }
+}
+
+// Recursive function to copy a tree of files and directories.
+// Outside of a `_forenv` directory (`for_env` is `None`), each `_forenv` name is expanded once per
+// environment. Within one, the copy is already for a single environment, so nested `_forenv` names
+// are substituted with that environment instead of being expanded again: `_forenv/_forenv.txt`
+// becomes `dev/dev.txt` and `prod/prod.txt`, never `dev/prod.txt`.
 async fn copy_tree(
     copytree: &CopyTree,
     copy_into: &Path,
-    env: Option<&str>,
+    for_env: Option<ForEnv<'_>>,
     variables: &Option<Map<String, Value>>,
-    environments: &HashSet<String>,
+    ctx: &mut CopyContext<'_>,
 ) -> Result<(), anyhow::Error> {
     for to_copy in &copytree.to_copy {
-        let prefix = "_forenv";
         // Check if the file/directory name needs environment-specific substitution
         if needs_substitution(
-            &to_copy
+            to_copy
                 .last_segment()
                 .with_context(|| format!("Failed to get last segment for {:?}", to_copy))?,
-            prefix,
+            &ctx.space.forenv_token,
         ) {
-            match env {
+            match for_env {
                 // If environment is specified, copy with that environment
-                Some(env) => {
-                    copy_tocopy_with_env(to_copy, copy_into, Some(env), variables, environments)
+                Some(for_env) => {
+                    copy_tocopy_with_env(to_copy, copy_into, Some(for_env), variables, ctx)
                         .await
                         .with_context(|| {
-                            format!("Failed to copy {:?} with environment: {}", to_copy, env)
+                            format!(
+                                "Failed to copy {:?} with environment: {}",
+                                to_copy,
+                                for_env.name()
+                            )
                         })?;
                 }
+                // Files are rendered once for the environments they render identically for
+                None if matches!(to_copy, ToCopy::File(_)) => {
+                    copy_forenv_file(to_copy, copy_into, variables, ctx)
+                        .await
+                        .with_context(|| format!("Failed to copy {:?}", to_copy))?;
+                }
                 // If no environment is specified, copy for all environments
                 None => {
-                    for env in environments {
-                        // Get environment-specific variables
-                        let variables = match variables {
-                            Some(variables) => {
-                                Some(get_environment_value(variables, env).with_context(|| {
-                                    format!(
-                                        "Failed to get environment value for '{}' in {:?}",
-                                        env, variables
-                                    )
-                                })?)
-                            }
-                            None => None,
-                        };
-                        copy_tocopy_with_env(
-                            to_copy,
-                            copy_into,
-                            Some(env),
-                            &variables,
-                            environments,
-                        )
-                        .await
-                        .with_context(|| {
-                            format!("Failed to copy {:?} for environment: {}", to_copy, env)
-                        })?;
+                    for (for_env, variables) in forenv_variants(ctx.space, variables)? {
+                        copy_tocopy_with_env(to_copy, copy_into, Some(for_env), &variables, ctx)
+                            .await
+                            .with_context(|| {
+                                format!("Failed to copy {:?} as: {}", to_copy, for_env.name())
+                            })?;
                     }
                 }
             }
         } else {
             // If no environment substitution is needed, copy without environment
-            copy_tocopy_with_env(to_copy, copy_into, None, variables, environments)
+            copy_tocopy_with_env(to_copy, copy_into, for_env, variables, ctx)
                 .await
                 .with_context(|| {
                     format!(
@@ -155,90 +399,1472 @@ async fn copy_tree(
     Ok(())
 }
 
+/// Copies a `_forenv` file for every environment and the default variant. Variants that see the same
+/// values for every variable the file references share a single rendering.
+async fn copy_forenv_file(
+    to_copy: &ToCopy,
+    copy_into: &Path,
+    variables: &Option<Map<String, Value>>,
+    ctx: &mut CopyContext<'_>,
+) -> Result<(), anyhow::Error> {
+    let ToCopy::File(file) = to_copy else {
+        return Err(anyhow::anyhow!("{:?} is not a file", to_copy));
+    };
+    let content = ctx
+        .fs
+        .read_to_string(file)
+        .await
+        .with_context(|| format!("Failed to read file: {:?}", file))?;
+    let references = template_references(&content)?;
+    // The output path differs for every variant
+    let shared = !references.iter().any(|reference| reference == &["__path"]);
+
+    let mut rendered: HashMap<String, Option<String>> = HashMap::new();
+    for (for_env, variables) in forenv_variants(ctx.space, variables)? {
+        ctx.cancel.check()?;
+        let template_variables = template_variables(ctx.space, &variables, Some(for_env))?;
+        let destination = copy_into.join(destination_name(
+            to_copy,
+            &ctx.space.forenv_token,
+            Some(for_env),
+            &template_variables,
+        )?);
+        let key = shared
+            .then(|| render_key(&references, &template_variables))
+            .flatten();
+        let output = match key.as_ref().and_then(|key| rendered.get(key)) {
+            Some(output) => output.clone(),
+            None => {
+                let output =
+                    render_copied_file(file, &content, &destination, template_variables, ctx)
+                        .with_context(|| {
+                            format!("Failed to render {:?} as: {}", file, for_env.name())
+                        })?;
+                if let Some(key) = key {
+                    rendered.insert(key, output.clone());
+                }
+                output
+            }
+        };
+        if let Some(output) = output {
+            write_copied_file(&destination, output, ctx).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Identifies the values a template sees for the variables it references, two variants with the same
+/// key render identically. `None` if a reference can not be looked up, the variant is then rendered
+/// on its own rather than risking to share the output of a different variant.
+fn render_key(
+    references: &[Vec<String>],
+    template_variables: &Option<Map<String, Value>>,
+) -> Option<String> {
+    let variables = template_variables.as_ref()?;
+    let values = references
+        .iter()
+        .map(|reference| {
+            let (first, rest) = reference.split_first()?;
+            rest.iter()
+                .try_fold(variables.get(first)?, |value, segment| value.get(segment))
+        })
+        .collect::<Option<Vec<&Value>>>()?;
+    serde_json::to_string(&values).ok()
+}
+
+/// The environments of the space a template has to be rendered for, the first of each group of
+/// environments it renders identically for, in declaration order. A template that does not depend
+/// on `env` or any variable differing between the environments needs only the first environment.
+pub fn minimal_environments(
+    space: &ResolvedSpace,
+    template: &str,
+) -> Result<Vec<String>, anyhow::Error> {
+    if space.environments.is_empty() {
+        return Ok(Vec::new());
+    }
+    let references = template_references(template)?;
+    if references.iter().any(|reference| reference == &["__path"]) {
+        return Ok(space.environments.iter().cloned().collect());
+    }
+    let mut keys = Vec::new();
+    let mut environments = Vec::new();
+    for (for_env, variables) in forenv_variants(space, &space.variables)? {
+        let Some(env) = for_env.env() else {
+            continue;
+        };
+        let key = render_key(
+            &references,
+            &template_variables(space, &variables, Some(for_env))?,
+        );
+        // An environment whose values can not be looked up is never grouped with another
+        if key.is_none() || !keys.contains(&key) {
+            keys.push(key);
+            environments.push(env.to_string());
+        }
+    }
+    Ok(environments)
+}
+
+/// The variables referenced by a template as paths of keys, including the variable of its
+/// `when:` condition.
+pub(crate) fn template_references(template: &str) -> Result<Vec<Vec<String>>, anyhow::Error> {
+    let mut references = Vec::new();
+    let content = match split_condition(template) {
+        Some((condition, rest)) => {
+            references.push(
+                condition_reference(condition)
+                    .with_context(|| format!("Failed to parse the condition '{}'", condition))?,
+            );
+            rest
+        }
+        None => template,
+    };
+    references.extend(collect_references(content)?);
+    Ok(references)
+}
+
 // Function to copy a single file or directory with environment-specific handling
 async fn copy_tocopy_with_env(
     to_copy: &ToCopy,
     copy_into: &Path,
-    env: Option<&str>,
+    for_env: Option<ForEnv<'_>>,
     variables: &Option<Map<String, Value>>,
-    environments: &HashSet<String>,
+    ctx: &mut CopyContext<'_>,
 ) -> Result<(), anyhow::Error> {
-    let last_segment = to_copy
-        .last_segment()
-        .with_context(|| "Failed to get last segment")?;
-    // Substitute environment in the file/directory name if needed
-    let substituted_name = match env {
-        Some(env) => substitute_path_segment(last_segment, "_forenv", env),
-        None => last_segment.to_string(),
-    };
-    let destination = copy_into.join(substituted_name);
+    ctx.cancel.check()?;
+    if let ToCopy::Link(file) = to_copy {
+        if to_copy.last_segment()?.contains("{{") {
+            return Err(anyhow::anyhow!(
+                "The file {:?} is linked by a `_weavelink` marker, but its name uses the template syntax. Linked files are not templated, move it out of the linked directory",
+                file
+            ));
+        }
+    }
+    let template_variables = template_variables(ctx.space, variables, for_env)?;
+    let destination = copy_into.join(destination_name(
+        to_copy,
+        &ctx.space.forenv_token,
+        for_env,
+        &template_variables,
+    )?);
 
     match to_copy {
         ToCopy::File(file) => {
             // Read file content
-            let content = tokio::fs::read_to_string(&file)
+            let content = ctx
+                .fs
+                .read_to_string(file)
                 .await
                 .with_context(|| format!("Failed to read file: {:?}", file))?;
-            // Apply variable substitution if variables are provided
-            let content = if let Some(variables) = variables {
-                let mut env_value = if let Some(env) = env {
-                    get_environment_value(variables, env).with_context(|| {
-                        format!(
-                            "Failed to get environment value for '{}' in {:?}",
-                            env, variables
-                        )
-                    })?
-                } else {
-                    variables.clone()
-                };
-                if let Some(env) = env {
-                    env_value.insert("env".to_string(), Value::String(env.to_string()));
-                }
-                template_file(&content, &env_value)
-                    .with_context(|| "Failed to apply variable substitution")?
-            } else {
-                content
-            };
-            // Write the processed content to the destination
-            tokio::fs::write(&destination, content)
+            copy_file(file, &content, &destination, template_variables, ctx).await?;
+        }
+        // Linked files are never read, their content is linked as it is
+        ToCopy::Link(file) => {
+            ctx.fs
+                .symlink(file, &destination)
                 .await
-                .with_context(|| format!("Failed to write to destination: {:?}", destination))?;
+                .with_context(|| format!("Failed to link {:?} to {:?}", destination, file))?;
+            ctx.stats.files_linked += 1;
         }
         ToCopy::Directory { subtree, .. } => {
-            // Create the directory if it doesn't exist
-            if !destination.exists() {
-                tokio::fs::create_dir(&destination)
-                    .await
-                    .with_context(|| format!("Failed to create directory: {:?}", destination))?;
-            }
+            create_dir(ctx.fs, &destination).await?;
             // Recursively copy the subdirectory
-            Box::pin(copy_tree(
-                subtree,
-                &destination,
-                env,
-                variables,
-                environments,
-            ))
-            .await
-            .with_context(|| {
-                format!("Failed to recursively copy subdirectory: {:?}", destination)
-            })?;
+            Box::pin(copy_tree(subtree, &destination, for_env, variables, ctx))
+                .await
+                .with_context(|| {
+                    format!("Failed to recursively copy subdirectory: {:?}", destination)
+                })?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A variant of a `_forenv` file or directory with the variables it is copied with.
+type ForEnvVariant<'a> = (ForEnv<'a>, Option<Map<String, Value>>);
+
+/// The variants a `_forenv` file or directory is copied as, with the variables of each:
+/// one per environment and the default variant if requested.
+/// A space without environments and default variant has none, which is an error
+/// as the file would silently not be copied.
+fn forenv_variants<'a>(
+    space: &'a ResolvedSpace,
+    variables: &Option<Map<String, Value>>,
+) -> Result<Vec<ForEnvVariant<'a>>, anyhow::Error> {
+    if space.environments.is_empty() && space.forenv_default.is_none() {
+        return Err(anyhow::anyhow!(
+            "The space {:?} has no environments to copy `_forenv` files for, declare environments or set `forenv_default`",
+            space.name
+        ));
+    }
+    let mut variants = Vec::new();
+    for env in &space.environments {
+        // Get environment-specific variables
+        let variables = match variables {
+            Some(variables) => Some(get_environment_value(variables, env).with_context(|| {
+                format!(
+                    "Failed to get environment value for '{}' in {:?}",
+                    env, variables
+                )
+            })?),
+            None => None,
+        };
+        variants.push((ForEnv::Env(env), variables));
+    }
+    // The default variant with the top-level variables if requested
+    if let Some(default) = &space.forenv_default {
+        variants.push((ForEnv::Default(default), variables.clone()));
+    }
+    Ok(variants)
+}
+
+/// The variables available to the templates of a file or directory name, if variables are provided.
+/// Copies for an environment additionally get `env`, `envs` and the `is_<env>` flags.
+fn template_variables(
+    space: &ResolvedSpace,
+    variables: &Option<Map<String, Value>>,
+    for_env: Option<ForEnv<'_>>,
+) -> Result<Option<Map<String, Value>>, anyhow::Error> {
+    let Some(variables) = variables else {
+        return Ok(None);
+    };
+    let env = for_env.and_then(|for_env| for_env.env());
+    let mut env_value = if let Some(env) = env {
+        get_environment_value(variables, env).with_context(|| {
+            format!(
+                "Failed to get environment value for '{}' in {:?}",
+                env, variables
+            )
+        })?
+    } else {
+        variables.clone()
+    };
+    if let Some(env) = env {
+        insert_builtin(&mut env_value, "env", Value::String(env.to_string()))?;
+        // All environments and an `is_<env>` flag per environment, to branch on the environment
+        insert_builtin(
+            &mut env_value,
+            "envs",
+            Value::Array(
+                space
+                    .environments
+                    .iter()
+                    .map(|env| Value::String(env.clone()))
+                    .collect(),
+            ),
+        )?;
+        for other in &space.environments {
+            insert_builtin(
+                &mut env_value,
+                &format!("is_{}", other),
+                Value::Bool(other == env),
+            )?;
+        }
+    }
+    insert_builtin(&mut env_value, "__space", Value::String(space.name.clone()))?;
+    Ok(Some(env_value))
+}
+
+/// The name a file or directory is copied as, with the `_forenv` token substituted and the name templated.
+fn destination_name(
+    to_copy: &ToCopy,
+    forenv_token: &str,
+    for_env: Option<ForEnv<'_>>,
+    template_variables: &Option<Map<String, Value>>,
+) -> Result<String, anyhow::Error> {
+    let last_segment = to_copy
+        .last_segment()
+        .with_context(|| "Failed to get last segment")?;
+    // Substitute environment in the file/directory name if needed
+    let substituted_name = match for_env {
+        Some(for_env) => substitute_path_segment(last_segment, forenv_token, for_env.name()),
+        None => last_segment.to_string(),
+    };
+    template_path_segment(&substituted_name, template_variables)
+        .with_context(|| format!("Failed to template the name of {:?}", to_copy))
+}
+
+/// Computes the files the copy step would write for a space, without reading or writing anything.
+/// Mirrors [`copy_tree`], the destinations are relative to the directory the space maps to.
+pub(crate) fn plan_copies(space: &ResolvedSpace) -> Result<Vec<CopyOutput>, anyhow::Error> {
+    let mut outputs = Vec::new();
+    plan_tree(
+        space,
+        &space.files_to_copy,
+        Path::new(""),
+        None,
+        &space.variables,
+        &mut outputs,
+    )?;
+    Ok(outputs)
+}
+
+fn plan_tree(
+    space: &ResolvedSpace,
+    copytree: &CopyTree,
+    copy_into: &Path,
+    for_env: Option<ForEnv<'_>>,
+    variables: &Option<Map<String, Value>>,
+    outputs: &mut Vec<CopyOutput>,
+) -> Result<(), anyhow::Error> {
+    for to_copy in &copytree.to_copy {
+        if for_env.is_none() && needs_substitution(to_copy.last_segment()?, &space.forenv_token) {
+            for (for_env, variables) in forenv_variants(space, variables)? {
+                plan_to_copy(
+                    space,
+                    to_copy,
+                    copy_into,
+                    Some(for_env),
+                    &variables,
+                    outputs,
+                )?;
+            }
+        } else {
+            plan_to_copy(space, to_copy, copy_into, for_env, variables, outputs)?;
+        }
+    }
+    Ok(())
+}
+
+fn plan_to_copy(
+    space: &ResolvedSpace,
+    to_copy: &ToCopy,
+    copy_into: &Path,
+    for_env: Option<ForEnv<'_>>,
+    variables: &Option<Map<String, Value>>,
+    outputs: &mut Vec<CopyOutput>,
+) -> Result<(), anyhow::Error> {
+    let template_variables = template_variables(space, variables, for_env)?;
+    let destination = copy_into.join(destination_name(
+        to_copy,
+        &space.forenv_token,
+        for_env,
+        &template_variables,
+    )?);
+    match to_copy {
+        ToCopy::File(source) | ToCopy::Link(source) => outputs.push(CopyOutput {
+            source: source.clone(),
+            destination,
+            env: for_env
+                .and_then(|for_env| for_env.env())
+                .map(str::to_string),
+        }),
+        ToCopy::Directory { subtree, .. } => {
+            plan_tree(space, subtree, &destination, for_env, variables, outputs)?
         }
     }
+    Ok(())
+}
 
+/// Templates a file and writes it to the destination, skipping it if its `when:` condition does not hold.
+async fn copy_file(
+    file: &Path,
+    content: &str,
+    destination: &Path,
+    template_variables: Option<Map<String, Value>>,
+    ctx: &mut CopyContext<'_>,
+) -> Result<(), anyhow::Error> {
+    if let Some(output) = render_copied_file(file, content, destination, template_variables, ctx)? {
+        write_copied_file(destination, output, ctx).await?;
+    }
     Ok(())
 }
 
-// Function to substitute environment in a path segment
+/// Templates a file for the destination, `None` if its `when:` condition does not hold.
+fn render_copied_file(
+    file: &Path,
+    content: &str,
+    destination: &Path,
+    mut template_variables: Option<Map<String, Value>>,
+    ctx: &mut CopyContext<'_>,
+) -> Result<Option<String>, anyhow::Error> {
+    if let Some(template_variables) = &mut template_variables {
+        insert_builtin(
+            template_variables,
+            "__path",
+            Value::String(relative_output_path(ctx.output_root, destination)?),
+        )?;
+    }
+    // Skip the file if it has a `when:` condition that does not hold
+    let content = match split_condition(content) {
+        Some((condition, rest)) => {
+            let empty = Map::new();
+            let holds =
+                evaluate_condition(condition, template_variables.as_ref().unwrap_or(&empty))
+                    .with_context(|| {
+                        format!("Failed to evaluate condition '{}' of {:?}", condition, file)
+                    })?;
+            if !holds {
+                return Ok(None);
+            }
+            rest
+        }
+        None => content,
+    };
+    // Apply variable substitution if variables are provided
+    let content = if let Some(template_variables) = &template_variables {
+        template_file(content, template_variables)
+            .with_context(|| "Failed to apply variable substitution")?
+    } else {
+        content.to_string()
+    };
+    ctx.stats.files_rendered += 1;
+    let content = match ctx.space.generate.final_newline {
+        Some(final_newline) => apply_final_newline(content, final_newline),
+        None => content,
+    };
+    Ok(Some(ctx.space.generate.line_endings.apply(content)))
+}
+
+/// Writes the rendered content of a copied file to the destination.
+async fn write_copied_file(
+    destination: &Path,
+    content: String,
+    ctx: &mut CopyContext<'_>,
+) -> Result<(), anyhow::Error> {
+    ctx.fs
+        .write(destination, content.as_bytes())
+        .await
+        .with_context(|| format!("Failed to write to destination: {:?}", destination))?;
+    ctx.stats.files_copied += 1;
+    ctx.stats.record_write(content.len());
+    Ok(())
+}
+
+/// Inserts a built-in template variable, failing if a variable of the space has the same name.
+/// `env`, `envs` and the `is_<env>` flags are built-ins of environments, other built-ins use the
+/// reserved `__` prefix.
+pub(crate) fn insert_builtin(
+    variables: &mut Map<String, Value>,
+    key: &str,
+    value: Value,
+) -> Result<(), anyhow::Error> {
+    if variables.contains_key(key) {
+        return Err(anyhow::anyhow!(
+            "The variable '{}' conflicts with the built-in template variable of the same name, `env`, `envs`, the `is_<env>` flags and variables starting with '__' are reserved",
+            key
+        ));
+    }
+    variables.insert(key.to_string(), value);
+    Ok(())
+}
+
+/// The path of a copied file relative to the directory the space maps to, using `/` as separator.
+fn relative_output_path(output_root: &Path, destination: &Path) -> Result<String, anyhow::Error> {
+    let relative = destination.strip_prefix(output_root).with_context(|| {
+        format!(
+            "Destination {:?} is not within the output directory {:?}",
+            destination, output_root
+        )
+    })?;
+    let segments = relative
+        .components()
+        .map(|component| {
+            component
+                .as_os_str()
+                .to_str()
+                .context("Path is not valid unicode")
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(segments.join("/"))
+}
+
+/// Splits a leading `when: <condition>` line off the content of a file.
+/// Returns the condition and the remaining content, or `None` if the file has no condition.
+pub(crate) fn split_condition(content: &str) -> Option<(&str, &str)> {
+    let rest = content.strip_prefix("when:")?;
+    let (condition, rest) = rest.split_once('\n').unwrap_or((rest, ""));
+    Some((condition.trim_end_matches('\r'), rest))
+}
+
+/// Runs a file or directory name containing `{{` through the template engine.
+/// The result must be a single path segment.
+fn template_path_segment(
+    segment: &str,
+    variables: &Option<Map<String, Value>>,
+) -> Result<String, anyhow::Error> {
+    if !segment.contains("{{") {
+        return Ok(segment.to_string());
+    }
+    let Some(variables) = variables else {
+        return Err(anyhow::anyhow!(
+            "The name '{}' is a template, but the space has no variables",
+            segment
+        ));
+    };
+    let name = template_file(segment, variables)?;
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+        return Err(anyhow::anyhow!(
+            "The name '{}' expands to '{}', which is not a single path segment",
+            segment,
+            name
+        ));
+    }
+    Ok(name)
+}
+
+// Function to substitute environment in a path segment.
+// Only the segment itself is substituted, the names nested in a directory are substituted when they
+// are copied. The default token is replaced once at the start, so `_forenv_forenv.txt` becomes
+// `dev_forenv.txt`, custom tokens are replaced everywhere in the segment.
 fn substitute_path_segment(segment: &str, from: &str, to: &str) -> String {
-    if needs_substitution(segment, from) {
-        segment.replacen(from, to, 1)
+    if from == DEFAULT_FORENV_TOKEN {
+        match segment.strip_prefix(from) {
+            Some(rest) => format!("{}{}", to, rest),
+            None => segment.to_string(),
+        }
+    } else {
+        segment.replace(from, to)
+    }
+}
+
+/// The default token replaced by the environment name, only matched at the start of a name.
+pub(crate) const DEFAULT_FORENV_TOKEN: &str = "_forenv";
+
+// Function to check if a segment needs environment substitution.
+// Custom tokens are matched anywhere, `_forenv` only as a prefix so existing names keep their meaning.
+pub(crate) fn needs_substitution(segment: &str, from: &str) -> bool {
+    if from == DEFAULT_FORENV_TOKEN {
+        segment.starts_with(from)
     } else {
-        segment.to_string()
+        segment.contains(from)
     }
 }
 
-// Function to check if a segment needs environment substitution
-fn needs_substitution(segment: &str, from: &str) -> bool {
-    segment.starts_with(from)
+#[cfg(test)]
+mod tests {
+    use super::{apply_resolved, apply_space_public, ApplyStats};
+    use crate::{
+        generate_weaveconfig, generate_weaveconfig_with, resolve_weaveconfig, test_utils::TempDir,
+        CancellationToken, Cancelled, DirEntry, FileMetadata, FileSystem, GenerateOptions,
+        TokioFileSystem, WeaveError,
+    };
+    use futures::future::BoxFuture;
+    use serde_json::{json, Value};
+    use std::{
+        io,
+        path::{Path, PathBuf},
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    #[tokio::test]
+    async fn test_forenv_default_variant() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"], "forenv_default": "default" }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "greeting": "hello", "dev": { "url": "dev.local" }, "prod": { "url": "prod.com" } }"#,
+        );
+        dir.write("weaveconfig/_forenv.txt", "{{ greeting }}");
+
+        generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        assert_eq!(dir.read("dev.txt"), "hello");
+        assert_eq!(dir.read("prod.txt"), "hello");
+        assert_eq!(dir.read("default.txt"), "hello");
+    }
+
+    #[tokio::test]
+    async fn test_forenv_without_default_variant() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "dev": { "url": "dev.local" }, "prod": { "url": "prod.com" } }"#,
+        );
+        dir.write("weaveconfig/_forenv.txt", "{{ env }}: {{ url }}");
+
+        generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        assert_eq!(dir.read("dev.txt"), "dev: dev.local");
+        assert_eq!(dir.read("prod.txt"), "prod: prod.com");
+        assert!(!dir.exists("default.txt"));
+    }
+
+    #[tokio::test]
+    async fn test_apply_single_space() {
+        let dir = TempDir::new();
+        dir.write("weaveconfig/_space.json", r#"{ "name": "root" }"#);
+        dir.write("weaveconfig/_env.json", r#"{ "shared": "value" }"#);
+        dir.write("weaveconfig/root.txt", "{{ shared }}");
+        dir.write("weaveconfig/app/_space.json", r#"{ "name": "app" }"#);
+        dir.write("weaveconfig/app/_env.json", r#"{ "port": 3000 }"#);
+        dir.write("weaveconfig/app/app.txt", "{{ shared }}:{{ port }}");
+        std::fs::create_dir(dir.path().join("app")).unwrap();
+
+        let weaveconfig_root = dir.path().join("weaveconfig");
+        let resolved = resolve_weaveconfig(&weaveconfig_root).await.unwrap();
+        apply_space_public(&resolved["app"], &weaveconfig_root, &TokioFileSystem)
+            .await
+            .unwrap();
+
+        assert_eq!(dir.read("app/app.txt"), "value:3000");
+        assert!(dir.exists("app/gen/config.json"));
+        assert!(dir.exists("app/gen/binding.ts"));
+        assert!(!dir.exists("root.txt"));
+        assert!(!dir.exists("gen"));
+    }
+
+    #[tokio::test]
+    async fn test_conditional_copy() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "dev": { "feature_x": false }, "prod": { "feature_x": true } }"#,
+        );
+        dir.write(
+            "weaveconfig/_forenv.feature.txt",
+            "when: feature_x == true\nenabled in {{ env }}\n",
+        );
+        dir.write(
+            "weaveconfig/_forenv.no-feature.txt",
+            "when: feature_x != true\ndisabled in {{ env }}\n",
+        );
+
+        generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        assert_eq!(dir.read("prod.feature.txt"), "enabled in prod\n");
+        assert!(!dir.exists("dev.feature.txt"));
+        assert_eq!(dir.read("dev.no-feature.txt"), "disabled in dev\n");
+        assert!(!dir.exists("prod.no-feature.txt"));
+    }
+
+    #[tokio::test]
+    async fn test_apply_stats() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"], "generate": false }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "dev": { "url": "dev.local" }, "prod": { "url": "prod.com" } }"#,
+        );
+        dir.write("weaveconfig/_forenv.txt", "{{ url }}");
+        dir.write("weaveconfig/static/readme.txt", "static");
+
+        let stats =
+            generate_weaveconfig_with(&dir.path().join("weaveconfig"), &GenerateOptions::default())
+                .await
+                .unwrap();
+
+        assert_eq!(
+            stats,
+            ApplyStats {
+                spaces: 1,
+                environments: 2,
+                files_copied: 3,
+                files_linked: 0,
+                files_rendered: 3,
+                files_written: 3,
+                bytes_written: "dev.local".len() + "prod.com".len() + "static".len(),
+                unformatted_bindings: Vec::new(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forenv_files_rendered_once() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "staging", "prod"], "generate": false }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "name": "app", "dev": { "port": 3000, "replicas": 1 }, "staging": { "port": 80, "replicas": 1 }, "prod": { "port": 80, "replicas": 3 } }"#,
+        );
+        dir.write("weaveconfig/_forenv.constant.txt", "{{ name }}");
+        let root = dir.path().join("weaveconfig");
+
+        let stats = generate_weaveconfig_with(&root, &GenerateOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(stats.files_rendered, 1);
+        assert_eq!(stats.files_copied, 3);
+        assert_eq!(dir.read("prod.constant.txt"), "app");
+        assert_eq!(dir.read("dev.constant.txt"), "app");
+
+        std::fs::remove_file(root.join("_forenv.constant.txt")).unwrap();
+        dir.write("weaveconfig/_forenv.port.txt", "{{ name }}:{{ port }}");
+        let stats = generate_weaveconfig_with(&root, &GenerateOptions::default())
+            .await
+            .unwrap();
+        // staging and prod share the port
+        assert_eq!(stats.files_rendered, 2);
+        assert_eq!(dir.read("staging.port.txt"), "app:80");
+        assert_eq!(dir.read("prod.port.txt"), "app:80");
+        assert_eq!(dir.read("dev.port.txt"), "app:3000");
+
+        let resolved = resolve_weaveconfig(&root).await.unwrap();
+        let space = &resolved["root"];
+        assert_eq!(
+            super::minimal_environments(space, "{{ name }}").unwrap(),
+            vec!["dev"]
+        );
+        assert_eq!(
+            super::minimal_environments(space, "{{ port }}").unwrap(),
+            vec!["dev", "staging"]
+        );
+        assert_eq!(
+            super::minimal_environments(space, "{{ env }}").unwrap(),
+            vec!["dev", "staging", "prod"]
+        );
+        assert_eq!(
+            super::minimal_environments(space, "when: is_prod\n{{ name }}").unwrap(),
+            vec!["dev", "prod"]
+        );
+
+        std::fs::remove_file(root.join("_forenv.port.txt")).unwrap();
+        dir.write("weaveconfig/_forenv.name.txt", "{{ env }}");
+        let stats = generate_weaveconfig_with(&root, &GenerateOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(stats.files_rendered, 3);
+        assert_eq!(dir.read("staging.name.txt"), "staging");
+    }
+
+    #[tokio::test]
+    async fn test_forenv_files_with_dotted_keys() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "staging", "prod"], "generate": false }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "dev": { "cfg": { "db.host": "dev-db" } }, "staging": { "cfg": { "db.host": "shared-db" } }, "prod": { "cfg": { "db.host": "shared-db" } } }"#,
+        );
+        dir.write("weaveconfig/_forenv.txt", r#"{{ cfg["db.host"] }}"#);
+        let root = dir.path().join("weaveconfig");
+
+        let stats = generate_weaveconfig_with(&root, &GenerateOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(dir.read("dev.txt"), "dev-db");
+        assert_eq!(dir.read("staging.txt"), "shared-db");
+        assert_eq!(dir.read("prod.txt"), "shared-db");
+        // The quoted key is looked up as one segment, so staging and prod still share the output
+        assert_eq!(stats.files_rendered, 2);
+
+        let resolved = resolve_weaveconfig(&root).await.unwrap();
+        assert_eq!(
+            super::minimal_environments(&resolved["root"], r#"{{ cfg["db.host"] }}"#).unwrap(),
+            vec!["dev", "staging"]
+        );
+        // A reference that can not be looked up is rendered for every environment
+        assert_eq!(
+            super::minimal_environments(&resolved["root"], "{{ cfg.missing }}").unwrap(),
+            vec!["dev", "staging", "prod"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_builtin_space_and_path() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev"] }"#,
+        );
+        dir.write("weaveconfig/_env.json", r#"{ "dev": {} }"#);
+        dir.write("weaveconfig/info.txt", "{{ __space }} {{ __path }}");
+        dir.write(
+            "weaveconfig/nested/_forenv.txt",
+            "{{ __space }} {{ __path }} {{ env }}",
+        );
+
+        generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        assert_eq!(dir.read("info.txt"), "root info.txt");
+        assert_eq!(dir.read("nested/dev.txt"), "root nested/dev.txt dev");
+    }
+
+    #[tokio::test]
+    async fn test_builtin_conflict() {
+        let dir = TempDir::new();
+        dir.write("weaveconfig/_space.json", r#"{ "name": "root" }"#);
+        dir.write("weaveconfig/_env.json", r#"{ "__space": "mine" }"#);
+        dir.write("weaveconfig/info.txt", "{{ __space }}");
+
+        let error = generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap_err();
+
+        assert!(format!("{:#}", error)
+            .contains("The variable '__space' conflicts with the built-in template variable"));
+
+        // The built-ins of environments are not silently replaced either
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"] }"#,
+        );
+        dir.write("weaveconfig/_env.json", r#"{ "env": "mine" }"#);
+        dir.write("weaveconfig/_forenv.txt", "{{ env }}");
+        std::fs::remove_file(dir.path().join("weaveconfig/info.txt")).unwrap();
+
+        let error = generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap_err();
+
+        assert!(format!("{:#}", error)
+            .contains("The variable 'env' conflicts with the built-in template variable"));
+        assert!(!dir.exists("dev.txt"));
+    }
+
+    #[tokio::test]
+    async fn test_templated_file_names() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "dev": { "region": "eu" }, "prod": { "region": "us" } }"#,
+        );
+        dir.write("weaveconfig/_forenv/{{ region }}.conf", "{{ env }}");
+
+        generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        assert_eq!(dir.read("dev/eu.conf"), "dev");
+        assert_eq!(dir.read("prod/us.conf"), "prod");
+    }
+
+    #[tokio::test]
+    async fn test_templated_file_name_must_be_a_segment() {
+        let dir = TempDir::new();
+        dir.write("weaveconfig/_space.json", r#"{ "name": "root" }"#);
+        dir.write("weaveconfig/_env.json", r#"{ "name": "nested/file" }"#);
+        dir.write("weaveconfig/{{ name }}.txt", "");
+
+        let error = generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap_err();
+
+        assert!(format!("{:#}", error).contains("which is not a single path segment"));
+    }
+
+    #[tokio::test]
+    async fn test_abstract_environment() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": [{ "name": "base", "abstract": true }, "dev", "prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{
+                "base": { "db": { "port": 5432 } },
+                "dev": { "db": { "host": "localhost" } },
+                "prod": { "db": { "host": "db.prod" } }
+            }"#,
+        );
+        dir.write("weaveconfig/_forenv.txt", "{{ db.host }}:{{ db.port }}");
+
+        let stats =
+            generate_weaveconfig_with(&dir.path().join("weaveconfig"), &GenerateOptions::default())
+                .await
+                .unwrap();
+
+        assert_eq!(stats.environments, 2);
+        assert_eq!(dir.read("dev.txt"), "localhost:5432");
+        assert_eq!(dir.read("prod.txt"), "db.prod:5432");
+        assert!(!dir.exists("base.txt"));
+
+        let config: Value = serde_json::from_str(&dir.read("gen/config.json")).unwrap();
+        assert_eq!(
+            config,
+            json!({
+                "dev": { "db": { "host": "localhost", "port": 5432 } },
+                "prod": { "db": { "host": "db.prod", "port": 5432 } }
+            })
+        );
+        assert!(!dir.read("gen/binding.ts").contains("\"base\""));
+    }
+
+    fn cancellation_fixture() -> TempDir {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"] }"#,
+        );
+        dir.write("weaveconfig/_env.json", r#"{ "dev": {}, "prod": {} }"#);
+        dir.write("weaveconfig/_forenv.txt", "{{ env }}");
+        dir.write("weaveconfig/app/_space.json", r#"{ "name": "app" }"#);
+        dir.write("weaveconfig/app/file.txt", "app");
+        std::fs::create_dir(dir.path().join("app")).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_generation() {
+        let dir = cancellation_fixture();
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+
+        let options = GenerateOptions {
+            cancel,
+            ..Default::default()
+        };
+        let error = generate_weaveconfig_with(&dir.path().join("weaveconfig"), &options)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(error, WeaveError::Cancelled(_)));
+        assert!(!dir.exists("gen"));
+    }
+
+    /// Writes to the disk and cancels the generation once the first file is written.
+    struct CancelAfterFirstWrite {
+        cancel: CancellationToken,
+        writes: AtomicUsize,
+    }
+
+    impl FileSystem for CancelAfterFirstWrite {
+        fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<DirEntry>>> {
+            TokioFileSystem.read_dir(path)
+        }
+
+        fn read<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<u8>>> {
+            TokioFileSystem.read(path)
+        }
+
+        fn write<'a>(
+            &'a self,
+            path: &'a Path,
+            contents: &'a [u8],
+        ) -> BoxFuture<'a, io::Result<()>> {
+            Box::pin(async move {
+                TokioFileSystem.write(path, contents).await?;
+                self.writes.fetch_add(1, Ordering::SeqCst);
+                self.cancel.cancel();
+                Ok(())
+            })
+        }
+
+        fn create_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+            TokioFileSystem.create_dir(path)
+        }
+
+        fn metadata<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<FileMetadata>> {
+            TokioFileSystem.metadata(path)
+        }
+
+        fn canonicalize<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<PathBuf>> {
+            TokioFileSystem.canonicalize(path)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancel_stops_further_writes() {
+        let dir = cancellation_fixture();
+        let root = dir.path().join("weaveconfig");
+        let resolved = resolve_weaveconfig(&root).await.unwrap();
+        let cancel = CancellationToken::new();
+        let fs = CancelAfterFirstWrite {
+            cancel: cancel.clone(),
+            writes: AtomicUsize::new(0),
+        };
+
+        // The all-config.json is written first, cancelling the run before any space is applied
+        let error = apply_resolved(&resolved, &root, None, true, &cancel, &fs)
+            .await
+            .unwrap_err();
+
+        assert!(error.is::<Cancelled>());
+        assert_eq!(fs.writes.load(Ordering::SeqCst), 1);
+        assert!(dir.exists("all-config.json"));
+        assert!(!dir.exists("gen"));
+        assert!(!dir.exists("dev.txt"));
+        assert!(!dir.exists("app/file.txt"));
+    }
+
+    #[tokio::test]
+    async fn test_out_dir() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev"], "generate": true }"#,
+        );
+        dir.write("weaveconfig/_env.json", r#"{ "port": 3000 }"#);
+        dir.write("weaveconfig/root.txt", "{{ port }}");
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{ "name": "app", "environments": ["dev"], "generate": { "typescript": false } }"#,
+        );
+        dir.write("weaveconfig/app/_env.json", r#"{ "name": "app" }"#);
+        dir.write("weaveconfig/app/_forenv.txt", "{{ env }}");
+        let out_dir = TempDir::new();
+
+        let options = GenerateOptions {
+            out_dir: Some(out_dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        generate_weaveconfig_with(&dir.path().join("weaveconfig"), &options)
+            .await
+            .unwrap();
+
+        assert_eq!(out_dir.read("root.txt"), "3000");
+        assert!(out_dir.exists("gen/config.json"));
+        assert!(out_dir.exists("gen/binding.ts"));
+        assert_eq!(out_dir.read("app/dev.txt"), "dev");
+        assert!(out_dir.exists("app/gen/config.json"));
+        let written: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(written, vec!["weaveconfig"]);
+    }
+
+    #[tokio::test]
+    async fn test_custom_forenv_token() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/.weaveconfigrc",
+            r#"{ "forenv_token": "@env@" }"#,
+        );
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"], "generate": false }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "dev": { "port": 3000 }, "prod": { "port": 80 } }"#,
+        );
+        dir.write("weaveconfig/config.@env@.yaml", "port: {{ port }}");
+        dir.write("weaveconfig/static/@env@/name.txt", "{{ env }}");
+
+        generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        assert_eq!(dir.read("config.dev.yaml"), "port: 3000");
+        assert_eq!(dir.read("config.prod.yaml"), "port: 80");
+        assert_eq!(dir.read("static/prod/name.txt"), "prod");
+        assert!(!dir.exists("config.@env@.yaml"));
+
+        dir.write(
+            "weaveconfig/.weaveconfigrc",
+            r#"{ "forenv_token": "_env_" }"#,
+        );
+        assert!(generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_nested_forenv() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"], "generate": false }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "dev": { "port": 3000 }, "prod": { "port": 80 } }"#,
+        );
+        dir.write("weaveconfig/_forenv/_forenv.txt", "{{ env }}:{{ port }}");
+        dir.write("weaveconfig/_forenv/sub/_forenv.json", "{{ port }}");
+        dir.write("weaveconfig/_forenv_forenv/name.txt", "{{ env }}");
+        let root = dir.path().join("weaveconfig");
+
+        generate_weaveconfig(&root).await.unwrap();
+
+        assert_eq!(dir.read("dev/dev.txt"), "dev:3000");
+        assert_eq!(dir.read("prod/prod.txt"), "prod:80");
+        assert_eq!(dir.read("prod/sub/prod.json"), "80");
+        assert!(!dir.exists("dev/prod.txt"));
+        assert!(!dir.exists("prod/dev.txt"));
+        assert_eq!(dir.read("dev_forenv/name.txt"), "dev");
+
+        // The planned outputs follow the same rules
+        let resolved = resolve_weaveconfig(&root).await.unwrap();
+        let mut destinations: Vec<String> = resolved["root"]
+            .copy_outputs()
+            .unwrap()
+            .into_iter()
+            .map(|output| output.destination.to_string_lossy().replace('\\', "/"))
+            .collect();
+        destinations.sort();
+        assert_eq!(
+            destinations,
+            vec![
+                "dev/dev.txt",
+                "dev/sub/dev.json",
+                "dev_forenv/name.txt",
+                "prod/prod.txt",
+                "prod/sub/prod.json",
+                "prod_forenv/name.txt"
+            ]
+        );
+
+        // Custom tokens are replaced everywhere in a name
+        std::fs::remove_dir_all(root.join("_forenv")).unwrap();
+        std::fs::remove_dir_all(root.join("_forenv_forenv")).unwrap();
+        dir.write(
+            "weaveconfig/.weaveconfigrc",
+            r#"{ "forenv_token": "@env@" }"#,
+        );
+        dir.write("weaveconfig/@env@/@env@.@env@.txt", "{{ env }}");
+        generate_weaveconfig(&root).await.unwrap();
+        assert_eq!(dir.read("prod/prod.prod.txt"), "prod");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_shared_directories() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "generate": false }"#,
+        );
+        for i in 0..24 {
+            dir.write(
+                &format!("weaveconfig/group/shared/s{}/_space.json", i),
+                &format!(
+                    r#"{{ "name": "s{}", "generate": {{ "typescript": false }} }}"#,
+                    i
+                ),
+            );
+            dir.write(
+                &format!("weaveconfig/group/shared/s{}/_env.json", i),
+                &format!(r#"{{ "index": {} }}"#, i),
+            );
+            dir.write(
+                &format!("weaveconfig/group/shared/s{}/nested/deep/index.txt", i),
+                "{{ index }}",
+            );
+        }
+        let root = dir.path().join("weaveconfig");
+        let out_dir = TempDir::new();
+        let options = GenerateOptions {
+            out_dir: Some(out_dir.path().to_path_buf()),
+            ..Default::default()
+        };
+
+        // Both generations create the same directories at the same time
+        let (first, second) = tokio::join!(
+            generate_weaveconfig_with(&root, &options),
+            generate_weaveconfig_with(&root, &options)
+        );
+        first.unwrap();
+        second.unwrap();
+
+        for i in 0..24 {
+            assert_eq!(
+                out_dir.read(&format!("group/shared/s{}/nested/deep/index.txt", i)),
+                i.to_string()
+            );
+            assert!(out_dir.exists(&format!("group/shared/s{}/gen/config.json", i)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gitignore_lists_generated_files() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "generate": true }"#,
+        );
+        dir.write("weaveconfig/_env.json", r#"{ "port": 3000 }"#);
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{ "name": "app", "generate": { "typescript": false, "config_format": "yaml" } }"#,
+        );
+        dir.write("weaveconfig/app/_env.json", r#"{ "name": "app" }"#);
+        // Lines added by hand are kept, missing generated files are appended
+        dir.write("app/gen/.gitignore", "config.json\n*.local");
+
+        generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        for (gen_folder, expected) in [
+            ("gen", "config.json\nbinding.ts\n"),
+            ("app/gen", "config.json\n*.local\nconfig.yaml\n"),
+        ] {
+            assert_eq!(dir.read(&format!("{}/.gitignore", gen_folder)), expected);
+            let generated: Vec<_> = std::fs::read_dir(dir.path().join(gen_folder))
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .filter(|name| name != ".gitignore")
+                .collect();
+            for file in generated {
+                assert!(expected.lines().any(|line| line == file), "{}", file);
+            }
+        }
+
+        // Nothing is missing, so it is not written again
+        let stats =
+            generate_weaveconfig_with(&dir.path().join("weaveconfig"), &GenerateOptions::default())
+                .await
+                .unwrap();
+        assert_eq!(
+            dir.read("app/gen/.gitignore"),
+            "config.json\n*.local\nconfig.yaml\n"
+        );
+        // The config.json and binding.ts of root and the config.yaml of app, no .gitignore
+        assert_eq!(stats.files_written, 3);
+    }
+
+    #[tokio::test]
+    async fn test_environment_flags() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"] }"#,
+        );
+        dir.write("weaveconfig/_env.json", r#"{ "name": "app" }"#);
+        dir.write(
+            "weaveconfig/_forenv.txt",
+            "{{ is_dev }} {{ is_prod }} {{ envs | join \",\" }}",
+        );
+        dir.write(
+            "weaveconfig/_forenv.prod-only.txt",
+            "when: is_prod\n{{ env }}",
+        );
+
+        generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        assert_eq!(dir.read("dev.txt"), "true false dev,prod");
+        assert_eq!(dir.read("prod.txt"), "false true dev,prod");
+        assert_eq!(dir.read("prod.prod-only.txt"), "prod");
+        assert!(!dir.exists("dev.prod-only.txt"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_linked_files() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev"], "generate": false }"#,
+        );
+        dir.write("weaveconfig/_env.json", r#"{ "port": 3000 }"#);
+        dir.write("weaveconfig/assets/_weavelink", "");
+        dir.write("weaveconfig/assets/logo.svg", "<svg />");
+        dir.write("weaveconfig/assets/fonts/font.txt", "static");
+        dir.write("weaveconfig/assets/port.txt", "{{ port }}");
+        let root = dir.path().join("weaveconfig");
+
+        // Running twice replaces the existing links
+        for _ in 0..2 {
+            let stats = generate_weaveconfig_with(&root, &GenerateOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(stats.files_linked, 3);
+            assert_eq!(stats.files_copied, 0);
+        }
+
+        let logo = dir.path().join("assets/logo.svg");
+        assert!(logo.symlink_metadata().unwrap().is_symlink());
+        assert_eq!(
+            std::fs::read_link(&logo).unwrap(),
+            root.canonicalize().unwrap().join("assets/logo.svg")
+        );
+        assert!(dir
+            .path()
+            .join("assets/fonts/font.txt")
+            .symlink_metadata()
+            .unwrap()
+            .is_symlink());
+        assert!(!dir.exists("assets/_weavelink"));
+        // Linked files are not templated, whatever their content
+        assert!(dir
+            .path()
+            .join("assets/port.txt")
+            .symlink_metadata()
+            .unwrap()
+            .is_symlink());
+        assert_eq!(dir.read("assets/port.txt"), "{{ port }}");
+
+        // A linked file whose name needs templating can not be linked
+        dir.write("weaveconfig/assets/{{ port }}.txt", "static");
+        let error = generate_weaveconfig(&root).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("its name uses the template syntax"));
+    }
+
+    #[tokio::test]
+    async fn test_all_config() {
+        let dir = TempDir::new();
+        dir.write("weaveconfig/.weaveconfigrc", r#"{ "all_config": true }"#);
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev"], "generate": false }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "port": 3000, "dev": { "url": "dev.local" } }"#,
+        );
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{ "name": "app", "generate": false }"#,
+        );
+        dir.write("weaveconfig/app/_env.json", r#"{ "name": "app" }"#);
+        std::fs::create_dir(dir.path().join("app")).unwrap();
+
+        let stats =
+            generate_weaveconfig_with(&dir.path().join("weaveconfig"), &GenerateOptions::default())
+                .await
+                .unwrap();
+
+        let all_config: Value = serde_json::from_str(&dir.read("all-config.json")).unwrap();
+        assert_eq!(
+            all_config,
+            json!({
+                "app": { "name": "app", "port": 3000, "dev": { "url": "dev.local" } },
+                "root": { "port": 3000, "dev": { "url": "dev.local" } },
+            })
+        );
+        assert_eq!(stats.files_written, 1);
+    }
+
+    #[tokio::test]
+    async fn test_zero_environments() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "generate": true }"#,
+        );
+        dir.write("weaveconfig/_env.json", r#"{ "port": 3000 }"#);
+        dir.write("weaveconfig/port.txt", "{{ port }}");
+
+        let stats =
+            generate_weaveconfig_with(&dir.path().join("weaveconfig"), &GenerateOptions::default())
+                .await
+                .unwrap();
+
+        assert_eq!(stats.environments, 0);
+        let config: Value = serde_json::from_str(&dir.read("gen/config.json")).unwrap();
+        assert_eq!(config, json!({ "port": 3000 }));
+        assert!(dir
+            .read("gen/binding.ts")
+            .contains("// static code starts here, using variant: zero_env"));
+        assert_eq!(dir.read("port.txt"), "3000");
+
+        dir.write("weaveconfig/_forenv.txt", "{{ port }}");
+        let error = generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("has no environments to copy `_forenv` files"));
+
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "generate": true, "forenv_default": "default" }"#,
+        );
+        generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+        assert_eq!(dir.read("default.txt"), "3000");
+    }
+
+    #[tokio::test]
+    async fn test_line_endings() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "generate": { "typescript": true, "line_endings": "crlf" } }"#,
+        );
+        dir.write("weaveconfig/_env.json", r#"{ "port": 3000 }"#);
+        dir.write("weaveconfig/mixed.txt", "a\r\nport: {{ port }}\nb\n");
+
+        generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        assert_eq!(dir.read("mixed.txt"), "a\r\nport: 3000\r\nb\r\n");
+        assert_eq!(
+            dir.read("gen/config.json"),
+            "{\r\n  \"port\": 3000\r\n}\r\n"
+        );
+        let binding = dir.read("gen/binding.ts");
+        assert_eq!(
+            binding.matches('\n').count(),
+            binding.matches("\r\n").count()
+        );
+
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "generate": { "typescript": true, "line_endings": "lf" } }"#,
+        );
+        generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        assert_eq!(dir.read("mixed.txt"), "a\nport: 3000\nb\n");
+        assert!(!dir.read("gen/config.json").contains('\r'));
+        assert!(!dir.read("gen/binding.ts").contains('\r'));
+    }
+
+    #[tokio::test]
+    async fn test_final_newline() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "generate": { "typescript": true } }"#,
+        );
+        dir.write("weaveconfig/_env.json", r#"{ "port": 3000 }"#);
+        dir.write("weaveconfig/bare.txt", "port: {{ port }}");
+        dir.write("weaveconfig/many.txt", "port: {{ port }}\n\n\n");
+        let root = dir.path().join("weaveconfig");
+
+        generate_weaveconfig(&root).await.unwrap();
+
+        assert_eq!(dir.read("gen/config.json"), "{\n  \"port\": 3000\n}\n");
+        assert!(dir.read("gen/binding.ts").ends_with("}\n"));
+        assert_eq!(dir.read("bare.txt"), "port: 3000");
+        assert_eq!(dir.read("many.txt"), "port: 3000\n\n\n");
+
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "generate": { "typescript": true, "final_newline": true } }"#,
+        );
+        generate_weaveconfig(&root).await.unwrap();
+
+        assert_eq!(dir.read("bare.txt"), "port: 3000\n");
+        assert_eq!(dir.read("many.txt"), "port: 3000\n");
+
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "generate": { "typescript": true, "final_newline": false } }"#,
+        );
+        generate_weaveconfig(&root).await.unwrap();
+
+        assert_eq!(dir.read("gen/config.json"), "{\n  \"port\": 3000\n}");
+        assert!(dir.read("gen/binding.ts").ends_with('}'));
+        assert_eq!(dir.read("many.txt"), "port: 3000");
+
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "generate": { "typescript": false, "config_format": "yaml" } }"#,
+        );
+        dir.write(
+            "weaveconfig/.weaveconfigrc",
+            r#"{ "final_newline": false }"#,
+        );
+        generate_weaveconfig(&root).await.unwrap();
+
+        assert!(dir.read("gen/config.yaml").ends_with("\nport: 3000"));
+    }
 }
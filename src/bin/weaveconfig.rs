@@ -1,12 +1,25 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    process::{Command, ExitCode},
+    time::{Duration, Instant},
+};
 
 #[cfg(target_os = "linux")]
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
 use anyhow::Context;
-use clap::{Parser, Subcommand};
-use weaveconfig::generate_weaveconfig;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde_json::{Map, Value};
+use weaveconfig::{
+    apply_resolution, check_weaveconfig_names, collect_templates, explain_weaveconfig,
+    export_resolution, find_constant_forenv_files, find_unused_variables, lock_hashes, parse_jsonc,
+    resolve_weaveconfig, resolve_weaveconfig_with, template_file, ApplyStats, Diagnostic,
+    Diagnostics, FileSystem, GenerateOptions, RemoteOptions, ResolvedSpace, Severity,
+    TokioFileSystem, WeaveError, LOCK_FILE_NAME,
+};
 
 #[derive(Parser)]
 #[command(
@@ -18,6 +31,63 @@ use weaveconfig::generate_weaveconfig;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// When to color the error output
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto, global = true)]
+    color: ColorChoice,
+    /// Never color the error output, the same as `--color never`
+    #[arg(long, global = true)]
+    no_color: bool,
+}
+
+#[derive(Args)]
+struct RemoteArgs {
+    /// Fetch variables read from a URL with `@url`, they are rejected otherwise
+    #[arg(long, conflicts_with = "since")]
+    allow_remote: bool,
+    /// How many seconds fetching a single URL may take
+    #[arg(long, default_value_t = 10, requires = "allow_remote")]
+    remote_timeout: u64,
+    /// Cache fetched documents in this directory, a cached document is used when fetching fails
+    #[arg(long, requires = "allow_remote")]
+    remote_cache: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct LockArgs {
+    /// Write a weave.lock with a hash of the resolved variables of every space
+    #[arg(long, conflicts_with_all = ["out_dir", "allow_remote", "locked"])]
+    lock: bool,
+    /// Fail without writing anything if the resolved variables no longer match the weave.lock
+    #[arg(long, conflicts_with = "allow_remote")]
+    locked: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ColorChoice {
+    /// Color if stderr is a terminal and NO_COLOR is not set
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => {
+                std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+            }
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum DiagnosticFormat {
+    /// One line per diagnostic
+    Text,
+    /// A JSON array of objects with severity, message, file, space and optionally line and col
+    Json,
 }
 
 #[derive(Subcommand)]
@@ -33,20 +103,119 @@ enum Commands {
         /// Path to the directory to generate the configuration for
         #[arg(default_value = ".")]
         path: String,
+        /// Print how many spaces, environments and files were processed and how long it took
+        #[arg(long)]
+        stats: bool,
+        /// Warn about variables that no copied file references
+        #[arg(long)]
+        warn_unused: bool,
+        /// Write all outputs below this directory instead of into the project
+        #[arg(long)]
+        out_dir: Option<PathBuf>,
+        /// Only regenerate the spaces affected by changes since this git ref
+        #[arg(long, conflicts_with = "out_dir")]
+        since: Option<String>,
+        #[command(flatten)]
+        remote: RemoteArgs,
+        #[command(flatten)]
+        lock: LockArgs,
     },
     /// Generates the weaveconfig configuration
     Gen {
         /// Path to the directory to generate the configuration for
         #[arg(default_value = ".")]
         path: String,
+        /// Print how many spaces, environments and files were processed and how long it took
+        #[arg(long)]
+        stats: bool,
+        /// Warn about variables that no copied file references
+        #[arg(long)]
+        warn_unused: bool,
+        /// Write all outputs below this directory instead of into the project
+        #[arg(long)]
+        out_dir: Option<PathBuf>,
+        /// Only regenerate the spaces affected by changes since this git ref
+        #[arg(long, conflicts_with = "out_dir")]
+        since: Option<String>,
+        #[command(flatten)]
+        remote: RemoteArgs,
+        #[command(flatten)]
+        lock: LockArgs,
+    },
+    /// Renders a single file with the variables of a JSON context and prints the result
+    Render {
+        /// The file to render
+        file: PathBuf,
+        /// A JSON or JSONC file containing the object of variables available in the template
+        #[arg(long)]
+        context: PathBuf,
+    },
+    /// Checks the weaveconfig for likely mistakes without generating anything
+    Lint {
+        /// Path to the directory to check the configuration for
+        #[arg(default_value = ".")]
+        path: String,
+        /// Also check the names of spaces, environments and variables against the conventions of the .weaveconfigrc
+        #[arg(long)]
+        check_names: bool,
+        /// Show at most this many warnings and how many more were found
+        #[arg(long, value_name = "N")]
+        max_errors: Option<usize>,
+    },
+    /// Resolves the weaveconfig and reports why it is invalid along with the lint warnings
+    Validate {
+        /// Path to the directory to validate the configuration for
+        #[arg(default_value = ".")]
+        path: String,
+        /// How the diagnostics are printed, json prints an array of objects to stdout
+        #[arg(long, value_enum, default_value_t = DiagnosticFormat::Text)]
+        format: DiagnosticFormat,
+        /// Show at most this many diagnostics and how many more were found
+        #[arg(long, value_name = "N")]
+        max_errors: Option<usize>,
+    },
+    /// Prints all resolved spaces as a single JSON document, keyed by space name
+    Export {
+        /// Path to the directory to export the configuration for
+        #[arg(default_value = ".")]
+        path: String,
+    },
+    /// Prints a resolved variable and the chain of spaces and files it was merged from
+    Explain {
+        /// The space and the dotted path of the variable, like `api.prod.db.host`
+        variable: String,
+        /// Path to the directory of the configuration
+        #[arg(default_value = ".")]
+        path: String,
+    },
+    /// Prints the directory containing the weaveconfig directory, without generating anything
+    Root {
+        /// Path to start searching from
+        #[arg(default_value = ".")]
+        path: String,
     },
 }
 
 #[tokio::main]
-async fn main() -> Result<(), anyhow::Error> {
+async fn main() -> ExitCode {
     let cli = Cli::parse();
+    let color = if cli.no_color {
+        ColorChoice::Never
+    } else {
+        cli.color
+    };
 
-    match cli.command {
+    match run(cli.command).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprint!("{}", format_error(&error, color.enabled()));
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(command: Commands) -> Result<(), anyhow::Error> {
+    match command {
         Commands::Init { dir } => {
             // Handle `init` command
             let init_path = Path::new(&dir);
@@ -57,32 +226,349 @@ async fn main() -> Result<(), anyhow::Error> {
             println!("Initializing weaveconfig in directory: {:?}", init_path);
             tokio::fs::create_dir(init_path.join("weaveconfig")).await?;
         }
-        Commands::Generate { path } | Commands::Gen { path } => {
+        Commands::Generate {
+            path,
+            stats,
+            warn_unused,
+            out_dir,
+            since,
+            remote,
+            lock,
+        }
+        | Commands::Gen {
+            path,
+            stats,
+            warn_unused,
+            out_dir,
+            since,
+            remote,
+            lock,
+        } => {
             // Handle `generate` command
             let path = Path::new(&path);
-            generate_config(path).await?;
+            let start = Instant::now();
+            let weaveconfig_config_root = locate_config_root(path)?;
+            let out_dir = match out_dir {
+                Some(out_dir) => {
+                    tokio::fs::create_dir_all(&out_dir)
+                        .await
+                        .with_context(|| format!("Failed to create the out dir {:?}", out_dir))?;
+                    Some(out_dir.canonicalize()?)
+                }
+                None => None,
+            };
+            let changed_files = match since {
+                Some(since) => Some(changed_files_since(&weaveconfig_config_root, &since)?),
+                None => None,
+            };
+            let options = GenerateOptions {
+                remote: remote.allow_remote.then(|| RemoteOptions {
+                    timeout: Duration::from_secs(remote.remote_timeout),
+                    cache_dir: remote.remote_cache,
+                }),
+                out_dir,
+                changed_files,
+                ..Default::default()
+            };
+            let resolution = resolve_weaveconfig_with(&weaveconfig_config_root, &options)
+                .await
+                .map_err(WeaveError::into_inner)?;
+            for warning in &resolution.warnings {
+                eprintln!("warning: {}", warning);
+            }
+            if lock.locked {
+                check_lockfile(&weaveconfig_config_root, &resolution.spaces).await?;
+            }
+            let apply_stats = apply_resolution(&resolution, &weaveconfig_config_root, &options)
+                .await
+                .map_err(WeaveError::into_inner)?;
+            for binding in &apply_stats.unformatted_bindings {
+                eprintln!(
+                    "warning: formatting the typescript binding {:?} took too long, it was written unformatted",
+                    binding
+                );
+            }
+            if lock.lock {
+                write_lockfile(&weaveconfig_config_root, &resolution.spaces).await?;
+            }
+            if stats {
+                print_stats(&apply_stats, start.elapsed().as_secs_f64());
+            }
+            if warn_unused {
+                warn_unused_variables(&resolution.spaces, options.fs.as_ref()).await?;
+            }
+        }
+        Commands::Render { file, context } => {
+            print!("{}", render_file(&file, &context).await?);
+        }
+        Commands::Lint {
+            path,
+            check_names,
+            max_errors,
+        } => {
+            let weaveconfig_config_root = locate_config_root(Path::new(&path))?;
+            let spaces = resolve_weaveconfig(&weaveconfig_config_root).await?;
+            let mut diagnostics = Diagnostics::new(max_errors);
+            diagnostics.extend(find_constant_forenv_files(&spaces, &TokioFileSystem).await?);
+            if check_names {
+                diagnostics.extend(check_weaveconfig_names(&weaveconfig_config_root).await?);
+            }
+            eprint!("{}", format_diagnostics(&diagnostics));
+        }
+        Commands::Validate {
+            path,
+            format,
+            max_errors,
+        } => {
+            let weaveconfig_config_root = locate_config_root(Path::new(&path))?;
+            let diagnostics = validate(&weaveconfig_config_root, max_errors).await;
+            match format {
+                DiagnosticFormat::Text => eprint!("{}", format_diagnostics(&diagnostics)),
+                DiagnosticFormat::Json => println!("{}", format_diagnostics_json(&diagnostics)?),
+            }
+            if diagnostics.errors() > 0 {
+                return Err(anyhow::anyhow!(
+                    "The weaveconfig is invalid, found {} errors",
+                    diagnostics.errors()
+                ));
+            }
+        }
+        Commands::Export { path } => {
+            let weaveconfig_config_root = locate_config_root(Path::new(&path))?;
+            let spaces = resolve_weaveconfig(&weaveconfig_config_root).await?;
+            let exported = export_resolution(&spaces, &weaveconfig_config_root)?;
+            println!("{}", serde_json::to_string_pretty(&exported)?);
+        }
+        Commands::Explain { variable, path } => {
+            let weaveconfig_config_root = locate_config_root(Path::new(&path))?;
+            let (space, variable) = variable.split_once('.').with_context(|| {
+                format!(
+                    "{:?} must be a space name followed by a dotted path",
+                    variable
+                )
+            })?;
+            let explanation =
+                explain_weaveconfig(&weaveconfig_config_root, space, variable).await?;
+            print!("{}", explanation);
+        }
+        Commands::Root { path } => {
+            println!("{}", locate_project_root(Path::new(&path))?.display());
         }
     }
 
     Ok(())
 }
 
-async fn generate_config(path: &Path) -> Result<(), anyhow::Error> {
+/// Renders an error with the contexts it was wrapped in as a list of causes, outermost first.
+fn format_error(error: &anyhow::Error, color: bool) -> String {
+    let paint = |code: &str, text: &str| {
+        if color {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        } else {
+            text.to_string()
+        }
+    };
+    let mut output = format!("{} {}\n", paint("1;31", "error:"), error);
+    let causes: Vec<_> = error.chain().skip(1).collect();
+    if !causes.is_empty() {
+        output.push_str(&format!("\n{}\n", paint("33", "caused by:")));
+        for (index, cause) in causes.iter().enumerate() {
+            output.push_str(&format!("  {}: {}\n", index + 1, cause));
+        }
+    }
+    output
+}
+
+/// Resolves the weaveconfig and lints it. A failed resolution is reported as an error diagnostic
+/// instead of failing, the lints need the resolved spaces and are skipped then.
+async fn validate(weaveconfig_config_root: &Path, max_errors: Option<usize>) -> Diagnostics {
+    let mut diagnostics = Diagnostics::new(max_errors);
+    let lints = match resolve_weaveconfig(weaveconfig_config_root).await {
+        Ok(spaces) => find_constant_forenv_files(&spaces, &TokioFileSystem).await,
+        Err(error) => Err(error),
+    };
+    match lints {
+        Ok(lints) => diagnostics.extend(lints),
+        Err(error) => diagnostics.push(Diagnostic::from_error(&error)),
+    }
+    diagnostics
+}
+
+/// Renders the kept diagnostics by severity, followed by a note if any were suppressed.
+fn format_diagnostics(diagnostics: &Diagnostics) -> String {
+    let mut output = String::new();
+    for diagnostic in diagnostics.kept() {
+        output.push_str(&format!("{}: {}\n", diagnostic.severity, diagnostic));
+    }
+    if diagnostics.suppressed() > 0 {
+        let kept_errors = diagnostics
+            .kept()
+            .iter()
+            .filter(|diagnostic| diagnostic.severity == Severity::Error)
+            .count();
+        let errors = diagnostics.errors() - kept_errors;
+        let warnings = diagnostics.suppressed() - errors;
+        let counts = [(errors, "error"), (warnings, "warning")]
+            .into_iter()
+            .filter(|(count, _)| *count > 0)
+            .map(|(count, kind)| match count {
+                1 => format!("1 more {}", kind),
+                _ => format!("{} more {}s", count, kind),
+            })
+            .collect::<Vec<_>>()
+            .join(" and ");
+        let verb = if diagnostics.suppressed() == 1 {
+            "was"
+        } else {
+            "were"
+        };
+        output.push_str(&format!(
+            "note: {} {} suppressed by --max-errors\n",
+            counts, verb
+        ));
+    }
+    output
+}
+
+/// Renders the kept diagnostics as a JSON array of objects, see [`Diagnostic`].
+fn format_diagnostics_json(diagnostics: &Diagnostics) -> Result<String, anyhow::Error> {
+    Ok(serde_json::to_string_pretty(diagnostics.kept())?)
+}
+
+fn locate_config_root(path: &Path) -> Result<PathBuf, anyhow::Error> {
+    let root = locate_project_root(path)?;
+    Ok(root.join("weaveconfig").canonicalize()?)
+}
+
+/// The absolute path of the closest directory containing a `weaveconfig` directory, starting at `path`.
+fn locate_project_root(path: &Path) -> Result<PathBuf, anyhow::Error> {
     let path = path
         .canonicalize()
         .with_context(|| format!("The path {:?} does not exist", path))?;
-    let root = locate_root(&path).with_context(|| {
-                format!(
-                    "Any of the parent directories must contain a 'weaveconfig' directory. None of {:?} and its parents do.",
-                    path.display()
-                )
-            })?;
-    let weaveconfig_config_root = root.join("weaveconfig").canonicalize()?;
-    generate_weaveconfig(&weaveconfig_config_root).await?;
+    locate_root(&path).with_context(|| {
+        format!(
+            "Any of the parent directories must contain a 'weaveconfig' directory. None of {:?} and its parents do.",
+            path.display()
+        )
+    })
+}
+
+/// Writes the hashes of the resolved spaces to the lockfile in the weaveconfig root.
+async fn write_lockfile(
+    weaveconfig_config_root: &Path,
+    spaces: &HashMap<String, ResolvedSpace>,
+) -> Result<(), anyhow::Error> {
+    let path = weaveconfig_config_root.join(LOCK_FILE_NAME);
+    let content = serde_json::to_string_pretty(&lock_hashes(spaces))?;
+    tokio::fs::write(&path, content + "\n")
+        .await
+        .with_context(|| format!("Failed to write the lockfile {:?}", path))
+}
 
+/// Fails if the hashes of the resolved spaces differ from the lockfile, naming the spaces that differ.
+async fn check_lockfile(
+    weaveconfig_config_root: &Path,
+    spaces: &HashMap<String, ResolvedSpace>,
+) -> Result<(), anyhow::Error> {
+    let path = weaveconfig_config_root.join(LOCK_FILE_NAME);
+    let content = tokio::fs::read_to_string(&path).await.with_context(|| {
+        format!(
+            "Failed to read the lockfile {:?}, create it with --lock",
+            path
+        )
+    })?;
+    let locked: BTreeMap<String, String> = serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse the lockfile {:?}", path))?;
+    let hashes = lock_hashes(spaces);
+
+    let mut differences = Vec::new();
+    for (name, hash) in &hashes {
+        match locked.get(name) {
+            Some(locked_hash) if locked_hash == hash => {}
+            Some(_) => differences.push(format!("{} changed", name)),
+            None => differences.push(format!("{} is not locked", name)),
+        }
+    }
+    for name in locked.keys().filter(|name| !hashes.contains_key(*name)) {
+        differences.push(format!("{} was removed", name));
+    }
+    if differences.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow::anyhow!(
+        "The resolved variables no longer match {:?}: {}. Run with --lock to update it.",
+        path,
+        differences.join(", ")
+    ))
+}
+
+async fn warn_unused_variables(
+    spaces: &HashMap<String, ResolvedSpace>,
+    fs: &dyn FileSystem,
+) -> Result<(), anyhow::Error> {
+    let templates = collect_templates(spaces, fs).await?;
+    for unused in find_unused_variables(spaces, &templates)? {
+        eprintln!("warning: {}", unused);
+    }
     Ok(())
 }
 
+/// The files in the weaveconfig directory changed since the git ref, including untracked files.
+/// The paths are relative to the weaveconfig directory.
+fn changed_files_since(
+    weaveconfig_config_root: &Path,
+    since: &str,
+) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let mut files = Vec::new();
+    for args in [
+        vec!["diff", "--name-only", "--relative", since, "--"],
+        vec!["ls-files", "--others", "--exclude-standard"],
+    ] {
+        let output = Command::new("git")
+            .args(&args)
+            .current_dir(weaveconfig_config_root)
+            .output()
+            .context("Failed to run git")?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "git {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        files.extend(
+            String::from_utf8(output.stdout)
+                .context("The output of git is not valid UTF-8")?
+                .lines()
+                .map(PathBuf::from),
+        );
+    }
+    Ok(files)
+}
+
+async fn render_file(file: &Path, context: &Path) -> Result<String, anyhow::Error> {
+    let content = tokio::fs::read_to_string(file)
+        .await
+        .with_context(|| format!("Failed to read file: {:?}", file))?;
+    let context_content = tokio::fs::read_to_string(context)
+        .await
+        .with_context(|| format!("Failed to read the context: {:?}", context))?;
+    let variables: Map<String, Value> = parse_jsonc(&context_content)
+        .with_context(|| format!("The context {:?} is not a JSON object", context))?;
+    template_file(&content, &variables).with_context(|| format!("Failed to render {:?}", file))
+}
+
+fn print_stats(stats: &ApplyStats, seconds: f64) {
+    println!("Spaces:         {}", stats.spaces);
+    println!("Environments:   {}", stats.environments);
+    println!("Files copied:   {}", stats.files_copied);
+    println!("Files linked:   {}", stats.files_linked);
+    println!("Files rendered: {}", stats.files_rendered);
+    println!("Files written:  {}", stats.files_written);
+    println!("Bytes written:  {}", stats.bytes_written);
+    println!("Time:           {:.2}ms", seconds * 1000.0);
+}
+
 fn locate_root(path: &Path) -> Option<PathBuf> {
     // The root is the first directory that contains a "weaveconfig" directory
     let root = path.ancestors().find(|dir| is_root(dir))?;
@@ -92,3 +578,166 @@ fn locate_root(path: &Path) -> Option<PathBuf> {
 fn is_root(path: &Path) -> bool {
     path.join("weaveconfig").exists()
 }
+
+// The temporary directories of the library tests
+#[cfg(test)]
+#[path = "../test_utils.rs"]
+mod test_utils;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_utils::TempDir;
+    use weaveconfig::generate_weaveconfig;
+
+    #[tokio::test]
+    async fn test_render_file() {
+        let dir = TempDir::new();
+        dir.write("template.txt", "{{ db.host }}:{{ db.port }}");
+        dir.write(
+            "context.jsonc",
+            "{\n  // the database\n  \"db\": { \"host\": \"localhost\", \"port\": 5432 }\n}",
+        );
+
+        let rendered = render_file(
+            &dir.path().join("template.txt"),
+            &dir.path().join("context.jsonc"),
+        )
+        .await;
+        assert_eq!(rendered.unwrap(), "localhost:5432");
+    }
+
+    #[test]
+    fn test_format_error() {
+        let error = anyhow::anyhow!("The path \"missing\" does not exist")
+            .context("Failed to read the weaveconfig directory")
+            .context("Failed to generate");
+
+        assert_eq!(
+            format_error(&error, false),
+            "error: Failed to generate\n\ncaused by:\n  1: Failed to read the weaveconfig directory\n  2: The path \"missing\" does not exist\n"
+        );
+        assert_eq!(
+            format_error(&anyhow::anyhow!("Failed"), true),
+            "\x1b[1;31merror:\x1b[0m Failed\n"
+        );
+    }
+
+    #[test]
+    fn test_format_diagnostics() {
+        let mut diagnostics = Diagnostics::new(Some(2));
+        diagnostics.extend(["first", "second", "third", "fourth"]);
+        assert_eq!(
+            format_diagnostics(&diagnostics),
+            "warning: first\nwarning: second\nnote: 2 more warnings were suppressed by --max-errors\n"
+        );
+
+        let mut diagnostics = Diagnostics::new(Some(2));
+        diagnostics.extend(["first", "second"]);
+        assert_eq!(
+            format_diagnostics(&diagnostics),
+            "warning: first\nwarning: second\n"
+        );
+
+        let mut diagnostics = Diagnostics::new(Some(1));
+        diagnostics.push(Diagnostic::new(Severity::Error, "first"));
+        diagnostics.push(Diagnostic::new(Severity::Error, "second"));
+        diagnostics.push("third");
+        assert_eq!(
+            format_diagnostics(&diagnostics),
+            "error: first\nnote: 1 more error and 1 more warning were suppressed by --max-errors\n"
+        );
+
+        let mut diagnostics = Diagnostics::new(Some(1));
+        diagnostics.extend(["first", "second"]);
+        assert_eq!(
+            format_diagnostics(&diagnostics),
+            "warning: first\nnote: 1 more warning was suppressed by --max-errors\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validate_json() {
+        let dir = TempDir::new();
+        dir.write("weaveconfig/_space.json", r#"{ "name": "root" }"#);
+        dir.write("weaveconfig/_env.json", r#"{ "port": "80" }"#);
+        dir.write(
+            "weaveconfig/_schema.json",
+            r#"{ "properties": { "port": { "type": "number" } } }"#,
+        );
+        let root = dir.path().join("weaveconfig").canonicalize().unwrap();
+
+        let diagnostics = validate(&root, None).await;
+        let json = format_diagnostics_json(&diagnostics).unwrap();
+
+        let parsed: Vec<Value> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.len(), 1, "{}", json);
+        let diagnostic = parsed[0].as_object().unwrap();
+        assert_eq!(diagnostic["severity"], "error");
+        assert_eq!(diagnostic["space"], "root");
+        assert!(diagnostic["file"]
+            .as_str()
+            .unwrap()
+            .ends_with("_schema.json"));
+        assert!(diagnostic["message"]
+            .as_str()
+            .unwrap()
+            .contains("Failed to validate variables against space schema"));
+        assert!(!diagnostic.contains_key("line"));
+        assert_eq!(diagnostics.errors(), 1);
+    }
+
+    #[test]
+    fn test_locate_project_root() {
+        let dir = TempDir::new();
+        dir.write("project/weaveconfig/.gitkeep", "");
+        dir.write("project/packages/api/.gitkeep", "");
+        dir.write("elsewhere/.gitkeep", "");
+
+        let found = locate_project_root(&dir.path().join("project/packages/api"));
+        let missing = locate_project_root(&dir.path().join("elsewhere"));
+
+        assert_eq!(
+            found.unwrap(),
+            dir.path().join("project").canonicalize().unwrap()
+        );
+        let error = missing.unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("must contain a 'weaveconfig' directory"),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn test_locked_detects_changed_variable() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "generate": false }"#,
+        );
+        dir.write("weaveconfig/_env.json", r#"{ "port": 3000, "host": "a" }"#);
+        let root = dir.path().join("weaveconfig");
+
+        let result = async {
+            write_lockfile(&root, &resolve_weaveconfig(&root).await?).await?;
+            generate_weaveconfig(&root).await?;
+            check_lockfile(&root, &resolve_weaveconfig(&root).await?).await?;
+            // Reordering keys does not change the resolved values
+            std::fs::write(root.join("_env.json"), r#"{ "host": "a", "port": 3000 }"#)?;
+            check_lockfile(&root, &resolve_weaveconfig(&root).await?).await?;
+            std::fs::write(root.join("_env.json"), r#"{ "host": "a", "port": 3001 }"#)?;
+            let spaces = resolve_weaveconfig(&root).await?;
+            Ok::<_, anyhow::Error>(check_lockfile(&root, &spaces).await)
+        }
+        .await;
+        let error = result.unwrap().unwrap_err();
+        assert!(error.to_string().contains("root changed"), "{}", error);
+        assert!(!dir.exists(LOCK_FILE_NAME));
+        let lockfile: Value =
+            serde_json::from_str(&dir.read(&format!("weaveconfig/{}", LOCK_FILE_NAME))).unwrap();
+        assert!(lockfile.get("root").is_some(), "{}", lockfile);
+    }
+}
@@ -0,0 +1,43 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use thiserror::Error;
+
+/// A token to cancel a running generation, for example when the files changed again.
+/// Clones share the same state, so one clone can be cancelled while another is passed to the generation.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+/// The error returned when a generation was cancelled.
+/// It is wrapped in an [`anyhow::Error`], use `error.is::<Cancelled>()` to detect it.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("The generation was cancelled")]
+pub struct Cancelled;
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels the generation using this token, it stops before the next directory, space or file.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Returns [`Cancelled`] if the token was cancelled.
+    pub(crate) fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            Err(Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
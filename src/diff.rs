@@ -0,0 +1,174 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde_json::{Map, Value};
+
+use crate::resolve_spaces::ResolvedSpace;
+
+/// The differences between two resolutions of a weaveconfig directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResolutionDiff {
+    /// The spaces only present in the new resolution, sorted by name.
+    pub added_spaces: Vec<String>,
+    /// The spaces only present in the old resolution, sorted by name.
+    pub removed_spaces: Vec<String>,
+    /// The spaces present in both resolutions that differ, by name.
+    pub changed_spaces: BTreeMap<String, SpaceDiff>,
+}
+
+impl ResolutionDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added_spaces.is_empty()
+            && self.removed_spaces.is_empty()
+            && self.changed_spaces.is_empty()
+    }
+}
+
+/// The differences between two resolutions of a single space.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SpaceDiff {
+    /// The environments only present in the new resolution, in declaration order.
+    pub added_environments: Vec<String>,
+    /// The environments only present in the old resolution, in declaration order.
+    pub removed_environments: Vec<String>,
+    /// The dotted paths of the variables that were added, removed or changed, sorted.
+    /// Environment specific variables are prefixed with their environment, like `prod.db.host`.
+    pub changed_paths: Vec<String>,
+}
+
+impl SpaceDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added_environments.is_empty()
+            && self.removed_environments.is_empty()
+            && self.changed_paths.is_empty()
+    }
+}
+
+/// Compares two resolutions, for example before and after a change to the weaveconfig directory.
+pub fn diff_resolutions(
+    old: &HashMap<String, ResolvedSpace>,
+    new: &HashMap<String, ResolvedSpace>,
+) -> ResolutionDiff {
+    let mut diff = ResolutionDiff::default();
+    for (name, new_space) in new {
+        match old.get(name) {
+            Some(old_space) => {
+                let space_diff = diff_space(old_space, new_space);
+                if !space_diff.is_empty() {
+                    diff.changed_spaces.insert(name.clone(), space_diff);
+                }
+            }
+            None => diff.added_spaces.push(name.clone()),
+        }
+    }
+    diff.removed_spaces = old
+        .keys()
+        .filter(|name| !new.contains_key(*name))
+        .cloned()
+        .collect();
+    diff.added_spaces.sort();
+    diff.removed_spaces.sort();
+    diff
+}
+
+fn diff_space(old: &ResolvedSpace, new: &ResolvedSpace) -> SpaceDiff {
+    let mut changed_paths = Vec::new();
+    let empty = Map::new();
+    diff_maps(
+        old.variables.as_ref().unwrap_or(&empty),
+        new.variables.as_ref().unwrap_or(&empty),
+        "",
+        &mut changed_paths,
+    );
+    changed_paths.sort();
+    SpaceDiff {
+        added_environments: new
+            .environments
+            .difference(&old.environments)
+            .cloned()
+            .collect(),
+        removed_environments: old
+            .environments
+            .difference(&new.environments)
+            .cloned()
+            .collect(),
+        changed_paths,
+    }
+}
+
+/// Collects the paths of the values that differ, descending into objects present on both sides.
+fn diff_maps(
+    old: &Map<String, Value>,
+    new: &Map<String, Value>,
+    prefix: &str,
+    paths: &mut Vec<String>,
+) {
+    for (key, new_value) in new {
+        let path = format!("{}{}", prefix, key);
+        match (old.get(key), new_value) {
+            (Some(Value::Object(old_map)), Value::Object(new_map)) => {
+                diff_maps(old_map, new_map, &format!("{}.", path), paths);
+            }
+            (Some(old_value), new_value) if old_value == new_value => {}
+            _ => paths.push(path),
+        }
+    }
+    for key in old.keys() {
+        if !new.contains_key(key) {
+            paths.push(format!("{}{}", prefix, key));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{resolve_weaveconfig, test_utils::TempDir};
+
+    fn setup(app_env: &str) -> TempDir {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "port": 3000, "prod": { "host": "prod.com" } }"#,
+        );
+        dir.write("weaveconfig/app/_space.json", r#"{ "name": "app" }"#);
+        dir.write("weaveconfig/app/_env.json", app_env);
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_diff_resolutions() {
+        let old_dir = setup(r#"{ "db": { "host": "localhost", "port": 5432 }, "stale": 1 }"#);
+        let new_dir = setup(r#"{ "db": { "host": "db.local", "port": 5432 }, "fresh": 2 }"#);
+        let old = resolve_weaveconfig(&old_dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+        let new = resolve_weaveconfig(&new_dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        assert!(diff_resolutions(&old, &old).is_empty());
+        assert_eq!(
+            diff_resolutions(&old, &new),
+            ResolutionDiff {
+                added_spaces: vec![],
+                removed_spaces: vec![],
+                changed_spaces: BTreeMap::from([(
+                    "app".to_string(),
+                    SpaceDiff {
+                        added_environments: vec![],
+                        removed_environments: vec![],
+                        changed_paths: vec![
+                            "db.host".to_string(),
+                            "fresh".to_string(),
+                            "stale".to_string()
+                        ],
+                    }
+                )]),
+            }
+        );
+    }
+}
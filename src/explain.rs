@@ -0,0 +1,330 @@
+use std::{collections::HashMap, fmt};
+
+use anyhow::Context;
+use serde_json::Value;
+
+use crate::{
+    merging::SourceId,
+    resolve_spaces::{dependency_env_targets, ResolvedSpace},
+    space_graph::{Space, SpaceGraph},
+};
+
+/// How a variable got into a space, one step of an [`Explanation`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Origin {
+    /// The variable is defined in this file of the space.
+    Defined(SourceId),
+    /// The variable is inherited from the parent space of this name.
+    Parent(String),
+    /// The variable is imported from the dependency of this name.
+    Dependency(String),
+    /// The variable is merged from the abstract environment of this name.
+    AbstractEnvironment(String),
+    /// The variable is a global variable of the weaveconfig root, defined in this file.
+    Global(SourceId),
+}
+
+/// A space and the dotted path a variable has in it, with how it got there.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExplainStep {
+    pub space: String,
+    pub path: String,
+    pub origin: Origin,
+}
+
+impl fmt::Display for ExplainStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} ", self.space, self.path)?;
+        match &self.origin {
+            Origin::Defined(source) => write!(f, "is defined in {:?}", source),
+            Origin::Parent(parent) => {
+                write!(f, "is inherited from the parent space \"{}\"", parent)
+            }
+            Origin::Dependency(dependency) => {
+                write!(f, "is imported from the dependency \"{}\"", dependency)
+            }
+            Origin::AbstractEnvironment(environment) => write!(
+                f,
+                "is merged from the abstract environment \"{}\"",
+                environment
+            ),
+            Origin::Global(source) => write!(f, "is a global variable defined in {:?}", source),
+        }
+    }
+}
+
+/// The origin of a resolved variable, see [`explain_variable`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Explanation {
+    /// The resolved value of the variable.
+    pub value: Value,
+    /// The file the value was defined in.
+    pub source: SourceId,
+    /// The steps from the explained space to the space defining the variable, the last step is
+    /// always [`Origin::Defined`], or [`Origin::Global`] for a global variable.
+    pub steps: Vec<ExplainStep>,
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.value)?;
+        for step in &self.steps {
+            writeln!(f, "  {}", step)?;
+        }
+        Ok(())
+    }
+}
+
+/// Traces the variable at the dotted `path` of a space back to the file defining it,
+/// through the parents, dependencies and abstract environments it was merged from.
+///
+/// The steps are reconstructed from the provenance of the spaces, a step is taken into the first
+/// space that has the variable from the same file, in the order the spaces are merged.
+pub fn explain_variable(
+    space_graph: &SpaceGraph,
+    spaces: &HashMap<String, ResolvedSpace>,
+    space: &str,
+    path: &str,
+) -> Result<Explanation, anyhow::Error> {
+    let resolved = spaces
+        .get(space)
+        .with_context(|| format!("The space {:?} does not exist", space))?;
+    let source = resolved
+        .provenance
+        .get(path)
+        .with_context(|| format!("The space {:?} has no variable {:?}", space, path))?
+        .clone();
+    let value = resolved
+        .variables
+        .as_ref()
+        .and_then(|variables| {
+            let mut segments = path.split('.');
+            let first = variables.get(segments.next()?);
+            segments.try_fold(first?, |value, segment| value.as_object()?.get(segment))
+        })
+        .cloned()
+        .unwrap_or(Value::Null);
+
+    // Global variables are merged into every space directly, no space defines them
+    let defined_in_space = space_graph
+        .values()
+        .any(|space| space.provenance.values().any(|defined| *defined == source));
+    if !defined_in_space {
+        return Ok(Explanation {
+            value,
+            steps: vec![ExplainStep {
+                space: space.to_string(),
+                path: path.to_string(),
+                origin: Origin::Global(source.clone()),
+            }],
+            source,
+        });
+    }
+
+    let mut steps = Vec::new();
+    let mut current = (space.to_string(), path.to_string());
+    // Every space is entered at most once per path, which bounds the trace
+    while steps.len() <= space_graph.len() * 2 {
+        let (space_name, path) = &current;
+        let space = space_graph
+            .get(space_name)
+            .with_context(|| format!("The space {:?} does not exist", space_name))?;
+        let (origin, next) = step(space, spaces, path, &source)
+            .with_context(|| format!("Failed to trace {:?} in the space {:?}", path, space_name))?;
+        let defined = matches!(origin, Origin::Defined(_));
+        steps.push(ExplainStep {
+            space: space_name.clone(),
+            path: path.clone(),
+            origin,
+        });
+        if defined {
+            return Ok(Explanation {
+                value,
+                source,
+                steps,
+            });
+        }
+        current = next;
+    }
+    Err(anyhow::anyhow!(
+        "Failed to trace {:?} in the space {:?}, the merges form a cycle",
+        path,
+        space
+    ))
+}
+
+/// Finds how the variable at `path` got into `space`, and the space and path it came from.
+fn step(
+    space: &Space,
+    spaces: &HashMap<String, ResolvedSpace>,
+    path: &str,
+    source: &SourceId,
+) -> Option<(Origin, (String, String))> {
+    let next = |space: &str, path: String| (space.to_string(), path);
+    if space.provenance.get(path) == Some(source) {
+        return Some((
+            Origin::Defined(source.clone()),
+            next(&space.name, path.into()),
+        ));
+    }
+    let has_source =
+        |space: &ResolvedSpace, path: &String| space.provenance.get(path) == Some(source);
+    let (env, rest) = match path.split_once('.') {
+        Some((env, rest)) if space.environments.contains(env) => (Some(env), rest),
+        _ => (None, path),
+    };
+
+    if let Some(parent) = space
+        .parent_space
+        .as_ref()
+        .and_then(|name| spaces.get(name))
+    {
+        let candidates = match env {
+            Some(env) => space
+                .parent_mapping
+                .get_ancestors(&env.to_string())
+                .iter()
+                .map(|parent_env| format!("{}.{}", parent_env, rest))
+                .collect(),
+            None => vec![path.to_string()],
+        };
+        if let Some(path) = candidates.into_iter().find(|path| has_source(parent, path)) {
+            return Some((
+                Origin::Parent(parent.name.clone()),
+                next(&parent.name, path),
+            ));
+        }
+    }
+
+    let own = spaces.get(&space.name)?;
+    for dependency in space
+        .dependencies
+        .iter()
+        .filter_map(|name| spaces.get(name))
+    {
+        // A restricted dependency only contributes to the environments it is imported into
+        let imported_into = space.dependency_environments.get(&dependency.name);
+        if imported_into
+            .is_some_and(|imported_into| !env.is_some_and(|env| imported_into.contains(env)))
+        {
+            continue;
+        }
+        let rest = match space.dependency_aliases.get(&dependency.name) {
+            Some(alias) => {
+                let Some(rest) = rest
+                    .strip_prefix(alias.as_str())
+                    .and_then(|rest| rest.strip_prefix('.'))
+                else {
+                    continue;
+                };
+                rest
+            }
+            None => rest,
+        };
+        let mut candidates = Vec::new();
+        if let Some(env) = env {
+            candidates.extend(
+                dependency
+                    .environments
+                    .iter()
+                    .filter(|dependency_env| {
+                        dependency_env_targets(space, dependency, &own.root_mapping, dependency_env)
+                            .iter()
+                            .any(|space_env| space_env.as_str() == env)
+                    })
+                    .map(|dependency_env| format!("{}.{}", dependency_env, rest)),
+            );
+        }
+        // Top-level variables of an aliased or restricted dependency are nested within the environments
+        if env.is_none()
+            || space.dependency_aliases.contains_key(&dependency.name)
+            || imported_into.is_some()
+        {
+            candidates.push(rest.to_string());
+        }
+        if let Some(path) = candidates
+            .into_iter()
+            .find(|path| has_source(dependency, path))
+        {
+            return Some((
+                Origin::Dependency(dependency.name.clone()),
+                next(&dependency.name, path),
+            ));
+        }
+    }
+
+    if env.is_some() {
+        for abstract_env in &space.abstract_environments {
+            let path = format!("{}.{}", abstract_env, rest);
+            if step(space, spaces, &path, source).is_some() {
+                return Some((
+                    Origin::AbstractEnvironment(abstract_env.clone()),
+                    next(&space.name, path),
+                ));
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{explain_weaveconfig, test_utils::TempDir};
+
+    #[tokio::test]
+    async fn test_explain_names_contributing_file() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "prod": { "db": { "host": "db.prod" } } }"#,
+        );
+        dir.write(
+            "weaveconfig/api/_space.json",
+            r#"{ "name": "api", "environments": [{ "name": "base", "abstract": true }, "dev", "production"], "space_to_parent_mapping": { "dev": ["dev"], "production": ["prod"] }, "dependencies": ["shared"] }"#,
+        );
+        dir.write(
+            "weaveconfig/api/_env.json",
+            r#"{ "base": { "retries": 3 } }"#,
+        );
+        dir.write("weaveconfig/shared/_space.json", r#"{ "name": "shared" }"#);
+        dir.write("weaveconfig/shared/_env.json", r#"{ "region": "eu" }"#);
+
+        let root = dir.path().join("weaveconfig");
+        let explanation = explain_weaveconfig(&root, "api", "production.db.host")
+            .await
+            .unwrap();
+        assert_eq!(explanation.source, root.join("_env.json"));
+        assert_eq!(
+            explanation.to_string(),
+            format!(
+                "\"db.prod\"\n  api: production.db.host is inherited from the parent space \"root\"\n  root: prod.db.host is defined in {:?}\n",
+                root.join("_env.json")
+            )
+        );
+
+        let explanation = explain_weaveconfig(&root, "api", "region").await.unwrap();
+        let steps: Vec<String> = explanation.steps.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            steps,
+            vec![
+                "api: region is imported from the dependency \"shared\"".to_string(),
+                format!(
+                    "shared: region is defined in {:?}",
+                    root.join("shared/_env.json")
+                ),
+            ]
+        );
+
+        let explanation = explain_weaveconfig(&root, "api", "dev.retries")
+            .await
+            .unwrap();
+        assert_eq!(explanation.steps.len(), 2);
+        assert_eq!(explanation.source, root.join("api/_env.json"));
+
+        assert!(explain_weaveconfig(&root, "api", "missing").await.is_err());
+    }
+}
@@ -0,0 +1,92 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::{map_path::map_path, resolve_spaces::ResolvedSpace};
+
+/// The view of a resolved space in the document of [`export_resolution`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExportedSpace {
+    /// The environments of the space, in declaration order.
+    pub environments: Vec<String>,
+    /// The resolved variables, including the objects of the environments.
+    pub variables: Option<Map<String, Value>>,
+    /// The directory the outputs of the space are written to.
+    pub output_path: PathBuf,
+}
+
+/// Builds a single document of all resolved spaces, keyed and sorted by space name,
+/// for tools that consume the configuration without reading the gen folders.
+pub fn export_resolution(
+    spaces: &HashMap<String, ResolvedSpace>,
+    weave_config_root: &Path,
+) -> Result<BTreeMap<String, ExportedSpace>, anyhow::Error> {
+    spaces
+        .values()
+        .map(|space| {
+            Ok((
+                space.name.clone(),
+                ExportedSpace {
+                    environments: space.environments.iter().cloned().collect(),
+                    variables: space.variables.clone(),
+                    // Collected from the components to drop the trailing separator of the root space
+                    output_path: map_path(weave_config_root, &space.path, None)?
+                        .components()
+                        .collect(),
+                },
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{resolve_weaveconfig, test_utils::TempDir};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_export_resolution() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "port": 3000, "prod": { "host": "prod.com" } }"#,
+        );
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{ "name": "app", "environments": ["dev", "prod"] }"#,
+        );
+        dir.write("weaveconfig/app/_env.json", r#"{ "name": "app" }"#);
+        std::fs::create_dir(dir.path().join("app")).unwrap();
+
+        let weaveconfig_root = dir.path().join("weaveconfig");
+        let resolved = resolve_weaveconfig(&weaveconfig_root).await.unwrap();
+        let exported =
+            serde_json::to_value(export_resolution(&resolved, &weaveconfig_root).unwrap()).unwrap();
+
+        let project = dir.path().canonicalize().unwrap();
+        assert_eq!(
+            exported,
+            json!({
+                "app": {
+                    "environments": ["dev", "prod"],
+                    "variables": { "name": "app", "port": 3000, "dev": {}, "prod": { "host": "prod.com" } },
+                    "output_path": project.join("app"),
+                },
+                "root": {
+                    "environments": ["dev", "prod"],
+                    "variables": { "port": 3000, "dev": {}, "prod": { "host": "prod.com" } },
+                    "output_path": project,
+                },
+            })
+        );
+    }
+}
@@ -1,12 +1,27 @@
 use std::{
-    collections::HashSet,
+    collections::HashMap,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use anyhow::{anyhow, Context};
 use futures::{stream::FuturesOrdered, StreamExt};
+use indexmap::IndexSet;
+use thiserror::Error;
+use tokio::sync::Semaphore;
 
-use crate::{merging::merge_map_consume, parse_jsonc::parse_jsonc, schemas::SpaceInfo};
+use crate::{
+    apply_resolved::insert_builtin,
+    cancellation::CancellationToken,
+    file_system::FileSystem,
+    merging::{
+        is_path_prefix, merge_map_consume_keyed, merge_map_consume_tracked, record_provenance,
+        ConflictPolicy, Conflicts, Provenance,
+    },
+    parse_jsonc::parse_jsonc,
+    schemas::{InlinePrecedence, SchemaDraft, SpaceInfo, UnknownFilePolicy, WeaveconfigRc},
+    template_file::{render_template, value_type, MissingBehavior},
+};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Directory {
@@ -15,60 +30,175 @@ pub struct Directory {
     pub parent_directory: Option<PathBuf>,
     pub space: Option<SpaceNode>,
     pub rest_to_copy: Vec<PathBuf>,
+    /// The `.weaveconfigrc` of the weaveconfig root, only set on the root directory.
+    pub defaults: Option<WeaveconfigRc>,
+    /// The variables of the weaveconfig root if it is not a space, only set on the root directory.
+    pub globals: Option<GlobalVariables>,
+    /// Whether the directory contains a `_weavelink` marker, see [`LINK_MARKER`].
+    pub link: bool,
+    /// The `_` prefixed files that are not known weaveconfig files, copied like other files
+    /// with a warning under [`UnknownFilePolicy::Warn`].
+    pub unknown_files: Vec<PathBuf>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct SpaceNode {
     pub info: SpaceInfo,
     pub variables: Option<serde_json::Map<String, serde_json::Value>>,
+    /// The file each variable was defined in.
+    pub provenance: Provenance,
+}
+
+/// Variables defined directly in a weaveconfig root without a `_space.json`.
+/// They are merged into every space with the lowest precedence, see [`crate::resolve_spaces`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobalVariables {
+    pub variables: serde_json::Map<String, serde_json::Value>,
+    /// The file each variable was defined in.
+    pub provenance: Provenance,
 }
 
 const FORENV_PREFIX: &str = "_forenv";
 
+/// A file marking that the files of its directory and all subdirectories are symlinked into the
+/// output instead of copied, unless they use the template syntax.
+pub const LINK_MARKER: &str = "_weavelink";
+
+/// The name of the defaults file in the weaveconfig root.
+pub(crate) const RC_FILE_NAME: &str = ".weaveconfigrc";
+
+/// The name of the lockfile in the weaveconfig root, see [`crate::lock_hashes`].
+/// It is not copied like other files.
+pub const LOCK_FILE_NAME: &str = "weave.lock";
+
+/// The default number of directories that are read at the same time.
+pub const DEFAULT_FANOUT: usize = 64;
+
+/// The state shared by all directories of a traversal.
+#[derive(Clone)]
+struct TraverseContext {
+    cancel: CancellationToken,
+    /// Limits the number of directories read at the same time, unbounded if `None`.
+    permits: Option<Arc<Semaphore>>,
+    /// What happens to `_` prefixed files that are not recognized.
+    unknown_files: UnknownFilePolicy,
+    fs: Arc<dyn FileSystem>,
+}
+
 /// Creates a graph of the weaveconfig configuration.
 /// The root of the graph is typically the `weaveconfig` directory within the project root.
+///
+/// At most `fanout` directories are read at the same time, or any number if `None`.
+/// The result does not depend on the fanout, subdirectories are sorted by name.
+///
+/// The variable files of a directory (`_env.json`, `_<env>.env.json`, `_env.<env>.json` and the inline
+/// `variables` of `_space.json`) are deep merged into one object. The files are merged in the order of
+/// their names, `_env.<env>.json` files last. Objects are merged key by key, other values must be equal
+/// in every file defining them, so the merged variables do not depend on this order. It only decides
+/// which file the provenance of a value defined identically in several files points to, the first one.
+/// The schemas are validated once all files are merged, against each environment object or, without
+/// environments, against the top level.
 pub async fn traverse_directory(
     weaveconfig_config_root: &Path,
+    cancel: &CancellationToken,
+    fanout: Option<usize>,
+    fs: Arc<dyn FileSystem>,
 ) -> Result<Directory, anyhow::Error> {
-    let path = weaveconfig_config_root
-        .canonicalize()
+    let path = fs
+        .canonicalize(weaveconfig_config_root)
+        .await
         .with_context(|| format!("Failed to canonicalize path: {:?}", weaveconfig_config_root))?;
 
+    // The defaults are read first, they decide how the other files are processed
+    let defaults = read_defaults(fs.as_ref(), &path.join(RC_FILE_NAME)).await?;
+    let unknown_files = defaults
+        .as_ref()
+        .and_then(|defaults| defaults.unknown_files)
+        .unwrap_or_default();
     let mut root_directory = Directory {
         directories: Vec::new(),
         path,
         parent_directory: None,
         space: None,
         rest_to_copy: Vec::new(),
+        defaults,
+        globals: None,
+        link: false,
+        unknown_files: Vec::new(),
     };
 
-    locate_directories(&mut root_directory).await?;
+    let ctx = TraverseContext {
+        cancel: cancel.clone(),
+        permits: fanout.map(|fanout| Arc::new(Semaphore::new(fanout.max(1)))),
+        unknown_files,
+        fs,
+    };
+    locate_directories(&mut root_directory, ctx).await?;
 
     Ok(root_directory)
 }
 
-async fn locate_directories(directory: &mut Directory) -> Result<(), anyhow::Error> {
-    let mut entries = tokio::fs::read_dir(&directory.path)
+/// Reads the `.weaveconfigrc` at `path`, `None` if there is none.
+async fn read_defaults(
+    fs: &dyn FileSystem,
+    path: &Path,
+) -> Result<Option<WeaveconfigRc>, anyhow::Error> {
+    match fs.metadata(path).await {
+        Ok(_) => {}
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => {
+            return Err(error)
+                .with_context(|| format!("Failed to check for the defaults file: {:?}", path))
+        }
+    }
+    let content = read_file_to_string(fs, path)
+        .await
+        .with_context(|| format!("Failed to read defaults file: {:?}", path))?;
+    let defaults = parse_jsonc(&content)
+        .with_context(|| format!("Failed to parse JSON in defaults file: {:?}", path))?;
+    Ok(Some(defaults))
+}
+
+async fn locate_directories(
+    directory: &mut Directory,
+    ctx: TraverseContext,
+) -> Result<(), anyhow::Error> {
+    ctx.cancel.check()?;
+    // The permit is held while the directory and its files are read. Subdirectories are only polled after it is
+    // released, so nested directories never wait on their parent.
+    let permit = match &ctx.permits {
+        Some(permits) => Some(permits.clone().acquire_owned().await?),
+        None => None,
+    };
+    let mut entries = ctx
+        .fs
+        .read_dir(&directory.path)
         .await
         .with_context(|| format!("Failed to read directory: {:?}", directory.path))?;
 
     let mut futures = FuturesOrdered::new();
     let mut variables: Option<serde_json::Map<String, serde_json::Value>> = None;
-    let mut validation_schema: Option<serde_json::Value> = None;
+    let mut provenance = Provenance::new();
+    let mut inline_variables: Option<(PathBuf, serde_json::Map<String, serde_json::Value>)> = None;
+    let mut validation_schema: Option<(PathBuf, String)> = None;
+    let mut environment_schemas: Vec<(String, PathBuf, String)> = Vec::new();
+    let mut environment_variables: Vec<(
+        String,
+        PathBuf,
+        serde_json::Map<String, serde_json::Value>,
+    )> = Vec::new();
 
-    while let Some(entry) = entries
-        .next_entry()
-        .await
-        .with_context(|| format!("Failed to read entry in directory: {:?}", directory.path))?
-    {
-        let metadata = entry
-            .metadata()
-            .await
-            .with_context(|| format!("Failed to get metadata for entry: {:?}", entry.path()))?;
-        let entry_path = entry.path();
+    // Entries are processed sorted by name, so neither the order the variable files are merged in
+    // nor the order of the subdirectories depends on the file system
+    entries.sort_by(|a, b| a.path.file_name().cmp(&b.path.file_name()));
+
+    for entry in entries {
+        let entry_path = entry.path;
+        let file_name = entry_path.file_name().unwrap_or_default();
 
-        if metadata.is_dir() {
+        if entry.is_dir {
             let parent_path = directory.path.clone();
+            let ctx = ctx.clone();
             futures.push_back(Box::pin(async move {
                 let mut sub_directory = Directory {
                     directories: Vec::new(),
@@ -76,9 +206,13 @@ async fn locate_directories(directory: &mut Directory) -> Result<(), anyhow::Err
                     parent_directory: Some(parent_path.clone()),
                     space: None,
                     rest_to_copy: Vec::new(),
+                    defaults: None,
+                    globals: None,
+                    link: false,
+                    unknown_files: Vec::new(),
                 };
 
-                if let Err(e) = locate_directories(&mut sub_directory).await {
+                if let Err(e) = locate_directories(&mut sub_directory, ctx).await {
                     return Err(e.context(format!(
                         "Failed to locate subdirectories in {:?}",
                         entry_path
@@ -86,12 +220,19 @@ async fn locate_directories(directory: &mut Directory) -> Result<(), anyhow::Err
                 }
                 Ok(sub_directory)
             }));
+        } else if directory.parent_directory.is_none()
+            && (file_name == RC_FILE_NAME || file_name == LOCK_FILE_NAME)
+        {
+            continue;
         } else {
-            let file_type = process_file(entry_path.clone())
+            let file_type = process_file(ctx.fs.as_ref(), entry_path.clone(), ctx.unknown_files)
                 .await
                 .with_context(|| format!("Failed to process file: {:?}", entry_path))?;
             match file_type {
-                FileType::Space(space) => {
+                FileType::Space(mut space) => {
+                    if let Some(inline) = space.variables.take() {
+                        inline_variables = Some((entry_path.clone(), inline));
+                    }
                     if directory.space.is_some() {
                         return Err(anyhow!(
                             "Directory {:?} has multiple space configurations. Only one '_space.json' file is allowed per directory.",
@@ -99,47 +240,102 @@ async fn locate_directories(directory: &mut Directory) -> Result<(), anyhow::Err
                         ));
                     }
                     directory.space = Some(SpaceNode {
-                        info: space,
+                        info: *space,
                         variables: None,
+                        provenance: Provenance::new(),
                     });
                 }
-                FileType::Schema(schema) => {
-                    validation_schema = Some(schema);
+                FileType::Schema(content) => {
+                    validation_schema = Some((entry_path, content));
+                }
+                FileType::EnvironmentSchema(environment, content) => {
+                    environment_schemas.push((environment, entry_path, content));
+                }
+                FileType::Variables(value) => {
+                    add_variables(&mut variables, &mut provenance, value, &entry_path)?;
+                }
+                FileType::EnvironmentVariables(environment, value) => {
+                    environment_variables.push((environment, entry_path, value));
+                }
+                FileType::LinkMarker => {
+                    directory.link = true;
                 }
-                FileType::Variables(value) => match (&mut variables, value) {
-                    (None, value) => variables = Some(value),
-                    (Some(main_map), map) => {
-                        merge_map_consume(main_map, map)?;
-                    }
-                },
                 FileType::Rest(path) => {
                     directory.rest_to_copy.push(path);
                 }
+                FileType::Unknown(path) => {
+                    directory.unknown_files.push(path.clone());
+                    directory.rest_to_copy.push(path);
+                }
             }
         }
     }
 
-    match (&mut directory.space, variables, validation_schema) {
-        (Some(space), Some(variables), schema) => {
-            if let Some(schema) = schema {
-                validate_space_schema(space, &variables, schema)?;
+    drop(permit);
+
+    // Added once the whole directory is read, as the environments are declared by the space
+    for (environment, path, value) in environment_variables {
+        if let Some(space) = &directory.space {
+            check_environment_declared(space, &environment)
+                .with_context(|| format!("Failed to add the variables of {:?}", path))?;
+        }
+        let mut map = serde_json::Map::new();
+        map.insert(environment, serde_json::Value::Object(value));
+        add_variables(&mut variables, &mut provenance, map, &path)?;
+    }
+    // Added once all variable files are merged, so the precedence does not depend on file names
+    if let (Some((path, inline)), Some(space)) = (inline_variables, &directory.space) {
+        add_inline_variables(
+            &mut variables,
+            &mut provenance,
+            inline,
+            &path,
+            space.info.inline_precedence,
+            space.info.strict,
+        )?;
+    }
+
+    let has_schema = validation_schema.is_some() || !environment_schemas.is_empty();
+    match (&mut directory.space, variables, has_schema) {
+        (Some(space), Some(variables), _) => {
+            if space.info.validate {
+                if let Some((path, content)) = validation_schema {
+                    let context = schema_context(space, &variables, None)
+                        .with_context(|| format!("Failed to template the schema {:?}", path))?;
+                    let schema = parse_schema(&content, &context, &path)?;
+                    validate_space_schema(space, &variables, schema, &path)?;
+                }
+                for (environment, path, content) in environment_schemas {
+                    let context = schema_context(space, &variables, Some(&environment))
+                        .with_context(|| format!("Failed to template the schema {:?}", path))?;
+                    let schema = parse_schema(&content, &context, &path)?;
+                    validate_environment_schema(space, &variables, &environment, schema, &path)
+                        .with_context(|| format!("Failed to validate the schema {:?}", path))?;
+                }
             }
 
             space.variables = Some(variables);
+            space.provenance = provenance;
         }
-        (Some(_), None, Some(_)) => {
+        (Some(_), None, true) => {
             return Err(anyhow!(
                 "Directory {:?} contains a schema but no variables, for example '_env.json'.",
                 directory.path
             ));
         }
+        (None, Some(variables), false) if directory.parent_directory.is_none() => {
+            directory.globals = Some(GlobalVariables {
+                variables,
+                provenance,
+            });
+        }
         (None, Some(_), _) => {
             return Err(anyhow!(
                 "Directory {:?} contains variables but no '_space.json' configuration file.",
                 directory.path
             ));
         }
-        (None, None, Some(_)) => {
+        (None, None, true) => {
             return Err(anyhow!(
                 "Directory {:?} contains a schema but no '_space.json' configuration file.",
                 directory.path
@@ -158,14 +354,125 @@ async fn locate_directories(directory: &mut Directory) -> Result<(), anyhow::Err
     Ok(())
 }
 
+/// Merges the variables of one file into the variables of its directory.
+fn add_variables(
+    variables: &mut Option<serde_json::Map<String, serde_json::Value>>,
+    provenance: &mut Provenance,
+    value: serde_json::Map<String, serde_json::Value>,
+    source: &Path,
+) -> Result<(), anyhow::Error> {
+    let source = source.to_path_buf();
+    match variables {
+        None => {
+            record_provenance(&value, &source, provenance);
+            *variables = Some(value);
+        }
+        Some(main_map) => {
+            merge_map_consume_tracked(main_map, value, &source, provenance)
+                .with_context(|| format!("Failed to merge the variables of {:?}", source))?;
+        }
+    }
+    Ok(())
+}
+
+/// Merges the inline `variables` of `_space.json` into the variables of the files of its directory.
+/// Different values at the same path are resolved by `precedence`, or are an error naming both
+/// files if `strict`.
+fn add_inline_variables(
+    variables: &mut Option<serde_json::Map<String, serde_json::Value>>,
+    provenance: &mut Provenance,
+    inline: serde_json::Map<String, serde_json::Value>,
+    source: &Path,
+    precedence: InlinePrecedence,
+    strict: bool,
+) -> Result<(), anyhow::Error> {
+    let Some(main_map) = variables else {
+        return add_variables(variables, provenance, inline, source);
+    };
+    let source = source.to_path_buf();
+    let mut inline_provenance = Provenance::new();
+    record_provenance(&inline, &source, &mut inline_provenance);
+
+    let mut conflicts = Conflicts::new(match precedence {
+        InlinePrecedence::Files => ConflictPolicy::KeepFirst,
+        InlinePrecedence::Inline => ConflictPolicy::KeepLast,
+    });
+    merge_map_consume_keyed(
+        main_map,
+        inline,
+        &HashMap::new(),
+        &IndexSet::new(),
+        &mut conflicts,
+    )?;
+    if strict && !conflicts.found.is_empty() {
+        let described: Vec<String> = conflicts
+            .found
+            .iter()
+            .map(|conflict| {
+                // The first file defining the path or a value within it
+                let defined_in = provenance
+                    .iter()
+                    .filter(|(path, _)| {
+                        **path == conflict.path || is_path_prefix(&conflict.path, path)
+                    })
+                    .map(|(_, source)| source)
+                    .min()
+                    .map_or("a variable file".to_string(), |source| {
+                        format!("{:?}", source)
+                    });
+                format!(
+                    "{} is {} in {} and {} inline",
+                    conflict.path, conflict.existing, defined_in, conflict.incoming
+                )
+            })
+            .collect();
+        return Err(anyhow!(
+            "The inline variables of {:?} conflict with the variable files of the strict space: {}",
+            source,
+            described.join(", ")
+        ));
+    }
+
+    let overridden = |path: &str| {
+        conflicts
+            .found
+            .iter()
+            .any(|conflict| conflict.path == path || is_path_prefix(&conflict.path, path))
+    };
+    if precedence == InlinePrecedence::Inline {
+        provenance.retain(|path, _| !overridden(path));
+    }
+    for (path, source) in inline_provenance {
+        if !overridden(&path) {
+            provenance.entry(path).or_insert(source);
+        } else if precedence == InlinePrecedence::Inline {
+            provenance.insert(path, source);
+        }
+    }
+    Ok(())
+}
+
 enum FileType {
-    Space(SpaceInfo),
+    Space(Box<SpaceInfo>),
     Variables(serde_json::Map<String, serde_json::Value>),
-    Schema(serde_json::Value),
+    /// The content of a schema, which is parsed once the variables it references are known.
+    Schema(String),
+    /// A schema that only applies to one environment, from `_schema.<env>.json`.
+    EnvironmentSchema(String, String),
+    /// Variables that only apply to one environment, from `_env.<env>.json`.
+    EnvironmentVariables(String, serde_json::Map<String, serde_json::Value>),
+    /// The `_weavelink` marker.
+    LinkMarker,
     Rest(PathBuf),
+    /// A `_` prefixed file that is not a known weaveconfig file, copied with a warning.
+    Unknown(PathBuf),
 }
 
-async fn process_file(file_path: PathBuf) -> Result<FileType, anyhow::Error> {
+async fn process_file(
+    fs: &dyn FileSystem,
+    file_path: PathBuf,
+    unknown_files: UnknownFilePolicy,
+) -> Result<FileType, anyhow::Error> {
     let file_name = file_path
         .file_name()
         .and_then(|name| name.to_str())
@@ -176,7 +483,7 @@ async fn process_file(file_path: PathBuf) -> Result<FileType, anyhow::Error> {
         match segments.as_slice() {
             ["_space", ext] => {
                 validate_json_extension(ext, file_name)?;
-                let content = read_file_to_string(&file_path)
+                let content = read_file_to_string(fs, &file_path)
                     .await
                     .with_context(|| format!("Failed to read space configuration file: {:?}", file_path))?;
                 let space_schema: SpaceInfo = parse_jsonc(&content).with_context(|| {
@@ -185,11 +492,11 @@ async fn process_file(file_path: PathBuf) -> Result<FileType, anyhow::Error> {
                         file_path
                     )
                 })?;
-                Ok(FileType::Space(space_schema))
+                Ok(FileType::Space(Box::new(space_schema)))
             }
             ["_env", ext] => {
                 validate_json_extension(ext, file_name)?;
-                let content = read_file_to_string(&file_path)
+                let content = read_file_to_string(fs, &file_path)
                     .await
                     .with_context(|| format!("Failed to read variables file: {:?}", file_path))?;
                 let map: serde_json::Map<String, serde_json::Value> = parse_jsonc(&content)
@@ -198,7 +505,7 @@ async fn process_file(file_path: PathBuf) -> Result<FileType, anyhow::Error> {
             }
             [prefix, "env", ext] if prefix.starts_with('_') => {
                 validate_json_extension(ext, file_name)?;
-                let content = read_file_to_string(&file_path)
+                let content = read_file_to_string(fs, &file_path)
                     .await
                     .with_context(|| format!("Failed to read prefixed variables file: {:?}", file_path))?;
                 let variables: serde_json::Map<String, serde_json::Value> =
@@ -214,20 +521,37 @@ async fn process_file(file_path: PathBuf) -> Result<FileType, anyhow::Error> {
                 map.insert(prefix, serde_json::Value::Object(variables));
                 Ok(FileType::Variables(map))
             }
+            ["_env", environment, ext] => {
+                validate_json_extension(ext, file_name)?;
+                let content = read_file_to_string(fs, &file_path)
+                    .await
+                    .with_context(|| format!("Failed to read variables file: {:?}", file_path))?;
+                let map: serde_json::Map<String, serde_json::Value> = parse_jsonc(&content)
+                    .with_context(|| format!("Failed to parse JSON variables in file: {:?}", file_path))?;
+                Ok(FileType::EnvironmentVariables(environment.to_string(), map))
+            }
             ["_schema", ext] => {
                 validate_json_extension(ext, file_name)?;
-                let content = read_file_to_string(&file_path)
+                let content = read_file_to_string(fs, &file_path)
                     .await
                     .with_context(|| format!("Failed to read schema file: {:?}", file_path))?;
-                let schema: serde_json::Value = parse_jsonc(&content)
-                    .with_context(|| format!("Failed to parse JSON schema in file: {:?}", file_path))?;
-                Ok(FileType::Schema(schema))
+                Ok(FileType::Schema(content))
             }
+            ["_schema", environment, ext] => {
+                validate_json_extension(ext, file_name)?;
+                let content = read_file_to_string(fs, &file_path)
+                    .await
+                    .with_context(|| format!("Failed to read schema file: {:?}", file_path))?;
+                Ok(FileType::EnvironmentSchema(environment.to_string(), content))
+            }
+            [LINK_MARKER] => Ok(FileType::LinkMarker),
             segments if segments.first() == Some(&FORENV_PREFIX) => {
                 Ok(FileType::Rest(file_path))
             }
+            _ if unknown_files == UnknownFilePolicy::Copy => Ok(FileType::Rest(file_path)),
+            _ if unknown_files == UnknownFilePolicy::Warn => Ok(FileType::Unknown(file_path)),
             _ => Err(anyhow!(
-                "Invalid file name format: '{}'. Expected '_space.json', '_env.json', '_<prefix>_env.json', '_schema.json' or '_forenv.<rest>'.",
+                "Invalid file name format: '{}'. Expected '_space.json', '_env.json', '_env.<env>.json', '_<prefix>_env.json', '_schema.json', '_schema.<env>.json', '_forenv.<rest>' or '_weavelink'.",
                 file_name
             )),
         }
@@ -249,42 +573,157 @@ fn validate_json_extension(ext: &str, file_name: &str) -> Result<(), anyhow::Err
 }
 
 /// Reads the entire contents of a file asynchronously as a String.
-async fn read_file_to_string(path: &Path) -> Result<String, anyhow::Error> {
-    tokio::fs::read_to_string(path)
+async fn read_file_to_string(fs: &dyn FileSystem, path: &Path) -> Result<String, anyhow::Error> {
+    fs.read_to_string(path)
         .await
         .with_context(|| format!("Failed to read file: {:?}", path))
 }
 
-fn validate_space_schema(
+/// The variables of a space do not match its schema.
+#[derive(Debug, Error)]
+#[error("{message}")]
+pub struct SchemaValidationError {
+    pub message: String,
+    /// The name of the space whose variables are invalid.
+    pub space: String,
+    /// The schema file the variables were validated against.
+    pub file: PathBuf,
+}
+
+fn jsonschema_draft(draft: SchemaDraft) -> jsonschema::Draft {
+    match draft {
+        SchemaDraft::Draft4 => jsonschema::Draft::Draft4,
+        SchemaDraft::Draft6 => jsonschema::Draft::Draft6,
+        SchemaDraft::Draft7 => jsonschema::Draft::Draft7,
+        SchemaDraft::Draft201909 => jsonschema::Draft::Draft201909,
+        SchemaDraft::Draft202012 => jsonschema::Draft::Draft202012,
+    }
+}
+
+/// The variables a schema is templated with, the variables of the space and the built-ins of copied
+/// files: `__space`, and for the schema of an environment `env`, `envs` and the `is_<env>` flags.
+fn schema_context(
     space: &SpaceNode,
     variables: &serde_json::Map<String, serde_json::Value>,
-    schema: serde_json::Value,
-) -> Result<(), anyhow::Error> {
-    let validator = jsonschema::Validator::new(&schema).with_context(|| {
+    environment: Option<&str>,
+) -> Result<serde_json::Map<String, serde_json::Value>, anyhow::Error> {
+    let mut context = variables.clone();
+    if let Some(environment) = environment {
+        let environments: Vec<&str> = space
+            .info
+            .environments
+            .iter()
+            .flatten()
+            .filter(|declared| !declared.is_abstract())
+            .map(|declared| declared.name())
+            .collect();
+        insert_builtin(&mut context, "env", environment.into())?;
+        insert_builtin(&mut context, "envs", environments.clone().into())?;
+        for other in environments {
+            insert_builtin(
+                &mut context,
+                &format!("is_{}", other),
+                (other == environment).into(),
+            )?;
+        }
+    }
+    insert_builtin(&mut context, "__space", space.info.name.clone().into())?;
+    Ok(context)
+}
+
+/// Parses a schema after replacing its `{{ variable }}` references with the variables of the space,
+/// e.g. `"enum": {{ regions }}` with an array of allowed values. References to variables that are not
+/// defined are kept as they are, so schemas containing a literal `{{`, like in a description, still load.
+fn parse_schema(
+    content: &str,
+    variables: &serde_json::Map<String, serde_json::Value>,
+    path: &Path,
+) -> Result<serde_json::Value, anyhow::Error> {
+    let content =
+        render_template(content, variables, MissingBehavior::KeepLiteral).with_context(|| {
+            format!(
+                "Failed to substitute the variables in the schema {:?}",
+                path
+            )
+        })?;
+    let schema: serde_json::Value = parse_jsonc(&content)
+        .with_context(|| format!("Failed to parse JSON schema in file: {:?}", path))?;
+    // Any other value would fail with a less helpful error when building the validator
+    if !schema.is_object() && !schema.is_boolean() {
+        return Err(anyhow!(
+            "The schema {:?} must be an object or a boolean, but is {}",
+            path,
+            value_type(&schema)
+        ));
+    }
+    Ok(schema)
+}
+
+fn build_validator(
+    space: &SpaceNode,
+    schema: &serde_json::Value,
+) -> Result<jsonschema::Validator, anyhow::Error> {
+    match space.info.schema_draft {
+        Some(pinned) => {
+            let draft = jsonschema_draft(pinned);
+            let declared = draft.detect(schema).with_context(|| {
+                format!(
+                    "Unknown '$schema' in space schema for space: {:?}",
+                    space.info.name
+                )
+            })?;
+            if declared != draft {
+                return Err(anyhow!(
+                    "The space schema of space {:?} declares draft {:?} in '$schema', but the space pins draft {:?}.",
+                    space.info.name,
+                    declared,
+                    draft
+                ));
+            }
+            jsonschema::options().with_draft(draft).build(schema)
+        }
+        None => jsonschema::Validator::new(schema),
+    }
+    .with_context(|| {
         format!(
             "Failed to create validator for space schema: {:?} for space: {:?}",
             schema, space.info.name
         )
-    })?;
+    })
+}
+
+fn validate_space_schema(
+    space: &SpaceNode,
+    variables: &serde_json::Map<String, serde_json::Value>,
+    schema: serde_json::Value,
+    path: &Path,
+) -> Result<(), anyhow::Error> {
+    let validator = build_validator(space, &schema)?;
 
     if space
         .info
         .environments
         .as_ref()
-        .unwrap_or(&HashSet::new())
+        .unwrap_or(&IndexSet::new())
         .is_empty()
     {
         // Validate on the top level
         let object = serde_json::Value::Object(variables.clone());
         if let Err(e) = validator.validate(&object) {
-            return Err(anyhow!(
-                "Failed to validate variables against space schema: {}",
-                e
+            return Err(schema_validation_error(
+                space,
+                path,
+                format!("Failed to validate variables against space schema: {}", e),
             ));
         }
     } else {
         // Validate on the environment level
         for environment in space.info.environments.as_ref().unwrap() {
+            // Abstract environments only hold a part of the variables of the other environments
+            if environment.is_abstract() {
+                continue;
+            }
+            let environment = environment.name();
             let object = variables
                 .get(environment)
                 .ok_or_else(|| {
@@ -294,10 +733,13 @@ fn validate_space_schema(
                     )
                 })?;
             if let Err(e) = validator.validate(object) {
-                return Err(anyhow!(
-                    "Failed to validate variables of environment {} against space schema: {}",
-                    environment,
-                    e
+                return Err(schema_validation_error(
+                    space,
+                    path,
+                    format!(
+                        "Failed to validate variables of environment {} against space schema: {}",
+                        environment, e
+                    ),
                 ));
             }
         }
@@ -305,3 +747,565 @@ fn validate_space_schema(
 
     Ok(())
 }
+
+/// Checks that a file scoped to an environment, like `_schema.<env>.json`, names an environment of the space.
+fn check_environment_declared(space: &SpaceNode, environment: &str) -> Result<(), anyhow::Error> {
+    let declared = space
+        .info
+        .environments
+        .iter()
+        .flatten()
+        .any(|declared| declared.name() == environment);
+    if !declared {
+        return Err(anyhow!(
+            "The file is for the environment {:?}, which the space {:?} does not declare.",
+            environment,
+            space.info.name
+        ));
+    }
+    Ok(())
+}
+
+/// Validates the variables of a single environment against its `_schema.<env>.json`.
+fn validate_environment_schema(
+    space: &SpaceNode,
+    variables: &serde_json::Map<String, serde_json::Value>,
+    environment: &str,
+    schema: serde_json::Value,
+    path: &Path,
+) -> Result<(), anyhow::Error> {
+    check_environment_declared(space, environment)?;
+    let validator = build_validator(space, &schema)?;
+    let object = variables.get(environment).ok_or_else(|| {
+        anyhow!(
+            "Environment {} not found in variables, but required due to the presence of a schema.",
+            environment
+        )
+    })?;
+    if let Err(e) = validator.validate(object) {
+        return Err(schema_validation_error(
+            space,
+            path,
+            format!(
+                "Failed to validate variables of environment {} against its schema: {}",
+                environment, e
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn schema_validation_error(space: &SpaceNode, path: &Path, message: String) -> anyhow::Error {
+    SchemaValidationError {
+        message,
+        space: space.info.name.clone(),
+        file: path.to_path_buf(),
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{traverse_directory, SchemaValidationError};
+    use crate::{
+        cancellation::CancellationToken, file_system::TokioFileSystem, generate_weaveconfig_with,
+        resolve_weaveconfig, test_utils::TempDir, GenerateOptions, WeaveError,
+    };
+    use std::sync::Arc;
+
+    fn setup(schema_draft: &str, schema: &str) -> TempDir {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            &format!(
+                r#"{{ "name": "root", "schema_draft": "{}" }}"#,
+                schema_draft
+            ),
+        );
+        dir.write("weaveconfig/_env.json", r#"{ "ports": ["http"] }"#);
+        dir.write("weaveconfig/_schema.json", schema);
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_pinned_schema_draft() {
+        // `prefixItems` only exists since draft 2020-12, older drafts ignore it
+        let schema = r#"{ "properties": { "ports": { "prefixItems": [{ "type": "number" }] } } }"#;
+
+        let dir = setup("draft-07", schema);
+        assert!(resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .is_ok());
+
+        let dir = setup("2020-12", schema);
+        assert!(resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pinned_schema_draft_conflict() {
+        let dir = setup(
+            "2020-12",
+            r#"{ "$schema": "http://json-schema.org/draft-07/schema#" }"#,
+        );
+        let error = resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap_err();
+        assert!(format!("{:?}", error).contains("pins draft"));
+    }
+
+    #[tokio::test]
+    async fn test_bounded_fanout() {
+        let dir = TempDir::new();
+        dir.write("weaveconfig/_space.json", r#"{ "name": "root" }"#);
+        let mut level = vec![String::from("weaveconfig")];
+        for depth in 0..4 {
+            level = level
+                .iter()
+                .flat_map(|parent| (0..4).map(move |i| format!("{}/d{}_{}", parent, depth, i)))
+                .collect();
+            for path in &level {
+                dir.write(&format!("{}/file.txt", path), path);
+            }
+        }
+
+        let root = dir.path().join("weaveconfig");
+        let cancel = CancellationToken::new();
+        let unbounded = traverse_directory(&root, &cancel, None, Arc::new(TokioFileSystem))
+            .await
+            .unwrap();
+        for fanout in [1, 3] {
+            let bounded =
+                traverse_directory(&root, &cancel, Some(fanout), Arc::new(TokioFileSystem))
+                    .await
+                    .unwrap();
+            assert_eq!(bounded, unbounded);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disabled_validation() {
+        let dir = TempDir::new();
+        dir.write("weaveconfig/_env.json", r#"{ "port": "not a number" }"#);
+        dir.write(
+            "weaveconfig/_schema.json",
+            r#"{ "properties": { "port": { "type": "number" } } }"#,
+        );
+
+        dir.write("weaveconfig/_space.json", r#"{ "name": "root" }"#);
+        assert!(resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .is_err());
+
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "validate": false }"#,
+        );
+        assert!(resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_inline_variables() {
+        let two_files = TempDir::new();
+        two_files.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev"] }"#,
+        );
+        two_files.write(
+            "weaveconfig/_env.json",
+            r#"{ "name": "app", "dev": { "port": 3000 } }"#,
+        );
+
+        let combined = TempDir::new();
+        combined.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev"], "variables": { "name": "app", "dev": { "port": 3000 } } }"#,
+        );
+
+        let mixed = TempDir::new();
+        mixed.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev"], "variables": { "name": "app" } }"#,
+        );
+        mixed.write("weaveconfig/_env.json", r#"{ "dev": { "port": 3000 } }"#);
+
+        let expected = resolve_weaveconfig(&two_files.path().join("weaveconfig"))
+            .await
+            .unwrap();
+        for dir in [combined, mixed] {
+            let resolved = resolve_weaveconfig(&dir.path().join("weaveconfig"))
+                .await
+                .unwrap();
+            assert_eq!(resolved["root"].variables, expected["root"].variables);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inline_precedence() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev"], "variables": { "name": "inline", "dev": { "port": 1 }, "region": "eu" } }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "name": "file", "dev": { "port": 3000 } }"#,
+        );
+        let root = dir.path().join("weaveconfig");
+        let canonical = root.canonicalize().unwrap();
+
+        let resolved = resolve_weaveconfig(&root).await.unwrap();
+        let space = &resolved["root"];
+        assert_eq!(
+            serde_json::Value::Object(space.variables.clone().unwrap()),
+            serde_json::json!({ "name": "file", "dev": { "port": 3000 }, "region": "eu" })
+        );
+        assert_eq!(space.provenance["name"], canonical.join("_env.json"));
+        assert_eq!(space.provenance["region"], canonical.join("_space.json"));
+
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev"], "inline_precedence": "inline", "variables": { "name": "inline", "dev": { "port": 1 }, "region": "eu" } }"#,
+        );
+        let resolved = resolve_weaveconfig(&root).await.unwrap();
+        let space = &resolved["root"];
+        assert_eq!(
+            serde_json::Value::Object(space.variables.clone().unwrap()),
+            serde_json::json!({ "name": "inline", "dev": { "port": 1 }, "region": "eu" })
+        );
+        assert_eq!(space.provenance["dev.port"], canonical.join("_space.json"));
+
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev"], "strict": true, "variables": { "name": "inline", "dev": { "port": 3000 } } }"#,
+        );
+        let error = resolve_weaveconfig(&root).await.unwrap_err();
+        let message = format!("{:#}", error);
+        assert!(
+            message.contains(&format!(
+                "name is \"file\" in {:?} and \"inline\" inline",
+                canonical.join("_env.json")
+            )),
+            "{}",
+            message
+        );
+        // Equal values are not a conflict
+        assert!(!message.contains("dev.port"));
+    }
+
+    #[tokio::test]
+    async fn test_environment_schema() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "dev": { "url": "localhost" }, "prod": { "url": "example.com" } }"#,
+        );
+        dir.write(
+            "weaveconfig/_schema.prod.json",
+            r#"{ "type": "object", "required": ["url", "replicas"] }"#,
+        );
+
+        let error = resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("environment prod against its schema"));
+
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "dev": { "url": "localhost" }, "prod": { "url": "example.com", "replicas": 3 } }"#,
+        );
+        resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        dir.write(
+            "weaveconfig/_schema.staging.json",
+            r#"{ "type": "object" }"#,
+        );
+        let error = resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("which the space \"root\" does not declare"));
+    }
+
+    #[tokio::test]
+    async fn test_typed_schema_error() {
+        let dir = setup(
+            "draft-07",
+            r#"{ "properties": { "ports": { "type": "string" } } }"#,
+        );
+        let error =
+            generate_weaveconfig_with(&dir.path().join("weaveconfig"), &GenerateOptions::default())
+                .await
+                .unwrap_err();
+        assert!(matches!(error, WeaveError::Schema(_)));
+        assert!(error.into_inner().is::<SchemaValidationError>());
+    }
+
+    #[tokio::test]
+    async fn test_environment_variables_file() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "name": "app", "prod": { "debug": false } }"#,
+        );
+        dir.write("weaveconfig/_env.prod.json", r#"{ "port": 443 }"#);
+
+        let resolved = resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+        let root = &resolved["root"];
+        assert_eq!(
+            serde_json::Value::Object(root.variables.clone().unwrap()),
+            serde_json::json!({
+                "name": "app",
+                "dev": {},
+                "prod": { "debug": false, "port": 443 }
+            })
+        );
+        assert_eq!(
+            root.provenance["prod.port"],
+            dir.path()
+                .join("weaveconfig/_env.prod.json")
+                .canonicalize()
+                .unwrap()
+        );
+
+        dir.write("weaveconfig/_env.staging.json", r#"{ "port": 8443 }"#);
+        let error = resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("which the space \"root\" does not declare"));
+    }
+
+    #[tokio::test]
+    async fn test_variable_file_merging() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "name": "app", "prod": { "db": { "host": "db.prod", "port": 5432 } } }"#,
+        );
+        // Overlaps the unprefixed file with the same value and adds a key to the same object
+        dir.write(
+            "weaveconfig/_prod.env.json",
+            r#"{ "db": { "host": "db.prod", "user": "admin" } }"#,
+        );
+        dir.write(
+            "weaveconfig/_dev.env.json",
+            r#"{ "db": { "host": "localhost" } }"#,
+        );
+        // Sorts after the prefixed files, but is merged last regardless
+        dir.write("weaveconfig/_env.dev.json", r#"{ "db": { "port": 5433 } }"#);
+        dir.write(
+            "weaveconfig/_schema.json",
+            r#"{ "required": ["db"], "properties": { "db": { "required": ["host", "port"] } } }"#,
+        );
+        let root = dir.path().join("weaveconfig");
+        let canonical = root.canonicalize().unwrap();
+
+        let resolved = resolve_weaveconfig(&root).await.unwrap();
+        let space = &resolved["root"];
+        assert_eq!(
+            serde_json::Value::Object(space.variables.clone().unwrap()),
+            serde_json::json!({
+                "name": "app",
+                "dev": { "db": { "host": "localhost", "port": 5433 } },
+                "prod": { "db": { "host": "db.prod", "port": 5432, "user": "admin" } }
+            })
+        );
+        // Identical values point to the file that sorts first
+        assert_eq!(
+            space.provenance["prod.db.host"],
+            canonical.join("_env.json")
+        );
+        assert_eq!(
+            space.provenance["prod.db.user"],
+            canonical.join("_prod.env.json")
+        );
+        assert_eq!(
+            space.provenance["dev.db.port"],
+            canonical.join("_env.dev.json")
+        );
+
+        // Different values fail, no matter which file is merged first
+        dir.write(
+            "weaveconfig/_prod.env.json",
+            r#"{ "db": { "host": "other" } }"#,
+        );
+        let error = resolve_weaveconfig(&root).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("_prod.env.json"));
+
+        // The schema is validated against each merged environment, so the port of prod defined
+        // only in _env.json is missing once it is removed there
+        dir.write(
+            "weaveconfig/_prod.env.json",
+            r#"{ "db": { "host": "db.prod" } }"#,
+        );
+        dir.write("weaveconfig/_env.json", r#"{ "prod": {} }"#);
+        let error = resolve_weaveconfig(&root).await.unwrap_err();
+        assert!(error.is::<SchemaValidationError>());
+    }
+
+    #[tokio::test]
+    async fn test_schema_variables() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "regions": ["eu", "us"], "dev": { "region": "eu" }, "prod": { "region": "us" } }"#,
+        );
+        dir.write(
+            "weaveconfig/_schema.json",
+            r#"{ "properties": { "region": { "enum": {{ regions }} } }, "required": ["region"] }"#,
+        );
+        let root = dir.path().join("weaveconfig");
+
+        resolve_weaveconfig(&root).await.unwrap();
+
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "regions": ["eu", "us"], "dev": { "region": "eu" }, "prod": { "region": "ap" } }"#,
+        );
+        let error = generate_weaveconfig_with(&root, &GenerateOptions::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, WeaveError::Schema(_)));
+
+        // Substituting a string where the schema expects an array produces an invalid schema
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "regions": "eu", "dev": { "region": "eu" }, "prod": { "region": "eu" } }"#,
+        );
+        dir.write(
+            "weaveconfig/_schema.json",
+            r#"{ "properties": { "region": { "enum": "{{ regions }}" } } }"#,
+        );
+        let error = resolve_weaveconfig(&root).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("Failed to create validator"));
+
+        dir.write("weaveconfig/_schema.json", r#"{{ regions }}"#);
+        let error = resolve_weaveconfig(&root).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("Failed to parse JSON schema"));
+
+        dir.write("weaveconfig/_schema.json", r#"{{ missing }}"#);
+        let error = resolve_weaveconfig(&root).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("Failed to parse JSON schema"));
+
+        dir.write("weaveconfig/_schema.json", r#"{{ regions.#len }}"#);
+        let error = resolve_weaveconfig(&root).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("Failed to substitute the variables in the schema"));
+
+        // A literal `{{` that is not a variable of the space is kept
+        dir.write(
+            "weaveconfig/_schema.json",
+            r#"{ "properties": { "greeting": { "description": "Rendered with {{ name }} at runtime", "const": "hi {{ name }}" } } }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "greeting": "hi {{ name }}", "dev": {}, "prod": {} }"#,
+        );
+        resolve_weaveconfig(&root).await.unwrap();
+
+        dir.write("weaveconfig/_schema.json", r#""{{ regions }}""#);
+        let error = resolve_weaveconfig(&root).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("must be an object or a boolean, but is string"));
+
+        // The schema of an environment sees the built-ins of its copied files
+        dir.write("weaveconfig/_schema.json", "true");
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "dev": { "host": "dev.example.com" }, "prod": { "host": "prod.example.com" } }"#,
+        );
+        dir.write(
+            "weaveconfig/_schema.prod.json",
+            r#"{ "properties": { "host": { "pattern": "^{{ env }}\\." }, "space": { "const": "{{ __space }}" } }, "required": ["host"] }"#,
+        );
+        resolve_weaveconfig(&root).await.unwrap();
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "dev": { "host": "dev.example.com" }, "prod": { "host": "dev.example.com" } }"#,
+        );
+        let error = generate_weaveconfig_with(&root, &GenerateOptions::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, WeaveError::Schema(_)));
+
+        // A variable named like a built-in is an error, as in copied files
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "envs": [], "dev": { "host": "dev.example.com" }, "prod": { "host": "prod.example.com" } }"#,
+        );
+        let error = resolve_weaveconfig(&root).await.unwrap_err();
+        assert!(format!("{:#}", error)
+            .contains("The variable 'envs' conflicts with the built-in template variable"));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_files_policy() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "generate": false }"#,
+        );
+        dir.write("weaveconfig/docs/_notes.txt", "notes");
+        let root = dir.path().join("weaveconfig");
+
+        let error = crate::generate_weaveconfig(&root).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("Invalid file name format: '_notes.txt'"));
+        assert!(!dir.exists("docs/_notes.txt"));
+
+        dir.write(
+            "weaveconfig/.weaveconfigrc",
+            r#"{ "unknown_files": "error" }"#,
+        );
+        assert!(crate::generate_weaveconfig(&root).await.is_err());
+
+        dir.write(
+            "weaveconfig/.weaveconfigrc",
+            r#"{ "unknown_files": "warn" }"#,
+        );
+        let resolution = crate::resolve_weaveconfig_with(&root, &Default::default())
+            .await
+            .unwrap();
+        assert_eq!(resolution.warnings.len(), 1);
+        assert!(resolution.warnings[0]
+            .message
+            .contains("is not a known weaveconfig file"));
+        assert_eq!(
+            resolution.warnings[0].file.as_deref(),
+            Some(dir.path().join("weaveconfig/docs/_notes.txt").as_path())
+        );
+        crate::generate_weaveconfig(&root).await.unwrap();
+        assert_eq!(dir.read("docs/_notes.txt"), "notes");
+
+        std::fs::remove_file(dir.path().join("docs/_notes.txt")).unwrap();
+        dir.write(
+            "weaveconfig/.weaveconfigrc",
+            r#"{ "unknown_files": "copy" }"#,
+        );
+        let resolution = crate::resolve_weaveconfig_with(&root, &Default::default())
+            .await
+            .unwrap();
+        assert!(resolution.warnings.is_empty());
+        crate::generate_weaveconfig(&root).await.unwrap();
+        assert_eq!(dir.read("docs/_notes.txt"), "notes");
+    }
+}
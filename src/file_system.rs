@@ -0,0 +1,416 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+};
+
+use futures::future::BoxFuture;
+
+/// An entry of a directory, see [`FileSystem::read_dir`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub path: PathBuf,
+    /// Whether the entry is a directory. Symlinks are not followed, a symlink to a directory is not one.
+    pub is_dir: bool,
+}
+
+/// The metadata of a file or directory, see [`FileSystem::metadata`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub is_dir: bool,
+}
+
+/// The file system a generation reads the weaveconfig directory from and writes its outputs to,
+/// see [`crate::GenerateOptions::fs`]. [`TokioFileSystem`] is the real file system,
+/// [`InMemoryFileSystem`] keeps everything in memory.
+pub trait FileSystem: Send + Sync {
+    /// The entries of a directory, in no particular order.
+    fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<DirEntry>>>;
+
+    fn read<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<u8>>>;
+
+    /// Writes a file, replacing its content. The parent directory must exist.
+    fn write<'a>(&'a self, path: &'a Path, contents: &'a [u8]) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Creates a directory and its parents if they are missing.
+    fn create_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>>;
+
+    /// The metadata of a file or directory, following symlinks. Missing paths are an error.
+    fn metadata<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<FileMetadata>>;
+
+    /// The absolute path without `.` and `..` segments. Missing paths are an error.
+    fn canonicalize<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<PathBuf>>;
+
+    /// Reads a file that must be valid UTF-8.
+    fn read_to_string<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<String>> {
+        Box::pin(async move {
+            String::from_utf8(self.read(path).await?)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+        })
+    }
+
+    /// Makes `link` point to `original`, replacing what is at `link`.
+    /// Without support for symlinks, the content of `original` is copied.
+    fn symlink<'a>(&'a self, original: &'a Path, link: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let contents = self.read(original).await?;
+            self.write(link, &contents).await
+        })
+    }
+}
+
+/// The real file system, using `tokio::fs`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioFileSystem;
+
+impl FileSystem for TokioFileSystem {
+    fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<DirEntry>>> {
+        Box::pin(async move {
+            let mut entries = tokio::fs::read_dir(path).await?;
+            let mut result = Vec::new();
+            while let Some(entry) = entries.next_entry().await? {
+                result.push(DirEntry {
+                    path: entry.path(),
+                    is_dir: entry.file_type().await?.is_dir(),
+                });
+            }
+            Ok(result)
+        })
+    }
+
+    fn read<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<u8>>> {
+        Box::pin(tokio::fs::read(path))
+    }
+
+    fn write<'a>(&'a self, path: &'a Path, contents: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(tokio::fs::write(path, contents))
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(tokio::fs::create_dir_all(path))
+    }
+
+    fn metadata<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<FileMetadata>> {
+        Box::pin(async move {
+            let metadata = tokio::fs::metadata(path).await?;
+            Ok(FileMetadata {
+                is_dir: metadata.is_dir(),
+            })
+        })
+    }
+
+    fn canonicalize<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<PathBuf>> {
+        Box::pin(tokio::fs::canonicalize(path))
+    }
+
+    fn read_to_string<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<String>> {
+        Box::pin(tokio::fs::read_to_string(path))
+    }
+
+    fn symlink<'a>(&'a self, original: &'a Path, link: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            if tokio::fs::symlink_metadata(link).await.is_ok() {
+                tokio::fs::remove_file(link).await?;
+            }
+            #[cfg(unix)]
+            let result = tokio::fs::symlink(original, link).await;
+            #[cfg(windows)]
+            let result = tokio::fs::symlink_file(original, link).await;
+            #[cfg(not(any(unix, windows)))]
+            let result = tokio::fs::copy(original, link).await.map(|_| ());
+            result
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum InMemoryEntry {
+    File(Vec<u8>),
+    Directory,
+}
+
+/// A file system kept in memory, for fast tests that do not touch the disk.
+/// Paths are used as given after removing `.` and `..` segments, relative paths are not resolved
+/// against a working directory. Symlinks are not supported, linked files are copied.
+#[derive(Debug, Default)]
+pub struct InMemoryFileSystem {
+    entries: Mutex<BTreeMap<PathBuf, InMemoryEntry>>,
+}
+
+impl InMemoryFileSystem {
+    pub fn new() -> Self {
+        InMemoryFileSystem::default()
+    }
+
+    /// Adds a file, creating its parent directories, and replaces a file at the same path.
+    pub fn insert(&self, path: impl AsRef<Path>, contents: impl Into<Vec<u8>>) -> &Self {
+        let path = normalize(path.as_ref());
+        let mut entries = self.entries.lock().unwrap();
+        for ancestor in path.ancestors().skip(1) {
+            entries
+                .entry(ancestor.to_path_buf())
+                .or_insert(InMemoryEntry::Directory);
+        }
+        entries.insert(path, InMemoryEntry::File(contents.into()));
+        self
+    }
+
+    /// The content of a file, `None` if there is no file at the path.
+    pub fn file(&self, path: impl AsRef<Path>) -> Option<Vec<u8>> {
+        match self.entries.lock().unwrap().get(&normalize(path.as_ref())) {
+            Some(InMemoryEntry::File(contents)) => Some(contents.clone()),
+            _ => None,
+        }
+    }
+
+    /// The paths of all files, sorted.
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| matches!(entry, InMemoryEntry::File(_)))
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    fn entry(&self, path: &Path) -> io::Result<InMemoryEntry> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(&normalize(path))
+            .cloned()
+            .ok_or_else(|| not_found(path))
+    }
+}
+
+impl FileSystem for InMemoryFileSystem {
+    fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<DirEntry>>> {
+        Box::pin(async move {
+            let path = normalize(path);
+            let entries = self.entries.lock().unwrap();
+            match entries.get(&path) {
+                Some(InMemoryEntry::Directory) => Ok(entries
+                    .iter()
+                    .filter(|(entry_path, _)| entry_path.parent() == Some(path.as_path()))
+                    .map(|(entry_path, entry)| DirEntry {
+                        path: entry_path.clone(),
+                        is_dir: *entry == InMemoryEntry::Directory,
+                    })
+                    .collect()),
+                Some(InMemoryEntry::File(_)) => Err(not_a_directory(&path)),
+                None => Err(not_found(&path)),
+            }
+        })
+    }
+
+    fn read<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<u8>>> {
+        Box::pin(async move {
+            match self.entry(path)? {
+                InMemoryEntry::File(contents) => Ok(contents),
+                InMemoryEntry::Directory => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{:?} is a directory", path),
+                )),
+            }
+        })
+    }
+
+    fn write<'a>(&'a self, path: &'a Path, contents: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let path = normalize(path);
+            let mut entries = self.entries.lock().unwrap();
+            let parent = path.parent().ok_or_else(|| not_found(&path))?;
+            match entries.get(parent) {
+                Some(InMemoryEntry::Directory) => {}
+                Some(InMemoryEntry::File(_)) => return Err(not_a_directory(parent)),
+                None => return Err(not_found(parent)),
+            }
+            if entries.get(&path) == Some(&InMemoryEntry::Directory) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{:?} is a directory", path),
+                ));
+            }
+            entries.insert(path, InMemoryEntry::File(contents.to_vec()));
+            Ok(())
+        })
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let path = normalize(path);
+            let mut entries = self.entries.lock().unwrap();
+            for ancestor in path.ancestors() {
+                if let Some(InMemoryEntry::File(_)) = entries.get(ancestor) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("{:?} is a file", ancestor),
+                    ));
+                }
+            }
+            for ancestor in path.ancestors() {
+                entries
+                    .entry(ancestor.to_path_buf())
+                    .or_insert(InMemoryEntry::Directory);
+            }
+            Ok(())
+        })
+    }
+
+    fn metadata<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<FileMetadata>> {
+        Box::pin(async move {
+            Ok(FileMetadata {
+                is_dir: self.entry(path)? == InMemoryEntry::Directory,
+            })
+        })
+    }
+
+    fn canonicalize<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<PathBuf>> {
+        Box::pin(async move {
+            self.entry(path)?;
+            Ok(normalize(path))
+        })
+    }
+}
+
+/// Removes the `.` and `..` segments of a path.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{:?} does not exist", path),
+    )
+}
+
+fn not_a_directory(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{:?} is not a directory", path),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileSystem, InMemoryFileSystem};
+    use crate::{
+        collect_templates, generate_weaveconfig_with, resolve_weaveconfig_with, GenerateOptions,
+    };
+    use serde_json::json;
+    use std::{
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
+
+    #[tokio::test]
+    async fn test_in_memory_generation() {
+        let fs = Arc::new(InMemoryFileSystem::new());
+        fs.insert(
+            "/project/weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"], "generate": { "typescript": true } }"#,
+        )
+        .insert(
+            "/project/weaveconfig/_env.json",
+            r#"{ "dev": { "port": 3000 }, "prod": { "port": 80 } }"#,
+        )
+        .insert(
+            "/project/weaveconfig/api/_space.json",
+            r#"{ "name": "api", "environments": ["dev", "prod"], "generate": false }"#,
+        )
+        .insert("/project/weaveconfig/api/_forenv.txt", "port={{ port }}")
+        .insert("/project/weaveconfig/api/assets/_weavelink", "")
+        .insert("/project/weaveconfig/api/assets/logo.svg", "<svg/>")
+        .insert("/project/api/README.md", "api");
+
+        let options = GenerateOptions {
+            fs: fs.clone(),
+            ..Default::default()
+        };
+        let stats = generate_weaveconfig_with(Path::new("/project/weaveconfig/."), &options)
+            .await
+            .unwrap();
+        assert_eq!(stats.spaces, 2);
+        assert_eq!(stats.files_linked, 1);
+
+        let read = |path: &str| String::from_utf8(fs.file(path).unwrap()).unwrap();
+        let config: serde_json::Value =
+            serde_json::from_str(&read("/project/gen/config.json")).unwrap();
+        assert_eq!(
+            config,
+            json!({ "dev": { "port": 3000 }, "prod": { "port": 80 } })
+        );
+        assert!(read("/project/gen/binding.ts").contains("export const environments"));
+        assert_eq!(read("/project/gen/.gitignore"), "config.json\nbinding.ts\n");
+        assert_eq!(read("/project/api/dev.txt"), "port=3000");
+        assert_eq!(read("/project/api/prod.txt"), "port=80");
+        // Linked files are copied, the in-memory file system has no symlinks
+        assert_eq!(read("/project/api/assets/logo.svg"), "<svg/>");
+
+        // The templates are read from the same file system
+        let resolution = resolve_weaveconfig_with(Path::new("/project/weaveconfig"), &options)
+            .await
+            .unwrap();
+        let templates = collect_templates(&resolution.spaces, fs.as_ref())
+            .await
+            .unwrap();
+        assert!(templates
+            .iter()
+            .any(|template| template == "port={{ port }}"));
+
+        // Nothing is written outside of the out dir
+        let before = fs.files();
+        let options = GenerateOptions {
+            out_dir: Some(PathBuf::from("/preview")),
+            ..options
+        };
+        generate_weaveconfig_with(Path::new("/project/weaveconfig"), &options)
+            .await
+            .unwrap();
+        assert_eq!(read("/preview/api/dev.txt"), "port=3000");
+        assert!(fs
+            .files()
+            .iter()
+            .all(|path| before.contains(path) || path.starts_with("/preview")));
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_errors() {
+        let fs = InMemoryFileSystem::new();
+        fs.insert("/a/file.txt", "content");
+
+        assert!(fs.read(Path::new("/a/missing.txt")).await.is_err());
+        assert!(fs.write(Path::new("/b/file.txt"), b"").await.is_err());
+        assert!(fs.create_dir(Path::new("/a/file.txt/sub")).await.is_err());
+        assert!(fs.read_dir(Path::new("/a/file.txt")).await.is_err());
+        assert!(fs.metadata(Path::new("/a")).await.unwrap().is_dir);
+
+        fs.create_dir(Path::new("/a/b/c")).await.unwrap();
+        fs.write(Path::new("/a/b/c/../d.txt"), b"d").await.unwrap();
+        let mut entries: Vec<_> = fs
+            .read_dir(Path::new("/a/b"))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.path, entry.is_dir))
+            .collect();
+        entries.sort();
+        assert_eq!(
+            entries,
+            vec![("/a/b/c".into(), true), ("/a/b/d.txt".into(), false)]
+        );
+    }
+}
@@ -1,15 +1,54 @@
-use std::path::Path;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use anyhow::Result;
-use apply_resolved::apply_resolved;
-use file_graph::traverse_directory;
-use resolve_spaces::resolve_spaces;
-use space_graph::create_space_graph;
+use apply_resolved::{apply_resolved, write_all_config};
+use file_graph::{traverse_directory, Directory, GlobalVariables, DEFAULT_FANOUT};
+use resolve_spaces::{fetch_remote_variables, resolve_spaces, resolve_spaces_collecting};
+use schemas::WeaveconfigRc;
+use serde::Serialize;
+use serde_json::Value;
+use space_graph::{affected_spaces, check_names, create_space_graph};
+use thiserror::Error;
+use variable_source::resolve_source_variables;
+
+pub use ancestor_mapping::AncestorMapping;
+pub use apply_resolved::{apply_space_public, minimal_environments, ApplyStats};
+pub use cancellation::{CancellationToken, Cancelled};
+pub use diff::{diff_resolutions, ResolutionDiff, SpaceDiff};
+pub use explain::{explain_variable, ExplainStep, Explanation, Origin};
+pub use export::{export_resolution, ExportedSpace};
+pub use file_graph::{SchemaValidationError, LOCK_FILE_NAME};
+pub use file_system::{DirEntry, FileMetadata, FileSystem, InMemoryFileSystem, TokioFileSystem};
+pub use lint::{
+    collect_templates, find_constant_forenv_files, find_unused_variables, ConstantForenvFile,
+    UnusedVariable,
+};
+pub use merging::{Conflict, ConflictPolicy, Provenance, SourceId};
+pub use parse_jsonc::parse_jsonc;
+pub use resolve_spaces::{CopyOutput, MergeConflict, RemoteOptions, ResolvedSpace};
+pub use schemas::{ConfigFormat, ConfigLayout, LineEndings};
+pub use space_graph::{
+    render_tree, CopyTree, GenerateSpace, NameViolation, Space, SpaceGraph, SpaceTree, ToCopy,
+};
+pub use template_file::{template_file, TemplateError, VariableError};
+pub use variable_source::{InMemorySource, VariableSource, VariableSources};
+pub use write_json_file::write_config_to;
 
 mod ancestor_mapping;
 mod apply_resolved;
+mod cancellation;
+mod diff;
+mod explain;
+mod export;
 mod file_graph;
+mod file_system;
 mod get_environment_value;
+mod lint;
 mod map_path;
 mod merging;
 mod parse_jsonc;
@@ -17,12 +56,589 @@ mod resolve_spaces;
 mod schemas;
 mod space_graph;
 mod template_file;
+#[cfg(test)]
+mod test_utils;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 mod ts_binding;
+mod variable_source;
 mod write_json_file;
 
+/// An error of [`generate_weaveconfig_with`], by the stage of the generation that failed.
+/// Each variant wraps the error of that stage, including its context.
+#[derive(Debug, Error)]
+pub enum WeaveError {
+    /// Reading or parsing the files of the weaveconfig directory failed.
+    #[error("Failed to read the weaveconfig directory: {0:#}")]
+    Traverse(anyhow::Error),
+    /// The variables of a space do not match its schema, see [`SchemaValidationError`].
+    #[error("Schema validation failed: {0:#}")]
+    Schema(anyhow::Error),
+    /// The spaces do not form a valid graph, for example two spaces share a name.
+    #[error("Failed to build the space graph: {0:#}")]
+    Graph(anyhow::Error),
+    /// Merging the variables of the spaces failed.
+    #[error("Failed to resolve the spaces: {0:#}")]
+    Resolve(anyhow::Error),
+    /// Writing the generated or copied files failed.
+    #[error("Failed to apply the spaces: {0:#}")]
+    Apply(anyhow::Error),
+    /// The generation was cancelled.
+    #[error("{0:#}")]
+    Cancelled(anyhow::Error),
+}
+
+impl WeaveError {
+    /// The error of the failed stage.
+    pub fn into_inner(self) -> anyhow::Error {
+        match self {
+            WeaveError::Traverse(error)
+            | WeaveError::Schema(error)
+            | WeaveError::Graph(error)
+            | WeaveError::Resolve(error)
+            | WeaveError::Apply(error)
+            | WeaveError::Cancelled(error) => error,
+        }
+    }
+
+    /// Wraps the error of a stage, cancellations are reported as such in every stage.
+    fn stage(error: anyhow::Error, variant: fn(anyhow::Error) -> WeaveError) -> WeaveError {
+        if error.is::<Cancelled>() {
+            WeaveError::Cancelled(error)
+        } else {
+            variant(error)
+        }
+    }
+}
+
+impl From<Cancelled> for WeaveError {
+    fn from(cancelled: Cancelled) -> Self {
+        WeaveError::Cancelled(cancelled.into())
+    }
+}
+
+impl From<SchemaValidationError> for WeaveError {
+    fn from(error: SchemaValidationError) -> Self {
+        WeaveError::Schema(error.into())
+    }
+}
+
+/// How [`generate_weaveconfig_with`] and [`resolve_weaveconfig_with`] read, resolve and write a
+/// weaveconfig. The default reads from and writes to the real file system like [`generate_weaveconfig`],
+/// options are set with struct update syntax, e.g.
+/// `GenerateOptions { out_dir: Some(dir), ..Default::default() }`.
+#[derive(Clone)]
+pub struct GenerateOptions {
+    /// The file system the weaveconfig directory is read from and the outputs are written to,
+    /// e.g. an [`InMemoryFileSystem`] for tests.
+    pub fs: Arc<dyn FileSystem>,
+    /// Stops with a [`Cancelled`] error once cancelled. The token is checked between directory reads,
+    /// spaces and copied files, so no further files are written after the cancellation.
+    pub cancel: CancellationToken,
+    /// Fetches the variables read from a URL with `@url` as configured, they are rejected if `None`.
+    pub remote: Option<RemoteOptions>,
+    /// The sources strings like `"@source:vault:db/password"` are read from, see [`VariableSource`].
+    /// Strings naming a source that is not registered are an error.
+    pub sources: VariableSources,
+    /// Writes all outputs below this directory instead of next to the weaveconfig directory,
+    /// mirroring the structure they would have in the project. Nothing is written outside of it,
+    /// which allows previewing a generation.
+    pub out_dir: Option<PathBuf>,
+    /// Only applies the spaces affected by changes to these files, relative to the weaveconfig root:
+    /// the spaces containing them and every space inheriting from or depending on those.
+    /// All spaces are still resolved, so the `all-config.json` is rewritten with every space if enabled.
+    pub changed_files: Option<Vec<PathBuf>>,
+}
+
+impl Default for GenerateOptions {
+    fn default() -> Self {
+        GenerateOptions {
+            fs: Arc::new(TokioFileSystem),
+            cancel: CancellationToken::new(),
+            remote: None,
+            sources: VariableSources::new(),
+            out_dir: None,
+            changed_files: None,
+        }
+    }
+}
+
+/// The resolved spaces of a weaveconfig directory, see [`resolve_weaveconfig_with`].
+/// They are written with [`apply_resolution`].
+#[derive(Debug, Clone)]
+pub struct Resolution {
+    /// The resolved spaces, keyed by their name.
+    pub spaces: HashMap<String, ResolvedSpace>,
+    /// Warnings about the weaveconfig directory that do not stop the generation,
+    /// like unknown files copied under `"unknown_files": "warn"`.
+    pub warnings: Vec<Diagnostic>,
+    /// The spaces affected by [`GenerateOptions::changed_files`], `None` applies every space.
+    affected: Option<HashSet<String>>,
+    /// Whether the `.weaveconfigrc` enables the `all-config.json`.
+    all_config: bool,
+}
+
+/// The space graph of a weaveconfig directory, before its variables are resolved.
+struct LoadedGraph {
+    space_graph: SpaceGraph,
+    globals: Option<GlobalVariables>,
+    defaults: WeaveconfigRc,
+    all_config: bool,
+    warnings: Vec<Diagnostic>,
+}
+
 pub async fn generate_weaveconfig(weaveconfig_config_root: &Path) -> Result<()> {
-    let directory = traverse_directory(weaveconfig_config_root).await?;
-    let space_graph = create_space_graph(directory)?;
-    let resolved_spaces = resolve_spaces(space_graph)?;
-    apply_resolved(resolved_spaces, weaveconfig_config_root).await
+    generate_weaveconfig_with(weaveconfig_config_root, &GenerateOptions::default())
+        .await
+        .map_err(WeaveError::into_inner)?;
+    Ok(())
+}
+
+/// Like [`generate_weaveconfig`], configured by `options`. Returns what was written,
+/// the error tells which stage of the generation failed.
+pub async fn generate_weaveconfig_with(
+    weaveconfig_config_root: &Path,
+    options: &GenerateOptions,
+) -> Result<ApplyStats, WeaveError> {
+    let resolution = resolve_weaveconfig_with(weaveconfig_config_root, options).await?;
+    apply_resolution(&resolution, weaveconfig_config_root, options).await
+}
+
+/// Reads and resolves all spaces below the weaveconfig root as configured by `options`,
+/// without writing anything.
+pub async fn resolve_weaveconfig_with(
+    weaveconfig_config_root: &Path,
+    options: &GenerateOptions,
+) -> Result<Resolution, WeaveError> {
+    let mut loaded = load_space_graph(weaveconfig_config_root, options).await?;
+    fetch_variables(&mut loaded.space_graph, options).await?;
+    let affected = options.changed_files.as_ref().map(|changed_files| {
+        affected_spaces(&loaded.space_graph, weaveconfig_config_root, changed_files)
+    });
+    let spaces =
+        resolve_spaces(loaded.space_graph, loaded.globals.as_ref()).map_err(WeaveError::Resolve)?;
+    Ok(Resolution {
+        spaces,
+        warnings: loaded.warnings,
+        affected,
+        all_config: loaded.all_config,
+    })
+}
+
+/// Writes the generated and copied files of the resolved spaces as configured by `options`.
+/// With [`GenerateOptions::changed_files`], only the affected spaces are written.
+pub async fn apply_resolution(
+    resolution: &Resolution,
+    weaveconfig_config_root: &Path,
+    options: &GenerateOptions,
+) -> Result<ApplyStats, WeaveError> {
+    let out_dir = options.out_dir.as_deref();
+    let fs = options.fs.as_ref();
+    let apply = async {
+        let Some(affected) = &resolution.affected else {
+            return apply_resolved(
+                &resolution.spaces,
+                weaveconfig_config_root,
+                out_dir,
+                resolution.all_config,
+                &options.cancel,
+                fs,
+            )
+            .await;
+        };
+        let mut stats = ApplyStats::default();
+        if resolution.all_config {
+            options.cancel.check()?;
+            stats.record_write(
+                write_all_config(&resolution.spaces, weaveconfig_config_root, out_dir, fs).await?,
+            );
+        }
+        let affected_spaces: HashMap<String, ResolvedSpace> = resolution
+            .spaces
+            .iter()
+            .filter(|(name, _)| affected.contains(*name))
+            .map(|(name, space)| (name.clone(), space.clone()))
+            .collect();
+        let applied = apply_resolved(
+            &affected_spaces,
+            weaveconfig_config_root,
+            out_dir,
+            false,
+            &options.cancel,
+            fs,
+        )
+        .await?;
+        stats.add(applied);
+        Ok(stats)
+    };
+    apply
+        .await
+        .map_err(|error| WeaveError::stage(error, WeaveError::Apply))
+}
+
+/// Reads the weaveconfig directory and builds its space graph.
+async fn load_space_graph(
+    weaveconfig_config_root: &Path,
+    options: &GenerateOptions,
+) -> Result<LoadedGraph, WeaveError> {
+    let directory = traverse_directory(
+        weaveconfig_config_root,
+        &options.cancel,
+        Some(DEFAULT_FANOUT),
+        options.fs.clone(),
+    )
+    .await
+    .map_err(|error| {
+        if error.is::<SchemaValidationError>() {
+            WeaveError::Schema(error)
+        } else {
+            WeaveError::stage(error, WeaveError::Traverse)
+        }
+    })?;
+    let all_config = all_config_enabled(&directory);
+    let mut warnings = Vec::new();
+    unknown_file_warnings(&directory, &mut warnings);
+    let globals = directory.globals.clone();
+    let defaults = directory.defaults.clone().unwrap_or_default();
+    let space_graph = create_space_graph(directory).map_err(WeaveError::Graph)?;
+    Ok(LoadedGraph {
+        space_graph,
+        globals,
+        defaults,
+        all_config,
+        warnings,
+    })
+}
+
+/// A warning for every unknown file copied under `"unknown_files": "warn"`, in traversal order.
+fn unknown_file_warnings(directory: &Directory, warnings: &mut Vec<Diagnostic>) {
+    for path in &directory.unknown_files {
+        let mut warning = Diagnostic::new(
+            Severity::Warning,
+            format!(
+                "the file name {:?} is not a known weaveconfig file, copying it like other files",
+                path
+            ),
+        );
+        warning.file = Some(path.clone());
+        warnings.push(warning);
+    }
+    for subdirectory in &directory.directories {
+        unknown_file_warnings(subdirectory, warnings);
+    }
+}
+
+/// Replaces the remote and source variables of the space graph with their values.
+async fn fetch_variables(
+    space_graph: &mut SpaceGraph,
+    options: &GenerateOptions,
+) -> Result<(), WeaveError> {
+    fetch_remote_variables(space_graph, options.remote.as_ref(), options.fs.as_ref())
+        .await
+        .map_err(WeaveError::Resolve)?;
+    resolve_source_variables(space_graph, &options.sources)
+        .await
+        .map_err(WeaveError::Resolve)
+}
+
+/// Whether the `.weaveconfigrc` enables the `all-config.json`.
+fn all_config_enabled(directory: &Directory) -> bool {
+    directory
+        .defaults
+        .as_ref()
+        .and_then(|defaults| defaults.all_config)
+        .unwrap_or(false)
+}
+
+/// Checks the names of all spaces, their environments and variables against the conventions of the
+/// `.weaveconfigrc`, without resolving or writing anything.
+pub async fn check_weaveconfig_names(weaveconfig_config_root: &Path) -> Result<Vec<NameViolation>> {
+    let loaded = load_space_graph(weaveconfig_config_root, &GenerateOptions::default())
+        .await
+        .map_err(WeaveError::into_inner)?;
+    check_names(&loaded.space_graph, &loaded.defaults)
+}
+
+/// Reads and resolves all spaces below the weaveconfig root without writing anything.
+/// The resolved spaces are keyed by their name.
+pub async fn resolve_weaveconfig(
+    weaveconfig_config_root: &Path,
+) -> Result<HashMap<String, ResolvedSpace>> {
+    let resolution = resolve_weaveconfig_with(weaveconfig_config_root, &GenerateOptions::default())
+        .await
+        .map_err(WeaveError::into_inner)?;
+    Ok(resolution.spaces)
+}
+
+/// Reads and resolves all spaces, then traces the variable at the dotted `path` of a space
+/// back to the file defining it, see [`explain_variable`].
+pub async fn explain_weaveconfig(
+    weaveconfig_config_root: &Path,
+    space: &str,
+    path: &str,
+) -> Result<Explanation> {
+    let options = GenerateOptions::default();
+    let mut loaded = load_space_graph(weaveconfig_config_root, &options)
+        .await
+        .map_err(WeaveError::into_inner)?;
+    fetch_variables(&mut loaded.space_graph, &options)
+        .await
+        .map_err(WeaveError::into_inner)?;
+    let resolved_spaces = resolve_spaces(loaded.space_graph.clone(), loaded.globals.as_ref())?;
+    explain_variable(&loaded.space_graph, &resolved_spaces, space, path)
+}
+
+/// Like [`resolve_weaveconfig`], but merge conflicts are handled by `policy` and reported
+/// together instead of failing on the first one.
+pub async fn resolve_weaveconfig_collecting(
+    weaveconfig_config_root: &Path,
+    policy: ConflictPolicy,
+) -> Result<(HashMap<String, ResolvedSpace>, Vec<MergeConflict>)> {
+    let options = GenerateOptions::default();
+    let mut loaded = load_space_graph(weaveconfig_config_root, &options)
+        .await
+        .map_err(WeaveError::into_inner)?;
+    fetch_variables(&mut loaded.space_graph, &options)
+        .await
+        .map_err(WeaveError::into_inner)?;
+    resolve_spaces_collecting(loaded.space_graph, loaded.globals.as_ref(), policy)
+}
+
+/// A hash of the resolved variables of every space, keyed by space name, as written to the
+/// [`LOCK_FILE_NAME`] lockfile to detect changes of the resolved values over time.
+/// The hashes only depend on the values, not on the order of keys, and are stable across versions.
+pub fn lock_hashes(spaces: &HashMap<String, ResolvedSpace>) -> BTreeMap<String, String> {
+    spaces
+        .values()
+        .map(|space| {
+            let variables = space.variables.clone().map(Value::Object);
+            let mut canonical = String::new();
+            write_canonical(variables.as_ref().unwrap_or(&Value::Null), &mut canonical);
+            (
+                space.name.clone(),
+                format!("{:016x}", fnv1a(canonical.as_bytes())),
+            )
+        })
+        .collect()
+}
+
+/// Writes the value as JSON with the keys of every object sorted.
+fn write_canonical(value: &Value, output: &mut String) {
+    match value {
+        Value::Object(object) => {
+            let mut entries: Vec<_> = object.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+            output.push('{');
+            for (index, (key, value)) in entries.into_iter().enumerate() {
+                if index > 0 {
+                    output.push(',');
+                }
+                output.push_str(&Value::String(key.clone()).to_string());
+                output.push(':');
+                write_canonical(value, output);
+            }
+            output.push('}');
+        }
+        Value::Array(array) => {
+            output.push('[');
+            for (index, value) in array.iter().enumerate() {
+                if index > 0 {
+                    output.push(',');
+                }
+                write_canonical(value, output);
+            }
+            output.push(']');
+        }
+        value => output.push_str(&value.to_string()),
+    }
+}
+
+/// The 64 bit FNV-1a hash, which unlike the std hashers is specified and does not change between releases.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// How severe a [`Diagnostic`] is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The weaveconfig can not be generated.
+    Error,
+    /// A likely mistake, like a lint warning.
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A problem found in a weaveconfig, with the file and space it is in if known.
+/// Serializes to a flat JSON object, for editors to show the problems.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub file: Option<PathBuf>,
+    pub space: Option<String>,
+    /// The 1-based line within the file, if known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    /// The 1-based column within the line, if known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub col: Option<usize>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: impl fmt::Display) -> Self {
+        Diagnostic {
+            severity,
+            message: message.to_string(),
+            file: None,
+            space: None,
+            line: None,
+            col: None,
+        }
+    }
+
+    /// An error diagnostic for a failed generation, with all its contexts in the message.
+    /// Schema validation errors are located at the schema and the space, JSON syntax errors at
+    /// their line and column.
+    pub fn from_error(error: &anyhow::Error) -> Self {
+        let mut diagnostic = Diagnostic::new(Severity::Error, format!("{:#}", error));
+        if let Some(schema_error) = error.downcast_ref::<SchemaValidationError>() {
+            diagnostic.file = Some(schema_error.file.clone());
+            diagnostic.space = Some(schema_error.space.clone());
+        }
+        if let Some(json_error) = error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<serde_json::Error>())
+        {
+            diagnostic.line = Some(json_error.line());
+            diagnostic.col = Some(json_error.column());
+        }
+        diagnostic
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl From<&str> for Diagnostic {
+    fn from(message: &str) -> Self {
+        Diagnostic::new(Severity::Warning, message)
+    }
+}
+
+impl From<ConstantForenvFile> for Diagnostic {
+    fn from(file: ConstantForenvFile) -> Self {
+        Diagnostic {
+            file: Some(file.path.clone()),
+            space: Some(file.space.clone()),
+            ..Diagnostic::new(Severity::Warning, &file)
+        }
+    }
+}
+
+impl From<NameViolation> for Diagnostic {
+    fn from(violation: NameViolation) -> Self {
+        let space = match &violation {
+            NameViolation::SpaceName { space, .. }
+            | NameViolation::EnvironmentName { space, .. }
+            | NameViolation::ReservedKey { space, .. } => space.clone(),
+        };
+        Diagnostic {
+            space: Some(space),
+            ..Diagnostic::new(Severity::Warning, &violation)
+        }
+    }
+}
+
+/// Collects diagnostics like lint warnings, keeping at most `max` of them and counting the rest,
+/// so a badly broken weaveconfig does not flood the output.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Diagnostics {
+    max: Option<usize>,
+    kept: Vec<Diagnostic>,
+    suppressed: usize,
+    errors: usize,
+}
+
+impl Diagnostics {
+    /// Creates a collector keeping at most `max` diagnostics, or all of them if `None`.
+    pub fn new(max: Option<usize>) -> Self {
+        Diagnostics {
+            max,
+            ..Diagnostics::default()
+        }
+    }
+
+    pub fn push(&mut self, diagnostic: impl Into<Diagnostic>) {
+        let diagnostic = diagnostic.into();
+        if diagnostic.severity == Severity::Error {
+            self.errors += 1;
+        }
+        if self.max.is_some_and(|max| self.kept.len() >= max) {
+            self.suppressed += 1;
+        } else {
+            self.kept.push(diagnostic);
+        }
+    }
+
+    pub fn extend<T: Into<Diagnostic>>(&mut self, diagnostics: impl IntoIterator<Item = T>) {
+        for diagnostic in diagnostics {
+            self.push(diagnostic);
+        }
+    }
+
+    /// The diagnostics that were kept, in the order they were pushed.
+    pub fn kept(&self) -> &[Diagnostic] {
+        &self.kept
+    }
+
+    /// The number of diagnostics pushed after the cap was reached.
+    pub fn suppressed(&self) -> usize {
+        self.suppressed
+    }
+
+    /// The number of error diagnostics pushed, including suppressed ones.
+    pub fn errors(&self) -> usize {
+        self.errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Diagnostic, Diagnostics, Severity};
+
+    #[test]
+    fn test_diagnostics_cap() {
+        let mut diagnostics = Diagnostics::new(Some(2));
+        diagnostics.extend(["a", "b", "c"]);
+        diagnostics.push("d");
+        let messages: Vec<&str> = diagnostics
+            .kept()
+            .iter()
+            .map(|diagnostic| diagnostic.message.as_str())
+            .collect();
+        assert_eq!(messages, ["a", "b"]);
+        assert_eq!(diagnostics.suppressed(), 2);
+        // Suppressed errors still count
+        diagnostics.push(Diagnostic::new(Severity::Error, "e"));
+        assert_eq!(diagnostics.errors(), 1);
+
+        let mut unlimited = Diagnostics::new(None);
+        unlimited.extend(["a", "b", "c"]);
+        assert_eq!(unlimited.kept().len(), 3);
+        assert_eq!(unlimited.suppressed(), 0);
+    }
 }
@@ -0,0 +1,335 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    path::PathBuf,
+};
+
+use anyhow::Context;
+
+use crate::{
+    apply_resolved::{needs_substitution, template_references},
+    file_system::FileSystem,
+    merging::SourceId,
+    resolve_spaces::ResolvedSpace,
+    space_graph::{CopyTree, ToCopy},
+};
+
+/// A variable that is defined in an `_env` file but never referenced.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UnusedVariable {
+    /// The dotted path of the variable, without the environment it is defined in.
+    pub path: String,
+    /// The file the variable is defined in.
+    pub source: SourceId,
+}
+
+impl fmt::Display for UnusedVariable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The variable \"{}\" defined in {:?} is never used",
+            self.path, self.source
+        )
+    }
+}
+
+/// A `_forenv` file that references neither `env` nor an environment specific variable,
+/// so every environment gets an identical copy.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ConstantForenvFile {
+    /// The name of the space the file belongs to.
+    pub space: String,
+    /// The path of the file in the weaveconfig directory.
+    pub path: PathBuf,
+}
+
+impl fmt::Display for ConstantForenvFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} in the space \"{}\" is copied per environment, but does not use `env` or any environment specific variable",
+            self.path, self.space
+        )
+    }
+}
+
+/// Reads the templates of all spaces: the content of every copied file and every
+/// file or directory name containing a variable.
+pub async fn collect_templates(
+    spaces: &HashMap<String, ResolvedSpace>,
+    fs: &dyn FileSystem,
+) -> Result<Vec<String>, anyhow::Error> {
+    let mut templates = Vec::new();
+    for space in spaces.values() {
+        collect_tree_templates(&space.files_to_copy, &mut templates, fs).await?;
+    }
+    Ok(templates)
+}
+
+async fn collect_tree_templates(
+    tree: &CopyTree,
+    templates: &mut Vec<String>,
+    fs: &dyn FileSystem,
+) -> Result<(), anyhow::Error> {
+    for to_copy in &tree.to_copy {
+        let name = to_copy.last_segment()?;
+        if name.contains("{{") {
+            templates.push(name.to_string());
+        }
+        match to_copy {
+            ToCopy::File(file) => {
+                let content = fs
+                    .read_to_string(file)
+                    .await
+                    .with_context(|| format!("Failed to read file: {:?}", file))?;
+                templates.push(content);
+            }
+            // Linked files are not templated, so they can not reference variables
+            ToCopy::Link(_) => {}
+            ToCopy::Directory { subtree, .. } => {
+                Box::pin(collect_tree_templates(subtree, templates, fs)).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Finds the variables defined in `_env` files that no template references.
+///
+/// A variable counts as used if a template references it, a value containing it or a value within it,
+/// so `{{ db }}` uses `db.host`. Environment specific variables are matched without their environment,
+/// as that is how templates reference them. Variables consumed only through `config.json` or the
+/// TypeScript bindings are reported as well, so this is meant as an opt-in check.
+pub fn find_unused_variables(
+    spaces: &HashMap<String, ResolvedSpace>,
+    templates: &[String],
+) -> Result<Vec<UnusedVariable>, anyhow::Error> {
+    let mut references = Vec::new();
+    for template in templates {
+        references.extend(dotted_references(template)?);
+    }
+
+    let mut unused = BTreeSet::new();
+    for space in spaces.values() {
+        for (path, source) in &space.provenance {
+            if space.environments.contains(path) {
+                continue;
+            }
+            let path = strip_environment(space, path).unwrap_or(path);
+            if !references.iter().any(|reference| overlaps(reference, path)) {
+                unused.insert(UnusedVariable {
+                    path: path.to_string(),
+                    source: source.clone(),
+                });
+            }
+        }
+    }
+    Ok(unused.into_iter().collect())
+}
+
+/// Finds the `_forenv` files that would be copied identically for every environment.
+/// Spaces with less than two environments are skipped.
+pub async fn find_constant_forenv_files(
+    spaces: &HashMap<String, ResolvedSpace>,
+    fs: &dyn FileSystem,
+) -> Result<Vec<ConstantForenvFile>, anyhow::Error> {
+    let mut constant = Vec::new();
+    for space in spaces.values() {
+        if space.environments.len() < 2 {
+            continue;
+        }
+        // The environment specific variables and the `is_<env>` flags differ between environments
+        let flags: Vec<String> = space
+            .environments
+            .iter()
+            .map(|env| format!("is_{}", env))
+            .collect();
+        let varying: Vec<&str> = space
+            .provenance
+            .keys()
+            .filter_map(|path| strip_environment(space, path))
+            .chain(flags.iter().map(String::as_str))
+            .collect();
+        let mut files = Vec::new();
+        collect_forenv_files(
+            &space.files_to_copy,
+            &space.forenv_token,
+            &varying,
+            false,
+            &mut files,
+            fs,
+        )
+        .await?;
+        constant.extend(files.into_iter().map(|path| ConstantForenvFile {
+            space: space.name.clone(),
+            path,
+        }));
+    }
+    constant.sort();
+    Ok(constant)
+}
+
+/// Collects the constant files in the `_forenv` parts of a tree.
+/// `in_forenv` is whether the tree is within a `_forenv` directory.
+async fn collect_forenv_files(
+    tree: &CopyTree,
+    forenv_token: &str,
+    varying: &[&str],
+    in_forenv: bool,
+    files: &mut Vec<PathBuf>,
+    fs: &dyn FileSystem,
+) -> Result<(), anyhow::Error> {
+    for to_copy in &tree.to_copy {
+        let name = to_copy.last_segment()?;
+        let in_forenv = in_forenv || needs_substitution(name, forenv_token);
+        match to_copy {
+            ToCopy::File(file) if in_forenv => {
+                let content = fs
+                    .read_to_string(file)
+                    .await
+                    .with_context(|| format!("Failed to read file: {:?}", file))?;
+                if !references_varying(&dotted_references(name)?, varying)
+                    && !references_varying(&dotted_references(&content)?, varying)
+                {
+                    files.push(file.clone());
+                }
+            }
+            // Linked files are expected to be identical, that is why they are linked
+            ToCopy::File(_) | ToCopy::Link(_) => {}
+            ToCopy::Directory { subtree, .. } => {
+                // Files below a directory whose name differs per environment are not identical
+                if in_forenv && references_varying(&dotted_references(name)?, varying) {
+                    continue;
+                }
+                Box::pin(collect_forenv_files(
+                    subtree,
+                    forenv_token,
+                    varying,
+                    in_forenv,
+                    files,
+                    fs,
+                ))
+                .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The variables referenced by a template as dotted paths, like the paths of the provenance.
+fn dotted_references(template: &str) -> Result<Vec<String>, anyhow::Error> {
+    Ok(template_references(template)?
+        .iter()
+        .map(|reference| reference.join("."))
+        .collect())
+}
+
+fn references_varying(references: &[String], varying: &[&str]) -> bool {
+    references
+        .iter()
+        .any(|reference| reference == "env" || varying.iter().any(|path| overlaps(reference, path)))
+}
+
+/// The rest of a dotted path that starts with an environment of the space.
+fn strip_environment<'a>(space: &ResolvedSpace, path: &'a str) -> Option<&'a str> {
+    path.split_once('.')
+        .filter(|(env, _)| space.environments.contains(*env))
+        .map(|(_, rest)| rest)
+}
+
+/// Whether one dotted path is a prefix of the other.
+fn overlaps(a: &str, b: &str) -> bool {
+    let (shorter, longer) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    longer == shorter
+        || longer
+            .strip_prefix(shorter)
+            .is_some_and(|rest| rest.starts_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{file_system::TokioFileSystem, resolve_weaveconfig, test_utils::TempDir};
+    use std::path::Path;
+
+    #[tokio::test]
+    async fn test_unused_variables() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "name": "app", "unused": 1, "db": { "host": "localhost", "port": 5432 }, "prod": { "flag": true, "stale": "x" } }"#,
+        );
+        dir.write("weaveconfig/_forenv.txt", "when: flag\n{{ name }} {{ db }}");
+
+        let weaveconfig_root = dir.path().join("weaveconfig");
+        let resolved = resolve_weaveconfig(&weaveconfig_root).await.unwrap();
+        let templates = collect_templates(&resolved, &TokioFileSystem)
+            .await
+            .unwrap();
+        let unused = find_unused_variables(&resolved, &templates).unwrap();
+
+        let source = weaveconfig_root.join("_env.json");
+        assert_eq!(
+            unused,
+            vec![
+                UnusedVariable {
+                    path: "stale".to_string(),
+                    source: source.clone(),
+                },
+                UnusedVariable {
+                    path: "unused".to_string(),
+                    source,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_overlaps() {
+        assert!(overlaps("db", "db.host"));
+        assert!(overlaps("db.host", "db"));
+        assert!(overlaps("db", "db"));
+        assert!(!overlaps("db", "dbx.host"));
+        assert!(!overlaps("db.host", "db.port"));
+    }
+
+    #[tokio::test]
+    async fn test_constant_forenv_files() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "name": "app", "dev": { "url": "dev.local" }, "prod": { "url": "prod.com" } }"#,
+        );
+        dir.write("weaveconfig/_forenv.constant.txt", "{{ name }}");
+        dir.write("weaveconfig/_forenv.uses-env.txt", "{{ env }}");
+        dir.write("weaveconfig/_forenv.url.txt", "{{ name }} at {{ url }}");
+        dir.write("weaveconfig/_forenv.flag.txt", "when: is_prod\n{{ name }}");
+        dir.write("weaveconfig/_forenv/nested.txt", "constant");
+        dir.write("weaveconfig/shared.txt", "{{ name }}");
+
+        let weaveconfig_root = dir.path().join("weaveconfig");
+        let resolved = resolve_weaveconfig(&weaveconfig_root).await.unwrap();
+        let constant = find_constant_forenv_files(&resolved, &TokioFileSystem)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            constant
+                .iter()
+                .map(|file| file.path.strip_prefix(&weaveconfig_root).unwrap())
+                .collect::<Vec<_>>(),
+            vec![
+                Path::new("_forenv/nested.txt"),
+                Path::new("_forenv.constant.txt")
+            ]
+        );
+    }
+}
@@ -2,21 +2,42 @@ use std::path::{Path, PathBuf};
 
 use anyhow::Context;
 
-pub fn map_path(weaveconfig_root: &Path, path: &Path) -> Result<PathBuf, anyhow::Error> {
+/// Maps a directory in the weaveconfig directory to the directory its output is written to.
+/// Outputs are placed relative to the parent of the weaveconfig directory, or to `out_dir` if given.
+pub fn map_path(
+    weaveconfig_root: &Path,
+    path: &Path,
+    out_dir: Option<&Path>,
+) -> Result<PathBuf, anyhow::Error> {
     // Canonicalize both the root and the path
-    let canonical_root = weaveconfig_root
-        .canonicalize()
-        .with_context(|| format!("Failed to canonicalize root path: {}", weaveconfig_root.display()))?;
+    let canonical_root = weaveconfig_root.canonicalize().with_context(|| {
+        format!(
+            "Failed to canonicalize root path: {}",
+            weaveconfig_root.display()
+        )
+    })?;
     let canonical_path = path
         .canonicalize()
         .with_context(|| format!("Failed to canonicalize path: {}", path.display()))?;
 
-    // Get the parent of the canonicalized root to remove the last segment
-    let trimmed_root = canonical_root.parent().context("Root has no parent")?;
+    map_canonical_path(&canonical_root, &canonical_path, out_dir)
+}
+
+/// Like [`map_path`], for a root and path that are already canonical.
+pub fn map_canonical_path(
+    canonical_root: &Path,
+    canonical_path: &Path,
+    out_dir: Option<&Path>,
+) -> Result<PathBuf, anyhow::Error> {
+    // Use the out dir, or the parent of the canonicalized root to remove the last segment
+    let trimmed_root = match out_dir {
+        Some(out_dir) => out_dir,
+        None => canonical_root.parent().context("Root has no parent")?,
+    };
 
     // Strip the prefix (canonicalized root) from the canonicalized path
     let relative_path = canonical_path
-        .strip_prefix(&canonical_root)
+        .strip_prefix(canonical_root)
         .with_context(|| {
             format!(
                 "Path {} is not within root {}",
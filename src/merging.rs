@@ -1,4 +1,7 @@
+use std::{collections::HashMap, path::PathBuf};
+
 use anyhow::Error;
+use indexmap::IndexSet;
 use serde_json::{Map, Value};
 
 pub fn merge_values_consume(v1: &mut Value, v2: Value) -> Result<(), Error> {
@@ -26,3 +29,409 @@ pub fn merge_map_consume(m1: &mut Map<String, Value>, m2: Map<String, Value>) ->
     }
     Ok(())
 }
+
+/// Identifies where a value came from, the path of the file that defined it.
+pub type SourceId = PathBuf;
+
+/// Maps the dotted path of each leaf value to the source that contributed it.
+/// Leaves are all values except non-empty objects, so arrays are recorded as a whole.
+pub type Provenance = HashMap<String, SourceId>;
+
+/// Merges two maps like [`merge_map_consume`], recording `source` for every leaf of `m2` in `provenance`.
+/// Leaves that already have a source keep it, as merging keeps the existing value.
+pub fn merge_map_consume_tracked(
+    m1: &mut Map<String, Value>,
+    m2: Map<String, Value>,
+    source: &SourceId,
+    provenance: &mut Provenance,
+) -> Result<(), Error> {
+    record_provenance(&m2, source, provenance);
+    merge_map_consume(m1, m2)
+}
+
+/// Records `source` for every leaf of `map` that has no source yet.
+pub fn record_provenance(map: &Map<String, Value>, source: &SourceId, provenance: &mut Provenance) {
+    for (k, v) in map {
+        record_value_provenance(k.clone(), v, source, provenance);
+    }
+}
+
+/// Records `source` for every leaf of `value`, which is at `path`, that has no source yet.
+pub fn record_value_provenance(
+    path: String,
+    value: &Value,
+    source: &SourceId,
+    provenance: &mut Provenance,
+) {
+    match value {
+        Value::Object(object) if !object.is_empty() => {
+            for (k, v) in object {
+                record_value_provenance(format!("{}.{}", path, k), v, source, provenance);
+            }
+        }
+        _ => {
+            provenance.entry(path).or_insert_with(|| source.clone());
+        }
+    }
+}
+
+/// Whether the dotted `prefix` is a parent path of `path`.
+pub fn is_path_prefix(prefix: &str, path: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.starts_with('.'))
+}
+
+/// How two different values at the same path are handled when merging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// Fail on the first conflict.
+    #[default]
+    Fail,
+    /// Keep the value that was merged first and record the conflict.
+    KeepFirst,
+    /// Keep the value that was merged last and record the conflict.
+    KeepLast,
+}
+
+/// Two different values at the same path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    /// The dotted path of the value, including its environment.
+    pub path: String,
+    /// The value that was merged first.
+    pub existing: Value,
+    /// The value that was merged last.
+    pub incoming: Value,
+}
+
+/// The conflicts found while merging, handled according to a [`ConflictPolicy`].
+#[derive(Debug, Clone, Default)]
+pub struct Conflicts {
+    pub policy: ConflictPolicy,
+    pub found: Vec<Conflict>,
+}
+
+impl Conflicts {
+    pub fn new(policy: ConflictPolicy) -> Self {
+        Conflicts {
+            policy,
+            found: Vec::new(),
+        }
+    }
+
+    /// Merges like [`merge_values_consume`], but conflicts only fail with [`ConflictPolicy::Fail`].
+    fn merge(&mut self, v1: &mut Value, v2: Value, path: &str) -> Result<(), Error> {
+        match (v1, v2) {
+            (Value::Object(ref mut o1), Value::Object(o2)) => {
+                for (k, v) in o2 {
+                    let child_path = format!("{}.{}", path, k);
+                    match o1.get_mut(&k) {
+                        Some(existing_value) => self.merge(existing_value, v, &child_path)?,
+                        None => {
+                            o1.insert(k, v);
+                        }
+                    }
+                }
+                Ok(())
+            }
+            (v1, v2) => {
+                if v1 == &v2 {
+                    return Ok(());
+                }
+                if self.policy == ConflictPolicy::Fail {
+                    return Err(anyhow::anyhow!(
+                        "Conflicting values at {:?}: {:?} and {:?}",
+                        path,
+                        v1,
+                        v2
+                    ));
+                }
+                self.found.push(Conflict {
+                    path: path.to_string(),
+                    existing: v1.clone(),
+                    incoming: v2.clone(),
+                });
+                if self.policy == ConflictPolicy::KeepLast {
+                    *v1 = v2;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Merges two maps like [`merge_map_consume`], but arrays at the paths in `array_keys` are merged
+/// element-wise instead of having to be equal.
+///
+/// `array_keys` maps a dotted path to the field identifying the elements of the array at that path.
+/// Object elements with the same value for that field are merged, other object elements are appended.
+/// Scalar elements are merged as a set, only values not yet present are appended.
+///
+/// Paths are relative to the top level, the objects of the keys in `environments` are treated as
+/// top level as well. So `services` matches both the top level `services` and `prod.services`.
+///
+/// Conflicting values are handled by `conflicts`.
+pub fn merge_map_consume_keyed(
+    m1: &mut Map<String, Value>,
+    m2: Map<String, Value>,
+    array_keys: &HashMap<String, String>,
+    environments: &IndexSet<String>,
+    conflicts: &mut Conflicts,
+) -> Result<(), Error> {
+    let mut merger = KeyedMerger {
+        array_keys,
+        conflicts,
+    };
+    for (k, v) in m2 {
+        let path = if environments.contains(&k) {
+            None
+        } else {
+            Some(k.clone())
+        };
+        let full_path = k.clone();
+        merger.merge_entry(m1, k, v, path, &full_path)?;
+    }
+    Ok(())
+}
+
+/// The state of [`merge_map_consume_keyed`].
+struct KeyedMerger<'a> {
+    array_keys: &'a HashMap<String, String>,
+    conflicts: &'a mut Conflicts,
+}
+
+impl KeyedMerger<'_> {
+    /// `path` is the path used to look up array keys, `full_path` includes the environment.
+    fn merge_entry(
+        &mut self,
+        m1: &mut Map<String, Value>,
+        k: String,
+        v: Value,
+        path: Option<String>,
+        full_path: &str,
+    ) -> Result<(), Error> {
+        if let Some(existing_value) = m1.get_mut(&k) {
+            self.merge_values(existing_value, v, path.as_deref(), full_path)
+        } else {
+            m1.insert(k, v);
+            Ok(())
+        }
+    }
+
+    fn merge_values(
+        &mut self,
+        v1: &mut Value,
+        v2: Value,
+        path: Option<&str>,
+        full_path: &str,
+    ) -> Result<(), Error> {
+        let array_key = path.and_then(|path| self.array_keys.get(path));
+        match (v1, v2, array_key) {
+            (Value::Object(ref mut o1), Value::Object(o2), _) => {
+                for (k, v) in o2 {
+                    let child_path = match path {
+                        Some(path) => format!("{}.{}", path, k),
+                        None => k.clone(),
+                    };
+                    let child_full_path = format!("{}.{}", full_path, k);
+                    self.merge_entry(o1, k, v, Some(child_path), &child_full_path)?;
+                }
+                Ok(())
+            }
+            (Value::Array(ref mut a1), Value::Array(a2), Some(key)) => {
+                let key = key.clone();
+                merge_arrays_by_key(a1, a2, &key, full_path, self.conflicts)
+            }
+            (v1, v2, _) => self.conflicts.merge(v1, v2, full_path),
+        }
+    }
+}
+
+fn merge_arrays_by_key(
+    a1: &mut Vec<Value>,
+    a2: Vec<Value>,
+    key: &str,
+    path: &str,
+    conflicts: &mut Conflicts,
+) -> Result<(), Error> {
+    for element in a2 {
+        match element {
+            Value::Object(object) => {
+                let id = object.get(key).cloned().ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Array element {:?} is missing the merge key {:?}",
+                        object,
+                        key
+                    )
+                })?;
+                let existing = a1.iter_mut().find(|existing| {
+                    existing.as_object().and_then(|existing| existing.get(key)) == Some(&id)
+                });
+                match existing {
+                    Some(existing) => conflicts.merge(existing, Value::Object(object), path)?,
+                    None => a1.push(Value::Object(object)),
+                }
+            }
+            element => {
+                if !a1.contains(&element) {
+                    a1.push(element);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn object(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn test_merge_arrays_by_key() {
+        let mut m1 = object(json!({
+            "prod": {
+                "services": [{ "name": "api", "port": 80 }, { "name": "web" }],
+                "tags": ["a", "b"]
+            }
+        }));
+        let m2 = object(json!({
+            "prod": {
+                "services": [{ "name": "web", "port": 8080 }, { "name": "worker" }],
+                "tags": ["b", "c"]
+            }
+        }));
+        let array_keys = HashMap::from([
+            ("services".to_string(), "name".to_string()),
+            ("tags".to_string(), "name".to_string()),
+        ]);
+        let environments = IndexSet::from(["prod".to_string()]);
+
+        merge_map_consume_keyed(
+            &mut m1,
+            m2,
+            &array_keys,
+            &environments,
+            &mut Conflicts::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            Value::Object(m1),
+            json!({
+                "prod": {
+                    "services": [
+                        { "name": "api", "port": 80 },
+                        { "name": "web", "port": 8080 },
+                        { "name": "worker" }
+                    ],
+                    "tags": ["a", "b", "c"]
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_merge_arrays_without_key_conflicts() {
+        let mut m1 = object(json!({ "services": [{ "name": "api" }] }));
+        let m2 = object(json!({ "services": [{ "name": "web" }] }));
+
+        assert!(merge_map_consume_keyed(
+            &mut m1,
+            m2,
+            &HashMap::new(),
+            &IndexSet::new(),
+            &mut Conflicts::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_merge_arrays_by_key_conflicting_element() {
+        let mut m1 = object(json!({ "services": [{ "name": "api", "port": 80 }] }));
+        let m2 = object(json!({ "services": [{ "name": "api", "port": 81 }] }));
+        let array_keys = HashMap::from([("services".to_string(), "name".to_string())]);
+
+        assert!(merge_map_consume_keyed(
+            &mut m1,
+            m2,
+            &array_keys,
+            &IndexSet::new(),
+            &mut Conflicts::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_merge_tracked() {
+        let first = SourceId::from("_env.json");
+        let second = SourceId::from("_prod.env.json");
+        let mut provenance = Provenance::new();
+
+        let mut m1 = object(json!({ "name": "app", "prod": { "debug": false } }));
+        record_provenance(&m1, &first, &mut provenance);
+        let m2 = object(json!({ "name": "app", "prod": { "port": 80, "hosts": [] } }));
+        merge_map_consume_tracked(&mut m1, m2, &second, &mut provenance).unwrap();
+
+        assert_eq!(
+            provenance,
+            Provenance::from([
+                ("name".to_string(), first.clone()),
+                ("prod.debug".to_string(), first),
+                ("prod.port".to_string(), second.clone()),
+                ("prod.hosts".to_string(), second),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_merge_collecting_conflicts() {
+        let m1 = object(json!({ "name": "app", "prod": { "port": 80, "debug": false } }));
+        let m2 = object(json!({ "name": "web", "prod": { "port": 81, "debug": false } }));
+        let environments = IndexSet::from(["prod".to_string()]);
+
+        let mut kept_first = m1.clone();
+        let mut conflicts = Conflicts::new(ConflictPolicy::KeepFirst);
+        merge_map_consume_keyed(
+            &mut kept_first,
+            m2.clone(),
+            &HashMap::new(),
+            &environments,
+            &mut conflicts,
+        )
+        .unwrap();
+        assert_eq!(kept_first, m1);
+        assert_eq!(
+            conflicts.found,
+            vec![
+                Conflict {
+                    path: "name".to_string(),
+                    existing: json!("app"),
+                    incoming: json!("web"),
+                },
+                Conflict {
+                    path: "prod.port".to_string(),
+                    existing: json!(80),
+                    incoming: json!(81),
+                },
+            ]
+        );
+
+        let mut kept_last = m1;
+        let mut conflicts = Conflicts::new(ConflictPolicy::KeepLast);
+        merge_map_consume_keyed(
+            &mut kept_last,
+            m2.clone(),
+            &HashMap::new(),
+            &environments,
+            &mut conflicts,
+        )
+        .unwrap();
+        assert_eq!(kept_last, m2);
+        assert_eq!(conflicts.found.len(), 2);
+    }
+}
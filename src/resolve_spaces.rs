@@ -1,40 +1,231 @@
 use crate::{
     ancestor_mapping::AncestorMapping,
-    merging::merge_map_consume,
-    space_graph::{CopyTree, GenerateSpace, SpaceGraph},
+    apply_resolved::plan_copies,
+    file_graph::GlobalVariables,
+    file_system::FileSystem,
+    merging::{
+        is_path_prefix, merge_map_consume, merge_map_consume_keyed, record_value_provenance,
+        Conflict, ConflictPolicy, Conflicts, Provenance,
+    },
+    space_graph::{CopyTree, GenerateSpace, Space, SpaceGraph, ToCopy},
 };
 use anyhow::{Context, Result};
+use indexmap::IndexSet;
 use serde_json::{Map, Value};
 use std::{
+    borrow::Cow,
     collections::{HashMap, HashSet},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct ResolvedSpace {
+    pub name: String,
     pub variables: Option<Map<String, Value>>,
+    /// The file each variable was defined in, including inherited and imported variables.
+    /// Keys are dotted paths like `prod.db.host`.
+    pub provenance: Provenance,
     pub root_mapping: AncestorMapping,
-    pub environments: HashSet<String>,
+    pub environments: IndexSet<String>,
     pub path: PathBuf,
     pub files_to_copy: CopyTree,
     pub generate: GenerateSpace,
+    pub forenv_default: Option<String>,
+    /// The token replaced by the environment name in the names of copied files.
+    pub forenv_token: String,
 }
 
-pub fn resolve_spaces(space_graph: SpaceGraph) -> Result<HashMap<String, ResolvedSpace>> {
+/// A file the copy step writes for a space, see [`ResolvedSpace::copy_outputs`].
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CopyOutput {
+    /// The file in the weaveconfig directory.
+    pub source: PathBuf,
+    /// The path the file is written to, relative to the directory the space maps to.
+    pub destination: PathBuf,
+    /// The environment the file is copied for, if it is below a `_forenv` name.
+    /// The default variant of `forenv_default` has none.
+    pub env: Option<String>,
+}
+
+impl ResolvedSpace {
+    /// The files the copy step writes for this space, with `_forenv` and templated names substituted.
+    /// Files are listed even if their `when:` condition would skip them, as their content is not read.
+    pub fn copy_outputs(&self) -> Result<Vec<CopyOutput>> {
+        plan_copies(self)
+    }
+
+    /// Applies the variables of `overlay` on top of this space, for deployments that combine a base
+    /// with an overlay like production. The overlay wins: objects are merged key by key, any other
+    /// value of the overlay replaces the value of the base.
+    ///
+    /// The environments are the union of both, the base ones first, and the overlay's root mapping
+    /// takes precedence. Files of the overlay replace files of the base with the same name.
+    /// The name, path and generate options of the base are kept, as the result is written like the base.
+    pub fn overlay(&self, overlay: &ResolvedSpace) -> Result<ResolvedSpace> {
+        let variables = match (&self.variables, &overlay.variables) {
+            (Some(base), Some(variables)) => {
+                let mut base = base.clone();
+                remove_overridden(&mut base, variables);
+                merge_map_consume(&mut base, variables.clone()).with_context(|| {
+                    format!("Failed to overlay {:?} onto {:?}", overlay.name, self.name)
+                })?;
+                Some(base)
+            }
+            (base, variables) => variables.clone().or_else(|| base.clone()),
+        };
+
+        let overridden = |path: &String| {
+            overlay.provenance.keys().any(|overlay_path| {
+                overlay_path == path
+                    || is_path_prefix(overlay_path, path)
+                    || is_path_prefix(path, overlay_path)
+            })
+        };
+        let mut provenance = overlay.provenance.clone();
+        for (path, source) in &self.provenance {
+            if !overridden(path) {
+                provenance.insert(path.clone(), source.clone());
+            }
+        }
+
+        let mut root_mapping = self.root_mapping.clone();
+        for (ancestor, env) in overlay.root_mapping.list_ancestor_to_space() {
+            if root_mapping.contains_ancestor(ancestor) {
+                root_mapping.replace_mapping(ancestor.clone(), env.clone());
+            } else {
+                root_mapping.add_mapping(ancestor.clone(), env.clone())?;
+            }
+        }
+
+        let mut environments = self.environments.clone();
+        environments.extend(overlay.environments.iter().cloned());
+
+        let overlay_names = overlay
+            .files_to_copy
+            .to_copy
+            .iter()
+            .map(ToCopy::last_segment)
+            .collect::<Result<HashSet<_>>>()?;
+        let mut to_copy = Vec::new();
+        for base in &self.files_to_copy.to_copy {
+            if !overlay_names.contains(base.last_segment()?) {
+                to_copy.push(base.clone());
+            }
+        }
+        to_copy.extend(overlay.files_to_copy.to_copy.iter().cloned());
+
+        Ok(ResolvedSpace {
+            name: self.name.clone(),
+            variables,
+            provenance,
+            root_mapping,
+            environments,
+            path: self.path.clone(),
+            files_to_copy: CopyTree { to_copy },
+            generate: self.generate.clone(),
+            forenv_default: overlay
+                .forenv_default
+                .clone()
+                .or_else(|| self.forenv_default.clone()),
+            forenv_token: self.forenv_token.clone(),
+        })
+    }
+}
+
+/// Removes the values of `base` that `overlay` replaces, every key of the overlay unless both
+/// values are objects, which are merged instead.
+fn remove_overridden(base: &mut Map<String, Value>, overlay: &Map<String, Value>) {
+    for (key, value) in overlay {
+        match (base.get_mut(key), value) {
+            (Some(Value::Object(base)), Value::Object(value)) => remove_overridden(base, value),
+            _ => {
+                base.remove(key);
+            }
+        }
+    }
+}
+
+/// A merge conflict found in a space while resolving with [`crate::resolve_weaveconfig_collecting`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeConflict {
+    /// The name of the space the values were merged into.
+    pub space: String,
+    pub conflict: Conflict,
+}
+
+/// Resolves the variables of every space.
+///
+/// The `globals` of a weaveconfig root without a `_space.json` are merged into every space last,
+/// with the lowest precedence: a global variable is only added where the space does not already
+/// have a value for it, at the top level or in one of its environments.
+pub fn resolve_spaces(
+    space_graph: SpaceGraph,
+    globals: Option<&GlobalVariables>,
+) -> Result<HashMap<String, ResolvedSpace>> {
+    resolve_spaces_collecting(space_graph, globals, ConflictPolicy::Fail).map(|(spaces, _)| spaces)
+}
+
+/// Resolves the spaces like [`resolve_spaces`], but merge conflicts are handled by `policy`
+/// and returned instead of failing on the first one, so they can be fixed in bulk.
+///
+/// The variables of a space are merged first, then those of its parent and then those of its
+/// dependencies in order. Abstract environments are merged into the other environments last.
+/// Conflicts are reported once for the space they occur in, spaces inheriting the result do not
+/// report them again.
+pub fn resolve_spaces_collecting(
+    space_graph: SpaceGraph,
+    globals: Option<&GlobalVariables>,
+    policy: ConflictPolicy,
+) -> Result<(HashMap<String, ResolvedSpace>, Vec<MergeConflict>)> {
     let mut resolved_spaces = HashMap::new();
-    let mut visited = HashSet::new();
+    let mut state = ResolveState {
+        visited: HashSet::new(),
+        policy,
+        conflicts: Vec::new(),
+    };
 
     for space_name in space_graph.keys() {
-        resolve_space(
-            &space_name,
-            &mut visited,
-            &mut resolved_spaces,
-            &space_graph,
-        )
-        .with_context(|| format!("Failed to resolve space for path: {:?}", space_name))?;
+        resolve_space(space_name, &mut state, &mut resolved_spaces, &space_graph)
+            .with_context(|| format!("Failed to resolve space for path: {:?}", space_name))?;
+    }
+
+    // Merged only once every space is resolved, so the globals never conflict with the values of
+    // a parent or dependency
+    if let Some(globals) = globals {
+        for resolved in resolved_spaces.values_mut() {
+            merge_globals(resolved, globals);
+        }
+    }
+
+    // Pruned only once every space is resolved, so children and dependents still see all environments
+    for space in space_graph.values() {
+        if space.prune_environments {
+            if let Some(resolved) = resolved_spaces.get_mut(&space.name) {
+                prune_environments(resolved);
+            }
+        }
     }
 
-    Ok(resolved_spaces)
+    state
+        .conflicts
+        .sort_by(|a, b| (&a.space, &a.conflict.path).cmp(&(&b.space, &b.conflict.path)));
+    Ok((resolved_spaces, state.conflicts))
+}
+
+/// The state shared while resolving all spaces.
+struct ResolveState {
+    /// The spaces currently being resolved, used to detect cycles.
+    visited: HashSet<String>,
+    policy: ConflictPolicy,
+    conflicts: Vec<MergeConflict>,
+}
+
+/// The variables of a space while its parent and dependencies are merged into it.
+struct MergedVariables {
+    variables: Option<Map<String, Value>>,
+    provenance: Provenance,
+    conflicts: Conflicts,
 }
 
 // The root mapping is the mapping from the ENV variable to this space's environments.
@@ -42,7 +233,7 @@ pub fn resolve_spaces(space_graph: SpaceGraph) -> Result<HashMap<String, Resolve
 
 fn resolve_space(
     name: &str,
-    visited: &mut HashSet<String>,
+    state: &mut ResolveState,
     resolved_spaces: &mut HashMap<String, ResolvedSpace>,
     space_graph: &SpaceGraph,
 ) -> Result<()> {
@@ -54,24 +245,50 @@ fn resolve_space(
         return Ok(()); // Space already resolved.
     }
 
-    if visited.contains(name) {
+    if state.visited.contains(name) {
         return Err(anyhow::anyhow!(
             "Cyclic dependency detected for name: {:?}",
             name
         ));
     }
 
-    visited.insert(name.to_string());
+    state.visited.insert(name.to_string());
+
+    let (resolved, conflicts) = build_space(space, None, state, resolved_spaces, space_graph)?;
+    state
+        .conflicts
+        .extend(conflicts.into_iter().map(|conflict| MergeConflict {
+            space: name.to_string(),
+            conflict,
+        }));
+    resolved_spaces.insert(name.to_string(), resolved);
+
+    Ok(())
+}
 
-    let mut variables = space.variables.clone();
+/// Resolves a space without caching it, returning the conflicts found while merging into it.
+/// The dependency named `excluded_dependency` is not imported, see [`resolve_parent`].
+fn build_space(
+    space: &Space,
+    excluded_dependency: Option<&str>,
+    state: &mut ResolveState,
+    resolved_spaces: &mut HashMap<String, ResolvedSpace>,
+    space_graph: &SpaceGraph,
+) -> Result<(ResolvedSpace, Vec<Conflict>)> {
+    let name = space.name.as_str();
+    let mut merged = MergedVariables {
+        variables: space.variables.clone(),
+        provenance: space.provenance.clone(),
+        conflicts: Conflicts::new(state.policy),
+    };
 
     let mut root_mapping = space.parent_mapping.clone();
     if let Some(parent_space) = &space.parent_space {
         let parent_space = resolve_parent(
             parent_space,
-            &space.parent_mapping,
-            &mut variables,
-            visited,
+            space,
+            &mut merged,
+            state,
             resolved_spaces,
             space_graph,
         )
@@ -93,11 +310,15 @@ fn resolve_space(
     }
 
     for dependency in &space.dependencies {
+        if Some(dependency.as_str()) == excluded_dependency {
+            continue;
+        }
         resolve_dependency(
             dependency,
+            space,
             &root_mapping,
-            &mut variables,
-            visited,
+            &mut merged,
+            state,
             resolved_spaces,
             space_graph,
         )
@@ -109,6 +330,12 @@ fn resolve_space(
         })?;
     }
 
+    let MergedVariables {
+        mut variables,
+        mut provenance,
+        mut conflicts,
+    } = merged;
+
     if let Some(variables) = &mut variables {
         // insert empty object for each environment if not present
         for env in &space.environments {
@@ -142,112 +369,1616 @@ fn resolve_space(
         }
     }
 
-    resolved_spaces.insert(
-        name.to_string(),
+    let environments = merge_abstract_environments(
+        space,
+        &mut variables,
+        &mut provenance,
+        &mut root_mapping,
+        &mut conflicts,
+    )
+    .with_context(|| format!("Failed to merge abstract environments of space: {:?}", name))?;
+
+    check_required(space, &variables, &environments)?;
+
+    Ok((
         ResolvedSpace {
+            name: name.to_string(),
             variables,
-            environments: space.environments.clone(),
+            provenance,
+            environments,
             path: space.path.clone(),
             files_to_copy: space.files_to_copy.clone(),
             generate: space.generate.clone(),
+            forenv_default: space.forenv_default.clone(),
+            forenv_token: space.forenv_token.clone(),
             root_mapping,
         },
-    );
-
-    Ok(())
+        conflicts.found,
+    ))
 }
 
+/// Merges the parent into the space.
+///
+/// A parent can import a child space as a dependency. The child then inherits the parent resolved
+/// without that import, which is what the parent has before importing it, so the data flows in one
+/// direction only. The result is not cached, the parent itself is resolved with the import.
 fn resolve_parent<'a>(
     parent_name: &str,
-    parent_mapping: &AncestorMapping,
-    this_variables: &mut Option<Map<String, Value>>,
-    visited: &mut HashSet<String>,
+    space: &Space,
+    this: &mut MergedVariables,
+    state: &mut ResolveState,
     resolved_spaces: &'a mut HashMap<String, ResolvedSpace>,
     space_graph: &SpaceGraph,
-) -> Result<&'a ResolvedSpace> {
-    resolve_space(parent_name, visited, resolved_spaces, space_graph)
-        .with_context(|| format!("Failed to resolve dependency path: {:?}", parent_name))?;
-
-    let resolved_space = resolved_spaces
+) -> Result<Cow<'a, ResolvedSpace>> {
+    let parent = space_graph
         .get(parent_name)
-        .with_context(|| format!("Resolved space not found for path: {:?}", parent_name))?;
+        .with_context(|| format!("Space not found for name: {:?}", parent_name))?;
+    let resolved_space = if parent.dependencies.contains(&space.name) {
+        // The conflicts are reported when the parent itself is resolved
+        let (resolved, _) = build_space(
+            parent,
+            Some(&space.name),
+            state,
+            resolved_spaces,
+            space_graph,
+        )
+        .with_context(|| {
+            format!(
+                "Failed to resolve the parent {:?} without its import of {:?}",
+                parent_name, space.name
+            )
+        })?;
+        Cow::Owned(resolved)
+    } else {
+        resolve_space(parent_name, state, resolved_spaces, space_graph)
+            .with_context(|| format!("Failed to resolve dependency path: {:?}", parent_name))?;
+        Cow::Borrowed(
+            resolved_spaces
+                .get(parent_name)
+                .with_context(|| format!("Resolved space not found for path: {:?}", parent_name))?,
+        )
+    };
 
     let mut to_merge = resolved_space.variables.clone();
+    let mut renamed_envs = HashMap::new();
 
     for dependency_env in &resolved_space.environments {
-        let space_env = parent_mapping.get_space(dependency_env);
-        if let Some(space_env) = space_env {
-            if let Some(ref mut value) = to_merge {
-                if let Some(moved_value) = value.remove(dependency_env) {
-                    value.insert(space_env.clone(), moved_value.clone());
+        let space_env = space.parent_mapping.get_space(dependency_env);
+        if let Some(ref mut value) = to_merge {
+            match space_env {
+                Some(space_env) => {
+                    if let Some(moved_value) = value.remove(dependency_env) {
+                        value.insert(space_env.clone(), moved_value);
+                        renamed_envs.insert(dependency_env.as_str(), vec![space_env.as_str()]);
+                    }
                 }
+                // A parent environment without a child environment is dropped, strict spaces
+                // reject it when building the space graph. Spaces without environments keep them all.
+                None if !space.environments.is_empty()
+                    && value.remove(dependency_env).is_some() =>
+                {
+                    renamed_envs.insert(dependency_env.as_str(), Vec::new());
+                }
+                None => {}
             }
         }
     }
+    inherit_provenance(
+        &mut this.provenance,
+        &resolved_space.provenance,
+        &renamed_envs,
+    );
 
     if let Some(to_merge) = to_merge {
-        if let Some(ref mut value) = this_variables {
+        if let Some(ref mut value) = &mut this.variables {
             let value_clone = value.clone();
             let to_merge_clone = to_merge.clone();
-            merge_map_consume(value, to_merge).with_context(|| {
+            merge_map_consume_keyed(
+                value,
+                to_merge,
+                &space.array_merge_keys,
+                &space.environments,
+                &mut this.conflicts,
+            )
+            .with_context(|| {
                 format!(
                     "Failed to merge variables for dependency: {:?}, {:?}, {:?}",
                     parent_name, value_clone, to_merge_clone
                 )
             })?;
         } else {
-            *this_variables = Some(to_merge);
+            this.variables = Some(to_merge);
         }
     }
 
-    Ok(&resolved_space)
+    Ok(resolved_space)
+}
+
+/// The environments of the space that import the environment `dependency_env` of a dependency.
+/// The environment mapping of the dependency decides for the environments it maps, the other
+/// environments import the dependency environments the root mappings connect them to.
+pub(crate) fn dependency_env_targets<'a>(
+    space: &'a Space,
+    dependency: &ResolvedSpace,
+    root_mapping: &'a AncestorMapping,
+    dependency_env: &String,
+) -> Vec<&'a String> {
+    let mapping = space.dependency_mappings.get(&dependency.name);
+    let mut targets: Vec<&String> = dependency
+        .root_mapping
+        .get_ancestors(dependency_env)
+        .iter()
+        .filter_map(|rooted_dependency_env| root_mapping.get_space(rooted_dependency_env))
+        .filter(|space_env| mapping.is_none_or(|mapping| !mapping.contains_space(space_env)))
+        .collect();
+    if let Some(space_env) = mapping.and_then(|mapping| mapping.get_space(dependency_env)) {
+        targets.push(space_env);
+    }
+    targets
 }
 
 fn resolve_dependency<'a>(
     dependency_name: &str,
+    space: &Space,
     root_mapping: &AncestorMapping,
-    this_variables: &mut Option<Map<String, Value>>,
-    visited: &mut HashSet<String>,
+    this: &mut MergedVariables,
+    state: &mut ResolveState,
     resolved_spaces: &'a mut HashMap<String, ResolvedSpace>,
     space_graph: &SpaceGraph,
 ) -> Result<&'a ResolvedSpace> {
-    resolve_space(dependency_name, visited, resolved_spaces, space_graph)
+    resolve_space(dependency_name, state, resolved_spaces, space_graph)
         .with_context(|| format!("Failed to resolve dependency path: {:?}", dependency_name))?;
 
     let resolved_space = resolved_spaces
         .get(dependency_name)
         .with_context(|| format!("Resolved space not found for path: {:?}", dependency_name))?;
 
+    if let Some(mapping) = space.dependency_mappings.get(dependency_name) {
+        let mut unknown: Vec<&String> = mapping
+            .list_ancestor_to_space()
+            .keys()
+            .filter(|environment| !resolved_space.environments.contains(*environment))
+            .collect();
+        if !unknown.is_empty() {
+            unknown.sort();
+            return Err(anyhow::anyhow!(
+                "The environment mapping of the dependency {:?} of the space {:?} imports the environments {:?}, which are not environments of the dependency",
+                dependency_name,
+                space.name,
+                unknown
+            ));
+        }
+    }
+
     let mut to_merge = resolved_space.variables.clone();
+    let mut renamed_envs = HashMap::new();
 
     if let Some(to_merge) = to_merge.as_mut() {
         for dependency_env in &resolved_space.environments {
-            let rooted_dependency_envs = resolved_space.root_mapping.get_ancestors(dependency_env);
             if let Some(moved_value) = to_merge.remove(dependency_env) {
-                for rooted_dependency_env in rooted_dependency_envs {
-                    let space_env = root_mapping.get_space(rooted_dependency_env);
-                    if let Some(space_env) = space_env {
-                        to_merge.insert(space_env.clone(), moved_value.clone());
-                    }
+                let space_envs: &mut Vec<&str> =
+                    renamed_envs.entry(dependency_env.as_str()).or_default();
+                for space_env in
+                    dependency_env_targets(space, resolved_space, root_mapping, dependency_env)
+                {
+                    to_merge.insert(space_env.clone(), moved_value.clone());
+                    space_envs.push(space_env.as_str());
                 }
             }
         }
     }
+    let mut renamed = Provenance::new();
+    inherit_provenance(&mut renamed, &resolved_space.provenance, &renamed_envs);
+    if let Some(alias) = space.dependency_aliases.get(dependency_name) {
+        renamed = renamed
+            .into_iter()
+            .flat_map(|(path, source)| {
+                alias_paths(&path, alias, &space.environments)
+                    .into_iter()
+                    .map(move |path| (path, source.clone()))
+            })
+            .collect();
+        to_merge = to_merge.map(|to_merge| alias_variables(to_merge, alias, &space.environments));
+    }
+    if let Some(imported_into) = space.dependency_environments.get(dependency_name) {
+        renamed = renamed
+            .into_iter()
+            .flat_map(|(path, source)| {
+                restricted_paths(&path, imported_into, &space.environments)
+                    .into_iter()
+                    .map(move |path| (path, source.clone()))
+            })
+            .collect();
+        to_merge = to_merge
+            .map(|to_merge| restrict_variables(to_merge, imported_into, &space.environments));
+    }
+    inherit_provenance(&mut this.provenance, &renamed, &HashMap::new());
 
     if let Some(to_merge) = to_merge {
-        if let Some(ref mut value) = this_variables {
+        if let Some(ref mut value) = &mut this.variables {
             let value_clone = value.clone();
             let to_merge_clone = to_merge.clone();
-            merge_map_consume(value, to_merge).with_context(|| {
+            merge_map_consume_keyed(
+                value,
+                to_merge,
+                &space.array_merge_keys,
+                &space.environments,
+                &mut this.conflicts,
+            )
+            .with_context(|| {
                 format!(
                     "Failed to merge variables for dependency: {:?}, {:?}, {:?}",
                     dependency_name, value_clone, to_merge_clone
                 )
             })?;
         } else {
-            *this_variables = Some(to_merge);
+            this.variables = Some(to_merge);
+        }
+    }
+
+    Ok(resolved_space)
+}
+
+/// Merges the abstract environments of a space into its other environments and removes them.
+/// Returns the remaining environments.
+fn merge_abstract_environments(
+    space: &Space,
+    variables: &mut Option<Map<String, Value>>,
+    provenance: &mut Provenance,
+    root_mapping: &mut AncestorMapping,
+    conflicts: &mut Conflicts,
+) -> Result<IndexSet<String>> {
+    let concrete: IndexSet<String> = space
+        .environments
+        .iter()
+        .filter(|env| !space.abstract_environments.contains(*env))
+        .cloned()
+        .collect();
+
+    for abstract_env in &space.abstract_environments {
+        root_mapping.remove_mapping_by_space(abstract_env);
+
+        let Some(variables) = variables.as_mut() else {
+            continue;
+        };
+        let Some(Value::Object(abstract_variables)) = variables.remove(abstract_env) else {
+            continue;
+        };
+        for env in &concrete {
+            if let Some(Value::Object(env_variables)) = variables.get_mut(env) {
+                let found = conflicts.found.len();
+                merge_map_consume_keyed(
+                    env_variables,
+                    abstract_variables.clone(),
+                    &space.array_merge_keys,
+                    &IndexSet::new(),
+                    conflicts,
+                )
+                .with_context(|| {
+                    format!(
+                        "Failed to merge abstract environment {:?} into environment {:?}",
+                        abstract_env, env
+                    )
+                })?;
+                // The paths are relative to the environment the abstract one is merged into
+                for conflict in &mut conflicts.found[found..] {
+                    conflict.path = format!("{}.{}", env, conflict.path);
+                }
+            }
+        }
+
+        let abstract_provenance: Provenance = provenance
+            .iter()
+            .filter(|(path, _)| path.split('.').next() == Some(abstract_env.as_str()))
+            .map(|(path, source)| (path.clone(), source.clone()))
+            .collect();
+        for path in abstract_provenance.keys() {
+            provenance.remove(path);
+        }
+        let renamed_envs = HashMap::from([(
+            abstract_env.as_str(),
+            concrete.iter().map(String::as_str).collect(),
+        )]);
+        inherit_provenance(provenance, &abstract_provenance, &renamed_envs);
+    }
+
+    Ok(concrete)
+}
+
+/// Adds the global variables the space does not define itself, see [`resolve_spaces`].
+fn merge_globals(space: &mut ResolvedSpace, globals: &GlobalVariables) {
+    let variables = space.variables.get_or_insert_with(Map::new);
+    let environment_keys: HashSet<String> = space
+        .environments
+        .iter()
+        .filter_map(|env| variables.get(env)?.as_object())
+        .flat_map(|env| env.keys().cloned())
+        .collect();
+    for (key, value) in &globals.variables {
+        if environment_keys.contains(key) {
+            continue;
+        }
+        fill_missing(variables, key, value, key, globals, &mut space.provenance);
+    }
+}
+
+/// Inserts `value` at `key` unless the object has a value there, objects are filled key by key.
+fn fill_missing(
+    object: &mut Map<String, Value>,
+    key: &str,
+    value: &Value,
+    path: &str,
+    globals: &GlobalVariables,
+    provenance: &mut Provenance,
+) {
+    match (object.get_mut(key), value) {
+        (None, value) => {
+            object.insert(key.to_string(), value.clone());
+            let prefix = format!("{}.", path);
+            for (global_path, source) in &globals.provenance {
+                if global_path == path || global_path.starts_with(&prefix) {
+                    provenance
+                        .entry(global_path.clone())
+                        .or_insert_with(|| source.clone());
+                }
+            }
+        }
+        (Some(Value::Object(existing)), Value::Object(value)) => {
+            for (key, value) in value {
+                let path = format!("{}.{}", path, key);
+                fill_missing(existing, key, value, &path, globals, provenance);
+            }
+        }
+        (Some(_), _) => {}
+    }
+}
+
+/// Collapses the environments of a space into the first one if they all have the same variables.
+/// The ancestor environments mapped to the removed environments are mapped to the remaining one,
+/// so selecting any of the original environments still works.
+fn prune_environments(space: &mut ResolvedSpace) {
+    let Some(variables) = space.variables.as_mut() else {
+        return;
+    };
+    let Some(kept) = space.environments.first().cloned() else {
+        return;
+    };
+    let kept_value = variables.get(&kept);
+    if space.environments.len() < 2
+        || space
+            .environments
+            .iter()
+            .any(|env| variables.get(env) != kept_value)
+    {
+        return;
+    }
+
+    for env in space.environments.split_off(1) {
+        variables.remove(&env);
+        space
+            .provenance
+            .retain(|path, _| path.split('.').next() != Some(env.as_str()));
+        for ancestor in space.root_mapping.get_ancestors(&env).clone() {
+            space.root_mapping.replace_mapping(ancestor, kept.clone());
+        }
+    }
+}
+
+/// Ensures every required path is present, in every environment if the space has environments.
+/// The error lists all missing paths.
+fn check_required(
+    space: &Space,
+    variables: &Option<Map<String, Value>>,
+    environments: &IndexSet<String>,
+) -> Result<()> {
+    if space.required.is_empty() {
+        return Ok(());
+    }
+    let empty = Map::new();
+    let variables = variables.as_ref().unwrap_or(&empty);
+
+    let mut missing = Vec::new();
+    if environments.is_empty() {
+        for path in &space.required {
+            if !has_path(variables, None, path) {
+                missing.push(path.clone());
+            }
+        }
+    } else {
+        let mut environments: Vec<&String> = environments.iter().collect();
+        environments.sort();
+        for env in environments {
+            let env_variables = variables.get(env).and_then(Value::as_object);
+            for path in &space.required {
+                if !has_path(variables, env_variables, path) {
+                    missing.push(format!("{}.{}", env, path));
+                }
+            }
+        }
+    }
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "The space {:?} is missing required variables: {}",
+            space.name,
+            missing.join(", ")
+        ))
+    }
+}
+
+/// Whether a dotted path is present in the environment variables or the top-level variables.
+fn has_path(
+    variables: &Map<String, Value>,
+    env_variables: Option<&Map<String, Value>>,
+    path: &str,
+) -> bool {
+    let lookup = |map: &Map<String, Value>| {
+        let mut segments = path.split('.');
+        let first = segments.next().and_then(|segment| map.get(segment));
+        segments
+            .try_fold(first, |value, segment| {
+                Some(value?.as_object()?.get(segment))
+            })
+            .flatten()
+            .is_some()
+    };
+    env_variables.is_some_and(lookup) || lookup(variables)
+}
+
+/// Nests the variables of an aliased dependency under the alias.
+/// If the space has environments, each environment gets the variables of the dependency for it,
+/// the top-level variables merged with those of the environment, as top-level keys and environment
+/// keys can not overlap.
+fn alias_variables(
+    variables: Map<String, Value>,
+    alias: &str,
+    environments: &IndexSet<String>,
+) -> Map<String, Value> {
+    let nest = |value: Map<String, Value>| {
+        let mut nested = Map::new();
+        nested.insert(alias.to_string(), Value::Object(value));
+        nested
+    };
+    if environments.is_empty() {
+        return nest(variables);
+    }
+
+    let mut top_level = variables;
+    let mut environment_values: HashMap<String, Map<String, Value>> = environments
+        .iter()
+        .filter_map(|env| match top_level.remove(env) {
+            Some(Value::Object(value)) => Some((env.clone(), value)),
+            _ => None,
+        })
+        .collect();
+    environments
+        .iter()
+        .map(|env| {
+            let mut value = top_level.clone();
+            value.extend(environment_values.remove(env).unwrap_or_default());
+            (env.clone(), Value::Object(nest(value)))
+        })
+        .collect()
+}
+
+/// The dotted paths a variable of an aliased dependency ends up at, see [`alias_variables`].
+fn alias_paths(path: &str, alias: &str, environments: &IndexSet<String>) -> Vec<String> {
+    match path.split_once('.') {
+        Some((env, rest)) if environments.contains(env) => {
+            vec![format!("{}.{}.{}", env, alias, rest)]
         }
+        _ if environments.contains(path) => vec![format!("{}.{}", path, alias)],
+        _ if environments.is_empty() => vec![format!("{}.{}", alias, path)],
+        _ => environments
+            .iter()
+            .map(|env| format!("{}.{}.{}", env, alias, path))
+            .collect(),
     }
+}
+
+/// Restricts the variables of a dependency to the environments it is imported into. The other
+/// environments are dropped and the top-level variables are nested within each of these
+/// environments, overridden by the variables of the environment like in the generated config.
+fn restrict_variables(
+    variables: Map<String, Value>,
+    imported_into: &HashSet<String>,
+    environments: &IndexSet<String>,
+) -> Map<String, Value> {
+    let mut top_level = variables;
+    let mut environment_values: HashMap<String, Map<String, Value>> = environments
+        .iter()
+        .filter_map(|env| match top_level.remove(env) {
+            Some(Value::Object(value)) => Some((env.clone(), value)),
+            _ => None,
+        })
+        .collect();
+    environments
+        .iter()
+        .filter(|env| imported_into.contains(*env))
+        .map(|env| {
+            let mut value = top_level.clone();
+            value.extend(environment_values.remove(env).unwrap_or_default());
+            (env.clone(), Value::Object(value))
+        })
+        .collect()
+}
 
-    Ok(&resolved_space)
+/// The dotted paths a variable of a restricted dependency ends up at, see [`restrict_variables`].
+fn restricted_paths(
+    path: &str,
+    imported_into: &HashSet<String>,
+    environments: &IndexSet<String>,
+) -> Vec<String> {
+    let root = path.split_once('.').map_or(path, |(root, _)| root);
+    if environments.contains(root) {
+        return if imported_into.contains(root) {
+            vec![path.to_string()]
+        } else {
+            Vec::new()
+        };
+    }
+    environments
+        .iter()
+        .filter(|env| imported_into.contains(*env))
+        .map(|env| format!("{}.{}", env, path))
+        .collect()
+}
+
+/// Adds the provenance of a parent or dependency to the provenance of a space.
+/// Paths below a renamed environment are moved to the environments it was renamed to, those below
+/// an environment renamed to none are dropped. Paths that already have a source keep it.
+fn inherit_provenance(
+    provenance: &mut Provenance,
+    from: &Provenance,
+    renamed_envs: &HashMap<&str, Vec<&str>>,
+) {
+    for (path, source) in from {
+        let (root, rest) = match path.split_once('.') {
+            Some((root, rest)) => (root, Some(rest)),
+            None => (path.as_str(), None),
+        };
+        match renamed_envs.get(root) {
+            Some(space_envs) => {
+                for space_env in space_envs {
+                    let path = match rest {
+                        Some(rest) => format!("{}.{}", space_env, rest),
+                        None => space_env.to_string(),
+                    };
+                    provenance.entry(path).or_insert_with(|| source.clone());
+                }
+            }
+            None => {
+                provenance
+                    .entry(path.clone())
+                    .or_insert_with(|| source.clone());
+            }
+        }
+    }
+}
+
+/// The key of an object whose value is fetched from a URL, see [`fetch_remote_variables`].
+const URL_DIRECTIVE: &str = "@url";
+/// The key of the value used instead of a remote value that can not be fetched.
+const URL_DEFAULT: &str = "default";
+
+/// How remote variables are fetched, see [`crate::GenerateOptions::remote`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteOptions {
+    /// How long fetching a single URL may take.
+    pub timeout: Duration,
+    /// A directory every fetched document is cached in.
+    /// The cached document is used when fetching fails, before the `default`.
+    pub cache_dir: Option<PathBuf>,
+}
+
+impl Default for RemoteOptions {
+    fn default() -> Self {
+        RemoteOptions {
+            timeout: Duration::from_secs(10),
+            cache_dir: None,
+        }
+    }
+}
+
+/// Replaces every object like `{ "@url": "https://...", "default": ... }` in the variables of the
+/// spaces with the JSON document fetched from the URL, so it is merged like any other value.
+/// If fetching fails the cached document or the optional `default` is used, otherwise it is an error.
+///
+/// Remote variables are opt-in, without `remote` every such object is an error.
+/// The `_schema.json` of a space is validated before fetching, against the object itself.
+pub async fn fetch_remote_variables(
+    space_graph: &mut SpaceGraph,
+    remote: Option<&RemoteOptions>,
+    fs: &dyn FileSystem,
+) -> Result<()> {
+    let mut urls = HashSet::new();
+    for space in space_graph.values() {
+        if let Some(variables) = &space.variables {
+            collect_urls(variables, &mut urls).with_context(|| {
+                format!("Invalid remote variable in the space {:?}", space.name)
+            })?;
+        }
+    }
+    if urls.is_empty() {
+        return Ok(());
+    }
+    let Some(remote) = remote else {
+        return Err(anyhow::anyhow!(
+            "The variables read {:?} from a remote URL, but remote variables are not enabled",
+            urls.into_iter().min().unwrap_or_default()
+        ));
+    };
+
+    let client = reqwest::Client::builder()
+        .timeout(remote.timeout)
+        .build()
+        .context("Failed to create the HTTP client")?;
+    let mut fetched = HashMap::new();
+    for url in urls {
+        let value = fetch_url(&client, &url, remote.cache_dir.as_deref(), fs).await;
+        fetched.insert(url, value);
+    }
+
+    for space in space_graph.values_mut() {
+        if let Some(variables) = &mut space.variables {
+            for (key, value) in variables.iter_mut() {
+                replace_directives(value, key.clone(), &fetched, &mut space.provenance)
+                    .with_context(|| {
+                        format!(
+                            "Failed to read a remote variable of the space {:?}",
+                            space.name
+                        )
+                    })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The URL and default of an `@url` object, `None` for other objects.
+fn url_directive(object: &Map<String, Value>) -> Result<Option<(&str, Option<&Value>)>> {
+    let Some(url) = object.get(URL_DIRECTIVE) else {
+        return Ok(None);
+    };
+    let url = url
+        .as_str()
+        .with_context(|| format!("The {:?} must be a string, got {}", URL_DIRECTIVE, url))?;
+    if let Some(key) = object
+        .keys()
+        .find(|key| *key != URL_DIRECTIVE && *key != URL_DEFAULT)
+    {
+        return Err(anyhow::anyhow!(
+            "An object with {:?} can only contain {:?}, found {:?}",
+            URL_DIRECTIVE,
+            URL_DEFAULT,
+            key
+        ));
+    }
+    Ok(Some((url, object.get(URL_DEFAULT))))
+}
+
+fn collect_urls(variables: &Map<String, Value>, urls: &mut HashSet<String>) -> Result<()> {
+    for value in variables.values() {
+        if let Value::Object(object) = value {
+            match url_directive(object)? {
+                Some((url, _)) => {
+                    urls.insert(url.to_string());
+                }
+                None => collect_urls(object, urls)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fetches a JSON document, falling back to and updating the cache if there is one.
+async fn fetch_url(
+    client: &reqwest::Client,
+    url: &str,
+    cache_dir: Option<&Path>,
+    fs: &dyn FileSystem,
+) -> Result<Value> {
+    let cache_path = cache_dir.map(|cache_dir| cache_dir.join(cache_file_name(url)));
+    let fetched = async {
+        let response = client.get(url).send().await?.error_for_status()?;
+        let text = response.text().await?;
+        let value: Value = serde_json::from_str(&text).context("The response is not valid JSON")?;
+        Ok::<_, anyhow::Error>((text, value))
+    }
+    .await
+    .with_context(|| format!("Failed to fetch {:?}", url));
+    match (fetched, cache_path) {
+        (Ok((text, value)), Some(cache_path)) => {
+            fs.create_dir(cache_path.parent().unwrap_or(Path::new(".")))
+                .await
+                .with_context(|| format!("Failed to create the cache directory of {:?}", url))?;
+            fs.write(&cache_path, text.as_bytes())
+                .await
+                .with_context(|| format!("Failed to cache {:?} at {:?}", url, cache_path))?;
+            Ok(value)
+        }
+        (Ok((_, value)), None) => Ok(value),
+        (Err(error), Some(cache_path)) => match fs.read_to_string(&cache_path).await {
+            Ok(text) => serde_json::from_str(&text)
+                .with_context(|| format!("The cached {:?} is not valid JSON", cache_path)),
+            Err(_) => Err(error),
+        },
+        (Err(error), None) => Err(error),
+    }
+}
+
+/// The name of the file a URL is cached in, a readable prefix of the URL followed by a hash of the
+/// whole URL. The prefix has every character that is not alphanumeric, `-` or `.` replaced, so it
+/// is ambiguous on its own, the hash keeps different URLs apart.
+fn cache_file_name(url: &str) -> String {
+    let prefix: String = url
+        .chars()
+        .take(64)
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{}-{:016x}.json", prefix, crate::fnv1a(url.as_bytes()))
+}
+
+/// Replaces the `@url` objects within `value`, which is at the dotted `path`, with their fetched
+/// value or default. The fetched leaves get the source of the `@url` object.
+fn replace_directives(
+    value: &mut Value,
+    path: String,
+    fetched: &HashMap<String, Result<Value>>,
+    provenance: &mut Provenance,
+) -> Result<()> {
+    let Value::Object(object) = value else {
+        return Ok(());
+    };
+    let Some((url, default)) = url_directive(object)? else {
+        for (key, value) in object.iter_mut() {
+            replace_directives(value, format!("{}.{}", path, key), fetched, provenance)?;
+        }
+        return Ok(());
+    };
+    let replacement = match (&fetched[url], default) {
+        (Ok(value), _) => value.clone(),
+        (Err(_), Some(default)) => default.clone(),
+        (Err(error), None) => {
+            return Err(anyhow::anyhow!("{:#}", error))
+                .with_context(|| format!("{:?} has no default", path))
+        }
+    };
+    let prefix = format!("{}.", path);
+    let source = provenance
+        .get(&format!("{}{}", prefix, URL_DIRECTIVE))
+        .cloned();
+    provenance.retain(|key, _| !key.starts_with(&prefix));
+    if let Some(source) = source {
+        record_value_provenance(path, &replacement, &source, provenance);
+    }
+    *value = replacement;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cache_file_name, fetch_remote_variables, CopyOutput, RemoteOptions};
+    use crate::{
+        cancellation::CancellationToken,
+        file_graph::traverse_directory,
+        file_system::TokioFileSystem,
+        generate_weaveconfig_with,
+        merging::ConflictPolicy,
+        merging::Provenance,
+        resolve_weaveconfig, resolve_weaveconfig_collecting,
+        space_graph::{create_space_graph, ToCopy},
+        test_utils::TempDir,
+        GenerateOptions,
+    };
+    use indexmap::IndexSet;
+    use serde_json::json;
+    use std::{path::PathBuf, sync::Arc, time::Duration};
+
+    #[tokio::test]
+    async fn test_merge_arrays_by_key_within_environment() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "prod": { "services": [{ "name": "api", "port": 80 }, { "name": "web" }] } }"#,
+        );
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{ "name": "app", "environments": ["prod"], "array_merge_keys": { "services": "name" } }"#,
+        );
+        dir.write(
+            "weaveconfig/app/_env.json",
+            r#"{ "prod": { "services": [{ "name": "web", "port": 8080 }, { "name": "worker" }] } }"#,
+        );
+
+        let resolved = resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            resolved["app"].variables.as_ref().unwrap()["prod"],
+            json!({
+                "services": [
+                    { "name": "web", "port": 8080 },
+                    { "name": "worker" },
+                    { "name": "api", "port": 80 }
+                ]
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_merge_arrays_without_key_conflicts() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "prod": { "services": [{ "name": "api" }] } }"#,
+        );
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{ "name": "app", "environments": ["prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/app/_env.json",
+            r#"{ "prod": { "services": [{ "name": "web" }] } }"#,
+        );
+
+        assert!(resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_parent_imports_child() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev"], "dependencies": [{ "name": "db", "as": "db" }] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "project": "shop", "dev": { "debug": true } }"#,
+        );
+        dir.write(
+            "weaveconfig/db/_space.json",
+            r#"{ "name": "db", "environments": ["dev"] }"#,
+        );
+        dir.write(
+            "weaveconfig/db/_env.json",
+            r#"{ "dev": { "host": "localhost" } }"#,
+        );
+
+        let resolved = resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            resolved["db"].variables,
+            Some(
+                json!({ "project": "shop", "dev": { "debug": true, "host": "localhost" } })
+                    .as_object()
+                    .unwrap()
+                    .clone()
+            )
+        );
+        assert_eq!(
+            resolved["root"].variables,
+            Some(
+                json!({ "project": "shop", "dev": { "debug": true, "db": { "project": "shop", "debug": true, "host": "localhost" } } })
+                    .as_object()
+                    .unwrap()
+                    .clone()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_overlay() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/base/_space.json",
+            r#"{ "name": "base", "environments": ["dev", "prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/base/_env.json",
+            r#"{ "db": { "host": "localhost", "port": 5432 }, "replicas": 1, "prod": { "debug": false } }"#,
+        );
+        dir.write("weaveconfig/base/app.conf", "base");
+        dir.write("weaveconfig/base/other.conf", "other");
+        dir.write(
+            "weaveconfig/production/_space.json",
+            r#"{ "name": "production", "environments": ["prod", "canary"] }"#,
+        );
+        dir.write(
+            "weaveconfig/production/_env.json",
+            r#"{ "db": { "host": "db.internal" }, "replicas": { "min": 3 }, "canary": { "debug": true } }"#,
+        );
+        dir.write("weaveconfig/production/app.conf", "production");
+
+        let root = dir.path().join("weaveconfig");
+        let resolved = resolve_weaveconfig(&root).await.unwrap();
+        let merged = resolved["base"].overlay(&resolved["production"]).unwrap();
+
+        assert_eq!(merged.name, "base");
+        assert_eq!(merged.path, resolved["base"].path);
+        assert_eq!(
+            merged.variables,
+            Some(
+                json!({
+                    "db": { "host": "db.internal", "port": 5432 },
+                    "replicas": { "min": 3 },
+                    "dev": {},
+                    "prod": { "debug": false },
+                    "canary": { "debug": true }
+                })
+                .as_object()
+                .unwrap()
+                .clone()
+            )
+        );
+        assert_eq!(
+            merged.environments.iter().collect::<Vec<_>>(),
+            vec!["dev", "prod", "canary"]
+        );
+        assert_eq!(
+            merged.root_mapping.get_space(&"canary".to_string()),
+            Some(&"canary".to_string())
+        );
+        let root = root.canonicalize().unwrap();
+        assert_eq!(
+            merged.provenance.get("db.host"),
+            Some(&root.join("production/_env.json"))
+        );
+        assert_eq!(
+            merged.provenance.get("db.port"),
+            Some(&root.join("base/_env.json"))
+        );
+        assert_eq!(merged.provenance.get("replicas"), None);
+
+        let mut files: Vec<_> = merged
+            .files_to_copy
+            .to_copy
+            .iter()
+            .map(|to_copy| match to_copy {
+                ToCopy::File(path) | ToCopy::Link(path) => path.clone(),
+                ToCopy::Directory { path, .. } => path.clone(),
+            })
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                root.join("base/other.conf"),
+                root.join("production/app.conf")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_global_variables() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "company": "acme", "log": { "level": "info", "format": "json" }, "port": 80 }"#,
+        );
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{ "name": "app", "environments": ["dev"] }"#,
+        );
+        dir.write(
+            "weaveconfig/app/_env.json",
+            r#"{ "log": { "level": "debug" }, "dev": { "port": 3000 } }"#,
+        );
+        dir.write(
+            "weaveconfig/app/api/_space.json",
+            r#"{ "name": "api", "environments": ["dev"] }"#,
+        );
+        dir.write("weaveconfig/app/api/_env.json", r#"{ "name": "api" }"#);
+
+        let root = dir.path().join("weaveconfig");
+        let resolved = resolve_weaveconfig(&root).await.unwrap();
+
+        assert_eq!(
+            resolved["app"].variables,
+            Some(
+                json!({ "company": "acme", "log": { "level": "debug", "format": "json" }, "dev": { "port": 3000 } })
+                    .as_object()
+                    .unwrap()
+                    .clone()
+            )
+        );
+        assert_eq!(
+            resolved["api"].variables,
+            Some(
+                json!({ "name": "api", "company": "acme", "log": { "level": "debug", "format": "json" }, "dev": { "port": 3000 } })
+                    .as_object()
+                    .unwrap()
+                    .clone()
+            )
+        );
+        assert_eq!(
+            resolved["api"].provenance.get("company"),
+            Some(&root.canonicalize().unwrap().join("_env.json"))
+        );
+        assert_eq!(
+            resolved["api"].provenance.get("log.level"),
+            Some(&root.canonicalize().unwrap().join("app/_env.json"))
+        );
+
+        let explanation = crate::explain_weaveconfig(&root, "api", "company")
+            .await
+            .unwrap();
+        assert_eq!(
+            explanation.steps[0].origin,
+            crate::Origin::Global(root.canonicalize().unwrap().join("_env.json"))
+        );
+
+        dir.write("weaveconfig/_schema.json", r#"{ "type": "object" }"#);
+        assert!(resolve_weaveconfig(&root).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_parent_and_child_import_each_other() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "dependencies": [{ "name": "db", "as": "db" }] }"#,
+        );
+        dir.write(
+            "weaveconfig/db/_space.json",
+            r#"{ "name": "db", "dependencies": [{ "name": "root", "as": "root" }] }"#,
+        );
+
+        let error = resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("Cyclic dependency detected"));
+    }
+
+    #[tokio::test]
+    async fn test_copy_outputs() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "dev": { "host": "dev.local" }, "prod": { "host": "example.com" } }"#,
+        );
+        dir.write("weaveconfig/_forenv.{{ host }}.txt", "{{ env }}");
+        dir.write("weaveconfig/config/shared.txt", "shared");
+
+        let root = dir.path().join("weaveconfig");
+        let resolved = resolve_weaveconfig(&root).await.unwrap();
+        let mut outputs = resolved["root"].copy_outputs().unwrap();
+        outputs.sort();
+
+        let forenv = root.join("_forenv.{{ host }}.txt");
+        assert_eq!(
+            outputs,
+            vec![
+                CopyOutput {
+                    source: forenv.clone(),
+                    destination: PathBuf::from("dev.dev.local.txt"),
+                    env: Some("dev".to_string()),
+                },
+                CopyOutput {
+                    source: forenv,
+                    destination: PathBuf::from("prod.example.com.txt"),
+                    env: Some("prod".to_string()),
+                },
+                CopyOutput {
+                    source: root.join("config/shared.txt"),
+                    destination: PathBuf::from("config/shared.txt"),
+                    env: None,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_provenance() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["prod"] }"#,
+        );
+        dir.write("weaveconfig/_env.json", r#"{ "name": "app" }"#);
+        dir.write(
+            "weaveconfig/_prod.env.json",
+            r#"{ "db": { "port": 5432 } }"#,
+        );
+        dir.write(
+            "weaveconfig/api/_space.json",
+            r#"{ "name": "api", "environments": ["production"], "space_to_parent_mapping": { "production": ["prod"] } }"#,
+        );
+        dir.write(
+            "weaveconfig/api/_env.json",
+            r#"{ "production": { "db": { "host": "db" } } }"#,
+        );
+
+        let resolved = resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        let root = dir.path().canonicalize().unwrap().join("weaveconfig");
+        let provenance = &resolved["api"].provenance;
+        assert_eq!(provenance.len(), 3);
+        assert_eq!(provenance["name"], root.join("_env.json"));
+        assert_eq!(
+            provenance["production.db.port"],
+            root.join("_prod.env.json")
+        );
+        assert_eq!(provenance["production.db.host"], root.join("api/_env.json"));
+    }
+
+    #[tokio::test]
+    async fn test_required_variables() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"], "required": ["db.host", "db.port"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "dev": { "db": { "host": "localhost" } }, "prod": { "db": { "host": "db" } } }"#,
+        );
+
+        let error = resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains(
+            "The space \"root\" is missing required variables: dev.db.port, prod.db.port"
+        ));
+
+        // The top-level variables satisfy the requirement for all environments
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "dev": { "db": { "host": "localhost" } }, "prod": { "db": { "host": "db" } }, "port": 1 }"#,
+        );
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"], "required": ["db.host", "port"] }"#,
+        );
+        assert!(resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_prune_environments() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"], "prune_environments": true }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "name": "app", "dev": { "port": 80 }, "prod": { "port": 80 } }"#,
+        );
+        dir.write(
+            "weaveconfig/child/_space.json",
+            r#"{ "name": "child", "environments": ["dev", "prod"], "prune_environments": true }"#,
+        );
+        dir.write(
+            "weaveconfig/child/_env.json",
+            r#"{ "prod": { "debug": false } }"#,
+        );
+
+        let resolved = resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        let root = &resolved["root"];
+        assert_eq!(root.environments, IndexSet::from(["dev".to_string()]));
+        assert_eq!(
+            root.variables,
+            Some(
+                json!({ "name": "app", "dev": { "port": 80 } })
+                    .as_object()
+                    .unwrap()
+                    .clone()
+            )
+        );
+        assert_eq!(
+            root.root_mapping.get_space(&"prod".to_string()),
+            Some(&"dev".to_string())
+        );
+        assert!(!root.provenance.contains_key("prod.port"));
+
+        // The environments of the child differ, it still inherits both from the root
+        let child = &resolved["child"];
+        assert_eq!(child.environments.len(), 2);
+        assert_eq!(child.variables.as_ref().unwrap()["prod"]["port"], json!(80));
+    }
+
+    #[tokio::test]
+    async fn test_aliased_dependencies() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/db/_space.json",
+            r#"{ "name": "db", "environments": ["prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/db/_env.json",
+            r#"{ "host": "db.local", "prod": { "port": 5432 } }"#,
+        );
+        dir.write(
+            "weaveconfig/cache/_space.json",
+            r#"{ "name": "cache", "environments": ["prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/cache/_env.json",
+            r#"{ "host": "cache.local", "prod": { "port": 6379 } }"#,
+        );
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{
+                "name": "app",
+                "environments": ["prod"],
+                "dependencies": [{ "name": "db", "as": "database" }, { "name": "cache", "as": "cache" }]
+            }"#,
+        );
+        dir.write("weaveconfig/app/_env.json", r#"{ "host": "app.local" }"#);
+
+        let resolved = resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+        let app = &resolved["app"];
+        assert_eq!(
+            app.variables,
+            Some(
+                json!({
+                    "host": "app.local",
+                    "prod": {
+                        "database": { "host": "db.local", "port": 5432 },
+                        "cache": { "host": "cache.local", "port": 6379 }
+                    }
+                })
+                .as_object()
+                .unwrap()
+                .clone()
+            )
+        );
+        assert_eq!(
+            app.provenance["prod.database.port"],
+            dir.path().join("weaveconfig/db/_env.json")
+        );
+        assert_eq!(
+            app.provenance["prod.cache.host"],
+            dir.path().join("weaveconfig/cache/_env.json")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dependency_environment_mapping() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "staging", "prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/db/_space.json",
+            r#"{ "name": "db", "environments": ["dev", "staging", "prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/db/_env.json",
+            r#"{ "dev": { "host": "db.dev" }, "staging": { "host": "db.staging" }, "prod": { "host": "db.prod" } }"#,
+        );
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{
+                "name": "app",
+                "environments": ["dev", "staging", "prod"],
+                "dependencies": [{ "name": "db", "space_to_dependency_mapping": { "staging": ["prod"] } }]
+            }"#,
+        );
+        let root = dir.path().join("weaveconfig");
+
+        let resolved = resolve_weaveconfig(&root).await.unwrap();
+        let variables = resolved["app"].variables.as_ref().unwrap();
+        assert_eq!(variables["dev"]["host"], json!("db.dev"));
+        assert_eq!(variables["staging"]["host"], json!("db.prod"));
+        assert_eq!(variables["prod"]["host"], json!("db.prod"));
+        let explanation = crate::explain_weaveconfig(&root, "app", "staging.host")
+            .await
+            .unwrap();
+        assert_eq!(explanation.steps[1].path, "prod.host");
+
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{ "name": "app", "environments": ["dev"], "dependencies": [{ "name": "db", "space_to_dependency_mapping": { "qa": ["prod"] } }] }"#,
+        );
+        let error = resolve_weaveconfig(&root).await.unwrap_err();
+        assert!(
+            format!("{:#}", error).contains(
+                "maps the environments [\"qa\"], which are not environments of the space"
+            ),
+            "{:#}",
+            error
+        );
+
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{ "name": "app", "environments": ["dev"], "dependencies": [{ "name": "db", "space_to_dependency_mapping": { "dev": ["qa"] } }] }"#,
+        );
+        let error = resolve_weaveconfig(&root).await.unwrap_err();
+        assert!(
+            format!("{:#}", error).contains(
+                "imports the environments [\"qa\"], which are not environments of the dependency"
+            ),
+            "{:#}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dependency_environments() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/monitoring/_space.json",
+            r#"{ "name": "monitoring", "environments": ["dev", "prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/monitoring/_env.json",
+            r#"{ "endpoint": "https://metrics", "dev": { "sample": 1 }, "prod": { "sample": 10 } }"#,
+        );
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{
+                "name": "app",
+                "environments": ["dev", "prod"],
+                "dependencies": [{ "name": "monitoring", "environments": ["prod"] }]
+            }"#,
+        );
+        dir.write(
+            "weaveconfig/app/_env.json",
+            r#"{ "dev": { "debug": true } }"#,
+        );
+        let root = dir.path().join("weaveconfig");
+
+        let resolved = resolve_weaveconfig(&root).await.unwrap();
+        let app = &resolved["app"];
+        assert_eq!(
+            app.variables.as_ref().unwrap(),
+            json!({
+                "dev": { "debug": true },
+                "prod": { "endpoint": "https://metrics", "sample": 10 }
+            })
+            .as_object()
+            .unwrap()
+        );
+        assert!(app.provenance.contains_key("prod.endpoint"));
+        assert!(!app.provenance.contains_key("endpoint"));
+        assert!(!app.provenance.contains_key("dev.sample"));
+        let explanation = crate::explain_weaveconfig(&root, "app", "prod.endpoint")
+            .await
+            .unwrap();
+        assert_eq!(explanation.steps[1].path, "endpoint");
+
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{ "name": "app", "environments": ["dev", "prod"], "dependencies": [{ "name": "monitoring", "as": "metrics", "environments": ["prod"] }] }"#,
+        );
+        let resolved = resolve_weaveconfig(&root).await.unwrap();
+        assert_eq!(
+            resolved["app"].variables.as_ref().unwrap(),
+            json!({
+                "dev": { "debug": true },
+                "prod": { "metrics": { "endpoint": "https://metrics", "sample": 10 } }
+            })
+            .as_object()
+            .unwrap()
+        );
+
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{ "name": "app", "environments": ["dev"], "dependencies": [{ "name": "monitoring", "environments": ["prod"] }] }"#,
+        );
+        let error = resolve_weaveconfig(&root).await.unwrap_err();
+        assert!(
+            format!("{:#}", error).contains(
+                "is imported into the environments [\"prod\"], which are not environments of the space"
+            ),
+            "{:#}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn test_collect_merge_conflicts() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "name": "root", "prod": { "port": 80 } }"#,
+        );
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{ "name": "app", "environments": ["dev", "prod"], "dependencies": ["db"] }"#,
+        );
+        dir.write(
+            "weaveconfig/app/_env.json",
+            r#"{ "name": "app", "prod": { "port": 81 }, "timeout": 5 }"#,
+        );
+        dir.write(
+            "weaveconfig/db/_space.json",
+            r#"{ "name": "db", "environments": ["dev", "prod"] }"#,
+        );
+        dir.write("weaveconfig/db/_env.json", r#"{ "timeout": 10 }"#);
+        let weaveconfig_root = dir.path().join("weaveconfig");
+
+        assert!(resolve_weaveconfig(&weaveconfig_root).await.is_err());
+
+        let (resolved, conflicts) =
+            resolve_weaveconfig_collecting(&weaveconfig_root, ConflictPolicy::KeepLast)
+                .await
+                .unwrap();
+        let conflicts: Vec<_> = conflicts
+            .iter()
+            .map(|conflict| {
+                (
+                    conflict.space.as_str(),
+                    conflict.conflict.path.as_str(),
+                    conflict.conflict.existing.clone(),
+                    conflict.conflict.incoming.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            conflicts,
+            vec![
+                ("app", "name", json!("app"), json!("root")),
+                ("app", "prod.port", json!(81), json!(80)),
+                ("app", "timeout", json!(5), json!(10)),
+            ]
+        );
+        let app = resolved["app"].variables.as_ref().unwrap();
+        assert_eq!(app["name"], json!("root"));
+        assert_eq!(app["timeout"], json!(10));
+    }
+
+    /// Serves `body` to every request on a local port, returns the base URL.
+    async fn serve_json(body: &'static str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        format!("http://{}", address)
+    }
+
+    #[tokio::test]
+    async fn test_remote_variables() {
+        let url = serve_json(r#"{ "region": "eu", "replicas": 3 }"#).await;
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            &format!(
+                r#"{{ "port": 3000, "shared": {{ "@url": "{}/base.json" }}, "dev": {{ "fallback": {{ "@url": "http://127.0.0.1:1/missing.json", "default": {{ "region": "local" }} }} }} }}"#,
+                url
+            ),
+        );
+        let root = dir.path().join("weaveconfig");
+
+        let error = resolve_weaveconfig(&root).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("remote variables are not enabled"));
+
+        let cache = TempDir::new();
+        let options = RemoteOptions {
+            timeout: Duration::from_secs(5),
+            cache_dir: Some(cache.path().to_path_buf()),
+        };
+        let options = GenerateOptions {
+            remote: Some(options),
+            ..Default::default()
+        };
+        generate_weaveconfig_with(&root, &options).await.unwrap();
+
+        let config: serde_json::Value = serde_json::from_str(&dir.read("gen/config.json")).unwrap();
+        assert_eq!(
+            config,
+            json!({
+                "port": 3000,
+                "shared": { "region": "eu", "replicas": 3 },
+                "dev": { "fallback": { "region": "local" } },
+            })
+        );
+        assert!(cache.exists(&cache_file_name(&format!("{}/base.json", url))));
+    }
+
+    #[test]
+    fn test_cache_file_names_are_distinct() {
+        let urls = [
+            "https://h/a?b=1",
+            "https://h/a/b=1",
+            "https://h/a_b=1",
+            "https://h/ä",
+            "https://h/ö",
+            &format!("https://h/{}/a", "x".repeat(100)),
+            &format!("https://h/{}/b", "x".repeat(100)),
+        ];
+        let names: std::collections::HashSet<_> =
+            urls.iter().map(|url| cache_file_name(url)).collect();
+        assert_eq!(names.len(), urls.len());
+        assert_eq!(cache_file_name(urls[0]), cache_file_name(urls[0]));
+        assert!(cache_file_name(urls[0]).starts_with("https___h_a_b_1-"));
+    }
+
+    #[tokio::test]
+    async fn test_remote_variable_provenance() {
+        let url = serve_json(r#"{ "region": "eu" }"#).await;
+        let dir = TempDir::new();
+        dir.write("weaveconfig/_space.json", r#"{ "name": "root" }"#);
+        dir.write(
+            "weaveconfig/_env.json",
+            &format!(r#"{{ "shared": {{ "@url": "{}" }} }}"#, url),
+        );
+        let root = dir.path().join("weaveconfig");
+        let directory = traverse_directory(
+            &root,
+            &CancellationToken::new(),
+            None,
+            Arc::new(TokioFileSystem),
+        )
+        .await
+        .unwrap();
+        let mut graph = create_space_graph(directory).unwrap();
+
+        fetch_remote_variables(
+            &mut graph,
+            Some(&RemoteOptions::default()),
+            &TokioFileSystem,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            graph["root"].provenance,
+            Provenance::from([("shared.region".to_string(), root.join("_env.json"))])
+        );
+    }
 }
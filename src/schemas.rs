@@ -1,3 +1,4 @@
+use indexmap::IndexSet;
 use serde::Deserialize;
 use std::collections::{HashMap, HashSet};
 
@@ -11,20 +12,181 @@ pub struct SpaceInfo {
     /// It must be unique within the graph.
     pub name: String,
     /// A list of dependencies that this space imports.
-    /// Each element must be a name of another space.
+    /// Each element must be a name of another space, or a glob like `plugin-*` matching the names of other spaces.
+    /// A dependency can be imported under an alias, see [`DependencyObjectSchema`].
     /// If not present, the space will not import any dependencies.
-    pub dependencies: Option<Vec<String>>,
+    pub dependencies: Option<Vec<DependencySchema>>,
     /// A mapping from the environments in this space to the environments in the parent space.
     pub space_to_parent_mapping: Option<HashMap<String, HashSet<String>>>,
     /// A list of environments that this space supports.
     /// An environment describes a particular configuration of the space
     /// for example, prod, dev, staging, etc.
     /// If not present, the space will have a single unnamed environment with just the global variables.
-    pub environments: Option<HashSet<String>>,
+    /// An environment can be marked as abstract, see [`EnvironmentObjectSchema`].
+    pub environments: Option<IndexSet<EnvironmentSchema>>,
     /// weaveconfig can generate a /gen folder in the folder this space maps to.
     /// This folder contains the config.json itself, as well as the typescript bindings to that config.
     /// This is enabled by default, and can be disabled by setting this to false.
     pub generate: Option<GenerateSchema>,
+    /// When set, `_forenv` files are additionally copied once using only the top-level variables,
+    /// with `_forenv` replaced by this name instead of an environment name.
+    pub forenv_default: Option<String>,
+    /// Arrays that are merged element-wise when inheriting from the parent or importing dependencies,
+    /// instead of having to be equal.
+    /// Maps the dotted path of an array, relative to the top level or an environment, to the field
+    /// identifying its elements. Object elements with the same value for that field are merged,
+    /// scalar elements are merged as a set.
+    pub array_merge_keys: Option<HashMap<String, String>>,
+    /// Pins the JSON schema draft the `_schema.json` of this space is validated with.
+    /// If not present, the draft is detected from the `$schema` keyword of the schema.
+    /// If present, a schema declaring a different draft in `$schema` is an error.
+    pub schema_draft: Option<SchemaDraft>,
+    /// Whether the variables are validated against the `_schema.json` of this space, true by default.
+    /// Setting it to false skips validation while keeping the schema file in place.
+    #[serde(default = "default_validate")]
+    pub validate: bool,
+    /// Dotted paths of variables that must be present after resolution, for example `db.host`.
+    /// If the space has environments, every environment must define them,
+    /// either directly or through the top-level variables.
+    pub required: Option<Vec<String>>,
+    /// Enables stricter checks for this space, for example dependency globs must match at least one space
+    /// and every environment of the parent must be mapped to an environment of this space.
+    #[serde(default)]
+    pub strict: bool,
+    /// Collapse the environments into the first one if they all resolve to the same values.
+    #[serde(default)]
+    pub prune_environments: bool,
+    /// Do not inherit from the closest ancestor space, the space is resolved as if it were a root space.
+    /// Spaces nested in it still inherit from it.
+    #[serde(default)]
+    pub ignore_parent: bool,
+    /// Variables of the space, defined inline instead of in a separate `_env.json`.
+    /// They are merged with the variables of the other files in the directory.
+    pub variables: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Which value is kept if the inline `variables` and a variable file of the directory define
+    /// different values at the same path. In a strict space, this is an error instead.
+    #[serde(default)]
+    pub inline_precedence: InlinePrecedence,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InlinePrecedence {
+    /// The variable files win, the inline variables act as defaults.
+    #[default]
+    Files,
+    /// The inline variables win.
+    Inline,
+}
+
+fn default_validate() -> bool {
+    true
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchemaDraft {
+    #[serde(rename = "draft-04")]
+    Draft4,
+    #[serde(rename = "draft-06")]
+    Draft6,
+    #[serde(rename = "draft-07")]
+    Draft7,
+    #[serde(rename = "2019-09")]
+    Draft201909,
+    #[serde(rename = "2020-12")]
+    Draft202012,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum EnvironmentSchema {
+    /// A regular environment, given by its name.
+    Name(String),
+    /// An environment with options.
+    Environment(EnvironmentObjectSchema),
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EnvironmentObjectSchema {
+    pub name: String,
+    /// An abstract environment is only used for inheritance.
+    /// Its variables are merged into every other environment of the space,
+    /// but it is not emitted itself, neither in the generated files nor in the bindings.
+    #[serde(rename = "abstract", default)]
+    pub is_abstract: bool,
+}
+
+impl EnvironmentSchema {
+    pub fn name(&self) -> &str {
+        match self {
+            EnvironmentSchema::Name(name) => name,
+            EnvironmentSchema::Environment(environment) => &environment.name,
+        }
+    }
+
+    pub fn is_abstract(&self) -> bool {
+        match self {
+            EnvironmentSchema::Name(_) => false,
+            EnvironmentSchema::Environment(environment) => environment.is_abstract,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum DependencySchema {
+    /// A dependency given by its name or a glob.
+    Name(String),
+    /// A dependency with options.
+    Dependency(DependencyObjectSchema),
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct DependencyObjectSchema {
+    pub name: String,
+    /// Nests the variables of the dependency under this key instead of merging them into the top level.
+    /// Environment specific variables are nested within their environment.
+    #[serde(rename = "as")]
+    pub alias: Option<String>,
+    /// A mapping from the environments in this space to the environments of the dependency they import.
+    /// The mapped environments of this space import only these environments, instead of the
+    /// environments the root mappings of both spaces connect them to.
+    pub space_to_dependency_mapping: Option<HashMap<String, HashSet<String>>>,
+    /// The environments of this space the dependency is imported into, all if not present.
+    /// The top-level variables of the dependency are nested within each of these environments.
+    pub environments: Option<HashSet<String>>,
+}
+
+impl DependencySchema {
+    pub fn name(&self) -> &str {
+        match self {
+            DependencySchema::Name(name) => name,
+            DependencySchema::Dependency(dependency) => &dependency.name,
+        }
+    }
+
+    pub fn alias(&self) -> Option<&str> {
+        match self {
+            DependencySchema::Name(_) => None,
+            DependencySchema::Dependency(dependency) => dependency.alias.as_deref(),
+        }
+    }
+
+    pub fn space_to_dependency_mapping(&self) -> Option<&HashMap<String, HashSet<String>>> {
+        match self {
+            DependencySchema::Name(_) => None,
+            DependencySchema::Dependency(dependency) => {
+                dependency.space_to_dependency_mapping.as_ref()
+            }
+        }
+    }
+
+    pub fn environments(&self) -> Option<&HashSet<String>> {
+        match self {
+            DependencySchema::Name(_) => None,
+            DependencySchema::Dependency(dependency) => dependency.environments.as_ref(),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Clone, PartialEq)]
@@ -36,8 +198,143 @@ pub enum GenerateSchema {
     Generate(GenerateObjectSchema),
 }
 
+/// The options that are not set fall back to the `.weaveconfigrc`, see [`WeaveconfigRc`].
 #[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct GenerateObjectSchema {
-    /// Toggle the typescript bindings on or off.
-    pub typescript: bool,
+    /// Toggle the typescript bindings on or off, the `.weaveconfigrc` default or on if not present.
+    pub typescript: Option<bool>,
+    /// Emit the typescript bindings as a `binding.d.ts` containing only the types, without the code
+    /// reading the config.
+    pub declaration_only: Option<bool>,
+    /// Remove empty objects, empty arrays and nulls from the generated config.json.
+    /// Environments are always kept, even if they are empty.
+    pub prune_empty: Option<bool>,
+    /// Write integer valued numbers without a fraction, like `3` instead of `3.0` or `1e15`.
+    /// Other numbers are always written in their shortest representation that parses back to the same value.
+    pub canonical_numbers: Option<bool>,
+    /// The format of the generated config file, strict JSON by default.
+    pub config_format: Option<ConfigFormat>,
+    /// How the environments are laid out in the generated config.
+    pub config_layout: Option<ConfigLayout>,
+    /// The line endings of the generated and copied files.
+    pub line_endings: Option<LineEndings>,
+    /// Whether files end with exactly one newline, or with none.
+    /// Generated files end with one if not present, copied files are kept as they are.
+    pub final_newline: Option<bool>,
+    /// The environment variable the typescript binding reads to select the environment, `ENV` if not present.
+    pub env_variable: Option<String>,
+}
+
+/// The `.weaveconfigrc` file in the weaveconfig root.
+/// It sets defaults for the generate options of all spaces, the options of a space take precedence,
+/// as well as the naming conventions checked by [`crate::check_weaveconfig_names`].
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WeaveconfigRc {
+    /// Whether the gen folder is generated, true if not present.
+    pub generate: Option<bool>,
+    /// Whether the typescript bindings are generated, true if not present.
+    pub typescript: Option<bool>,
+    pub declaration_only: Option<bool>,
+    pub prune_empty: Option<bool>,
+    pub canonical_numbers: Option<bool>,
+    pub config_format: Option<ConfigFormat>,
+    pub config_layout: Option<ConfigLayout>,
+    pub line_endings: Option<LineEndings>,
+    pub final_newline: Option<bool>,
+    pub env_variable: Option<String>,
+    /// Whether an `all-config.json` mapping each space name to its variables is written
+    /// next to the weaveconfig directory, false if not present.
+    pub all_config: Option<bool>,
+    /// The token in file and directory names that is replaced by the environment name,
+    /// `_forenv` if not present. A custom token is matched anywhere in a name, like `config.@env@.yaml`.
+    pub forenv_token: Option<String>,
+    /// What happens to `_` prefixed files that are not known weaveconfig files, an error if not present.
+    pub unknown_files: Option<UnknownFilePolicy>,
+    /// A regex every space name must match, checked by `weaveconfig lint --check-names`.
+    pub space_name_pattern: Option<String>,
+    /// A regex every environment name must match, checked by `weaveconfig lint --check-names`.
+    pub environment_name_pattern: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownFilePolicy {
+    /// Fail, as the file is likely a misspelled weaveconfig file like `_evn.json`.
+    #[default]
+    Error,
+    /// Copy the file like other files, with a warning.
+    Warn,
+    /// Copy the file like other files.
+    Copy,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigLayout {
+    /// Each environment is an object next to the top-level variables.
+    #[default]
+    Nested,
+    /// The top-level variables stay at the top level, the environment objects are moved into
+    /// a single `environments` object.
+    Environments,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEndings {
+    /// Keep the line endings of the templates, generated files use `\n`.
+    #[default]
+    Preserve,
+    /// Convert all line endings to `\n`.
+    Lf,
+    /// Convert all line endings to `\r\n`.
+    Crlf,
+}
+
+impl LineEndings {
+    /// Converts the line endings of the content.
+    pub fn apply(self, content: String) -> String {
+        match self {
+            LineEndings::Preserve => content,
+            LineEndings::Lf => content.replace("\r\n", "\n"),
+            LineEndings::Crlf => content.replace("\r\n", "\n").replace('\n', "\r\n"),
+        }
+    }
+}
+
+/// Ends the content with exactly one newline if `final_newline` is true, or with none otherwise.
+/// Empty content stays empty.
+pub fn apply_final_newline(content: String, final_newline: bool) -> String {
+    let trimmed = content.trim_end_matches(['\n', '\r']);
+    if final_newline && !trimmed.is_empty() {
+        format!("{}\n", trimmed)
+    } else {
+        trimmed.to_string()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigFormat {
+    /// Strict JSON, written to `config.json`.
+    #[default]
+    Json,
+    /// JSON5 with unquoted keys where possible and trailing commas, written to `config.json5`.
+    /// The typescript bindings read `config.json`, so they can not be combined with this format.
+    Json5,
+    /// YAML in block style with sorted keys, written to `config.yaml`.
+    /// Like JSON5, this can not be combined with the typescript bindings.
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// The name of the config file written in this format.
+    pub fn file_name(self) -> &'static str {
+        match self {
+            ConfigFormat::Json => "config.json",
+            ConfigFormat::Json5 => "config.json5",
+            ConfigFormat::Yaml => "config.yaml",
+        }
+    }
 }
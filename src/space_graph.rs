@@ -1,11 +1,19 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Context;
+use indexmap::IndexSet;
+use regex::Regex;
 
 use crate::{
-    ancestor_mapping::{AncestorMapping, RootMappingError},
-    file_graph::Directory,
-    schemas::GenerateSchema,
+    ancestor_mapping::AncestorMapping,
+    apply_resolved::DEFAULT_FORENV_TOKEN,
+    file_graph::{Directory, RC_FILE_NAME},
+    merging::Provenance,
+    schemas::{ConfigFormat, ConfigLayout, GenerateSchema, LineEndings, WeaveconfigRc},
 };
 use std::collections::HashSet;
 
@@ -14,14 +22,37 @@ pub struct Space {
     pub name: String,
     pub path: PathBuf,
     pub dependencies: Vec<String>,
+    /// The keys the variables of aliased dependencies are nested under, by dependency name.
+    pub dependency_aliases: HashMap<String, String>,
+    /// The environment mappings replacing the root mapping when importing a dependency, by dependency name.
+    /// The ancestor environments are the environments of the dependency.
+    pub dependency_mappings: HashMap<String, AncestorMapping>,
+    /// The environments dependencies are only imported into, by dependency name.
+    /// Dependencies without an entry are imported into every environment.
+    pub dependency_environments: HashMap<String, HashSet<String>>,
     // spaces are resolved individually, so these map to their parent, not the root.
     // the root mapping is resolved later based on the parent mapping.
     pub parent_mapping: AncestorMapping,
-    pub environments: HashSet<String>,
+    pub environments: IndexSet<String>,
+    /// The environments that are only used for inheritance, a subset of `environments`.
+    pub abstract_environments: HashSet<String>,
     pub variables: Option<serde_json::Map<String, serde_json::Value>>,
+    /// The file each variable of this space was defined in, see [`Provenance`].
+    pub provenance: Provenance,
     pub files_to_copy: CopyTree,
     pub parent_space: Option<String>,
     pub generate: GenerateSpace,
+    pub forenv_default: Option<String>,
+    /// The token replaced by the environment name in the names of copied files, from the `.weaveconfigrc`.
+    pub forenv_token: String,
+    /// Maps the dotted path of an array to the field its elements are merged by.
+    pub array_merge_keys: HashMap<String, String>,
+    /// Dotted paths of variables that must be present after resolution.
+    pub required: Vec<String>,
+    /// Whether stricter checks are enabled for this space.
+    pub strict: bool,
+    /// Whether identical environments are collapsed after resolution.
+    pub prune_environments: bool,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -32,13 +63,18 @@ pub struct CopyTree {
 #[derive(Debug, Clone, PartialEq)]
 pub enum ToCopy {
     File(PathBuf),
-    Directory { path: PathBuf, subtree: CopyTree },
+    /// A file below a `_weavelink` marker, symlinked instead of copied. It is never read or templated.
+    Link(PathBuf),
+    Directory {
+        path: PathBuf,
+        subtree: CopyTree,
+    },
 }
 
 impl ToCopy {
     pub fn last_segment(&self) -> Result<&str, anyhow::Error> {
         let path = match self {
-            ToCopy::File(path) => path,
+            ToCopy::File(path) | ToCopy::Link(path) => path,
             ToCopy::Directory { path, .. } => path,
         };
         let file_name = path.file_name().context("File has no name")?;
@@ -53,24 +89,268 @@ impl ToCopy {
 pub struct GenerateSpace {
     pub generate: bool,
     pub typescript: bool,
+    pub declaration_only: bool,
+    pub prune_empty: bool,
+    pub canonical_numbers: bool,
+    pub config_format: ConfigFormat,
+    pub config_layout: ConfigLayout,
+    pub line_endings: LineEndings,
+    /// Whether files end with a newline, `None` ends generated files with one and keeps copied files.
+    pub final_newline: Option<bool>,
+    /// The environment variable the typescript binding reads to select the environment.
+    pub env_variable: String,
 }
 
 pub type SpaceGraph = HashMap<String, Space>;
 
+/// Displays a space graph as an indented tree.
+/// Each line contains a space name, followed by its environments and dependencies.
+/// Children are indented below their parent space, siblings are sorted by name.
+pub struct SpaceTree<'a>(pub &'a SpaceGraph);
+
+impl fmt::Display for SpaceTree<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut roots: Vec<&Space> = self
+            .0
+            .values()
+            .filter(|space| space.parent_space.is_none())
+            .collect();
+        roots.sort_by(|a, b| a.name.cmp(&b.name));
+        for root in roots {
+            write_space_tree(f, self.0, root, 0)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_space_tree(
+    f: &mut fmt::Formatter<'_>,
+    graph: &SpaceGraph,
+    space: &Space,
+    depth: usize,
+) -> fmt::Result {
+    write!(f, "{}{}", "  ".repeat(depth), space.name)?;
+    if !space.environments.is_empty() {
+        let mut environments: Vec<&String> = space.environments.iter().collect();
+        environments.sort();
+        write!(
+            f,
+            " [{}]",
+            environments
+                .iter()
+                .map(|env| env.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )?;
+    }
+    if !space.dependencies.is_empty() {
+        let mut dependencies = space.dependencies.clone();
+        dependencies.sort();
+        write!(f, " depends on: {}", dependencies.join(", "))?;
+    }
+    writeln!(f)?;
+
+    let mut children: Vec<&Space> = graph
+        .values()
+        .filter(|child| child.parent_space.as_deref() == Some(space.name.as_str()))
+        .collect();
+    children.sort_by(|a, b| a.name.cmp(&b.name));
+    for child in children {
+        write_space_tree(f, graph, child, depth + 1)?;
+    }
+    Ok(())
+}
+
+/// Renders the space graph as an indented tree, see [`SpaceTree`].
+pub fn render_tree(graph: &SpaceGraph) -> String {
+    SpaceTree(graph).to_string()
+}
+
 pub fn create_space_graph(root_directory: Directory) -> Result<SpaceGraph, anyhow::Error> {
     let mut space_graph = HashMap::new();
 
-    add_to_spaces_graph(root_directory, &mut space_graph, None)
+    let defaults = root_directory.defaults.clone().unwrap_or_default();
+    add_to_spaces_graph(root_directory, &mut space_graph, None, &defaults)
         .with_context(|| "Failed to add to spaces graph")?;
+    expand_dependency_globs(&mut space_graph)?;
+    check_parent_mappings(&space_graph)?;
 
     Ok(space_graph)
 }
 
+/// Ensures strict spaces with environments map every concrete environment of their parent,
+/// as the variables of unmapped environments would silently be dropped.
+fn check_parent_mappings(space_graph: &SpaceGraph) -> Result<(), anyhow::Error> {
+    for space in space_graph.values() {
+        if !space.strict || space.environments.is_empty() {
+            continue;
+        }
+        let Some(parent) = space
+            .parent_space
+            .as_ref()
+            .and_then(|name| space_graph.get(name))
+        else {
+            continue;
+        };
+        let parent_environments = parent
+            .environments
+            .iter()
+            .filter(|env| !parent.abstract_environments.contains(*env));
+        space
+            .parent_mapping
+            .validate(parent_environments)
+            .with_context(|| {
+                format!(
+                    "The space {:?} does not map all environments of its parent {:?}",
+                    space.name, parent.name
+                )
+            })?;
+    }
+    Ok(())
+}
+
+/// Replaces dependencies containing `*` or `?` with the names of all other spaces they match, sorted by name.
+/// A glob matching nothing is dropped, or an error for strict spaces.
+fn expand_dependency_globs(space_graph: &mut SpaceGraph) -> Result<(), anyhow::Error> {
+    let mut names: Vec<String> = space_graph.keys().cloned().collect();
+    names.sort();
+
+    for space in space_graph.values_mut() {
+        if !space
+            .dependencies
+            .iter()
+            .any(|dependency| is_glob(dependency))
+        {
+            continue;
+        }
+        let mut dependencies = Vec::new();
+        for dependency in std::mem::take(&mut space.dependencies) {
+            if !is_glob(&dependency) {
+                if !dependencies.contains(&dependency) {
+                    dependencies.push(dependency);
+                }
+                continue;
+            }
+            let matches: Vec<&String> = names
+                .iter()
+                .filter(|name| **name != space.name && glob_matches(&dependency, name))
+                .collect();
+            if matches.is_empty() && space.strict {
+                return Err(anyhow::anyhow!(
+                    "The dependency glob {:?} of the space {:?} does not match any space",
+                    dependency,
+                    space.name
+                ));
+            }
+            for name in matches {
+                if !dependencies.contains(name) {
+                    dependencies.push(name.clone());
+                }
+            }
+        }
+        space.dependencies = dependencies;
+    }
+
+    Ok(())
+}
+
+fn is_glob(dependency: &str) -> bool {
+    dependency.contains(['*', '?'])
+}
+
+/// Matches a name against a glob, `*` matches any number of characters and `?` exactly one.
+fn glob_matches(glob: &str, name: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // The position after the last `*` and the name position it was tried with
+    let mut star: Option<(usize, usize)> = None;
+    let (mut g, mut n) = (0, 0);
+    while n < name.len() {
+        match glob.get(g) {
+            Some('*') => {
+                star = Some((g + 1, n));
+                g += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                g += 1;
+                n += 1;
+            }
+            _ => match star {
+                // Let the last `*` consume one more character
+                Some((star_g, star_n)) => {
+                    star = Some((star_g, star_n + 1));
+                    g = star_g;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|c| *c == '*')
+}
+
+/// The spaces affected by changes to the given files: the spaces containing the files,
+/// and transitively every space inheriting from or depending on an affected space.
+/// Files are relative to the weaveconfig root, files outside of every space are ignored,
+/// except for the global variable files and the `.weaveconfigrc` of the root which affect every space.
+pub fn affected_spaces(
+    space_graph: &SpaceGraph,
+    weaveconfig_root: &Path,
+    changed_files: &[PathBuf],
+) -> HashSet<String> {
+    let mut affected = HashSet::new();
+    for file in changed_files {
+        let file = weaveconfig_root.join(file);
+        if file == weaveconfig_root.join(RC_FILE_NAME) {
+            return space_graph.keys().cloned().collect();
+        }
+        let owner = space_graph
+            .values()
+            .filter(|space| file.starts_with(&space.path))
+            .max_by_key(|space| space.path.components().count());
+        if let Some(owner) = owner {
+            affected.insert(owner.name.clone());
+        } else if is_global_variables_file(&file, weaveconfig_root) {
+            return space_graph.keys().cloned().collect();
+        }
+    }
+
+    // Add the dependents until nothing changes
+    loop {
+        let dependents: Vec<String> = space_graph
+            .values()
+            .filter(|space| !affected.contains(&space.name))
+            .filter(|space| {
+                space
+                    .parent_space
+                    .iter()
+                    .chain(&space.dependencies)
+                    .any(|name| affected.contains(name))
+            })
+            .map(|space| space.name.clone())
+            .collect();
+        if dependents.is_empty() {
+            return affected;
+        }
+        affected.extend(dependents);
+    }
+}
+
+/// Whether the file is a variable file directly in the weaveconfig root, see [`crate::resolve_spaces`].
+fn is_global_variables_file(file: &Path, weaveconfig_root: &Path) -> bool {
+    file.parent() == Some(weaveconfig_root)
+        && file
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with('_') && name.contains("_env"))
+}
+
 fn add_to_spaces_graph(
     mut dir: Directory,
     space_graph: &mut SpaceGraph,
     closest_parent_space: Option<String>,
-) -> Result<(), RootMappingError> {
+    defaults: &WeaveconfigRc,
+) -> Result<(), anyhow::Error> {
     let space_name = dir
         .space
         .as_ref()
@@ -81,67 +361,824 @@ fn add_to_spaces_graph(
             Some(m) => AncestorMapping::from_space_to_ancestors(m)?,
             None => AncestorMapping::new(),
         };
-        let environments = space.info.environments.unwrap_or_default();
-        for environment in &environments {
-            if !mapping.contains_space(environment) {
-                mapping
-                    .add_mapping(environment.clone(), environment.clone())
-                    .expect(&format!(
-                        "Failed to add mapping for environment: {}",
-                        environment
+        let dependencies = space.info.dependencies.unwrap_or_default();
+        let mut dependency_aliases = HashMap::new();
+        for dependency in &dependencies {
+            if let Some(alias) = dependency.alias() {
+                if is_glob(dependency.name()) {
+                    return Err(anyhow::anyhow!(
+                        "The dependency glob {:?} of the space {:?} can not have an alias",
+                        dependency.name(),
+                        space.info.name
                     ));
+                }
+                dependency_aliases.insert(dependency.name().to_string(), alias.to_string());
+            }
+        }
+        let schema_environments = space.info.environments.unwrap_or_default();
+        let environments: IndexSet<String> = schema_environments
+            .iter()
+            .map(|environment| environment.name().to_string())
+            .collect();
+        let mut dependency_mappings = HashMap::new();
+        for dependency in &dependencies {
+            let Some(dependency_mapping) = dependency.space_to_dependency_mapping() else {
+                continue;
+            };
+            if is_glob(dependency.name()) {
+                return Err(anyhow::anyhow!(
+                    "The dependency glob {:?} of the space {:?} can not have an environment mapping",
+                    dependency.name(),
+                    space.info.name
+                ));
+            }
+            let mut unknown: Vec<&String> = dependency_mapping
+                .keys()
+                .filter(|environment| !environments.contains(*environment))
+                .collect();
+            if !unknown.is_empty() {
+                unknown.sort();
+                return Err(anyhow::anyhow!(
+                    "The environment mapping of the dependency {:?} of the space {:?} maps the environments {:?}, which are not environments of the space",
+                    dependency.name(),
+                    space.info.name,
+                    unknown
+                ));
+            }
+            let mapping = AncestorMapping::from_space_to_ancestors(dependency_mapping.clone())
+                .with_context(|| {
+                    format!(
+                        "Invalid environment mapping of the dependency {:?} of the space {:?}",
+                        dependency.name(),
+                        space.info.name
+                    )
+                })?;
+            dependency_mappings.insert(dependency.name().to_string(), mapping);
+        }
+        let mut dependency_environments = HashMap::new();
+        for dependency in &dependencies {
+            let Some(imported_into) = dependency.environments() else {
+                continue;
+            };
+            if is_glob(dependency.name()) {
+                return Err(anyhow::anyhow!(
+                    "The dependency glob {:?} of the space {:?} can not be restricted to environments",
+                    dependency.name(),
+                    space.info.name
+                ));
+            }
+            if imported_into.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "The dependency {:?} of the space {:?} must be imported into at least one environment",
+                    dependency.name(),
+                    space.info.name
+                ));
+            }
+            let mut unknown: Vec<&String> = imported_into
+                .iter()
+                .filter(|environment| !environments.contains(*environment))
+                .collect();
+            if !unknown.is_empty() {
+                unknown.sort();
+                return Err(anyhow::anyhow!(
+                    "The dependency {:?} of the space {:?} is imported into the environments {:?}, which are not environments of the space",
+                    dependency.name(),
+                    space.info.name,
+                    unknown
+                ));
+            }
+            dependency_environments.insert(dependency.name().to_string(), imported_into.clone());
+        }
+        let abstract_environments = schema_environments
+            .iter()
+            .filter(|environment| environment.is_abstract())
+            .map(|environment| environment.name().to_string())
+            .collect();
+        // Environments without an explicit mapping inherit the parent environment of the same name,
+        // unless the explicit mapping already maps that parent environment to another environment
+        for environment in &environments {
+            if !mapping.contains_space(environment) && !mapping.contains_ancestor(environment) {
+                mapping.add_mapping(environment.clone(), environment.clone())?;
             }
         }
 
         let space = Space {
             name: space.info.name,
             path: dir.path.clone(),
-            dependencies: space.info.dependencies.unwrap_or_default(),
+            dependencies: dependencies
+                .iter()
+                .map(|dependency| dependency.name().to_string())
+                .collect(),
+            dependency_aliases,
+            dependency_mappings,
+            dependency_environments,
             parent_mapping: mapping,
             environments,
+            abstract_environments,
             variables: space.variables,
-            files_to_copy: resolve_files_to_copy(&dir),
-            parent_space: closest_parent_space,
-            generate: {
-                match space.info.generate {
-                    Some(GenerateSchema::Generate(generate)) => GenerateSpace {
-                        generate: true,
-                        typescript: generate.typescript,
-                    },
-                    Some(GenerateSchema::ShouldGenerate(generate)) => GenerateSpace {
-                        generate,
-                        typescript: true,
-                    },
-                    None => GenerateSpace {
-                        generate: true,
-                        typescript: true,
-                    },
-                }
+            provenance: space.provenance,
+            files_to_copy: resolve_files_to_copy(&dir, false),
+            parent_space: if space.info.ignore_parent {
+                None
+            } else {
+                closest_parent_space
             },
+            generate: generate_space(space.info.generate, defaults),
+            forenv_default: space.info.forenv_default,
+            forenv_token: forenv_token(defaults)?,
+            array_merge_keys: space.info.array_merge_keys.unwrap_or_default(),
+            required: space.info.required.unwrap_or_default(),
+            strict: space.info.strict,
+            prune_environments: space.info.prune_environments,
         };
+        check_unique_name(&space, space_graph)?;
         space_graph.insert(space.name.clone(), space);
     }
 
     for entry in dir.directories {
-        add_to_spaces_graph(entry, space_graph, space_name.clone())?;
+        add_to_spaces_graph(entry, space_graph, space_name.clone(), defaults)?;
     }
     Ok(())
 }
 
-fn resolve_files_to_copy(dir: &Directory) -> CopyTree {
+/// The `forenv_token` of the `.weaveconfigrc`, or `_forenv`.
+fn forenv_token(defaults: &WeaveconfigRc) -> Result<String, anyhow::Error> {
+    let Some(token) = &defaults.forenv_token else {
+        return Ok(DEFAULT_FORENV_TOKEN.to_string());
+    };
+    // Other names starting with `_` are rejected when the directory is read
+    if token.is_empty() || token.contains(['/', '\\']) || token.starts_with('_') {
+        return Err(anyhow::anyhow!(
+            "The forenv_token {:?} must be non-empty, must not start with '_' and must not contain a path separator",
+            token
+        ));
+    }
+    Ok(token.clone())
+}
+
+/// The environment variable selecting the environment at runtime, if not configured.
+pub(crate) const DEFAULT_ENV_VARIABLE: &str = "ENV";
+
+/// The generate options of a space, falling back to the `.weaveconfigrc` for the options it does not set.
+fn generate_space(generate: Option<GenerateSchema>, defaults: &WeaveconfigRc) -> GenerateSpace {
+    let mut space = GenerateSpace {
+        generate: defaults.generate.unwrap_or(true),
+        typescript: defaults.typescript.unwrap_or(true),
+        declaration_only: defaults.declaration_only.unwrap_or(false),
+        prune_empty: defaults.prune_empty.unwrap_or(false),
+        canonical_numbers: defaults.canonical_numbers.unwrap_or(false),
+        config_format: defaults.config_format.unwrap_or_default(),
+        config_layout: defaults.config_layout.unwrap_or_default(),
+        line_endings: defaults.line_endings.unwrap_or_default(),
+        final_newline: defaults.final_newline,
+        env_variable: defaults
+            .env_variable
+            .clone()
+            .unwrap_or_else(|| DEFAULT_ENV_VARIABLE.to_string()),
+    };
+    match generate {
+        Some(GenerateSchema::Generate(generate)) => {
+            space.generate = true;
+            space.typescript = generate.typescript.unwrap_or(space.typescript);
+            space.declaration_only = generate.declaration_only.unwrap_or(space.declaration_only);
+            space.prune_empty = generate.prune_empty.unwrap_or(space.prune_empty);
+            space.canonical_numbers = generate
+                .canonical_numbers
+                .unwrap_or(space.canonical_numbers);
+            space.config_format = generate.config_format.unwrap_or(space.config_format);
+            space.config_layout = generate.config_layout.unwrap_or(space.config_layout);
+            space.line_endings = generate.line_endings.unwrap_or(space.line_endings);
+            space.final_newline = generate.final_newline.or(space.final_newline);
+            if let Some(env_variable) = generate.env_variable {
+                space.env_variable = env_variable;
+            }
+        }
+        Some(GenerateSchema::ShouldGenerate(generate)) => space.generate = generate,
+        None => {}
+    }
+    space
+}
+
+/// Ensures no other space has the name of `space`, with a dedicated error if an ancestor has it.
+fn check_unique_name(space: &Space, space_graph: &SpaceGraph) -> Result<(), anyhow::Error> {
+    let Some(existing) = space_graph.get(&space.name) else {
+        return Ok(());
+    };
+    let mut ancestor = space.parent_space.as_ref();
+    while let Some(name) = ancestor {
+        if *name == space.name {
+            return Err(anyhow::anyhow!(
+                "The space at {:?} has the same name {:?} as its ancestor space at {:?}, space names must be unique",
+                space.path,
+                space.name,
+                existing.path
+            ));
+        }
+        ancestor = space_graph
+            .get(name)
+            .and_then(|parent| parent.parent_space.as_ref());
+    }
+    Err(anyhow::anyhow!(
+        "The spaces at {:?} and {:?} are both named {:?}, space names must be unique",
+        existing.path,
+        space.path,
+        space.name
+    ))
+}
+
+/// `link` is whether an ancestor directory of the space contains a `_weavelink` marker.
+fn resolve_files_to_copy(dir: &Directory, link: bool) -> CopyTree {
+    let link = link || dir.link;
     let mut files = vec![];
     for file in &dir.rest_to_copy {
-        files.push(ToCopy::File(file.clone()));
+        files.push(match link {
+            true => ToCopy::Link(file.clone()),
+            false => ToCopy::File(file.clone()),
+        });
     }
 
     for entry in &dir.directories {
         if entry.space.is_none() {
             files.push(ToCopy::Directory {
                 path: entry.path.clone(),
-                subtree: resolve_files_to_copy(entry),
+                subtree: resolve_files_to_copy(entry, link),
             });
         }
     }
 
     CopyTree { to_copy: files }
 }
+
+/// A name that does not follow the naming conventions, found by [`crate::check_weaveconfig_names`].
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum NameViolation {
+    /// The name of a space does not match the `space_name_pattern` of the `.weaveconfigrc`.
+    SpaceName { space: String, pattern: String },
+    /// The name of an environment does not match the `environment_name_pattern` of the `.weaveconfigrc`.
+    EnvironmentName {
+        space: String,
+        environment: String,
+        pattern: String,
+    },
+    /// A variable key starts with `__`, which is reserved for built-in template variables.
+    ReservedKey { space: String, path: String },
+}
+
+impl fmt::Display for NameViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NameViolation::SpaceName { space, pattern } => write!(
+                f,
+                "The space name \"{}\" does not match the pattern \"{}\"",
+                space, pattern
+            ),
+            NameViolation::EnvironmentName {
+                space,
+                environment,
+                pattern,
+            } => write!(
+                f,
+                "The environment \"{}\" of the space \"{}\" does not match the pattern \"{}\"",
+                environment, space, pattern
+            ),
+            NameViolation::ReservedKey { space, path } => write!(
+                f,
+                "The variable \"{}\" of the space \"{}\" starts with the reserved prefix \"__\"",
+                path, space
+            ),
+        }
+    }
+}
+
+/// Checks the names of all spaces, their environments and the keys of their own variables.
+/// The patterns come from the `.weaveconfigrc` and must match the whole name, names are only
+/// checked against patterns that are set. Every violation is reported, sorted by space.
+pub fn check_names(
+    space_graph: &SpaceGraph,
+    defaults: &WeaveconfigRc,
+) -> Result<Vec<NameViolation>, anyhow::Error> {
+    let compile = |pattern: &Option<String>| {
+        pattern
+            .as_ref()
+            .map(|pattern| {
+                Regex::new(&format!("^(?:{})$", pattern))
+                    .with_context(|| format!("Invalid name pattern: {:?}", pattern))
+                    .map(|regex| (regex, pattern.clone()))
+            })
+            .transpose()
+    };
+    let space_pattern = compile(&defaults.space_name_pattern)?;
+    let environment_pattern = compile(&defaults.environment_name_pattern)?;
+
+    let mut violations = Vec::new();
+    for space in space_graph.values() {
+        if let Some((regex, pattern)) = &space_pattern {
+            if !regex.is_match(&space.name) {
+                violations.push(NameViolation::SpaceName {
+                    space: space.name.clone(),
+                    pattern: pattern.clone(),
+                });
+            }
+        }
+        if let Some((regex, pattern)) = &environment_pattern {
+            for environment in &space.environments {
+                if !regex.is_match(environment) {
+                    violations.push(NameViolation::EnvironmentName {
+                        space: space.name.clone(),
+                        environment: environment.clone(),
+                        pattern: pattern.clone(),
+                    });
+                }
+            }
+        }
+        if let Some(variables) = &space.variables {
+            collect_reserved_keys(space, variables, "", &mut violations);
+        }
+    }
+    violations.sort();
+    Ok(violations)
+}
+
+fn collect_reserved_keys(
+    space: &Space,
+    variables: &serde_json::Map<String, serde_json::Value>,
+    prefix: &str,
+    violations: &mut Vec<NameViolation>,
+) {
+    for (key, value) in variables {
+        let path = format!("{}{}", prefix, key);
+        if key.starts_with("__") {
+            violations.push(NameViolation::ReservedKey {
+                space: space.name.clone(),
+                path: path.clone(),
+            });
+        }
+        if let serde_json::Value::Object(object) = value {
+            collect_reserved_keys(space, object, &format!("{}.", path), violations);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cancellation::CancellationToken, check_weaveconfig_names, file_graph::traverse_directory,
+        file_system::TokioFileSystem, resolve_weaveconfig, test_utils::TempDir,
+    };
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_render_tree() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["prod", "dev"] }"#,
+        );
+        dir.write(
+            "weaveconfig/services/_space.json",
+            r#"{ "name": "services", "dependencies": ["shared", "db"] }"#,
+        );
+        dir.write("weaveconfig/shared/_space.json", r#"{ "name": "shared" }"#);
+        dir.write("weaveconfig/db/_space.json", r#"{ "name": "db" }"#);
+
+        let directory = traverse_directory(
+            &dir.path().join("weaveconfig"),
+            &CancellationToken::new(),
+            None,
+            Arc::new(TokioFileSystem),
+        )
+        .await
+        .unwrap();
+        let graph = create_space_graph(directory).unwrap();
+
+        assert_eq!(
+            render_tree(&graph),
+            "root [dev, prod]\n  db\n  services depends on: db, shared\n  shared\n"
+        );
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("plugin-*", "plugin-auth"));
+        assert!(glob_matches("plugin-*", "plugin-"));
+        assert!(glob_matches("*-db", "users-db"));
+        assert!(glob_matches("p?ug*n-*", "plugin-a"));
+        assert!(!glob_matches("plugin-*", "plugins"));
+        assert!(!glob_matches("plugin-?", "plugin-ab"));
+    }
+
+    #[tokio::test]
+    async fn test_glob_dependencies() {
+        let dir = TempDir::new();
+        dir.write("weaveconfig/_space.json", r#"{ "name": "root" }"#);
+        for plugin in ["auth", "billing", "search"] {
+            dir.write(
+                &format!("weaveconfig/plugin-{}/_space.json", plugin),
+                &format!(r#"{{ "name": "plugin-{}" }}"#, plugin),
+            );
+            dir.write(
+                &format!("weaveconfig/plugin-{}/_env.json", plugin),
+                &format!(r#"{{ "{}": true }}"#, plugin),
+            );
+        }
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{ "name": "app", "dependencies": ["plugin-*", "missing-*"] }"#,
+        );
+        dir.write("weaveconfig/app/_env.json", "{}");
+
+        let resolved = resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+        assert_eq!(
+            resolved["app"].variables,
+            Some(
+                json!({ "auth": true, "billing": true, "search": true })
+                    .as_object()
+                    .unwrap()
+                    .clone()
+            )
+        );
+
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{ "name": "app", "dependencies": ["plugin-*", "missing-*"], "strict": true }"#,
+        );
+        let error = resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", error)
+            .contains("\"missing-*\" of the space \"app\" does not match any space"));
+    }
+
+    #[tokio::test]
+    async fn test_strict_unmapped_parent_environment() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "staging", "prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{ "name": "app", "environments": ["dev", "production"], "space_to_parent_mapping": { "production": ["prod"] } }"#,
+        );
+        resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{ "name": "app", "environments": ["dev", "production"], "space_to_parent_mapping": { "production": ["prod"] }, "strict": true }"#,
+        );
+        let error = resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains(
+            "The space \"app\" does not map all environments of its parent \"root\": The ancestor environments [\"staging\"] are not mapped to any environment"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_parent_environment_mapping() {
+        let dir = TempDir::new();
+        let root = dir.path().join("weaveconfig");
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "staging", "prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "dev": { "db": "dev-db" }, "staging": { "db": "staging-db" }, "prod": { "db": "prod-db" } }"#,
+        );
+
+        // The child declares `prod` as well, the explicit mapping of the parent's `prod` wins
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{ "name": "app", "environments": ["dev", "prod", "live"], "space_to_parent_mapping": { "live": ["prod"] } }"#,
+        );
+        let resolved = resolve_weaveconfig(&root).await.unwrap();
+        let app = &resolved["app"];
+        assert_eq!(app.variables.as_ref().unwrap()["live"]["db"], "prod-db");
+        assert_eq!(app.variables.as_ref().unwrap()["dev"]["db"], "dev-db");
+        assert_eq!(
+            app.variables.as_ref().unwrap()["prod"],
+            serde_json::json!({})
+        );
+        assert_eq!(
+            app.root_mapping.get_space(&"prod".to_string()),
+            Some(&"live".to_string())
+        );
+        assert_eq!(app.root_mapping.get_space(&"staging".to_string()), None);
+
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{ "name": "app", "environments": ["dev", "prod", "live"], "space_to_parent_mapping": { "live": ["prod"] }, "strict": true }"#,
+        );
+        let error = resolve_weaveconfig(&root).await.unwrap_err();
+        assert!(format!("{:#}", error)
+            .contains("The ancestor environments [\"staging\"] are not mapped to any environment"));
+
+        // A parent environment can not be mapped to two environments
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{ "name": "app", "environments": ["blue", "green", "dev"], "space_to_parent_mapping": { "green": ["prod", "staging"], "blue": ["prod", "staging"] } }"#,
+        );
+        let error = resolve_weaveconfig(&root).await.unwrap_err();
+        assert!(format!("{:#}", error).contains(
+            "The ancestor environment 'prod' is mapped to several environments [\"blue\", \"green\"]"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_unmapped_parent_environment_dropped() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "staging"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "dev": { "db": "dev-db" }, "staging": { "db": "staging-db" } }"#,
+        );
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{ "name": "app", "environments": ["dev"] }"#,
+        );
+
+        let resolved = resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        let app = &resolved["app"];
+        let variables = app.variables.as_ref().unwrap();
+        assert_eq!(variables["dev"]["db"], "dev-db");
+        assert!(!variables.contains_key("staging"));
+        assert!(!app
+            .provenance
+            .keys()
+            .any(|path| path.starts_with("staging")));
+        assert!(app.environments.iter().eq(["dev"]));
+        assert_eq!(app.root_mapping.get_space(&"staging".to_string()), None);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_name_of_ancestor() {
+        let dir = TempDir::new();
+        dir.write("weaveconfig/_space.json", r#"{ "name": "root" }"#);
+        dir.write("weaveconfig/apps/_space.json", r#"{ "name": "apps" }"#);
+        dir.write("weaveconfig/apps/web/_space.json", r#"{ "name": "root" }"#);
+
+        let error = resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("has the same name \"root\" as its ancestor space"));
+
+        dir.write("weaveconfig/other/_space.json", r#"{ "name": "other" }"#);
+        dir.write("weaveconfig/other/web/_space.json", r#"{ "name": "web" }"#);
+        dir.write("weaveconfig/apps/web/_space.json", r#"{ "name": "web" }"#);
+        let error = resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("are both named \"web\""));
+    }
+
+    #[tokio::test]
+    async fn test_ignore_parent() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "port": 3000, "dev": { "url": "dev.local" } }"#,
+        );
+        dir.write(
+            "weaveconfig/tools/_space.json",
+            r#"{ "name": "tools", "ignore_parent": true }"#,
+        );
+        dir.write("weaveconfig/tools/_env.json", r#"{ "name": "tools" }"#);
+        dir.write(
+            "weaveconfig/tools/lint/_space.json",
+            r#"{ "name": "lint" }"#,
+        );
+
+        let resolved = resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            resolved["tools"].variables,
+            Some(
+                serde_json::json!({ "name": "tools" })
+                    .as_object()
+                    .unwrap()
+                    .clone()
+            )
+        );
+        assert!(resolved["tools"].environments.is_empty());
+        assert_eq!(resolved["lint"].variables, resolved["tools"].variables);
+    }
+
+    #[tokio::test]
+    async fn test_affected_spaces() {
+        let dir = TempDir::new();
+        dir.write("weaveconfig/_space.json", r#"{ "name": "root" }"#);
+        dir.write("weaveconfig/db/_space.json", r#"{ "name": "db" }"#);
+        dir.write("weaveconfig/db/nested/file.txt", "");
+        dir.write(
+            "weaveconfig/api/_space.json",
+            r#"{ "name": "api", "dependencies": ["db"] }"#,
+        );
+        dir.write("weaveconfig/api/v2/_space.json", r#"{ "name": "api-v2" }"#);
+        dir.write("weaveconfig/web/_space.json", r#"{ "name": "web" }"#);
+
+        let weaveconfig_root = dir.path().join("weaveconfig");
+        let directory = traverse_directory(
+            &weaveconfig_root,
+            &CancellationToken::new(),
+            None,
+            Arc::new(TokioFileSystem),
+        )
+        .await
+        .unwrap();
+        let graph = create_space_graph(directory).unwrap();
+
+        let affected = affected_spaces(
+            &graph,
+            &weaveconfig_root,
+            &[PathBuf::from("db/nested/file.txt")],
+        );
+        assert_eq!(
+            affected,
+            HashSet::from(["db", "api", "api-v2"].map(String::from))
+        );
+        assert_eq!(
+            affected_spaces(
+                &graph,
+                &weaveconfig_root,
+                &[PathBuf::from("web/_space.json")]
+            ),
+            HashSet::from(["web".to_string()])
+        );
+        assert_eq!(
+            affected_spaces(&graph, &weaveconfig_root, &[PathBuf::from("_space.json")]).len(),
+            5
+        );
+    }
+
+    #[tokio::test]
+    async fn test_weaveconfigrc_affects_all_spaces() {
+        let dir = TempDir::new();
+        dir.write("weaveconfig/.weaveconfigrc", r#"{ "typescript": false }"#);
+        dir.write("weaveconfig/db/_space.json", r#"{ "name": "db" }"#);
+        dir.write("weaveconfig/web/_space.json", r#"{ "name": "web" }"#);
+
+        let weaveconfig_root = dir.path().join("weaveconfig");
+        let directory = traverse_directory(
+            &weaveconfig_root,
+            &CancellationToken::new(),
+            None,
+            Arc::new(TokioFileSystem),
+        )
+        .await
+        .unwrap();
+        let graph = create_space_graph(directory).unwrap();
+
+        assert_eq!(
+            affected_spaces(
+                &graph,
+                &weaveconfig_root,
+                &[PathBuf::from(".weaveconfigrc")]
+            ),
+            HashSet::from(["db", "web"].map(String::from))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_generate_changed() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "generate": false }"#,
+        );
+        dir.write(
+            "weaveconfig/db/_space.json",
+            r#"{ "name": "db", "generate": false }"#,
+        );
+        dir.write("weaveconfig/db/_env.json", r#"{ "host": "db.local" }"#);
+        dir.write("weaveconfig/db/db.txt", "{{ host }}");
+        dir.write(
+            "weaveconfig/api/_space.json",
+            r#"{ "name": "api", "dependencies": ["db"], "generate": false }"#,
+        );
+        dir.write("weaveconfig/api/api.txt", "{{ host }}");
+        dir.write(
+            "weaveconfig/web/_space.json",
+            r#"{ "name": "web", "generate": false }"#,
+        );
+        dir.write("weaveconfig/web/web.txt", "web");
+        for space in ["db", "api", "web"] {
+            std::fs::create_dir(dir.path().join(space)).unwrap();
+        }
+
+        let options = crate::GenerateOptions {
+            changed_files: Some(vec![PathBuf::from("db/_env.json")]),
+            ..Default::default()
+        };
+        let stats = crate::generate_weaveconfig_with(&dir.path().join("weaveconfig"), &options)
+            .await
+            .unwrap();
+
+        assert_eq!(stats.spaces, 2);
+        assert_eq!(dir.read("db/db.txt"), "db.local");
+        assert_eq!(dir.read("api/api.txt"), "db.local");
+        assert!(!dir.exists("web/web.txt"));
+    }
+
+    #[tokio::test]
+    async fn test_weaveconfigrc_defaults() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/.weaveconfigrc",
+            r#"{ "typescript": false, "prune_empty": true }"#,
+        );
+        dir.write("weaveconfig/_space.json", r#"{ "name": "root" }"#);
+        dir.write(
+            "weaveconfig/app/_space.json",
+            r#"{ "name": "app", "generate": { "typescript": true } }"#,
+        );
+        dir.write(
+            "weaveconfig/lib/_space.json",
+            r#"{ "name": "lib", "generate": { "typescript": false, "prune_empty": false } }"#,
+        );
+        dir.write(
+            "weaveconfig/web/_space.json",
+            r#"{ "name": "web", "generate": { "prune_empty": false } }"#,
+        );
+
+        let directory = traverse_directory(
+            &dir.path().join("weaveconfig"),
+            &CancellationToken::new(),
+            None,
+            Arc::new(TokioFileSystem),
+        )
+        .await
+        .unwrap();
+        let graph = create_space_graph(directory).unwrap();
+
+        assert!(!graph["root"].generate.typescript);
+        assert!(graph["root"].generate.prune_empty);
+        assert!(graph["app"].generate.typescript);
+        assert!(graph["app"].generate.prune_empty);
+        assert!(!graph["lib"].generate.prune_empty);
+        // Without `typescript`, the default of the `.weaveconfigrc` applies
+        assert!(!graph["web"].generate.typescript);
+        assert!(!graph["web"].generate.prune_empty);
+        assert!(graph["root"].files_to_copy.to_copy.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_check_names() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/.weaveconfigrc",
+            r#"{ "space_name_pattern": "[a-z]+(-[a-z]+)*", "environment_name_pattern": "[a-z]+" }"#,
+        );
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "Prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "db": { "__host": "localhost" } }"#,
+        );
+        dir.write("weaveconfig/app/_space.json", r#"{ "name": "web-app" }"#);
+        dir.write("weaveconfig/lib/_space.json", r#"{ "name": "Shared_Lib" }"#);
+
+        let violations = check_weaveconfig_names(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            violations,
+            vec![
+                NameViolation::SpaceName {
+                    space: "Shared_Lib".to_string(),
+                    pattern: "[a-z]+(-[a-z]+)*".to_string(),
+                },
+                NameViolation::EnvironmentName {
+                    space: "root".to_string(),
+                    environment: "Prod".to_string(),
+                    pattern: "[a-z]+".to_string(),
+                },
+                NameViolation::ReservedKey {
+                    space: "root".to_string(),
+                    path: "db.__host".to_string(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use serde_json::{Map, Value};
+
+use super::{
+    parse_variable, resolve_variable, strip_whitespace_left, variable_path, TemplateError,
+};
+
+/// Whether a value counts as true in a condition.
+/// `null`, `false`, `0`, empty strings, empty arrays and empty objects are false, everything else is true.
+pub fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64() != Some(0.0),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(array) => !array.is_empty(),
+        Value::Object(object) => !object.is_empty(),
+    }
+}
+
+/// Evaluates a condition against the variables.
+///
+/// A condition is a variable path, using the same syntax as in templates, optionally followed by
+/// `==` or `!=` and a JSON literal, for example `feature_x`, `db.port == 5432` or `region != "eu"`.
+/// Without a comparison the truthiness of the value is used, see [`is_truthy`].
+pub fn evaluate_condition(
+    condition: &str,
+    variables: &Map<String, Value>,
+) -> Result<bool, TemplateError> {
+    let (variable, rest) = parse_variable(strip_whitespace_left(condition))?;
+    let value = resolve_variable(&variable, variables).map_err(TemplateError::VariableError)?;
+
+    let rest = rest.trim();
+    if rest.is_empty() {
+        return Ok(is_truthy(&value));
+    }
+
+    let (negate, literal) = if let Some(literal) = rest.strip_prefix("==") {
+        (false, literal)
+    } else if let Some(literal) = rest.strip_prefix("!=") {
+        (true, literal)
+    } else {
+        return Err(TemplateError::SyntaxError(format!(
+            "Expected '==' or '!=' in condition, got: {}",
+            rest
+        )));
+    };
+
+    let literal: Value = serde_json::from_str(literal.trim()).map_err(|_| {
+        TemplateError::SyntaxError(format!(
+            "Expected a JSON literal in condition, got: {}",
+            literal.trim()
+        ))
+    })?;
+
+    Ok((*value == literal) != negate)
+}
+
+/// The path of the variable a condition references, see [`super::collect_references`].
+pub fn condition_reference(condition: &str) -> Result<Vec<String>, TemplateError> {
+    let (variable, _) = parse_variable(strip_whitespace_left(condition))?;
+    Ok(variable_path(&variable))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template_file::VariableError;
+    use serde_json::json;
+
+    fn variables() -> Map<String, Value> {
+        json!({
+            "feature_x": true,
+            "empty": "",
+            "db": { "port": 5432 },
+            "region": "eu"
+        })
+        .as_object()
+        .unwrap()
+        .clone()
+    }
+
+    #[test]
+    fn test_truthiness() {
+        let variables = variables();
+        assert!(evaluate_condition("feature_x", &variables).unwrap());
+        assert!(!evaluate_condition("empty", &variables).unwrap());
+        assert!(evaluate_condition("db", &variables).unwrap());
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let variables = variables();
+        assert!(evaluate_condition("feature_x == true", &variables).unwrap());
+        assert!(evaluate_condition("db.port == 5432", &variables).unwrap());
+        assert!(!evaluate_condition("region != \"eu\"", &variables).unwrap());
+        assert!(evaluate_condition(" region == \"eu\" ", &variables).unwrap());
+    }
+
+    #[test]
+    fn test_invalid_conditions() {
+        let variables = variables();
+        assert!(matches!(
+            evaluate_condition("feature_x > 1", &variables),
+            Err(TemplateError::SyntaxError(_))
+        ));
+        assert!(matches!(
+            evaluate_condition("region == eu", &variables),
+            Err(TemplateError::SyntaxError(_))
+        ));
+        assert!(matches!(
+            evaluate_condition("missing", &variables),
+            Err(TemplateError::VariableError(
+                VariableError::MissingVariable(_)
+            ))
+        ));
+    }
+}
@@ -0,0 +1,435 @@
+use std::borrow::Cow;
+
+use serde_json::Value;
+
+use super::{
+    apply_modifiers, parse_modifiers, parse_segment_template, render_value, strip_whitespace_left,
+    take_first, value_type, Modifier, TemplateError, VariableError,
+};
+
+/// A filter transforming the value of a variable, applied with `{{ variable | filter }}`.
+/// Filters can be chained, each one is applied to the result of the previous one.
+pub enum Filter {
+    /// `map(.path)` replaces each element of an array with the value at the path within it.
+    /// Elements that do not contain the path become `null`.
+    Map(Vec<Modifier>),
+    /// `join ", "` renders the elements of an array like variables are rendered and joins them with the separator.
+    Join(String),
+    /// `or "fallback"` replaces `null` and the empty string with the fallback string, other values pass through.
+    /// A variable that is not defined at all is still an error.
+    Or(String),
+    /// `replace "/" "."` renders the value like a variable and replaces every occurrence of the first string with the second.
+    Replace(String, String),
+    /// `fixed 2` renders a number with exactly this many decimals. The exact binary value of the number
+    /// is rounded to the nearest, ties to even, so `0.125` becomes `0.12` and `2.675`,
+    /// which is stored slightly below, becomes `2.67`.
+    Fixed(usize),
+}
+
+/// The most decimals `fixed` renders, more would only pad the output with zeros.
+const MAX_FIXED_DECIMALS: usize = 100;
+
+/// Parses the filters following a variable, each introduced by `|`.
+pub fn parse_filters(input: &str) -> Result<(Vec<Filter>, &str), TemplateError> {
+    let mut filters = Vec::new();
+    let mut input = input;
+
+    while let Some(rest) = strip_whitespace_left(input).strip_prefix('|') {
+        let (filter, rest) = parse_filter(strip_whitespace_left(rest))?;
+        filters.push(filter);
+        input = rest;
+    }
+
+    Ok((filters, input))
+}
+
+fn parse_filter(input: &str) -> Result<(Filter, &str), TemplateError> {
+    let (name, input) = parse_segment_template(input)?;
+    match name.as_str() {
+        "map" => {
+            let input = expect_char(strip_whitespace_left(input), '(')?;
+            let (modifiers, input) = parse_modifiers(strip_whitespace_left(input))?;
+            if modifiers.is_empty() {
+                return Err(TemplateError::SyntaxError(
+                    "Expected a path like .key in map".to_string(),
+                ));
+            }
+            let input = expect_char(strip_whitespace_left(input), ')')?;
+            Ok((Filter::Map(modifiers), input))
+        }
+        "join" => {
+            let input = strip_whitespace_left(input);
+            if !input.starts_with(['"', '\'']) {
+                return Err(TemplateError::SyntaxError(
+                    "Expected a quoted separator in join".to_string(),
+                ));
+            }
+            let (separator, input) = parse_segment_template(input)?;
+            Ok((Filter::Join(separator), input))
+        }
+        "or" => {
+            let input = strip_whitespace_left(input);
+            if !input.starts_with(['"', '\'']) {
+                return Err(TemplateError::SyntaxError(
+                    "Expected a quoted fallback in or".to_string(),
+                ));
+            }
+            let (fallback, input) = parse_segment_template(input)?;
+            Ok((Filter::Or(fallback), input))
+        }
+        "replace" => {
+            let (from, input) = parse_quoted(input, "the string to replace")?;
+            let (to, input) = parse_quoted(input, "the replacement")?;
+            Ok((Filter::Replace(from, to), input))
+        }
+        "fixed" => {
+            let input = strip_whitespace_left(input);
+            let end = input
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(input.len());
+            let precision = input[..end].parse().map_err(|_| {
+                TemplateError::SyntaxError("Expected a number of decimals in fixed".to_string())
+            })?;
+            if precision > MAX_FIXED_DECIMALS {
+                return Err(TemplateError::SyntaxError(format!(
+                    "fixed supports at most {} decimals, got {}",
+                    MAX_FIXED_DECIMALS, precision
+                )));
+            }
+            Ok((Filter::Fixed(precision), &input[end..]))
+        }
+        _ => Err(TemplateError::SyntaxError(format!(
+            "Unknown filter: {}",
+            name
+        ))),
+    }
+}
+
+fn parse_quoted<'a>(input: &'a str, what: &str) -> Result<(String, &'a str), TemplateError> {
+    let input = strip_whitespace_left(input);
+    if !input.starts_with(['"', '\'']) {
+        return Err(TemplateError::SyntaxError(format!(
+            "Expected a quoted string for {} in replace",
+            what
+        )));
+    }
+    parse_segment_template(input)
+}
+
+fn expect_char(input: &str, expected: char) -> Result<&str, TemplateError> {
+    match take_first(input) {
+        Some((char, rest)) if char == expected => Ok(rest),
+        Some((char, _)) => Err(TemplateError::SyntaxError(format!(
+            "Expected '{}', got: {}",
+            expected, char
+        ))),
+        None => Err(TemplateError::SyntaxError("Unexpected EOF".to_string())),
+    }
+}
+
+/// Applies the filters in order.
+pub fn apply_filters<'a>(
+    value: &'a Value,
+    filters: &[Filter],
+) -> Result<Cow<'a, Value>, VariableError> {
+    let mut value = Cow::Borrowed(value);
+    for filter in filters {
+        value = Cow::Owned(apply_filter(&value, filter)?);
+    }
+    Ok(value)
+}
+
+fn apply_filter(value: &Value, filter: &Filter) -> Result<Value, VariableError> {
+    match filter {
+        Filter::Map(modifiers) => {
+            let array = as_array(value)?;
+            Ok(Value::Array(
+                array
+                    .iter()
+                    .map(|element| match apply_modifiers(element, modifiers) {
+                        Ok(value) => Ok(value.into_owned()),
+                        Err(
+                            VariableError::KeyNotFound(_) | VariableError::IndexOutOfBounds(_, _),
+                        ) => Ok(Value::Null),
+                        Err(e) => Err(e),
+                    })
+                    .collect::<Result<_, _>>()?,
+            ))
+        }
+        Filter::Join(separator) => {
+            let array = as_array(value)?;
+            Ok(Value::String(
+                array
+                    .iter()
+                    .map(render_value)
+                    .collect::<Vec<_>>()
+                    .join(separator),
+            ))
+        }
+        Filter::Or(fallback) => match value {
+            Value::Null => Ok(Value::String(fallback.clone())),
+            Value::String(string) if string.is_empty() => Ok(Value::String(fallback.clone())),
+            value => Ok(value.clone()),
+        },
+        Filter::Replace(from, to) => Ok(Value::String(render_value(value).replace(from, to))),
+        Filter::Fixed(precision) => match value.as_f64() {
+            Some(number) => Ok(Value::String(format!("{:.*}", precision, number))),
+            None => Err(VariableError::InvalidType(
+                "number".to_string(),
+                value_type(value),
+            )),
+        },
+    }
+}
+
+fn as_array(value: &Value) -> Result<&Vec<Value>, VariableError> {
+    match value {
+        Value::Array(array) => Ok(array),
+        _ => Err(VariableError::InvalidType(
+            "array".to_string(),
+            value_type(value),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::template_file::{template_file, TemplateError, VariableError};
+    use serde_json::{json, Map, Value};
+
+    fn variables() -> Map<String, Value> {
+        json!({
+            "servers": [
+                { "host": "a.local", "port": 80 },
+                { "host": "b.local" },
+                { "port": 8080 }
+            ],
+            "name": "app"
+        })
+        .as_object()
+        .unwrap()
+        .clone()
+    }
+
+    #[test]
+    fn test_map() {
+        let variables = variables();
+        assert_eq!(
+            template_file("{{ servers | map(.host) }}", &variables).unwrap(),
+            r#"["a.local","b.local",null]"#
+        );
+        assert_eq!(
+            template_file("{{servers|map(.port)}}", &variables).unwrap(),
+            "[80,null,8080]"
+        );
+    }
+
+    #[test]
+    fn test_map_errors() {
+        let variables = variables();
+        assert!(matches!(
+            template_file("{{ name | map(.host) }}", &variables),
+            Err(TemplateError::VariableError(VariableError::InvalidType(
+                _,
+                _
+            )))
+        ));
+        assert!(matches!(
+            template_file("{{ servers | map() }}", &variables),
+            Err(TemplateError::SyntaxError(_))
+        ));
+        assert!(matches!(
+            template_file("{{ servers | unknown }}", &variables),
+            Err(TemplateError::SyntaxError(_))
+        ));
+    }
+
+    #[test]
+    fn test_join() {
+        let variables = json!({
+            "tags": ["a", "b", "c"],
+            "ports": [80, 443],
+            "name": "app"
+        })
+        .as_object()
+        .unwrap()
+        .clone();
+
+        assert_eq!(
+            template_file("{{ tags | join \", \" }}", &variables).unwrap(),
+            "a, b, c"
+        );
+        assert_eq!(
+            template_file("{{ ports | join ';' }}", &variables).unwrap(),
+            "80;443"
+        );
+        assert_eq!(
+            template_file("{{ tags | join \"\" }}", &variables).unwrap(),
+            "abc"
+        );
+        assert!(matches!(
+            template_file("{{ name | join \",\" }}", &variables),
+            Err(TemplateError::VariableError(VariableError::InvalidType(
+                _,
+                _
+            )))
+        ));
+        assert!(matches!(
+            template_file("{{ tags | join }}", &variables),
+            Err(TemplateError::SyntaxError(_))
+        ));
+    }
+
+    #[test]
+    fn test_map_join() {
+        assert_eq!(
+            template_file("{{ servers | map(.host) | join \",\" }}", &variables()).unwrap(),
+            "a.local,b.local,null"
+        );
+    }
+
+    #[test]
+    fn test_or() {
+        let variables = json!({
+            "missing_host": null,
+            "empty_host": "",
+            "host": "localhost",
+            "port": 0,
+            "tags": []
+        })
+        .as_object()
+        .unwrap()
+        .clone();
+
+        assert_eq!(
+            template_file("{{ missing_host | or \"none\" }}", &variables).unwrap(),
+            "none"
+        );
+        assert_eq!(
+            template_file("{{ empty_host | or 'none' }}", &variables).unwrap(),
+            "none"
+        );
+        assert_eq!(
+            template_file("{{ host | or \"none\" }}", &variables).unwrap(),
+            "localhost"
+        );
+        assert_eq!(
+            template_file("{{ port | or \"none\" }}", &variables).unwrap(),
+            "0"
+        );
+        assert_eq!(
+            template_file("{{ tags | join \",\" | or \"no tags\" }}", &variables).unwrap(),
+            "no tags"
+        );
+        assert!(matches!(
+            template_file("{{ undefined | or \"none\" }}", &variables),
+            Err(TemplateError::VariableError(
+                VariableError::MissingVariable(_)
+            ))
+        ));
+        assert!(matches!(
+            template_file("{{ host | or }}", &variables),
+            Err(TemplateError::SyntaxError(_))
+        ));
+    }
+
+    #[test]
+    fn test_replace() {
+        let variables = json!({
+            "path": "services/api/http",
+            "name": "my-app-name",
+            "port": 8080,
+            "tags": ["a", "b"]
+        })
+        .as_object()
+        .unwrap()
+        .clone();
+
+        assert_eq!(
+            template_file("{{ path | replace \"/\" \".\" }}", &variables).unwrap(),
+            "services.api.http"
+        );
+        assert_eq!(
+            template_file("{{ name | replace '-app-' '_' }}", &variables).unwrap(),
+            "my_name"
+        );
+        assert_eq!(
+            template_file("{{ port | replace \"80\" \"90\" }}", &variables).unwrap(),
+            "9090"
+        );
+        assert_eq!(
+            template_file("{{ tags | join \",\" | replace \",\" \"\" }}", &variables).unwrap(),
+            "ab"
+        );
+        assert!(matches!(
+            template_file("{{ path | replace \"/\" }}", &variables),
+            Err(TemplateError::SyntaxError(_))
+        ));
+        assert!(matches!(
+            template_file("{{ path | replace }}", &variables),
+            Err(TemplateError::SyntaxError(_))
+        ));
+    }
+
+    #[test]
+    fn test_fixed() {
+        let variables = json!({
+            "ratio": 0.6666,
+            "rounded_up": 1.235001,
+            "tie": 0.125,
+            "replicas": 3,
+            "negative": -1.5,
+            "name": "app"
+        })
+        .as_object()
+        .unwrap()
+        .clone();
+
+        assert_eq!(
+            template_file("{{ ratio | fixed 2 }}", &variables).unwrap(),
+            "0.67"
+        );
+        assert_eq!(
+            template_file("{{ rounded_up | fixed 2 }}", &variables).unwrap(),
+            "1.24"
+        );
+        assert_eq!(
+            template_file("{{ tie | fixed 2 }}", &variables).unwrap(),
+            "0.12"
+        );
+        assert_eq!(
+            template_file("{{ replicas | fixed 2 }}", &variables).unwrap(),
+            "3.00"
+        );
+        assert_eq!(
+            template_file("{{ negative|fixed 0 }}", &variables).unwrap(),
+            "-2"
+        );
+        assert!(matches!(
+            template_file("{{ name | fixed 2 }}", &variables),
+            Err(TemplateError::VariableError(VariableError::InvalidType(
+                _,
+                _
+            )))
+        ));
+        assert!(matches!(
+            template_file("{{ ratio | fixed }}", &variables),
+            Err(TemplateError::SyntaxError(_))
+        ));
+        assert_eq!(
+            template_file("{{ replicas | fixed 100 }}", &variables)
+                .unwrap()
+                .len(),
+            "3.".len() + 100
+        );
+        for precision in ["101", "4000000000", "99999999999999999999999"] {
+            assert!(matches!(
+                template_file(
+                    &format!("{{{{ ratio | fixed {} }}}}", precision),
+                    &variables
+                ),
+                Err(TemplateError::SyntaxError(_))
+            ));
+        }
+    }
+}
@@ -1,9 +1,14 @@
+mod condition;
+mod filter;
 mod integer;
 mod segment;
 
+pub use condition::{condition_reference, evaluate_condition};
+use filter::{apply_filters, parse_filters, Filter};
 use integer::parse_integer;
 use segment::{parse_segment, ParseSegmentError};
 use serde_json::{Map, Value};
+use std::borrow::Cow;
 use thiserror::Error;
 
 /// Enum representing possible errors during template rendering.
@@ -21,8 +26,9 @@ pub enum VariableError {
     MissingVariable(String),
     #[error("Key not found: {0}")]
     KeyNotFound(String),
+    /// The index as written, negative indices count from the end.
     #[error("Index out of bounds, tried to access {0} but array length is {1}")]
-    IndexOutOfBounds(usize, usize),
+    IndexOutOfBounds(i64, usize),
     #[error("Invalid type, expected {0}, got {1}")]
     InvalidType(String, String),
 }
@@ -39,28 +45,47 @@ pub fn value_type(value: &Value) -> String {
     .to_string()
 }
 
-fn render_variable(
+fn resolve_variable<'a>(
     variable: &Variable,
-    variables: &Map<String, Value>,
-) -> Result<String, VariableError> {
-    let mut value = variables
+    variables: &'a Map<String, Value>,
+) -> Result<Cow<'a, Value>, VariableError> {
+    let value = variables
         .get(&variable.base)
         .ok_or(VariableError::MissingVariable(variable.base.clone()))?;
 
-    for modifier in variable.modifiers.iter() {
+    apply_modifiers(value, &variable.modifiers)
+}
+
+/// Follows the keys and indices of the modifiers, starting at `value`.
+/// A length modifier produces a new value, the modifiers after it apply to that number.
+fn apply_modifiers<'a>(
+    mut value: &'a Value,
+    modifiers: &[Modifier],
+) -> Result<Cow<'a, Value>, VariableError> {
+    for (i, modifier) in modifiers.iter().enumerate() {
         match modifier {
-            Modifier::Index(index) => {
-                value = match value {
-                    Value::Array(array) => array.get(*index as usize).ok_or_else(|| {
-                        VariableError::IndexOutOfBounds(*index as usize, array.len())
-                    })?,
+            Modifier::Length => {
+                let length = match value {
+                    Value::Array(array) => array.len(),
+                    Value::Object(object) => object.len(),
                     _ => {
                         return Err(VariableError::InvalidType(
-                            "array".to_string(),
+                            "array or object".to_string(),
                             value_type(value),
                         ))
                     }
-                }
+                };
+                let length = Value::from(length);
+                return apply_modifiers(&length, &modifiers[i + 1..])
+                    .map(|value| Cow::Owned(value.into_owned()));
+            }
+            Modifier::Index(index) => {
+                value = index_array(value, *index as i64, |_| Some(*index as usize))?;
+            }
+            Modifier::IndexFromEnd(offset) => {
+                value = index_array(value, -(*offset as i64), |len| {
+                    len.checked_sub(*offset as usize)
+                })?;
             }
             Modifier::Key(key) => {
                 value = match value {
@@ -78,18 +103,106 @@ fn render_variable(
         }
     }
 
-    Ok(match value {
+    Ok(Cow::Borrowed(value))
+}
+
+/// The element of an array at the position computed from its length, `written` is the index
+/// as it appears in the template.
+fn index_array(
+    value: &Value,
+    written: i64,
+    position: impl FnOnce(usize) -> Option<usize>,
+) -> Result<&Value, VariableError> {
+    match value {
+        Value::Array(array) => position(array.len())
+            .and_then(|position| array.get(position))
+            .ok_or(VariableError::IndexOutOfBounds(written, array.len())),
+        _ => Err(VariableError::InvalidType(
+            "array".to_string(),
+            value_type(value),
+        )),
+    }
+}
+
+fn render_variable(
+    variable: &Variable,
+    filters: &[Filter],
+    variables: &Map<String, Value>,
+) -> Result<String, VariableError> {
+    let value = resolve_variable(variable, variables)?;
+    let value = apply_filters(&value, filters)?;
+
+    Ok(render_value(&value))
+}
+
+/// Renders a value into a template, strings are inserted without quotes, arrays and objects as JSON.
+fn render_value(value: &Value) -> String {
+    match value {
         Value::String(s) => s.to_string(),
         Value::Number(n) => n.to_string(),
         Value::Bool(b) => b.to_string(),
         Value::Null => "null".to_string(),
         _ => serde_json::to_string(value).unwrap(),
-    })
+    }
+}
+
+/// What to do when a template references a variable that is not defined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MissingBehavior {
+    /// Fail with [`VariableError::MissingVariable`].
+    #[default]
+    Error,
+    /// Keep the original `{{ ... }}` text, including modifiers and whitespace.
+    /// Escapes are kept as well, so the output is a template that a later pass can fill in.
+    KeepLiteral,
 }
 
 pub fn template_file(
     content: &str,
     variables: &Map<String, Value>,
+) -> Result<String, TemplateError> {
+    render_template(content, variables, MissingBehavior::Error)
+}
+
+/// Renders a template, handling undefined variables according to `missing`.
+pub fn render_template(
+    content: &str,
+    variables: &Map<String, Value>,
+    missing: MissingBehavior,
+) -> Result<String, TemplateError> {
+    process_template(content, missing, |var, filters| {
+        render_variable(var, filters, variables)
+    })
+}
+
+/// Collects the variables referenced by a template as paths of keys, in order of appearance.
+/// A path ends before the first index, so `{{ servers[0].host }}` references `servers`.
+/// Quoted keys are kept as one segment, even if they contain dots. Escaped variables are not references.
+pub fn collect_references(content: &str) -> Result<Vec<Vec<String>>, TemplateError> {
+    let mut references = Vec::new();
+    process_template(content, MissingBehavior::Error, |var, _| {
+        references.push(variable_path(var));
+        Ok(String::new())
+    })?;
+    Ok(references)
+}
+
+fn variable_path(variable: &Variable) -> Vec<String> {
+    let mut path = vec![variable.base.clone()];
+    for modifier in &variable.modifiers {
+        match modifier {
+            Modifier::Key(key) => path.push(key.clone()),
+            Modifier::Index(_) | Modifier::IndexFromEnd(_) | Modifier::Length => break,
+        }
+    }
+    path
+}
+
+/// Runs the template state machine, producing the value of each variable with `render`.
+fn process_template(
+    content: &str,
+    missing: MissingBehavior,
+    mut render: impl FnMut(&Variable, &[Filter]) -> Result<String, VariableError>,
 ) -> Result<String, TemplateError> {
     enum State {
         Text,
@@ -101,9 +214,14 @@ pub fn template_file(
         VariableEnd2,
     }
 
+    let keep_literal = missing == MissingBehavior::KeepLiteral;
     let mut state = State::Text;
     let mut output = String::new();
     let mut input = content;
+    // Whether the current brace follows an escaped backslash
+    let mut after_backslash = false;
+    // The start of a variable that is kept as is
+    let mut kept_start: Option<usize> = None;
 
     while let Some((char, rest)) = take_first(input) {
         input = rest;
@@ -119,21 +237,33 @@ pub fn template_file(
             },
             State::Brace => match char {
                 '{' => {
+                    let start = content.len() - input.len() - 2;
                     let rest = strip_whitespace_left(input);
                     let (var, rest) = parse_variable(rest)?;
+                    let (filters, rest) = parse_filters(rest)?;
                     input = rest;
-                    output.push_str(
-                        &render_variable(&var, variables)
-                            .map_err(|e| TemplateError::VariableError(e))?,
-                    );
+                    match render(&var, &filters) {
+                        Ok(rendered) => output.push_str(&rendered),
+                        Err(VariableError::MissingVariable(_)) if keep_literal => {
+                            if after_backslash {
+                                // Keep the backslash escaped, so it does not escape the braces
+                                output.push('\\');
+                            }
+                            kept_start = Some(start);
+                        }
+                        Err(e) => return Err(TemplateError::VariableError(e)),
+                    }
+                    after_backslash = false;
                     state = State::VariableEnd1;
                 }
                 '\\' => {
                     output.push('{');
+                    after_backslash = false;
                     state = State::Escape;
                 }
                 _ => {
                     state = State::Text;
+                    after_backslash = false;
                     output.push('{');
                     output.push(char);
                 }
@@ -154,6 +284,7 @@ pub fn template_file(
             State::DoubleEscape => match char {
                 '{' => {
                     output.push('\\');
+                    after_backslash = true;
                     state = State::Brace;
                 }
                 _ => {
@@ -165,6 +296,9 @@ pub fn template_file(
             },
             State::EscapeBrace1 => match char {
                 '{' => {
+                    if keep_literal {
+                        output.push('\\');
+                    }
                     output.push('{');
                     output.push('{');
                     state = State::Text;
@@ -190,6 +324,9 @@ pub fn template_file(
             },
             State::VariableEnd2 => match char {
                 '}' => {
+                    if let Some(start) = kept_start.take() {
+                        output.push_str(&content[start..content.len() - input.len()]);
+                    }
                     state = State::Text;
                 }
                 char if char.is_whitespace() => {}
@@ -251,7 +388,11 @@ struct Variable {
 
 enum Modifier {
     Index(u64),
+    /// A negative index, `[-1]` is the last element.
+    IndexFromEnd(u64),
     Key(String),
+    /// `.#len`, the number of elements of an array or keys of an object.
+    Length,
 }
 
 fn parse_variable(input: &str) -> Result<(Variable, &str), TemplateError> {
@@ -280,11 +421,21 @@ fn parse_segment_template(input: &str) -> Result<(String, &str), TemplateError>
     })
 }
 
+/// The maximum number of modifiers of a variable, longer chains are a syntax error.
+/// Keeps the work per variable bounded on adversarial templates.
+const MAX_MODIFIERS: usize = 256;
+
 fn parse_modifiers(input: &str) -> Result<(Vec<Modifier>, &str), TemplateError> {
     let mut modifiers = Vec::new();
     let mut span = input;
 
     while let Ok((modifier, rest)) = parse_modifier(span) {
+        if modifiers.len() == MAX_MODIFIERS {
+            return Err(TemplateError::SyntaxError(format!(
+                "More than {} modifiers in a variable",
+                MAX_MODIFIERS
+            )));
+        }
         modifiers.push(modifier);
         span = rest;
     }
@@ -292,15 +443,26 @@ fn parse_modifiers(input: &str) -> Result<(Vec<Modifier>, &str), TemplateError>
     Ok((modifiers, span))
 }
 
+/// The name of the length modifier after the dot. Keys can not start with `#` unless quoted,
+/// so a key named `#len` is still reachable with `["#len"]`.
+const LENGTH_MODIFIER: &str = "#len";
+
+/// Parses a `.key`, `.#len`, `[index]` or `["key"]` modifier. Whitespace is allowed before, within and
+/// after the brackets and around the dot, so `data . items [ 0 ]` is the same as `data.items[0]`.
+/// Whitespace after the last modifier is left to the caller.
 fn parse_modifier(input: &str) -> Result<(Modifier, &str), TemplateError> {
-    match take_first(input) {
+    match take_first(strip_whitespace_left(input)) {
         Some(('.', input)) => {
+            let input = strip_whitespace_left(input);
+            if let Some(input) = input.strip_prefix(LENGTH_MODIFIER) {
+                return Ok((Modifier::Length, input));
+            }
             let (segment, input) = parse_segment_template(input)?;
             Ok((Modifier::Key(segment), input))
         }
         Some(('[', input)) => {
-            let (modifier, input) = parse_access(input)?;
-            if let Some((char, input)) = take_first(input) {
+            let (modifier, input) = parse_access(strip_whitespace_left(input))?;
+            if let Some((char, input)) = take_first(strip_whitespace_left(input)) {
                 if char == ']' {
                     Ok((modifier, input))
                 } else {
@@ -325,7 +487,7 @@ fn parse_access(input: &str) -> Result<(Modifier, &str), TemplateError> {
     match parse_integer(input) {
         Ok((index, input)) => {
             if index < 0 {
-                Err(TemplateError::SyntaxError("Negative index".to_string()))
+                Ok((Modifier::IndexFromEnd(index.unsigned_abs()), input))
             } else {
                 Ok((Modifier::Index(index as u64), input))
             }
@@ -430,10 +592,98 @@ mod tests {
 
     #[test]
     fn test_invalid_array_index() {
-        let content = "Invalid index: {{ array[-1] }}";
+        let content = "Invalid index: {{ array[-5] }}";
         let variables = map(&[("array", json!([1, 2, 3]))]);
 
-        assert!(template_file(content, &variables).is_err());
+        assert!(matches!(
+            template_file(content, &variables).unwrap_err(),
+            TemplateError::VariableError(VariableError::IndexOutOfBounds(-5, 3))
+        ));
+        assert!(template_file("{{ array[3] }}", &variables).is_err());
+    }
+
+    #[test]
+    fn test_negative_array_index() {
+        let variables = map(&[("array", json!([1, 2, {"name": "last"}]))]);
+
+        assert_eq!(template_file("{{ array[0] }}", &variables).unwrap(), "1");
+        assert_eq!(template_file("{{ array[-2] }}", &variables).unwrap(), "2");
+        assert_eq!(
+            template_file("{{ array[-1].name }}", &variables).unwrap(),
+            "last"
+        );
+        assert_eq!(
+            template_file("{{ array[-3] }}", &variables).unwrap(),
+            template_file("{{ array[0] }}", &variables).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_length_modifier() {
+        let variables = map(&[
+            ("servers", json!([{"ports": [80, 443]}, {"ports": []}, {}])),
+            (
+                "db",
+                json!({"host": "localhost", "port": 5432, "#len": "key"}),
+            ),
+            ("name", json!("api")),
+        ]);
+
+        assert_eq!(
+            template_file("{{ servers.#len }}", &variables).unwrap(),
+            "3"
+        );
+        assert_eq!(
+            template_file("{{ servers[0] . #len }}", &variables).unwrap(),
+            "1"
+        );
+        assert_eq!(template_file("{{ db.#len }}", &variables).unwrap(), "3");
+        assert_eq!(
+            template_file("{{ db[\"#len\"] }}", &variables).unwrap(),
+            "key"
+        );
+        assert_eq!(
+            template_file("{{ servers | map(.ports.#len) }}", &variables).unwrap(),
+            "[2,0,null]"
+        );
+        assert!(matches!(
+            template_file("{{ name.#len }}", &variables).unwrap_err(),
+            TemplateError::VariableError(VariableError::InvalidType(_, got)) if got == "string"
+        ));
+        assert!(matches!(
+            template_file("{{ servers.#len.value }}", &variables).unwrap_err(),
+            TemplateError::VariableError(VariableError::InvalidType(_, got)) if got == "number"
+        ));
+        assert_eq!(
+            collect_references("{{ servers.#len }}").unwrap(),
+            vec![vec!["servers"]]
+        );
+    }
+
+    #[test]
+    fn test_modifier_limit() {
+        let mut nested = json!("leaf");
+        for _ in 0..MAX_MODIFIERS {
+            nested = json!({ "a": nested });
+        }
+        let variables = map(&[("a", nested)]);
+        let chain = |length: usize| format!("{{{{ a{} }}}}", ".a".repeat(length));
+
+        assert_eq!(
+            template_file(&chain(MAX_MODIFIERS), &variables).unwrap(),
+            "leaf"
+        );
+        assert!(matches!(
+            template_file(&chain(MAX_MODIFIERS + 1), &variables).unwrap_err(),
+            TemplateError::SyntaxError(_)
+        ));
+        let pathological = format!("{{{{ a{} }}}}", "[0].#len".repeat(100_000));
+        assert!(matches!(
+            template_file(&pathological, &variables).unwrap_err(),
+            TemplateError::SyntaxError(_)
+        ));
+        assert!(collect_references(&pathological).is_err());
+        assert!(evaluate_condition(&pathological[3..pathological.len() - 3], &variables).is_err());
     }
 
     #[test]
@@ -484,4 +734,213 @@ mod tests {
 
         assert_eq!(template_file(content, &variables).unwrap(), "  \\\\");
     }
+
+    #[test]
+    fn test_keep_literal_missing_variables() {
+        let content = "A {{ a }} B {{  b.c[0] \t}} C \\{{ escaped }} D \\\\{{ b.c[0] }}";
+        let first = map(&[("a", json!(1))]);
+
+        let intermediate = render_template(content, &first, MissingBehavior::KeepLiteral).unwrap();
+        assert_eq!(
+            intermediate,
+            "A 1 B {{  b.c[0] \t}} C \\{{ escaped }} D \\\\{{ b.c[0] }}"
+        );
+
+        let all = map(&[("a", json!(1)), ("b", json!({"c": ["x"]}))]);
+        assert_eq!(
+            template_file(&intermediate, &all).unwrap(),
+            template_file(content, &all).unwrap()
+        );
+        assert_eq!(
+            template_file(&intermediate, &all).unwrap(),
+            "A 1 B x C {{ escaped }} D \\x"
+        );
+    }
+
+    #[test]
+    fn test_keep_literal_in_multiple_passes() {
+        let content = "{{ a }}-{{ b }}-{{ c }}";
+        let mut output = content.to_string();
+        for (key, value) in [("c", json!(3)), ("a", json!(1)), ("b", json!(2))] {
+            output = render_template(&output, &map(&[(key, value)]), MissingBehavior::KeepLiteral)
+                .unwrap();
+        }
+        assert_eq!(output, "1-2-3");
+    }
+
+    #[test]
+    fn test_keep_literal_other_errors() {
+        let content = "{{ a.missing }}";
+        let variables = map(&[("a", json!({}))]);
+
+        assert!(matches!(
+            render_template(content, &variables, MissingBehavior::KeepLiteral).unwrap_err(),
+            TemplateError::VariableError(VariableError::KeyNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_collect_references() {
+        assert_eq!(
+            collect_references(
+                "{{ name }} {{db.host}} {{ servers[0].host }} \\{{ escaped }} {{ tags | join \",\" }}"
+            )
+            .unwrap(),
+            vec![
+                vec!["name"],
+                vec!["db", "host"],
+                vec!["servers"],
+                vec!["tags"]
+            ]
+        );
+        assert_eq!(
+            collect_references("{{ cfg[\"db.host\"] }} {{ cfg.'a b' }}").unwrap(),
+            vec![vec!["cfg", "db.host"], vec!["cfg", "a b"]]
+        );
+        assert!(collect_references("{{ unclosed").is_err());
+        assert_eq!(
+            collect_references("{{ data . items [ 0 ] . name }}").unwrap(),
+            vec![vec!["data", "items"]]
+        );
+    }
+
+    #[test]
+    fn test_whitespace_between_modifiers() {
+        let variables = map(&[(
+            "data",
+            json!({"items": [{"name": "first", "tags": ["a", "b"]}], "the key": 1}),
+        )]);
+        let compact = template_file("{{ data.items[0].name }}", &variables).unwrap();
+        assert_eq!(compact, "first");
+        for content in [
+            "{{ data . items [ 0 ] . name }}",
+            "{{data .items[0] .name}}",
+            "{{ data\n  .items\n  [0]\n  .name }}",
+            "{{ data\t.\titems\t[\t0\t]\t.\tname }}",
+        ] {
+            assert_eq!(
+                template_file(content, &variables).unwrap(),
+                compact,
+                "{content:?}"
+            );
+        }
+        assert_eq!(
+            template_file("{{ data . items [ 0 ] . tags [ 1 ] }}", &variables).unwrap(),
+            "b"
+        );
+        assert_eq!(
+            template_file("{{ data [ \"the key\" ] }}", &variables).unwrap(),
+            "1"
+        );
+        assert_eq!(
+            template_file("{{ data . items [ 0 ] . tags | join \"-\" }}", &variables).unwrap(),
+            "a-b"
+        );
+        assert!(template_file("{{ data . items [ 0 }}", &variables).is_err());
+    }
+}
+
+#[cfg(test)]
+mod property_tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn key() -> impl Strategy<Value = String> {
+        "[a-z][a-z0-9_]{0,6}"
+    }
+
+    fn leaf() -> impl Strategy<Value = Value> {
+        prop_oneof![
+            any::<bool>().prop_map(Value::from),
+            any::<i64>().prop_map(Value::from),
+            "\\PC{0,12}".prop_map(Value::from),
+            Just(Value::Null),
+        ]
+    }
+
+    fn variables() -> impl Strategy<Value = Map<String, Value>> {
+        let value = prop_oneof![
+            leaf(),
+            prop::collection::btree_map(key(), leaf(), 1..4)
+                .prop_map(|map| Value::Object(map.into_iter().collect())),
+        ];
+        prop::collection::btree_map(key(), value, 1..6).prop_map(|map| map.into_iter().collect())
+    }
+
+    /// Literal text without braces or backslashes.
+    fn plain_text() -> impl Strategy<Value = String> {
+        "[a-zA-Z0-9 ,.!?\n]{0,10}"
+    }
+
+    /// The dotted paths of all leaves, with their values.
+    fn leaves(variables: &Map<String, Value>) -> Vec<(String, Value)> {
+        let mut leaves = Vec::new();
+        for (key, value) in variables {
+            match value {
+                Value::Object(object) => {
+                    for (child, value) in object {
+                        leaves.push((format!("{}.{}", key, child), value.clone()));
+                    }
+                }
+                value => leaves.push((key.clone(), value.clone())),
+            }
+        }
+        leaves
+    }
+
+    /// Escapes literal text so it renders to itself. Text with a backslash directly before a brace
+    /// can not always be expressed, so it is excluded by the callers.
+    fn escape(text: &str) -> String {
+        let mut escaped = String::new();
+        let mut rest = text;
+        while let Some(index) = rest.find("{{") {
+            escaped.push_str(&rest[..index]);
+            escaped.push_str("\\{{");
+            rest = &rest[index + 2..];
+        }
+        escaped.push_str(rest);
+        escaped
+    }
+
+    proptest! {
+        #[test]
+        fn renders_valid_references(
+            variables in variables(),
+            parts in prop::collection::vec((plain_text(), any::<prop::sample::Index>()), 0..8),
+            tail in plain_text(),
+        ) {
+            let leaves = leaves(&variables);
+            let mut template = String::new();
+            let mut expected = String::new();
+            let mut paths = Vec::new();
+            for (text, index) in &parts {
+                let (path, value) = index.get(&leaves);
+                template.push_str(&format!("{}{{{{ {} }}}}", text, path));
+                expected.push_str(text);
+                expected.push_str(&render_value(value));
+                paths.push(path.split('.').map(String::from).collect::<Vec<_>>());
+            }
+            template.push_str(&tail);
+            expected.push_str(&tail);
+
+            prop_assert_eq!(template_file(&template, &variables).unwrap(), expected);
+            prop_assert_eq!(collect_references(&template).unwrap(), paths);
+        }
+
+        #[test]
+        fn escaped_literal_text_round_trips(text in "[a-z {}\\\\\n]{0,40}") {
+            prop_assume!(!text.contains("\\{"));
+            let variables = Map::new();
+
+            prop_assert_eq!(template_file(&escape(&text), &variables).unwrap(), text.clone());
+            prop_assert!(collect_references(&escape(&text)).unwrap().is_empty());
+        }
+
+        #[test]
+        fn never_panics(template in "[a-z0-9 {}\\\\|\\[\\].\"',]{0,40}|\\PC{0,40}", variables in variables()) {
+            let _ = template_file(&template, &variables);
+            let _ = render_template(&template, &variables, MissingBehavior::KeepLiteral);
+            let _ = collect_references(&template);
+        }
+    }
 }
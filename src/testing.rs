@@ -0,0 +1,120 @@
+//! Helpers for testing a weaveconfig setup as a whole, enabled by the `testing` feature.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{generate_weaveconfig_with, GenerateOptions, WeaveError};
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Generates the weaveconfig into a new temporary directory, like [`GenerateOptions::out_dir`],
+/// and returns the content of every generated file by its path relative to that directory.
+/// Paths use `/` as separator and the map is sorted, so it can be compared against a stored snapshot.
+/// Files that are not valid UTF-8 are read lossily. The temporary directory is removed afterwards.
+pub async fn snapshot_generation(
+    weaveconfig_config_root: &Path,
+) -> Result<BTreeMap<String, String>> {
+    let out_dir = std::env::temp_dir().join(format!(
+        "weaveconfig-snapshot-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    tokio::fs::create_dir_all(&out_dir)
+        .await
+        .with_context(|| format!("Failed to create the snapshot directory {:?}", out_dir))?;
+
+    let snapshot = async {
+        let out_dir = out_dir.canonicalize()?;
+        let options = GenerateOptions {
+            out_dir: Some(out_dir.clone()),
+            ..Default::default()
+        };
+        generate_weaveconfig_with(weaveconfig_config_root, &options)
+            .await
+            .map_err(WeaveError::into_inner)?;
+        read_files(&out_dir).await
+    }
+    .await;
+    let _ = tokio::fs::remove_dir_all(&out_dir).await;
+    snapshot
+}
+
+/// Reads all files below `dir` by their path relative to it.
+async fn read_files(dir: &Path) -> Result<BTreeMap<String, String>> {
+    let mut files = BTreeMap::new();
+    let mut pending: Vec<PathBuf> = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let mut entries = tokio::fs::read_dir(&current)
+            .await
+            .with_context(|| format!("Failed to read directory: {:?}", current))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if entry.file_type().await?.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            let relative = path
+                .strip_prefix(dir)?
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            // Follows symlinked files, so linked outputs show the content they point to
+            let content = tokio::fs::read(&path)
+                .await
+                .with_context(|| format!("Failed to read file: {:?}", path))?;
+            files.insert(relative, String::from_utf8_lossy(&content).into_owned());
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::snapshot_generation;
+    use crate::test_utils::TempDir;
+    use std::collections::BTreeMap;
+
+    #[tokio::test]
+    async fn test_snapshot_generation() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"], "generate": { "typescript": false } }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "dev": { "port": 3000 }, "prod": { "port": 80 } }"#,
+        );
+        dir.write(
+            "weaveconfig/api/_space.json",
+            r#"{ "name": "api", "environments": ["dev", "prod"], "generate": false }"#,
+        );
+        dir.write("weaveconfig/api/_forenv.txt", "port={{ port }}");
+
+        let snapshot = snapshot_generation(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        let expected: BTreeMap<String, String> = [
+            ("api/dev.txt", "port=3000"),
+            ("api/prod.txt", "port=80"),
+            (
+                "gen/config.json",
+                "{\n  \"dev\": {\n    \"port\": 3000\n  },\n  \"prod\": {\n    \"port\": 80\n  }\n}\n",
+            ),
+            ("gen/.gitignore", "config.json\n"),
+        ]
+        .into_iter()
+        .map(|(path, content)| (path.to_string(), content.to_string()))
+        .collect();
+        assert_eq!(snapshot, expected);
+        // Nothing is written next to the weaveconfig directory
+        assert!(!dir.exists("gen"));
+    }
+}
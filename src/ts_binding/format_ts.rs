@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use biome_formatter::{IndentStyle, IndentWidth, LineWidth, QuoteStyle};
 use biome_js_formatter::{
     context::{JsFormatOptions, Semicolons},
@@ -6,6 +8,9 @@ use biome_js_formatter::{
 use biome_js_parser::{parse, JsParserOptions};
 use biome_js_syntax::JsFileSource;
 
+/// How long formatting a binding may take before it is written unformatted.
+pub const FORMAT_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub fn format_ts_string(text: &str) -> Result<String, anyhow::Error> {
     let source_type = JsFileSource::ts();
     let tree = parse(text, source_type, JsParserOptions::default());
@@ -21,3 +26,56 @@ pub fn format_ts_string(text: &str) -> Result<String, anyhow::Error> {
     let result = doc.print()?.as_code().to_string();
     Ok(result)
 }
+
+/// Formats on the blocking thread pool, so a slow format does not stall the async runtime.
+/// `None` if formatting takes longer than `timeout`, the text is then written unformatted.
+pub async fn format_ts_string_blocking(
+    text: String,
+    timeout: Option<Duration>,
+) -> Result<Option<String>, anyhow::Error> {
+    let task = tokio::task::spawn_blocking(move || format_ts_string(&text));
+    let result = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, task).await {
+            Ok(result) => result,
+            // The formatting thread can not be interrupted, it finishes in the background
+            Err(_) => return Ok(None),
+        },
+        None => task.await,
+    };
+    result?.map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BINDING: &str = "type ConfigType = {port: number, dev: {url: string}};\nexport const environments = [\"dev\"] as const;\n";
+
+    fn is_valid_ts(text: &str) -> bool {
+        !parse(text, JsFileSource::ts(), JsParserOptions::default()).has_errors()
+    }
+
+    #[tokio::test]
+    async fn test_formats_within_timeout() {
+        let formatted = format_ts_string_blocking(BINDING.to_string(), Some(FORMAT_TIMEOUT))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(formatted, format_ts_string(BINDING).unwrap());
+        assert_ne!(formatted, BINDING);
+        assert!(formatted.contains("port: number;"));
+    }
+
+    #[tokio::test]
+    async fn test_timeout_falls_back_to_unformatted() {
+        let text = BINDING.repeat(200);
+
+        let result = format_ts_string_blocking(text.clone(), Some(Duration::ZERO))
+            .await
+            .unwrap();
+
+        assert_eq!(result, None);
+        assert!(is_valid_ts(&text));
+    }
+}
@@ -1,40 +1,82 @@
 use std::path::Path;
 
-use crate::{resolve_spaces::ResolvedSpace, ts_binding::format_ts::format_ts_string};
+use crate::{
+    apply_resolved::binding_file_name,
+    file_system::FileSystem,
+    resolve_spaces::ResolvedSpace,
+    schemas::{apply_final_newline, ConfigLayout},
+    ts_binding::format_ts::{format_ts_string_blocking, FORMAT_TIMEOUT},
+    write_json_file::apply_layout,
+};
 use anyhow::Error;
 use serde_json::Value;
 
 use super::format_ts_type::json_value_to_ts_type;
 
+/// Writes the typescript binding of a space into the output directory.
+/// Returns the number of bytes written and whether the binding was written unformatted,
+/// as formatting it took longer than [`FORMAT_TIMEOUT`].
 pub async fn generate_binding(
+    fs: &dyn FileSystem,
     resolved_space: &ResolvedSpace,
     output_dir: &Path,
-) -> Result<(), Error> {
+) -> Result<(usize, bool), Error> {
     if let Some(variables) = &resolved_space.variables {
         let zero_env_content = include_str!("./zero_env.ts");
         let single_env_content = include_str!("./one_env.ts");
         let multi_env_content = include_str!("./multi_env.ts");
 
-        let mut content = String::new();
         let ts_type = json_value_to_ts_type(&Value::Object(variables.clone()));
+        if resolved_space.generate.declaration_only {
+            return write_binding(
+                fs,
+                resolved_space,
+                output_dir,
+                declarations(resolved_space, &ts_type)?,
+            )
+            .await;
+        }
+
+        let mut content = String::new();
         content.push_str(&format!("type ConfigType = {};\n\n", ts_type));
 
+        // The config file may be laid out differently, it is converted to ConfigType when read
+        let file_type =
+            json_value_to_ts_type(&Value::Object(apply_layout(variables, resolved_space)?));
+        content.push_str(&format!("type ConfigFileType = {};\n\n", file_type));
+        content.push_str(match resolved_space.generate.config_layout {
+            ConfigLayout::Nested => {
+                "function from_layout(config: ConfigFileType): ConfigType {\n    return config;\n}\n\n"
+            }
+            ConfigLayout::Environments => {
+                "function from_layout(config: ConfigFileType): ConfigType {\n    const { environments, ...rest } = config;\n    return { ...rest, ...environments } as ConfigType;\n}\n\n"
+            }
+        });
+
         content.push_str("export const environments = ");
         content.push_str(&format!(
             "{} as const;",
             serde_json::to_string(&resolved_space.environments)?
         ));
-        content.push_str("\n");
+        content.push('\n');
         content.push_str("export type Environments = typeof environments[number];");
+        content.push_str(&format!(
+            "\n\nexport type TemplateBuiltins = {};\n\n",
+            template_builtins_type(resolved_space)?
+        ));
 
         content.push_str("const mappingFromRoot = ");
         content.push_str(&format!(
             "{} as const;",
             serde_json::to_string(&resolved_space.root_mapping.list_ancestor_to_space())?
         ));
+        content.push_str(&format!(
+            "\nconst envVariableName = {};",
+            serde_json::to_string(&resolved_space.generate.env_variable)?
+        ));
 
         content.push_str("\n\n// static code starts here, using variant: ");
-        if resolved_space.environments.len() == 0 {
+        if resolved_space.environments.is_empty() {
             content.push_str("zero_env\n\n");
             content.push_str(zero_env_content);
         } else if resolved_space.environments.len() == 1 {
@@ -45,10 +87,178 @@ pub async fn generate_binding(
             content.push_str(multi_env_content);
         }
 
-        let formatted = format_ts_string(&content)?;
+        return write_binding(fs, resolved_space, output_dir, content).await;
+    }
+    Ok((0, false))
+}
+
+/// The type declarations of the `binding.d.ts` of a space, without any runtime statements.
+fn declarations(resolved_space: &ResolvedSpace, ts_type: &str) -> Result<String, Error> {
+    let environments = resolved_space
+        .environments
+        .iter()
+        .map(serde_json::to_string)
+        .collect::<Result<Vec<_>, _>>()?;
+    let environment_type = if environments.is_empty() {
+        "never".to_string()
+    } else {
+        environments.join(" | ")
+    };
+    Ok(format!(
+        "export type ConfigType = {};\n\nexport type Environment = {};\n\nexport type TemplateBuiltins = {};\n",
+        ts_type,
+        environment_type,
+        template_builtins_type(resolved_space)?
+    ))
+}
+
+/// The type of the built-in variables available in the copied files of a space, in addition to its
+/// variables. Mirrors the built-ins inserted when copying: `env`, `envs` and the `is_<env>` flags
+/// in `_forenv` files of a space with environments, `__space` and `__path` in every file.
+fn template_builtins_type(resolved_space: &ResolvedSpace) -> Result<String, Error> {
+    let mut fields = Vec::new();
+    if !resolved_space.environments.is_empty() {
+        let environments = resolved_space
+            .environments
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<Vec<_>, _>>()?
+            .join(" | ");
+        fields.push(format!("env?: {}", environments));
+        fields.push(format!("envs?: Array<{}>", environments));
+        for environment in &resolved_space.environments {
+            fields.push(format!(
+                "{}?: boolean",
+                serde_json::to_string(&format!("is_{}", environment))?
+            ));
+        }
+    }
+    fields.push(format!(
+        "__space: {}",
+        serde_json::to_string(&resolved_space.name)?
+    ));
+    fields.push("__path: string".to_string());
+    Ok(format!("{{ {} }}", fields.join("; ")))
+}
+
+async fn write_binding(
+    fs: &dyn FileSystem,
+    resolved_space: &ResolvedSpace,
+    output_dir: &Path,
+    content: String,
+) -> Result<(usize, bool), Error> {
+    let (formatted, unformatted) =
+        match format_ts_string_blocking(content.clone(), Some(FORMAT_TIMEOUT)).await? {
+            Some(formatted) => (formatted, false),
+            None => (content, true),
+        };
+    let formatted = apply_final_newline(
+        formatted,
+        resolved_space.generate.final_newline.unwrap_or(true),
+    );
+    let formatted = resolved_space.generate.line_endings.apply(formatted);
+
+    let output_path = output_dir.join(binding_file_name(resolved_space));
+    fs.write(&output_path, formatted.as_bytes()).await?;
+    Ok((formatted.len(), unformatted))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{generate_weaveconfig, test_utils::TempDir};
+
+    #[tokio::test]
+    async fn test_environments_in_declaration_order() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["prod", "dev", "staging"], "generate": true }"#,
+        );
+        dir.write("weaveconfig/_env.json", r#"{ "port": 3000 }"#);
+
+        generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        let binding = dir.read("gen/binding.ts");
+        assert!(
+            binding.contains(r#"export const environments = ["prod", "dev", "staging"] as const;"#)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_template_builtins_type() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"], "generate": true }"#,
+        );
+        dir.write("weaveconfig/_env.json", r#"{ "port": 3000 }"#);
+        dir.write(
+            "weaveconfig/api/_space.json",
+            r#"{ "name": "api", "generate": { "typescript": true, "declaration_only": true } }"#,
+        );
+        dir.write("weaveconfig/api/_env.json", r#"{ "api_port": 8080 }"#);
+        dir.write("api/.gitkeep", "");
+
+        generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        let binding = dir.read("gen/binding.ts");
+        for field in [
+            r#"env?: "dev" | "prod";"#,
+            r#"envs?: Array<"dev" | "prod">;"#,
+            "is_dev?: boolean;",
+            "is_prod?: boolean;",
+            r#"__space: "root";"#,
+            "__path: string;",
+        ] {
+            assert!(binding.contains(field), "{}", binding);
+        }
+        // A space without environments only has the built-ins of every copied file
+        let declarations = dir.read("api/gen/binding.d.ts");
+        let builtins = &declarations[declarations.find("export type TemplateBuiltins").unwrap()..];
+        assert!(builtins.contains(r#"__space: "api";"#), "{}", declarations);
+        assert!(!builtins.contains("env"), "{}", declarations);
+    }
+
+    #[tokio::test]
+    async fn test_env_variable() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/.weaveconfigrc",
+            r#"{ "env_variable": "STAGE" }"#,
+        );
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"], "generate": true }"#,
+        );
+        dir.write("weaveconfig/_env.json", r#"{ "port": 3000 }"#);
+        dir.write(
+            "weaveconfig/api/_space.json",
+            r#"{ "name": "api", "generate": { "typescript": true, "env_variable": "APP_ENV" } }"#,
+        );
+        dir.write("weaveconfig/api/_env.json", r#"{ "api_port": 8080 }"#);
+        dir.write("api/.gitkeep", "");
+
+        generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
 
-        let output_path = output_dir.join("binding.ts");
-        tokio::fs::write(output_path, formatted).await?;
+        let binding = dir.read("gen/binding.ts");
+        assert!(
+            binding.contains(r#"const envVariableName = "STAGE";"#),
+            "{}",
+            binding
+        );
+        assert!(binding.contains("load_env_variable(__dirname, envVariableName)"));
+        assert!(!binding.contains(r#""ENV""#), "{}", binding);
+        let binding = dir.read("api/gen/binding.ts");
+        assert!(
+            binding.contains(r#"const envVariableName = "APP_ENV";"#),
+            "{}",
+            binding
+        );
     }
-    Ok(())
 }
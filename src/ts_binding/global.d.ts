@@ -3,6 +3,7 @@ declare global {
     var mappingFromRoot: {
         [key: string]: string;
     };
+    var envVariableName: string;
     type ConfigType = {
         sample_env1: {
             sample_key1: string;
@@ -13,6 +14,8 @@ declare global {
         sample_shared: number;
     };
     type Environments = "sample_env1" | "sample_env2";
+    type ConfigFileType = ConfigType;
+    function from_layout(config: ConfigFileType): ConfigType;
 }
 
 export type {};
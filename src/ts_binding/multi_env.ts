@@ -143,15 +143,15 @@ function read_config_file(): ConfigType | null {
         return null;
     }
     const file = readFileSync(path, "utf8");
-    return JSON.parse(file);
+    return from_layout(JSON.parse(file));
 }
 
 const config = read_config_file();
-const env_variable = load_env_variable(__dirname, "ENV");
+const env_variable = load_env_variable(__dirname, envVariableName);
 
 if (!env_variable) {
     throw new Error(
-        `ENV is not set, please set ENV variable to one of ${Object.keys(mappingFromRoot).join(", ")}, you can pass this environment variable directly or in a .env file`,
+        `${envVariableName} is not set, please set ${envVariableName} variable to one of ${Object.keys(mappingFromRoot).join(", ")}, you can pass this environment variable directly or in a .env file`,
     );
 }
 
@@ -159,7 +159,7 @@ export const usedEnvironment =
     mappingFromRoot[env_variable as keyof typeof mappingFromRoot];
 if (!usedEnvironment) {
     throw new Error(
-        `ENV is set, but is not a known environment, please set ENV variable to one of ${Object.keys(mappingFromRoot).join(", ")}, you can pass this environment variable directly or in a .env file`,
+        `${envVariableName} is set, but is not a known environment, please set ${envVariableName} variable to one of ${Object.keys(mappingFromRoot).join(", ")}, you can pass this environment variable directly or in a .env file`,
     );
 }
 
@@ -22,7 +22,7 @@ function read_config_file(): ConfigType | null {
 		return null;
 	}
 	const file = readFileSync(path, "utf8");
-	return JSON.parse(file);
+	return from_layout(JSON.parse(file));
 }
 
 const config = read_config_file();
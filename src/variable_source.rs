@@ -0,0 +1,270 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use futures::future::{try_join_all, BoxFuture};
+use serde_json::{Map, Value};
+
+use crate::{
+    merging::{record_value_provenance, Provenance},
+    space_graph::SpaceGraph,
+};
+
+/// The prefix of a string value read from a registered source, like `"@source:vault:db/password"`.
+const SOURCE_DIRECTIVE: &str = "@source:";
+
+/// A backend variables can be read from, like a secret store or a parameter store.
+/// Sources are registered by name in [`VariableSources`] and referenced from variable files
+/// with strings like `"@source:<name>:<key>"`.
+pub trait VariableSource: Send + Sync {
+    /// Reads the value of `key`, which can be any JSON value.
+    fn fetch<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Value>>;
+}
+
+/// A source reading from a fixed set of values, for tests and for values computed by the caller.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InMemorySource {
+    values: HashMap<String, Value>,
+}
+
+impl InMemorySource {
+    pub fn new() -> Self {
+        InMemorySource::default()
+    }
+
+    /// Sets the value of `key`, replacing the previous one.
+    pub fn insert(&mut self, key: impl Into<String>, value: Value) -> &mut Self {
+        self.values.insert(key.into(), value);
+        self
+    }
+}
+
+impl VariableSource for InMemorySource {
+    fn fetch<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Value>> {
+        Box::pin(async move {
+            self.values
+                .get(key)
+                .cloned()
+                .with_context(|| format!("The key {:?} does not exist", key))
+        })
+    }
+}
+
+/// The sources `@source` values are read from, by name, see [`crate::GenerateOptions::sources`].
+#[derive(Clone, Default)]
+pub struct VariableSources {
+    sources: HashMap<String, Arc<dyn VariableSource>>,
+}
+
+impl VariableSources {
+    pub fn new() -> Self {
+        VariableSources::default()
+    }
+
+    /// Registers a source under `name`, replacing a source registered under the same name.
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        source: impl VariableSource + 'static,
+    ) -> &mut Self {
+        self.sources.insert(name.into(), Arc::new(source));
+        self
+    }
+}
+
+impl fmt::Debug for VariableSources {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<&String> = self.sources.keys().collect();
+        names.sort();
+        f.debug_struct("VariableSources")
+            .field("sources", &names)
+            .finish()
+    }
+}
+
+/// Replaces every string like `"@source:<name>:<key>"` in the variables of the spaces with the value
+/// of the key in the source registered under that name, so it is merged like any other value.
+/// The key is everything after the second `:`. A source that is not registered or fails is an error.
+///
+/// Each key is fetched once, even if several spaces read it. The `_schema.json` of a space is
+/// validated before, against the string itself.
+pub async fn resolve_source_variables(
+    space_graph: &mut SpaceGraph,
+    sources: &VariableSources,
+) -> Result<()> {
+    let mut keys = HashSet::new();
+    for space in space_graph.values() {
+        if let Some(variables) = &space.variables {
+            collect_keys(variables, &mut keys).with_context(|| {
+                format!("Invalid source variable in the space {:?}", space.name)
+            })?;
+        }
+    }
+    if keys.is_empty() {
+        return Ok(());
+    }
+
+    let mut keys: Vec<(String, String)> = keys.into_iter().collect();
+    keys.sort();
+    let values = try_join_all(keys.iter().map(|(name, key)| async move {
+        let source = sources
+            .sources
+            .get(name)
+            .with_context(|| format!("The variable source {:?} is not registered", name))?;
+        source
+            .fetch(key)
+            .await
+            .with_context(|| format!("Failed to read {:?} from the source {:?}", key, name))
+    }))
+    .await?;
+    let fetched: HashMap<(String, String), Value> = keys.into_iter().zip(values).collect();
+
+    for space in space_graph.values_mut() {
+        if let Some(variables) = &mut space.variables {
+            for (key, value) in variables.iter_mut() {
+                replace_sources(value, key.clone(), &fetched, &mut space.provenance)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The source name and key of a `@source` string, `None` for other values.
+fn source_directive(value: &Value) -> Result<Option<(&str, &str)>> {
+    let Some(reference) = value
+        .as_str()
+        .and_then(|s| s.strip_prefix(SOURCE_DIRECTIVE))
+    else {
+        return Ok(None);
+    };
+    match reference.split_once(':') {
+        Some((name, key)) if !name.is_empty() && !key.is_empty() => Ok(Some((name, key))),
+        _ => Err(anyhow::anyhow!(
+            "{:?} must have the form \"{}<name>:<key>\"",
+            value,
+            SOURCE_DIRECTIVE
+        )),
+    }
+}
+
+fn collect_keys(
+    variables: &Map<String, Value>,
+    keys: &mut HashSet<(String, String)>,
+) -> Result<()> {
+    for value in variables.values() {
+        collect_value_keys(value, keys)?;
+    }
+    Ok(())
+}
+
+fn collect_value_keys(value: &Value, keys: &mut HashSet<(String, String)>) -> Result<()> {
+    match value {
+        Value::Object(object) => collect_keys(object, keys),
+        Value::Array(array) => array
+            .iter()
+            .try_for_each(|value| collect_value_keys(value, keys)),
+        value => {
+            if let Some((name, key)) = source_directive(value)? {
+                keys.insert((name.to_string(), key.to_string()));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Replaces the `@source` strings within `value`, which is at the dotted `path`, with their fetched
+/// values. The fetched leaves get the source of the string, arrays are recorded as a whole.
+fn replace_sources(
+    value: &mut Value,
+    path: String,
+    fetched: &HashMap<(String, String), Value>,
+    provenance: &mut Provenance,
+) -> Result<()> {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                replace_sources(value, format!("{}.{}", path, key), fetched, provenance)?;
+            }
+        }
+        Value::Array(array) => {
+            // Arrays are recorded as a whole, the provenance within them is discarded
+            for (index, value) in array.iter_mut().enumerate() {
+                let path = format!("{}.{}", path, index);
+                replace_sources(value, path, fetched, &mut Provenance::new())?;
+            }
+        }
+        _ => {
+            let Some((name, key)) = source_directive(value)? else {
+                return Ok(());
+            };
+            let replacement = fetched[&(name.to_string(), key.to_string())].clone();
+            if let Some(source) = provenance.remove(&path) {
+                record_value_provenance(path, &replacement, &source, provenance);
+            }
+            *value = replacement;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InMemorySource, VariableSources};
+    use crate::{
+        generate_weaveconfig_with, resolve_weaveconfig, test_utils::TempDir, GenerateOptions,
+    };
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_source_variables() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["prod"], "generate": { "typescript": false } }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "prod": { "db": { "password": "@source:vault:db/prod:password" } }, "hosts": ["@source:vault:host", "static", { "name": "@source:vault:host" }] }"#,
+        );
+        dir.write("weaveconfig/secret.txt", "{{ prod.db.password.value }}");
+        let root = dir.path().join("weaveconfig");
+
+        let mut vault = InMemorySource::new();
+        vault
+            .insert("db/prod:password", json!({ "value": "hunter2" }))
+            .insert("host", json!("db.internal"));
+        let mut sources = VariableSources::new();
+        sources.register("vault", vault);
+
+        let options = GenerateOptions {
+            sources,
+            ..Default::default()
+        };
+        generate_weaveconfig_with(&root, &options).await.unwrap();
+
+        let config: serde_json::Value = serde_json::from_str(&dir.read("gen/config.json")).unwrap();
+        assert_eq!(
+            config,
+            json!({
+                "hosts": ["db.internal", "static", { "name": "db.internal" }],
+                "prod": { "db": { "password": { "value": "hunter2" } } }
+            })
+        );
+        assert_eq!(dir.read("secret.txt"), "hunter2");
+
+        let error = resolve_weaveconfig(&root).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("The variable source \"vault\" is not registered"));
+
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "prod": { "db": { "password": "@source:vault:missing" } } }"#,
+        );
+        let error = generate_weaveconfig_with(&root, &options)
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("The key \"missing\" does not exist"));
+    }
+}
@@ -1,17 +1,613 @@
 use std::path::Path;
 
-use crate::resolve_spaces::ResolvedSpace;
-use tokio::fs;
+use crate::{
+    file_system::FileSystem,
+    get_environment_value::get_environment_value,
+    resolve_spaces::ResolvedSpace,
+    schemas::{apply_final_newline, ConfigFormat, ConfigLayout},
+};
+use serde_json::{Map, Value};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
+/// Writes the config.json, or config.json5 or config.yaml depending on the config format, of a space into the gen folder.
+/// Returns the number of bytes written.
 pub async fn write_json_file(
+    fs: &dyn FileSystem,
     resolved_space: &ResolvedSpace,
     gen_folder: &Path,
-) -> Result<(), anyhow::Error> {
+) -> Result<usize, anyhow::Error> {
     if let Some(variables) = &resolved_space.variables {
-        let env_file_path = gen_folder.join("config.json");
-        let env_file_content = serde_json::to_string_pretty(variables)?;
-        fs::write(env_file_path, env_file_content).await?;
+        let pruned;
+        let variables = if resolved_space.generate.prune_empty {
+            pruned = prune_variables(variables.clone(), resolved_space);
+            &pruned
+        } else {
+            variables
+        };
+        let canonical;
+        let variables = if resolved_space.generate.canonical_numbers {
+            canonical = canonicalize_map(variables.clone());
+            &canonical
+        } else {
+            variables
+        };
+        let variables = &apply_layout(variables, resolved_space)?;
+        let config_format = resolved_space.generate.config_format;
+        let env_file_content = match config_format {
+            ConfigFormat::Json => serde_json::to_string_pretty(variables)?,
+            ConfigFormat::Json5 => {
+                let mut content = String::new();
+                write_json5_map(&mut content, variables, 0);
+                content.push_str(&format!(
+                    "\n// Generated by weaveconfig for the space {}\n",
+                    serde_json::to_string(&resolved_space.name)?
+                ));
+                content
+            }
+            ConfigFormat::Yaml => {
+                let mut content = format!(
+                    "# Generated by weaveconfig for the space {}\n",
+                    serde_json::to_string(&resolved_space.name)?
+                );
+                write_yaml_map(&mut content, variables, 0);
+                content
+            }
+        };
+        let env_file_content = apply_final_newline(
+            env_file_content,
+            resolved_space.generate.final_newline.unwrap_or(true),
+        );
+        let env_file_content = resolved_space.generate.line_endings.apply(env_file_content);
+        fs.write(
+            &gen_folder.join(config_format.file_name()),
+            env_file_content.as_bytes(),
+        )
+        .await?;
+        return Ok(env_file_content.len());
     }
 
-    Ok(())
+    Ok(0)
+}
+
+/// Lays out the variables of a space as configured by its config layout.
+pub fn apply_layout(
+    variables: &Map<String, Value>,
+    space: &ResolvedSpace,
+) -> Result<Map<String, Value>, anyhow::Error> {
+    match space.generate.config_layout {
+        ConfigLayout::Nested => Ok(variables.clone()),
+        ConfigLayout::Environments => {
+            let mut top_level = Map::new();
+            let mut environments = Map::new();
+            for (key, value) in variables {
+                if space.environments.contains(key) {
+                    environments.insert(key.clone(), value.clone());
+                } else {
+                    top_level.insert(key.clone(), value.clone());
+                }
+            }
+            if top_level.contains_key("environments") {
+                return Err(anyhow::anyhow!(
+                    "The top-level variable 'environments' of the space {:?} conflicts with the environments config layout",
+                    space.name
+                ));
+            }
+            top_level.insert("environments".to_string(), Value::Object(environments));
+            Ok(top_level)
+        }
+    }
+}
+
+/// Writes the config of a space as pretty printed JSON to `writer`.
+/// With an environment, only the top-level variables merged with that environment's variables are written,
+/// like the config the bindings see at runtime. Without one, the variables of all environments are written.
+/// Returns the number of bytes written, nothing is written if the space has no variables.
+pub async fn write_config_to<W: AsyncWrite + Unpin>(
+    space: &ResolvedSpace,
+    env: Option<&str>,
+    mut writer: W,
+) -> Result<usize, anyhow::Error> {
+    let Some(variables) = &space.variables else {
+        return Ok(0);
+    };
+    let content = match env {
+        Some(env) => {
+            if !space.environments.contains(env) {
+                return Err(anyhow::anyhow!(
+                    "The space {:?} has no environment {:?}",
+                    space.name,
+                    env
+                ));
+            }
+            let mut merged = get_environment_value(variables, env)?;
+            for env in &space.environments {
+                merged.remove(env);
+            }
+            serde_json::to_string_pretty(&merged)?
+        }
+        None => serde_json::to_string_pretty(variables)?,
+    };
+    writer.write_all(content.as_bytes()).await?;
+    writer.flush().await?;
+    Ok(content.len())
+}
+
+const JSON5_INDENT: &str = "  ";
+
+/// Writes a value as pretty printed JSON5.
+/// Keys that are valid identifiers are not quoted, every element of an object or array ends with a comma.
+fn write_json5_value(output: &mut String, value: &Value, depth: usize) {
+    match value {
+        Value::Object(map) => write_json5_map(output, map, depth),
+        Value::Array(array) if array.is_empty() => output.push_str("[]"),
+        Value::Array(array) => {
+            output.push_str("[\n");
+            for element in array {
+                output.push_str(&JSON5_INDENT.repeat(depth + 1));
+                write_json5_value(output, element, depth + 1);
+                output.push_str(",\n");
+            }
+            output.push_str(&JSON5_INDENT.repeat(depth));
+            output.push(']');
+        }
+        // JSON scalars are valid JSON5
+        scalar => output.push_str(&scalar.to_string()),
+    }
+}
+
+fn write_json5_map(output: &mut String, map: &Map<String, Value>, depth: usize) {
+    if map.is_empty() {
+        output.push_str("{}");
+        return;
+    }
+    output.push_str("{\n");
+    for (key, value) in map {
+        output.push_str(&JSON5_INDENT.repeat(depth + 1));
+        if is_json5_identifier(key) {
+            output.push_str(key);
+        } else {
+            output.push_str(&Value::String(key.clone()).to_string());
+        }
+        output.push_str(": ");
+        write_json5_value(output, value, depth + 1);
+        output.push_str(",\n");
+    }
+    output.push_str(&JSON5_INDENT.repeat(depth));
+    output.push('}');
+}
+
+/// Whether a key can be written without quotes, limited to ASCII identifiers.
+fn is_json5_identifier(key: &str) -> bool {
+    let mut chars = key.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+}
+
+const YAML_INDENT: &str = "  ";
+
+/// Writes a map as block style YAML, one line per entry, starting at the current line.
+/// Scalars are written as JSON, which YAML parses to the same values,
+/// so strings are always quoted and never mistaken for numbers or booleans.
+/// Keys are sorted, as the map is sorted.
+fn write_yaml_map(output: &mut String, map: &Map<String, Value>, depth: usize) {
+    if map.is_empty() {
+        output.push_str(&YAML_INDENT.repeat(depth));
+        output.push_str("{}\n");
+        return;
+    }
+    for (key, value) in map {
+        output.push_str(&YAML_INDENT.repeat(depth));
+        if is_yaml_plain_key(key) {
+            output.push_str(key);
+        } else {
+            output.push_str(&Value::String(key.clone()).to_string());
+        }
+        output.push(':');
+        write_yaml_entry_value(output, value, depth);
+    }
+}
+
+fn write_yaml_array(output: &mut String, array: &[Value], depth: usize) {
+    for element in array {
+        output.push_str(&YAML_INDENT.repeat(depth));
+        output.push('-');
+        write_yaml_entry_value(output, element, depth);
+    }
+}
+
+/// Writes the value of a map entry or array element, inline if it is a scalar or empty,
+/// otherwise as a block on the following lines.
+fn write_yaml_entry_value(output: &mut String, value: &Value, depth: usize) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            output.push('\n');
+            write_yaml_map(output, map, depth + 1);
+        }
+        Value::Array(array) if !array.is_empty() => {
+            output.push('\n');
+            write_yaml_array(output, array, depth + 1);
+        }
+        Value::Object(_) => output.push_str(" {}\n"),
+        Value::Array(_) => output.push_str(" []\n"),
+        scalar => {
+            output.push(' ');
+            output.push_str(&scalar.to_string());
+            output.push('\n');
+        }
+    }
+}
+
+/// Whether a key can be written without quotes.
+/// Limited to ASCII identifiers that YAML does not read as a boolean or null.
+fn is_yaml_plain_key(key: &str) -> bool {
+    let reserved = ["true", "false", "null", "yes", "no", "on", "off", "y", "n"];
+    let mut chars = key.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        && !reserved.contains(&key.to_ascii_lowercase().as_str())
+}
+
+/// Prunes the variables of a space, keeping the environment objects even if they end up empty.
+fn prune_variables(variables: Map<String, Value>, space: &ResolvedSpace) -> Map<String, Value> {
+    variables
+        .into_iter()
+        .filter_map(|(key, value)| {
+            if space.environments.contains(&key) {
+                match value {
+                    Value::Object(map) => Some((key, Value::Object(prune_map(map)))),
+                    value => prune_empty(value).map(|value| (key, value)),
+                }
+            } else {
+                prune_empty(value).map(|value| (key, value))
+            }
+        })
+        .collect()
+}
+
+fn prune_map(map: Map<String, Value>) -> Map<String, Value> {
+    map.into_iter()
+        .filter_map(|(key, value)| prune_empty(value).map(|value| (key, value)))
+        .collect()
+}
+
+/// Removes empty objects, empty arrays and nulls, recursively.
+/// Returns `None` if the value itself is empty after pruning.
+fn prune_empty(value: Value) -> Option<Value> {
+    match value {
+        Value::Null => None,
+        Value::Object(map) => {
+            let map = prune_map(map);
+            (!map.is_empty()).then_some(Value::Object(map))
+        }
+        Value::Array(array) => {
+            let array: Vec<Value> = array.into_iter().filter_map(prune_empty).collect();
+            (!array.is_empty()).then_some(Value::Array(array))
+        }
+        value => Some(value),
+    }
+}
+
+fn canonicalize_map(map: Map<String, Value>) -> Map<String, Value> {
+    map.into_iter()
+        .map(|(key, value)| (key, canonicalize_numbers(value)))
+        .collect()
+}
+
+/// Turns integer valued floats into integers, recursively.
+/// Floats outside of the range of `i64` are kept, they are written in exponent notation.
+fn canonicalize_numbers(value: Value) -> Value {
+    match value {
+        Value::Number(number) => match number.as_f64() {
+            Some(float)
+                if number.is_f64()
+                    && float.fract() == 0.0
+                    && float >= i64::MIN as f64
+                    && float < i64::MAX as f64 =>
+            {
+                Value::from(float as i64)
+            }
+            _ => Value::Number(number),
+        },
+        Value::Object(map) => Value::Object(canonicalize_map(map)),
+        Value::Array(array) => Value::Array(array.into_iter().map(canonicalize_numbers).collect()),
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::write_config_to;
+    use crate::{
+        generate_weaveconfig, parse_jsonc::parse_jsonc, resolve_weaveconfig, test_utils::TempDir,
+    };
+    use serde_json::{json, Map, Value};
+
+    fn setup(prune_empty: bool) -> TempDir {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            &format!(
+                r#"{{ "name": "root", "environments": ["dev", "prod"], "generate": {{ "typescript": false, "prune_empty": {} }} }}"#,
+                prune_empty
+            ),
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{
+                "prod": { "db": { "host": "db", "options": {} } },
+                "shared": { "tags": [], "extra": null, "nested": { "empty": [{}] } },
+                "name": "app"
+            }"#,
+        );
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_unpruned_output() {
+        let dir = setup(false);
+        generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        let config: Value = serde_json::from_str(&dir.read("gen/config.json")).unwrap();
+        assert_eq!(
+            config,
+            json!({
+                "dev": {},
+                "prod": { "db": { "host": "db", "options": {} } },
+                "shared": { "tags": [], "extra": null, "nested": { "empty": [{}] } },
+                "name": "app"
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pruned_output() {
+        let dir = setup(true);
+        generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        let config: Value = serde_json::from_str(&dir.read("gen/config.json")).unwrap();
+        assert_eq!(
+            config,
+            json!({
+                "dev": {},
+                "prod": { "db": { "host": "db" } },
+                "name": "app"
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_json5_output() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev"], "generate": { "typescript": false, "config_format": "json5" } }"#,
+        );
+        let env = r#"{
+            "dev": { "db": { "host": "localhost", "port": 5432 } },
+            "service-name": "app",
+            "tags": ["a", "b: c"],
+            "empty": {},
+            "$ref": null
+        }"#;
+        dir.write("weaveconfig/_env.json", env);
+
+        generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        assert!(!dir.exists("gen/config.json"));
+        let content = dir.read("gen/config.json5");
+        assert!(content.contains("\n  dev: {\n"));
+        assert!(content.contains("\n  \"service-name\": \"app\",\n"));
+        assert!(content.ends_with("// Generated by weaveconfig for the space \"root\"\n"));
+
+        // Quote the unquoted keys again, comments and trailing commas are supported by the jsonc parser
+        let quoted = regex::Regex::new(r"(?m)^(\s*)([A-Za-z_$][A-Za-z0-9_$]*): ")
+            .unwrap()
+            .replace_all(&content, "$1\"$2\": ");
+        let parsed: Value = parse_jsonc(&quoted).unwrap();
+        let expected: Value = serde_json::from_str(env).unwrap();
+        assert_eq!(parsed, expected);
+    }
+
+    /// Parses the block style YAML written by [`write_yaml_map`], to check it round-trips.
+    fn parse_yaml_block(lines: &[(usize, &str)], index: &mut usize, indent: usize) -> Value {
+        let is_array = lines[*index].1.starts_with('-');
+        let mut map = Map::new();
+        let mut array = Vec::new();
+        while *index < lines.len() && lines[*index].0 == indent {
+            let content = lines[*index].1;
+            *index += 1;
+            let (key, rest) = if is_array {
+                (None, &content[1..])
+            } else {
+                let mut stream = serde_json::Deserializer::from_str(content).into_iter::<Value>();
+                let key = match content.starts_with('"') {
+                    true => stream
+                        .next()
+                        .unwrap()
+                        .unwrap()
+                        .as_str()
+                        .unwrap()
+                        .to_string(),
+                    false => content[..content.find(':').unwrap()].to_string(),
+                };
+                let offset = match content.starts_with('"') {
+                    true => stream.byte_offset(),
+                    false => key.len(),
+                };
+                (Some(key), &content[offset + 1..])
+            };
+            let value = match rest.trim() {
+                "" => parse_yaml_block(lines, index, indent + 2),
+                inline => serde_json::from_str(inline).unwrap(),
+            };
+            match key {
+                Some(key) => {
+                    map.insert(key, value);
+                }
+                None => array.push(value),
+            }
+        }
+        match is_array {
+            true => Value::Array(array),
+            false => Value::Object(map),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_yaml_output() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev"], "generate": { "typescript": false, "config_format": "yaml" } }"#,
+        );
+        let env = r#"{
+            "dev": { "db": { "host": "localhost", "port": 5432 } },
+            "service name": "app",
+            "yes": "true",
+            "tags": ["a", "b: c", { "nested": [1, [2, 3]] }, [], {}],
+            "empty": {},
+            "ratio": 0.5,
+            "unset": null
+        }"#;
+        dir.write("weaveconfig/_env.json", env);
+
+        generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        assert!(!dir.exists("gen/config.json"));
+        let content = dir.read("gen/config.yaml");
+        assert!(content.starts_with("# Generated by weaveconfig for the space \"root\"\n"));
+        assert!(content.contains("\ndev:\n  db:\n    host: \"localhost\"\n    port: 5432\n"));
+        assert!(content.contains("\n\"yes\": \"true\"\n"));
+        assert!(content.contains("\ntags:\n  - \"a\"\n  - \"b: c\"\n  -\n    nested:\n"));
+
+        let lines: Vec<(usize, &str)> = content
+            .lines()
+            .skip(1)
+            .map(|line| {
+                let trimmed = line.trim_start();
+                (line.len() - trimmed.len(), trimmed)
+            })
+            .collect();
+        let parsed = parse_yaml_block(&lines, &mut 0, 0);
+        let expected: Value = serde_json::from_str(env).unwrap();
+        assert_eq!(parsed, expected);
+    }
+
+    #[tokio::test]
+    async fn test_write_config_to_buffer() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"] }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "name": "app", "dev": { "port": 3000 }, "prod": { "port": 80 } }"#,
+        );
+        let resolved = resolve_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+        let space = &resolved["root"];
+
+        let mut buffer = Vec::new();
+        let written = write_config_to(space, Some("prod"), &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(written, buffer.len());
+        let config: Value = serde_json::from_slice(&buffer).unwrap();
+        assert_eq!(config, json!({ "name": "app", "port": 80 }));
+
+        let mut buffer = Vec::new();
+        write_config_to(space, None, &mut buffer).await.unwrap();
+        let config: Value = serde_json::from_slice(&buffer).unwrap();
+        assert_eq!(
+            config,
+            json!({ "name": "app", "dev": { "port": 3000 }, "prod": { "port": 80 } })
+        );
+
+        assert!(write_config_to(space, Some("staging"), Vec::new())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_environments_layout() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev", "prod"], "generate": { "typescript": true, "config_layout": "environments" } }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "name": "app", "dev": { "port": 3000 }, "prod": { "port": 80 } }"#,
+        );
+
+        generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        let config: Value = serde_json::from_str(&dir.read("gen/config.json")).unwrap();
+        assert_eq!(
+            config,
+            json!({
+                "name": "app",
+                "environments": { "dev": { "port": 3000 }, "prod": { "port": 80 } }
+            })
+        );
+        let binding = dir.read("gen/binding.ts");
+        assert!(binding.contains("type ConfigFileType = {"));
+        assert!(binding.contains("const { environments, ...rest } = config;"));
+    }
+
+    #[tokio::test]
+    async fn test_environments_layout_conflict() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "environments": ["dev"], "generate": { "typescript": false, "config_layout": "environments" } }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "environments": ["dev"], "dev": {} }"#,
+        );
+
+        assert!(generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_canonical_numbers() {
+        let dir = TempDir::new();
+        dir.write(
+            "weaveconfig/_space.json",
+            r#"{ "name": "root", "generate": { "typescript": false, "canonical_numbers": true } }"#,
+        );
+        dir.write(
+            "weaveconfig/_env.json",
+            r#"{ "decimal": 1.1, "large": 1e15, "whole": [2.0, -0.0], "third": 0.33333333333333333333, "huge": 1e300 }"#,
+        );
+
+        generate_weaveconfig(&dir.path().join("weaveconfig"))
+            .await
+            .unwrap();
+
+        let content = dir.read("gen/config.json");
+        assert!(content.contains("\"decimal\": 1.1,"));
+        assert!(content.contains("\"large\": 1000000000000000,"));
+        assert!(content.contains("\"third\": 0.3333333333333333,"));
+        assert!(content.contains("\"huge\": 1e300\n"));
+        assert_eq!(
+            serde_json::from_str::<Value>(&content).unwrap()["whole"],
+            json!([2, 0])
+        );
+    }
 }